@@ -0,0 +1,596 @@
+//! pg_sexp-core - binary encoding of pg_sexp values
+//!
+//! The `sexp` type stores a format version byte followed by one element in a
+//! tagged preorder encoding with varint lengths. This crate holds that
+//! encoding with no PostgreSQL dependency, so other extensions can build and
+//! read sexp values without linking the pg_sexp_rs extension itself:
+//!
+//! - [`Builder`] encodes a value atom by atom.
+//! - [`read`] gives a zero-copy [`Value`] view over an encoded value.
+//! - [`walk`] drives a [`Visitor`] over every node of a value.
+//!
+//! Encoded bytes are exactly the payload of a `sexp` datum: wrap them in a
+//! varlena to return a `sexp`, or return them as `bytea` and convert with
+//! `sexp_from_bytea`, which validates them. A dependent extension should
+//! list `requires = 'pg_sexp_rs'` in its control file so the type exists.
+//!
+//! ```
+//! use pg_sexp_core::{read, Builder, Value};
+//!
+//! let mut point = Builder::new();
+//! point.begin_list().symbol("point").int(1).int(2).end_list();
+//! let point = point.finish().unwrap();
+//!
+//! if let Value::List(items) = read(&point).unwrap() {
+//!     assert_eq!(items.get(0), Some(Value::Symbol("point")));
+//! }
+//! ```
+
+use std::fmt;
+
+/// On-disk format versions understood by this build
+pub mod format_versions {
+    /// Tagged preorder encoding with varint lengths
+    pub const V1: u8 = 1;
+}
+
+/// Binary format version used for all new values
+pub const FORMAT_VERSION: u8 = format_versions::V1;
+
+/// Type tags for binary encoding
+pub mod tags {
+    pub const NIL: u8 = 0x00;
+    pub const INTEGER: u8 = 0x01;
+    pub const FLOAT: u8 = 0x02;
+    pub const STRING: u8 = 0x03;
+    pub const SYMBOL: u8 = 0x04;
+    pub const LIST: u8 = 0x05;
+    pub const BOOL: u8 = 0x06;
+    pub const DECIMAL: u8 = 0x07;
+}
+
+// ============================================================================
+// Encoding
+// ============================================================================
+
+/// Destination of an encoding
+pub trait ByteSink {
+    fn push(&mut self, byte: u8);
+    fn extend_from_slice(&mut self, bytes: &[u8]);
+}
+
+impl ByteSink for Vec<u8> {
+    fn push(&mut self, byte: u8) {
+        Vec::push(self, byte);
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        Vec::extend_from_slice(self, bytes);
+    }
+}
+
+impl<W: ByteSink + ?Sized> ByteSink for &mut W {
+    fn push(&mut self, byte: u8) {
+        (**self).push(byte);
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        (**self).extend_from_slice(bytes);
+    }
+}
+
+pub fn write_varint(out: &mut impl ByteSink, mut value: u64) {
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+pub fn write_signed_varint(out: &mut impl ByteSink, value: i64) {
+    // Zigzag encoding
+    let encoded = ((value << 1) ^ (value >> 63)) as u64;
+    write_varint(out, encoded);
+}
+
+pub fn write_string(out: &mut impl ByteSink, s: &str) {
+    let bytes = s.as_bytes();
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Plain decimal text: optional sign, digits, optional fraction
+pub fn is_decimal_literal(s: &str) -> bool {
+    let unsigned = s.strip_prefix(['-', '+']).unwrap_or(s);
+    let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, "0"));
+    !int_part.is_empty()
+        && !frac_part.is_empty()
+        && int_part.bytes().all(|c| c.is_ascii_digit())
+        && frac_part.bytes().all(|c| c.is_ascii_digit())
+}
+
+// ============================================================================
+// Decoding
+// ============================================================================
+
+/// Strict varint read: fails on truncation or values wider than 64 bits
+pub fn read_varint_checked(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut result: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or("truncated varint")?;
+        *pos += 1;
+        if shift == 63 && byte > 1 {
+            return Err("varint overflows 64 bits".to_string());
+        }
+        result |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err("varint overflows 64 bits".to_string())
+}
+
+fn zigzag_decode(encoded: u64) -> i64 {
+    ((encoded >> 1) as i64) ^ (-((encoded & 1) as i64))
+}
+
+/// End offset of a length-prefixed payload starting at `pos`
+pub fn checked_end(data: &[u8], pos: usize, len: u64, what: &str) -> Result<usize, String> {
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| pos.checked_add(len))
+        .filter(|&end| end <= data.len())
+        .ok_or_else(|| format!("{} exceeds data", what))
+}
+
+pub fn try_skip_element(data: &[u8], pos: &mut usize) -> Result<(), String> {
+    // Elements still to skip; a list adds its children instead of recursing
+    let mut remaining: u64 = 1;
+    while remaining > 0 {
+        remaining -= 1;
+        skip_element_header(data, pos, &mut remaining)?;
+    }
+    Ok(())
+}
+
+/// Skip one atom, or a list's header, adding the list's children to `remaining`
+pub fn skip_element_header(data: &[u8], pos: &mut usize, remaining: &mut u64) -> Result<(), String> {
+    let tag = *data.get(*pos).ok_or("truncated element")?;
+    *pos += 1;
+
+    match tag {
+        tags::NIL => {}
+        tags::INTEGER => {
+            read_varint_checked(data, pos)?;
+        }
+        tags::FLOAT => {
+            *pos = checked_end(data, *pos, 8, "float")?;
+        }
+        tags::BOOL => {
+            *pos = checked_end(data, *pos, 1, "boolean")?;
+        }
+        tags::STRING | tags::SYMBOL | tags::DECIMAL => {
+            let len = read_varint_checked(data, pos)?;
+            *pos = checked_end(data, *pos, len, "string length")?;
+        }
+        tags::LIST => {
+            let count = read_varint_checked(data, pos)?;
+            *remaining = remaining.checked_add(count).ok_or("invalid list length")?;
+        }
+        other => return Err(format!("unknown tag 0x{:02x}", other)),
+    }
+    Ok(())
+}
+
+/// A structural problem in a binary value and the byte offset of the
+/// element where it was found
+#[derive(Debug, PartialEq)]
+pub struct BinaryError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+/// Validate a complete element encoding (everything after the version byte)
+pub fn validate_elements(data: &[u8], mut pos: usize) -> Result<(), BinaryError> {
+    // Remaining child counts of the open lists; starts with the single root
+    let mut pending: Vec<u64> = vec![1];
+    while let Some(remaining) = pending.last_mut() {
+        if *remaining == 0 {
+            pending.pop();
+            continue;
+        }
+        *remaining -= 1;
+
+        let offset = pos;
+        let fail = |message: String| BinaryError { offset, message };
+        let tag = *data.get(pos).ok_or_else(|| fail("truncated element".to_string()))?;
+        pos += 1;
+        match tag {
+            tags::NIL => {}
+            tags::INTEGER => {
+                read_varint_checked(data, &mut pos).map_err(fail)?;
+            }
+            tags::FLOAT => {
+                pos = pos
+                    .checked_add(8)
+                    .filter(|&end| end <= data.len())
+                    .ok_or_else(|| fail("truncated float".to_string()))?;
+            }
+            tags::BOOL => match data.get(pos) {
+                Some(0) | Some(1) => pos += 1,
+                Some(b) => return Err(fail(format!("invalid boolean byte 0x{:02x}", b))),
+                None => return Err(fail("truncated boolean".to_string())),
+            },
+            tags::STRING | tags::SYMBOL | tags::DECIMAL => {
+                let len = read_varint_checked(data, &mut pos).map_err(fail)?;
+                let end = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| pos.checked_add(len))
+                    .filter(|&end| end <= data.len())
+                    .ok_or_else(|| fail("string length exceeds data".to_string()))?;
+                std::str::from_utf8(&data[pos..end]).map_err(|_| fail("invalid UTF-8 in string".to_string()))?;
+                pos = end;
+            }
+            tags::LIST => {
+                let count = read_varint_checked(data, &mut pos).map_err(fail)?;
+                // Each element takes at least one byte; empty lists are encoded as nil
+                if count == 0 || count > (data.len() - pos) as u64 {
+                    return Err(fail(format!("invalid list length {}", count)));
+                }
+                pending.push(count);
+            }
+            other => return Err(fail(format!("unknown tag 0x{:02x}", other))),
+        }
+    }
+
+    if pos != data.len() {
+        return Err(BinaryError { offset: pos, message: "trailing data".to_string() });
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Reading
+// ============================================================================
+
+/// Borrowed view of one element of an encoded value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Nil,
+    Integer(i64),
+    Float(f64),
+    String(&'a str),
+    Symbol(&'a str),
+    Bool(bool),
+    /// Exact decimal, as its digits
+    Decimal(&'a str),
+    List(List<'a>),
+}
+
+/// Borrowed view of a non-empty list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct List<'a> {
+    body: &'a [u8],
+    len: usize,
+}
+
+impl<'a> List<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Elements in order, decoded as they are reached
+    pub fn iter(&self) -> Elements<'a> {
+        Elements { data: self.body, pos: 0, remaining: self.len }
+    }
+
+    /// Element at `index`, skipping the ones before it without decoding them
+    pub fn get(&self, index: usize) -> Option<Value<'a>> {
+        if index >= self.len {
+            return None;
+        }
+        let mut pos = 0;
+        for _ in 0..index {
+            skip_validated(self.body, &mut pos);
+        }
+        Some(read_value(self.body, &mut pos))
+    }
+}
+
+impl<'a> IntoIterator for List<'a> {
+    type Item = Value<'a>;
+    type IntoIter = Elements<'a>;
+
+    fn into_iter(self) -> Elements<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the elements of a [`List`]
+#[derive(Debug, Clone)]
+pub struct Elements<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Value<'a>;
+
+    fn next(&mut self) -> Option<Value<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(read_value(self.data, &mut self.pos))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// View the top-level element of an encoded value (version byte included).
+/// The whole encoding is validated first, so the view itself never fails.
+pub fn read(encoded: &[u8]) -> Result<Value<'_>, BinaryError> {
+    match encoded.first() {
+        None => return Err(BinaryError { offset: 0, message: "empty input".to_string() }),
+        Some(&FORMAT_VERSION) => {}
+        Some(v) => return Err(BinaryError { offset: 0, message: format!("unsupported format version {}", v) }),
+    }
+    validate_elements(encoded, 1)?;
+    Ok(read_value(encoded, &mut 1))
+}
+
+// The readers below only ever see data that passed validate_elements
+
+fn skip_validated(data: &[u8], pos: &mut usize) {
+    try_skip_element(data, pos).expect("validated encoding");
+}
+
+fn read_varint_validated(data: &[u8], pos: &mut usize) -> u64 {
+    read_varint_checked(data, pos).expect("validated encoding")
+}
+
+fn read_str<'a>(data: &'a [u8], pos: &mut usize) -> &'a str {
+    let len = read_varint_validated(data, pos) as usize;
+    let text = std::str::from_utf8(&data[*pos..*pos + len]).expect("validated encoding");
+    *pos += len;
+    text
+}
+
+fn read_value<'a>(data: &'a [u8], pos: &mut usize) -> Value<'a> {
+    let start = *pos;
+    let tag = data[start];
+    *pos += 1;
+    match tag {
+        tags::INTEGER => Value::Integer(zigzag_decode(read_varint_validated(data, pos))),
+        tags::FLOAT => {
+            let bytes = &data[*pos..*pos + 8];
+            *pos += 8;
+            Value::Float(f64::from_le_bytes(bytes.try_into().unwrap()))
+        }
+        tags::BOOL => {
+            *pos += 1;
+            Value::Bool(data[*pos - 1] != 0)
+        }
+        tags::STRING => Value::String(read_str(data, pos)),
+        tags::SYMBOL => Value::Symbol(read_str(data, pos)),
+        tags::DECIMAL => Value::Decimal(read_str(data, pos)),
+        tags::LIST => {
+            let len = read_varint_validated(data, pos) as usize;
+            let body = *pos;
+            *pos = start;
+            skip_validated(data, pos);
+            Value::List(List { body: &data[body..*pos], len })
+        }
+        _ => Value::Nil,
+    }
+}
+
+// ============================================================================
+// Visiting
+// ============================================================================
+
+/// Callbacks for [`walk`]. Nil is reported as an atom.
+pub trait Visitor<'a> {
+    fn atom(&mut self, value: Value<'a>);
+
+    fn enter_list(&mut self, _len: usize) {}
+
+    fn leave_list(&mut self) {}
+}
+
+/// Visit every node of an encoded value in preorder. The walk keeps its own
+/// stack rather than recursing, and fails before entering a list nested
+/// deeper than `max_depth`.
+pub fn walk<'a>(encoded: &'a [u8], visitor: &mut impl Visitor<'a>, max_depth: usize) -> Result<(), String> {
+    let mut open: Vec<Elements<'a>> = Vec::new();
+    let mut next = Some(read(encoded).map_err(|e| e.to_string())?);
+    loop {
+        let value = match next.take() {
+            Some(value) => value,
+            None => match open.last_mut().map(Iterator::next) {
+                None => return Ok(()),
+                Some(Some(value)) => value,
+                Some(None) => {
+                    open.pop();
+                    visitor.leave_list();
+                    continue;
+                }
+            },
+        };
+        match value {
+            Value::List(list) => {
+                if open.len() >= max_depth {
+                    return Err(format!("nesting deeper than {}", max_depth));
+                }
+                visitor.enter_list(list.len());
+                open.push(list.iter());
+            }
+            atom => visitor.atom(atom),
+        }
+    }
+}
+
+// ============================================================================
+// Building
+// ============================================================================
+
+/// Encodes a single value. Lists are opened and closed around their
+/// elements; [`Builder::finish`] checks that exactly one complete value was
+/// written and reports the first misuse, such as an invalid decimal.
+///
+/// A list header holds its element count, which is only known once the list
+/// is closed, so headers are kept aside and written in front of their bodies
+/// by `finish`, in one pass over the data.
+#[derive(Debug, Default)]
+pub struct Builder {
+    /// Elements without their list headers
+    data: Vec<u8>,
+    /// Body offset and element count of every list, in the order opened
+    lists: Vec<(usize, u64)>,
+    /// Indexes into `lists` of the lists still open
+    open: Vec<usize>,
+    roots: usize,
+    error: Option<String>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    fn element(&mut self) -> &mut Vec<u8> {
+        match self.open.last() {
+            Some(&list) => self.lists[list].1 += 1,
+            None => self.roots += 1,
+        }
+        &mut self.data
+    }
+
+    fn fail(&mut self, message: String) -> &mut Self {
+        self.error.get_or_insert(message);
+        self
+    }
+
+    fn text(&mut self, tag: u8, text: &str) -> &mut Self {
+        let out = self.element();
+        out.push(tag);
+        write_string(out, text);
+        self
+    }
+
+    pub fn nil(&mut self) -> &mut Self {
+        self.element().push(tags::NIL);
+        self
+    }
+
+    pub fn int(&mut self, value: i64) -> &mut Self {
+        let out = self.element();
+        out.push(tags::INTEGER);
+        write_signed_varint(out, value);
+        self
+    }
+
+    pub fn float(&mut self, value: f64) -> &mut Self {
+        let out = self.element();
+        out.push(tags::FLOAT);
+        out.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.element().extend_from_slice(&[tags::BOOL, value as u8]);
+        self
+    }
+
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.text(tags::STRING, value)
+    }
+
+    pub fn symbol(&mut self, name: &str) -> &mut Self {
+        self.text(tags::SYMBOL, name)
+    }
+
+    /// Exact decimal from its digits, e.g. `"12.30"`
+    pub fn decimal(&mut self, digits: &str) -> &mut Self {
+        if !is_decimal_literal(digits) {
+            return self.fail(format!("invalid decimal literal: {}", digits));
+        }
+        self.text(tags::DECIMAL, digits.trim_start_matches('+'))
+    }
+
+    /// Copy an existing encoded value (version byte included) in as the next
+    /// element
+    pub fn value(&mut self, encoded: &[u8]) -> &mut Self {
+        if let Err(e) = read(encoded) {
+            return self.fail(format!("invalid value: {}", e));
+        }
+        self.element().extend_from_slice(&encoded[1..]);
+        self
+    }
+
+    pub fn begin_list(&mut self) -> &mut Self {
+        self.element();
+        self.open.push(self.lists.len());
+        self.lists.push((self.data.len(), 0));
+        self
+    }
+
+    /// Close the innermost list; an empty list is written as nil
+    pub fn end_list(&mut self) -> &mut Self {
+        if self.open.pop().is_none() {
+            return self.fail("end_list without begin_list".to_string());
+        }
+        self
+    }
+
+    /// The finished encoding, version byte first
+    pub fn finish(self) -> Result<Vec<u8>, String> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if !self.open.is_empty() {
+            return Err(format!("{} list(s) left open", self.open.len()));
+        }
+        if self.roots != 1 {
+            return Err(format!("expected one value, got {}", self.roots));
+        }
+        // Offsets never decrease in opening order, and a list opened at the
+        // same offset as another is nested in it, so its header goes second
+        let mut data = Vec::with_capacity(self.data.len() + 1 + 2 * self.lists.len());
+        data.push(FORMAT_VERSION);
+        let mut copied = 0;
+        for &(body, count) in &self.lists {
+            data.extend_from_slice(&self.data[copied..body]);
+            copied = body;
+            if count == 0 {
+                data.push(tags::NIL);
+            } else {
+                data.push(tags::LIST);
+                write_varint(&mut data, count);
+            }
+        }
+        data.extend_from_slice(&self.data[copied..]);
+        Ok(data)
+    }
+}
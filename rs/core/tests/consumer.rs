@@ -0,0 +1,156 @@
+//! Builds and reads values the way a dependent extension would, through the
+//! public API only
+
+use pg_sexp_core::{read, tags, walk, Builder, Value, Visitor, FORMAT_VERSION};
+
+fn point() -> Vec<u8> {
+    let mut tag = Builder::new();
+    tag.begin_list().symbol("tag").symbol("x").end_list();
+    let tag = tag.finish().unwrap();
+
+    let mut builder = Builder::new();
+    builder
+        .begin_list()
+        .symbol("point")
+        .int(-3)
+        .float(1.5)
+        .string("a \"b\"")
+        .begin_list()
+        .end_list()
+        .value(&tag)
+        .decimal("+12.30")
+        .bool(true)
+        .end_list();
+    builder.finish().unwrap()
+}
+
+#[test]
+fn builds_the_extension_encoding() {
+    let mut builder = Builder::new();
+    builder.begin_list().symbol("a").int(-1).end_list();
+    assert_eq!(
+        builder.finish().unwrap(),
+        [FORMAT_VERSION, tags::LIST, 2, tags::SYMBOL, 1, b'a', tags::INTEGER, 1]
+    );
+
+    let mut empty = Builder::new();
+    empty.begin_list().end_list();
+    assert_eq!(empty.finish().unwrap(), [FORMAT_VERSION, tags::NIL]);
+}
+
+#[test]
+fn builds_nested_lists_in_one_pass() {
+    let mut nested = Builder::new();
+    nested
+        .begin_list()
+        .begin_list()
+        .begin_list()
+        .end_list()
+        .end_list()
+        .begin_list()
+        .symbol("a")
+        .end_list()
+        .end_list();
+    assert_eq!(
+        nested.finish().unwrap(),
+        [FORMAT_VERSION, tags::LIST, 2, tags::LIST, 1, tags::NIL, tags::LIST, 1, tags::SYMBOL, 1, b'a']
+    );
+
+    // Deep nesting stays linear: headers aren't spliced in list by list
+    let depth = 200_000;
+    let mut deep = Builder::new();
+    for _ in 0..depth {
+        deep.begin_list();
+    }
+    deep.int(1);
+    for _ in 0..depth {
+        deep.end_list();
+    }
+    let deep = deep.finish().unwrap();
+    assert_eq!(deep.len(), 1 + 2 * depth + 2);
+    assert_eq!(&deep[deep.len() - 4..], [tags::LIST, 1, tags::INTEGER, 2]);
+}
+
+#[test]
+fn reads_lists_and_atoms() {
+    let point = point();
+    let Value::List(items) = read(&point).unwrap() else {
+        panic!("expected a list");
+    };
+    assert_eq!(items.len(), 8);
+    assert_eq!(items.get(0), Some(Value::Symbol("point")));
+    assert_eq!(items.iter().nth(1), Some(Value::Integer(-3)));
+    assert_eq!(items.get(2), Some(Value::Float(1.5)));
+    assert_eq!(items.get(3), Some(Value::String("a \"b\"")));
+    assert_eq!(items.get(4), Some(Value::Nil));
+    assert_eq!(items.get(6), Some(Value::Decimal("12.30")));
+    assert_eq!(items.get(7), Some(Value::Bool(true)));
+    assert_eq!(items.get(8), None);
+    let Some(Value::List(tag)) = items.get(5) else {
+        panic!("expected a nested list");
+    };
+    assert_eq!(tag.iter().collect::<Vec<_>>(), [Value::Symbol("tag"), Value::Symbol("x")]);
+}
+
+#[test]
+fn rejects_corrupt_input() {
+    let mut point = point();
+    point.pop();
+    assert!(read(&point).is_err());
+    assert!(read(&[]).is_err());
+    assert!(read(&[9, tags::NIL]).is_err());
+    assert!(read(&[FORMAT_VERSION, tags::LIST, 200, tags::NIL]).is_err());
+}
+
+#[test]
+fn reports_builder_misuse() {
+    let mut open = Builder::new();
+    open.begin_list().int(1);
+    assert!(open.finish().is_err());
+
+    let mut two = Builder::new();
+    two.int(1).int(2);
+    assert!(two.finish().is_err());
+
+    let mut unbalanced = Builder::new();
+    unbalanced.int(1).end_list();
+    assert!(unbalanced.finish().is_err());
+
+    let mut decimal = Builder::new();
+    decimal.decimal("1e5");
+    assert_eq!(decimal.finish(), Err("invalid decimal literal: 1e5".to_string()));
+}
+
+#[derive(Default)]
+struct Symbols<'a> {
+    names: Vec<&'a str>,
+    lists: usize,
+    closed: usize,
+}
+
+impl<'a> Visitor<'a> for Symbols<'a> {
+    fn atom(&mut self, value: Value<'a>) {
+        if let Value::Symbol(name) = value {
+            self.names.push(name);
+        }
+    }
+
+    fn enter_list(&mut self, _len: usize) {
+        self.lists += 1;
+    }
+
+    fn leave_list(&mut self) {
+        self.closed += 1;
+    }
+}
+
+#[test]
+fn walks_in_preorder() {
+    let point = point();
+    let mut symbols = Symbols::default();
+    walk(&point, &mut symbols, 100).unwrap();
+    assert_eq!(symbols.names, ["point", "tag", "x"]);
+    assert_eq!((symbols.lists, symbols.closed), (2, 2));
+
+    assert!(walk(&point, &mut Symbols::default(), 1).is_err());
+}
@@ -5,23 +5,305 @@
 
 use pgrx::prelude::*;
 use pgrx::datum::Internal;
+use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting};
+use pgrx::PostgresGucEnum;
 use serde::{Serialize, Deserialize};
 use std::fmt;
 
+// The encoding itself lives in pg_sexp-core, which other extensions can
+// depend on without linking this one
+use pg_sexp_core::{
+    checked_end, format_versions, is_decimal_literal, read_varint_checked, skip_element_header, tags,
+    try_skip_element, validate_elements, write_signed_varint, write_string, write_varint, BinaryError, ByteSink,
+    FORMAT_VERSION,
+};
+
 pgrx::pg_module_magic!();
 
-/// Binary format version for Rust implementation
-const FORMAT_VERSION: u8 = 1;
+// ============================================================================
+// Configuration
+// ============================================================================
 
-/// Type tags for binary encoding
-mod tags {
-    pub const NIL: u8 = 0x00;
-    pub const INTEGER: u8 = 0x01;
-    pub const FLOAT: u8 = 0x02;
-    pub const STRING: u8 = 0x03;
-    pub const SYMBOL: u8 = 0x04;
-    pub const LIST: u8 = 0x05;
-    pub const BOOL: u8 = 0x06;
+/// Decimal places for floats printed by sexp_to_text and the tree display;
+/// -1 prints the shortest text that parses back to the identical value. The
+/// text output function always prints the shortest form.
+static FLOAT_PRECISION: GucSetting<i32> = GucSetting::<i32>::new(-1);
+
+/// How the empty list prints
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Debug)]
+enum NilOutput {
+    Parens,
+    Nil,
+}
+
+static NIL_OUTPUT: GucSetting<NilOutput> = GucSetting::<NilOutput>::new(NilOutput::Parens);
+
+/// Read `nil` as an ordinary symbol, distinct from the empty list `()`
+static NIL_IS_SYMBOL: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Also read `true`/`false` as booleans (`#t`/`#f` always are)
+static PARSE_TRUE_FALSE: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Read every plain decimal literal as an exact decimal rather than a float
+static EXACT_DECIMALS: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Literal syntax read by sexp_parse and the other parsing functions and
+/// printed by sexp_to_text
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum Dialect {
+    /// `#t`/`#f`
+    Scheme,
+    /// `?a` characters, `[...]` vectors, `#s(...)` records, dotted pairs;
+    /// `t`/`nil`
+    Elisp,
+    /// `[...]` vectors, `{...}` maps, `#{...}` sets, commas as whitespace;
+    /// `true`/`false`
+    Clojure,
+    /// SMT-LIB 2: `""` escapes in strings, plain decimals read exactly;
+    /// `true`/`false`. Bitvector literals such as `#b0101` and `#x1f` and
+    /// `:keyword` attributes read as symbols, so they print back unchanged,
+    /// bitvector width included.
+    Smtlib,
+    /// `yes`/`no`
+    Kicad,
+}
+
+static DIALECT: GucSetting<Dialect> = GucSetting::<Dialect>::new(Dialect::Scheme);
+
+/// Deepest nesting that containment and pattern matching will walk
+static MAX_DEPTH: GucSetting<i32> = GucSetting::<i32>::new(10000);
+
+/// Most GIN keys extracted from one value before it is indexed coarsely
+static GIN_MAX_KEYS: GucSetting<i32> = GucSetting::<i32>::new(1024);
+
+/// Most facts sexp_query may hold, source rows included
+static QUERY_MAX_FACTS: GucSetting<i32> = GucSetting::<i32>::new(1_000_000);
+
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    GucRegistry::define_int_guc(
+        c"pg_sexp.float_precision",
+        c"Decimal places used when printing floats (-1 = shortest round-trip form).",
+        c"Applies to sexp_to_text and sexp_to_tree_text; the sexp text output always prints the shortest round-trip form, so COPY and pg_dump keep every float exact.",
+        &FLOAT_PRECISION,
+        -1,
+        17,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        c"pg_sexp.nil_output",
+        c"Text used to print the empty list: parens for () or nil.",
+        c"",
+        &NIL_OUTPUT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_sexp.nil_is_symbol",
+        c"Parse nil as a symbol distinct from the empty list ().",
+        c"For Lisp dialects where nil and () are different values. Applies to sexp_parse and the other parsing functions; the sexp input function always reads nil as ().",
+        &NIL_IS_SYMBOL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_sexp.parse_true_false",
+        c"Parse the symbols true and false as booleans.",
+        c"#t and #f are always read as booleans. Applies to sexp_parse and the other parsing functions, not to the sexp input function.",
+        &PARSE_TRUE_FALSE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_sexp.exact_decimals",
+        c"Parse decimal literals such as 12.30 as exact decimals instead of floats.",
+        c"Literals with an m suffix (12.30m) are always exact. Applies to sexp_parse and the other parsing functions, not to the sexp input function.",
+        &EXACT_DECIMALS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        c"pg_sexp.dialect",
+        c"Lisp dialect whose literal syntax is read by sexp_parse and the other parsing functions.",
+        c"One of scheme, elisp, clojure, smtlib or kicad. The sexp input and output functions always use the canonical syntax, so dumps restore unchanged; use sexp_to_text to print in a dialect.",
+        &DIALECT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_sexp.max_depth",
+        c"Maximum nesting depth walked by containment and pattern matching.",
+        c"Deeper values raise an error instead of being searched.",
+        &MAX_DEPTH,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_sexp.gin_max_keys",
+        c"Maximum GIN index keys extracted from one sexp value.",
+        c"Values with more keys are indexed by their list heads only and always rechecked.",
+        &GIN_MAX_KEYS,
+        16,
+        1 << 20,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_sexp.query_max_facts",
+        c"Maximum facts sexp_query may hold, source rows included.",
+        c"Rules that keep deriving new facts raise an error once this many are known.",
+        &QUERY_MAX_FACTS,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        pgrx::pg_shmem_init!(STAT_PARSES);
+        pgrx::pg_shmem_init!(STAT_PARSE_ERRORS);
+        pgrx::pg_shmem_init!(STAT_PATTERN_MATCHES);
+        pgrx::pg_shmem_init!(STAT_GIN_RECHECKS);
+        pgrx::pg_shmem_init!(STAT_BYTES_DECODED);
+        STATS_ENABLED.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    unsafe { pg_sys::RegisterSubXactCallback(Some(symbol_dicts_subxact_callback), std::ptr::null_mut()) };
+}
+
+/// Raise an error when a walk goes deeper than pg_sexp.max_depth
+fn check_depth(depth: usize) {
+    let max = MAX_DEPTH.get();
+    if depth > max as usize {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_STATEMENT_TOO_COMPLEX,
+            format!("sexp nesting depth exceeds pg_sexp.max_depth ({})", max)
+        );
+    }
+}
+
+/// Printed form of the empty list
+fn nil_text() -> &'static str {
+    match NIL_OUTPUT.get() {
+        NilOutput::Parens => "()",
+        NilOutput::Nil => "nil",
+    }
+}
+
+/// Quoted name of the schema the extension lives in, taken from the calling
+/// function's own catalog entry so that search_path cannot redirect the
+/// queries built with it
+fn extension_schema(fcinfo: pg_sys::FunctionCallInfo) -> String {
+    unsafe {
+        let namespace = pg_sys::get_func_namespace((*(*fcinfo).flinfo).fn_oid);
+        let name = pg_sys::get_namespace_name(namespace);
+        std::ffi::CStr::from_ptr(pg_sys::quote_identifier(name)).to_string_lossy().into_owned()
+    }
+}
+
+/// Oid of the sexp type, read from the calling function's declared result
+/// type rather than looked up by name; callers must return sexp
+fn sexp_type_oid(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Oid {
+    unsafe { pg_sys::get_func_rettype((*(*fcinfo).flinfo).fn_oid) }
+}
+
+// ============================================================================
+// Statistics
+// ============================================================================
+//
+// Counters live in shared memory, which only exists when pg_sexp_rs is in
+// shared_preload_libraries. Without it counting is skipped and the
+// pg_sexp_stat view shows NULLs.
+
+type StatCounter = pgrx::PgAtomic<std::sync::atomic::AtomicU64>;
+
+static STAT_PARSES: StatCounter = unsafe { pgrx::PgAtomic::new(c"pg_sexp_stat_parses") };
+static STAT_PARSE_ERRORS: StatCounter = unsafe { pgrx::PgAtomic::new(c"pg_sexp_stat_parse_errors") };
+static STAT_PATTERN_MATCHES: StatCounter = unsafe { pgrx::PgAtomic::new(c"pg_sexp_stat_pattern_matches") };
+static STAT_GIN_RECHECKS: StatCounter = unsafe { pgrx::PgAtomic::new(c"pg_sexp_stat_gin_rechecks") };
+static STAT_BYTES_DECODED: StatCounter = unsafe { pgrx::PgAtomic::new(c"pg_sexp_stat_bytes_decoded") };
+
+/// Set by _PG_init once the counters have been requested in shared memory
+static STATS_ENABLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+fn count_stat(counter: &StatCounter, n: u64) {
+    if STATS_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+        counter.get().fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Cluster-wide activity counters since startup or the last
+/// sexp_stat_reset(); NULL when shared memory is unavailable
+#[pg_extern(name = "sexp_stat", volatile, parallel_safe)]
+fn sexp_stat() -> TableIterator<
+    'static,
+    (
+        name!(parses, Option<i64>),
+        name!(parse_errors, Option<i64>),
+        name!(pattern_matches, Option<i64>),
+        name!(gin_rechecks, Option<i64>),
+        name!(bytes_decoded, Option<i64>),
+    ),
+> {
+    let read = |counter: &StatCounter| {
+        STATS_ENABLED
+            .load(std::sync::atomic::Ordering::Relaxed)
+            .then(|| counter.get().load(std::sync::atomic::Ordering::Relaxed) as i64)
+    };
+    TableIterator::once((
+        read(&STAT_PARSES),
+        read(&STAT_PARSE_ERRORS),
+        read(&STAT_PATTERN_MATCHES),
+        read(&STAT_GIN_RECHECKS),
+        read(&STAT_BYTES_DECODED),
+    ))
+}
+
+/// Zero every counter
+#[pg_extern(name = "sexp_stat_reset", volatile, parallel_safe)]
+fn sexp_stat_reset() {
+    if !STATS_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
+    for counter in [&STAT_PARSES, &STAT_PARSE_ERRORS, &STAT_PATTERN_MATCHES, &STAT_GIN_RECHECKS, &STAT_BYTES_DECODED] {
+        counter.get().store(0, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW pg_sexp_stat AS SELECT * FROM sexp_stat();
+"#,
+    name = "pg_sexp_stat",
+    requires = [sexp_stat]
+);
+
+extension_sql!(
+    r#"
+-- The counters are shared by the whole cluster, so only superusers and roles
+-- granted EXECUTE may reset them
+REVOKE EXECUTE ON FUNCTION sexp_stat_reset() FROM PUBLIC;
+"#,
+    name = "sexp_stat_reset_privileges",
+    requires = [sexp_stat_reset]
+);
+
+/// Printed form of a boolean in a dialect
+fn bool_text(b: bool, dialect: Dialect) -> &'static str {
+    match (dialect, b) {
+        (Dialect::Scheme, true) => "#t",
+        (Dialect::Scheme, false) => "#f",
+        (Dialect::Elisp, true) => "t",
+        (Dialect::Elisp, false) => "nil",
+        (Dialect::Clojure | Dialect::Smtlib, true) => "true",
+        (Dialect::Clojure | Dialect::Smtlib, false) => "false",
+        (Dialect::Kicad, true) => "yes",
+        (Dialect::Kicad, false) => "no",
+    }
 }
 
 // ============================================================================
@@ -35,22 +317,130 @@ enum ParsedExpr {
     Integer(i64),
     Float(f64),
     String(String),
-    Symbol(String),
+    /// Symbol names are shared through the per-backend intern cache
+    Symbol(std::rc::Rc<str>),
+    Bool(bool),
+    /// Exact decimal, kept as its canonical text
+    Decimal(String),
     List(Vec<ParsedExpr>),
 }
 
+/// Most symbols the intern cache holds; later new names are allocated
+/// without being cached, so hostile input cannot grow it without bound
+const SYMBOL_CACHE_CAPACITY: usize = 4096;
+
+/// Longest symbol worth caching
+const SYMBOL_CACHE_MAX_LEN: usize = 64;
+
+thread_local! {
+    static SYMBOL_CACHE: std::cell::RefCell<std::collections::HashSet<std::rc::Rc<str>>> =
+        std::cell::RefCell::new(std::collections::HashSet::new());
+}
+
+/// Shared copy of a symbol name. Documents mostly reuse a small vocabulary,
+/// so bulk parsing hands out the same allocation instead of a fresh String
+/// per occurrence.
+fn intern_symbol(name: &str) -> std::rc::Rc<str> {
+    if name.len() > SYMBOL_CACHE_MAX_LEN {
+        return name.into();
+    }
+    SYMBOL_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if let Some(symbol) = cache.get(name) {
+            return symbol.clone();
+        }
+        let symbol: std::rc::Rc<str> = name.into();
+        if cache.len() < SYMBOL_CACHE_CAPACITY {
+            cache.insert(symbol.clone());
+        }
+        symbol
+    })
+}
+
 /// Parse state
 struct Parser<'a> {
     input: &'a [u8],
     pos: usize,
+    settings: ParseSettings,
+    /// Last closing-pipe search: where it started and the pipe it found
+    pipe_scan: Option<(usize, Option<usize>)>,
 }
 
 impl<'a> Parser<'a> {
-    fn new(input: &'a str) -> Self {
+    /// Parser for a dialect's literal syntax, with no other reader options
+    fn with_dialect(input: &'a str, dialect: Dialect) -> Self {
+        Parser::with_settings(input, ParseSettings { dialect, ..ParseSettings::CANONICAL })
+    }
+
+    fn with_settings(input: &'a str, settings: ParseSettings) -> Self {
         Parser {
             input: input.as_bytes(),
             pos: 0,
+            settings,
+            pipe_scan: None,
+        }
+    }
+
+    /// Bytes that end an atom
+    fn is_delimiter(&self, c: u8) -> bool {
+        self.is_whitespace(c)
+            || matches!(c, b'(' | b')' | b'"' | b';')
+            || match self.settings.dialect {
+                Dialect::Elisp => matches!(c, b'[' | b']'),
+                Dialect::Clojure => matches!(c, b'[' | b']' | b'{' | b'}'),
+                _ => false,
+            }
+    }
+
+    /// Whether the `|` at the cursor opens a `|...|` symbol, as in Scheme and
+    /// SMT-LIB. Pipes used to be ordinary symbol characters in the default
+    /// Scheme dialect, so there a symbol is only quoted when a closing pipe
+    /// ends a later token: `||` on its own and tokens such as `|>` with no
+    /// closing pipe still read as the bare symbols they always did.
+    fn at_pipe_symbol(&mut self) -> bool {
+        match self.settings.dialect {
+            Dialect::Smtlib => true,
+            Dialect::Scheme => {
+                let empty = self.input.get(self.pos + 1) == Some(&b'|') && self.ends_token(self.pos + 2);
+                !empty && self.closing_pipe().is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn ends_token(&self, at: usize) -> bool {
+        self.input.get(at).is_none_or(|&c| self.is_delimiter(c))
+    }
+
+    /// Offset of the first unescaped pipe after the cursor that ends its
+    /// token. A search that found nothing, or found a pipe past the cursor,
+    /// answers for every later cursor up to that pipe too, which keeps input
+    /// full of unclosed `|>` tokens linear.
+    fn closing_pipe(&mut self) -> Option<usize> {
+        let from = self.pos + 1;
+        if let Some((start, found)) = self.pipe_scan {
+            if start <= from && found.is_none_or(|at| at >= from) {
+                return found;
+            }
+        }
+        let mut at = from;
+        let mut found = None;
+        while let Some(&c) = self.input.get(at) {
+            match c {
+                b'\\' => at += 2,
+                b'|' if self.ends_token(at + 1) => {
+                    found = Some(at);
+                    break;
+                }
+                _ => at += 1,
+            }
         }
+        self.pipe_scan = Some((from, found));
+        found
+    }
+
+    fn is_whitespace(&self, c: u8) -> bool {
+        c.is_ascii_whitespace() || (c == b',' && self.settings.dialect == Dialect::Clojure)
     }
 
     fn peek(&self) -> Option<u8> {
@@ -65,7 +455,7 @@ impl<'a> Parser<'a> {
 
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek() {
-            if c.is_ascii_whitespace() {
+            if self.is_whitespace(c) {
                 self.advance();
             } else if c == b';' {
                 // Skip line comment
@@ -88,35 +478,190 @@ impl<'a> Parser<'a> {
             None => Ok(ParsedExpr::Nil),
             Some(b'(') => self.parse_list(),
             Some(b'"') => self.parse_string(),
+            Some(b'|') if self.at_pipe_symbol() => self.parse_pipe_symbol(),
+            Some(c) if self.settings.dialect == Dialect::Elisp => self.parse_elisp(c),
+            Some(c) if self.settings.dialect == Dialect::Clojure => self.parse_clojure(c),
             Some(_) => self.parse_atom(),
         }
     }
 
-    fn parse_list(&mut self) -> Result<ParsedExpr, String> {
-        self.advance(); // skip '('
+    /// Parse every top-level form until the end of input
+    fn parse_forms(&mut self) -> Result<Vec<ParsedExpr>, String> {
+        let mut forms = Vec::new();
         self.skip_whitespace();
-        
-        if self.peek() == Some(b')') {
-            self.advance();
+        while self.peek().is_some() {
+            forms.push(self.parse()?);
+            self.skip_whitespace();
+        }
+        Ok(forms)
+    }
+
+    fn parse_list(&mut self) -> Result<ParsedExpr, String> {
+        let items = if self.settings.dialect == Dialect::Elisp {
+            self.parse_dotted_items()?
+        } else {
+            self.parse_items(b')', "list")?
+        };
+        if items.is_empty() {
             return Ok(ParsedExpr::Nil);
         }
+        Ok(ParsedExpr::List(items))
+    }
+
+    /// Items between the opening byte at the cursor and `close`
+    fn parse_items(&mut self, close: u8, what: &str) -> Result<Vec<ParsedExpr>, String> {
+        self.advance(); // skip opening bracket
+        self.skip_whitespace();
         
         let mut items = Vec::new();
         
-        while self.peek().is_some() && self.peek() != Some(b')') {
+        while self.peek().is_some() && self.peek() != Some(close) {
             items.push(self.parse()?);
             self.skip_whitespace();
         }
         
-        if self.peek() != Some(b')') {
+        if self.peek() != Some(close) {
+            return Err(format!("unterminated {}", what));
+        }
+        self.advance(); // skip closing bracket
+        
+        Ok(items)
+    }
+
+    /// Items of an elisp list, its dotted tail spliced as `splice_dotted`
+    /// does when the tail is itself written as a list
+    fn parse_dotted_items(&mut self) -> Result<Vec<ParsedExpr>, String> {
+        self.advance(); // skip '('
+        self.skip_whitespace();
+        let mut items = Vec::new();
+        let mut last_opens = None;
+        while let Some(c) = self.peek().filter(|&c| c != b')') {
+            last_opens = Some(c);
+            items.push(self.parse()?);
+            self.skip_whitespace();
+        }
+        if self.peek().is_none() {
             return Err("unterminated list".to_string());
         }
         self.advance(); // skip ')'
-        
-        Ok(ParsedExpr::List(items))
+        splice_dotted(items, last_opens == Some(b'('))
+    }
+
+    /// Elisp-only syntax: vectors read as lists, `?` character literals and
+    /// `#s(...)` records
+    fn parse_elisp(&mut self, c: u8) -> Result<ParsedExpr, String> {
+        match c {
+            b'[' => {
+                let items = self.parse_items(b']', "vector")?;
+                Ok(if items.is_empty() { ParsedExpr::Nil } else { ParsedExpr::List(items) })
+            }
+            b'?' => self.parse_char(),
+            b'#' if self.input[self.pos..].starts_with(b"#s(") => {
+                self.advance(); // skip '#'
+                self.advance(); // skip 's'
+                match self.parse_list()? {
+                    ParsedExpr::List(items) => Ok(elisp_record(items)),
+                    empty => Ok(empty),
+                }
+            }
+            _ => self.parse_atom(),
+        }
+    }
+
+    /// Clojure collections, all read as lists: `[...]` vectors, `#{...}` sets
+    /// and `{k v ...}` maps as `((k v) ...)`
+    fn parse_clojure(&mut self, c: u8) -> Result<ParsedExpr, String> {
+        let items = match c {
+            b'[' => self.parse_items(b']', "vector")?,
+            b'#' if self.input[self.pos..].starts_with(b"#{") => {
+                self.advance(); // skip '#'
+                self.parse_items(b'}', "set")?
+            }
+            b'{' => {
+                let forms = self.parse_items(b'}', "map")?;
+                if forms.len() % 2 != 0 {
+                    return Err("map literal must have an even number of forms".to_string());
+                }
+                forms.chunks(2).map(|entry| ParsedExpr::List(entry.to_vec())).collect()
+            }
+            _ => return self.parse_atom(),
+        };
+        Ok(if items.is_empty() { ParsedExpr::Nil } else { ParsedExpr::List(items) })
+    }
+
+    /// Elisp character literal such as `?a`, `?\n`, `?\(`, `?\x41`, `?\101`
+    /// or `?\C-a`, read as its code point
+    fn parse_char(&mut self) -> Result<ParsedExpr, String> {
+        self.advance(); // skip '?'
+        let code = self.char_literal_code()?;
+        if self.peek().is_some_and(|c| !self.is_delimiter(c)) {
+            return Err("invalid character literal".to_string());
+        }
+        Ok(ParsedExpr::Integer(code))
+    }
+
+    /// Code of the character literal at the cursor, escapes included
+    fn char_literal_code(&mut self) -> Result<i64, String> {
+        let unterminated = || "unterminated character literal".to_string();
+        let c = self.next_char().ok_or_else(unterminated)?;
+        if c != '\\' {
+            return Ok(c as i64);
+        }
+        let code = match self.next_char().ok_or_else(unterminated)? {
+            'n' => 10,
+            't' => 9,
+            'r' => 13,
+            's' => 32,
+            'e' => 27,
+            'a' => 7,
+            'b' => 8,
+            'f' => 12,
+            'v' => 11,
+            'd' => 127,
+            'x' => self.char_digits(0, 16, usize::MAX)?,
+            c @ '0'..='7' => self.char_digits(c as i64 - '0' as i64, 8, 2)?,
+            'C' if self.peek() == Some(b'-') => {
+                self.advance(); // skip '-'
+                control_char(self.char_literal_code()?)?
+            }
+            '^' => control_char(self.char_literal_code()?)?,
+            c => c as i64,
+        };
+        Ok(code)
+    }
+
+    /// Up to `max` more digits of a numeric character escape, added to
+    /// `value`
+    fn char_digits(&mut self, mut value: i64, radix: u32, max: usize) -> Result<i64, String> {
+        let mut read = 0;
+        while let Some(digit) = self.peek().filter(|_| read < max).and_then(|c| (c as char).to_digit(radix)) {
+            self.advance();
+            read += 1;
+            value = value * radix as i64 + digit as i64;
+            if value > MAX_ELISP_CHAR {
+                return Err("character code out of range".to_string());
+            }
+        }
+        Ok(value)
+    }
+
+    /// The character at the cursor, consumed; only its own bytes are decoded
+    fn next_char(&mut self) -> Option<char> {
+        let width = match *self.input.get(self.pos)? {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        let c = std::str::from_utf8(self.input.get(self.pos..self.pos + width)?).ok()?.chars().next()?;
+        self.pos += width;
+        Some(c)
     }
 
     fn parse_string(&mut self) -> Result<ParsedExpr, String> {
+        if self.settings.dialect == Dialect::Smtlib {
+            return self.parse_smtlib_string();
+        }
         self.advance(); // skip opening '"'
         let mut s = String::new();
         
@@ -150,11 +695,63 @@ impl<'a> Parser<'a> {
         Ok(ParsedExpr::String(s))
     }
 
+    /// `|...|` symbol, which may hold any characters; outside SMT-LIB `\|`
+    /// and `\\` escape the delimiters
+    fn parse_pipe_symbol(&mut self) -> Result<ParsedExpr, String> {
+        self.advance(); // skip opening '|'
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err("unterminated |symbol|".to_string()),
+                Some(b'|') => {
+                    self.advance();
+                    break;
+                }
+                Some(b'\\') if self.settings.dialect != Dialect::Smtlib => {
+                    self.advance();
+                    bytes.push(self.peek().ok_or("unterminated |symbol|")?);
+                    self.advance();
+                }
+                Some(c) => {
+                    bytes.push(c);
+                    self.advance();
+                }
+            }
+        }
+        let name = String::from_utf8(bytes).map_err(|_| "invalid UTF-8")?;
+        Ok(ParsedExpr::Symbol(intern_symbol(&name)))
+    }
+
+    /// SMT-LIB string literal: `""` stands for one quote and there are no
+    /// other escapes
+    fn parse_smtlib_string(&mut self) -> Result<ParsedExpr, String> {
+        self.advance(); // skip opening '"'
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err("unterminated string".to_string()),
+                Some(b'"') => {
+                    self.advance();
+                    if self.peek() != Some(b'"') {
+                        break;
+                    }
+                    bytes.push(b'"');
+                    self.advance();
+                }
+                Some(c) => {
+                    bytes.push(c);
+                    self.advance();
+                }
+            }
+        }
+        String::from_utf8(bytes).map(ParsedExpr::String).map_err(|_| "invalid UTF-8".to_string())
+    }
+
     fn parse_atom(&mut self) -> Result<ParsedExpr, String> {
         let start = self.pos;
         
         while let Some(c) = self.peek() {
-            if c.is_ascii_whitespace() || c == b'(' || c == b')' || c == b'"' || c == b';' {
+            if self.is_delimiter(c) {
                 break;
             }
             self.advance();
@@ -168,10 +765,31 @@ impl<'a> Parser<'a> {
         }
         
         // Check for nil
-        if token == "nil" || token == "()" {
+        if (token == "nil" && !self.settings.nil_is_symbol) || token == "()" {
             return Ok(ParsedExpr::Nil);
         }
         
+        // Boolean literals
+        match (self.settings.dialect, token) {
+            (_, "#t") => return Ok(ParsedExpr::Bool(true)),
+            (_, "#f") => return Ok(ParsedExpr::Bool(false)),
+            (Dialect::Clojure | Dialect::Smtlib, "true" | "false") => return Ok(ParsedExpr::Bool(token == "true")),
+            (_, "true" | "false") if self.settings.parse_true_false => return Ok(ParsedExpr::Bool(token == "true")),
+            (Dialect::Elisp, "t") => return Ok(ParsedExpr::Bool(true)),
+            (Dialect::Kicad, "yes" | "no") => return Ok(ParsedExpr::Bool(token == "yes")),
+            _ => {}
+        }
+        
+        // Exact decimals: 12.30m, or any plain decimal when exact_decimals is
+        // on. The suffix needs a fraction, so durations like 5m stay symbols.
+        if let Some(digits) = token.strip_suffix('m').filter(|d| d.contains('.') && is_decimal_literal(d)) {
+            return Ok(ParsedExpr::Decimal(digits.trim_start_matches('+').to_string()));
+        }
+        let exact = self.settings.exact_decimals || self.settings.dialect == Dialect::Smtlib;
+        if exact && token.contains('.') && is_decimal_literal(token) {
+            return Ok(ParsedExpr::Decimal(token.trim_start_matches('+').to_string()));
+        }
+        
         // Try to parse as number
         if let Ok(i) = token.parse::<i64>() {
             return Ok(ParsedExpr::Integer(i));
@@ -182,15 +800,179 @@ impl<'a> Parser<'a> {
         }
         
         // It's a symbol
-        Ok(ParsedExpr::Symbol(token.to_string()))
+        Ok(ParsedExpr::Symbol(intern_symbol(token)))
+    }
+
+    /// Split the input into lexical tokens as (kind, start, end) byte ranges,
+    /// including comments. Malformed input ends with an "error" token
+    /// covering the rest of the input.
+    fn tokenize(&mut self) -> Vec<(&'static str, usize, usize)> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek() {
+            let start = self.pos;
+            let rest = &self.input[self.pos..];
+            // Openers and closers of the dialect's collections, as parse()
+            // dispatches on them
+            let bracket = match (self.settings.dialect, c) {
+                (_, b'(') => Some((1, "open")),
+                (_, b')') => Some((1, "close")),
+                (Dialect::Elisp | Dialect::Clojure, b'[') => Some((1, "open")),
+                (Dialect::Elisp | Dialect::Clojure, b']') => Some((1, "close")),
+                (Dialect::Elisp, b'#') if rest.starts_with(b"#s(") => Some((3, "open")),
+                (Dialect::Clojure, b'{') => Some((1, "open")),
+                (Dialect::Clojure, b'}') => Some((1, "close")),
+                (Dialect::Clojure, b'#') if rest.starts_with(b"#{") => Some((2, "open")),
+                _ => None,
+            };
+            if let Some((width, kind)) = bracket {
+                self.pos += width;
+                tokens.push((kind, start, self.pos));
+                continue;
+            }
+            let kind = match c {
+                c if self.is_whitespace(c) => {
+                    self.advance();
+                    continue;
+                }
+                b';' => {
+                    while self.peek().is_some_and(|c| c != b'\n') {
+                        self.advance();
+                    }
+                    "comment"
+                }
+                b'"' => match self.parse_string() {
+                    Ok(_) => "string",
+                    Err(_) => "error",
+                },
+                b'|' if self.at_pipe_symbol() => match self.parse_pipe_symbol() {
+                    Ok(_) => "symbol",
+                    Err(_) => "error",
+                },
+                b'?' if self.settings.dialect == Dialect::Elisp => match self.parse_char() {
+                    Ok(_) => "integer",
+                    Err(_) => "error",
+                },
+                _ => match self.parse_atom() {
+                    Ok(ParsedExpr::Nil) => "nil",
+                    Ok(ParsedExpr::Integer(_)) => "integer",
+                    Ok(ParsedExpr::Float(_)) => "float",
+                    Ok(ParsedExpr::Decimal(_)) => "decimal",
+                    Ok(ParsedExpr::Bool(_)) => "boolean",
+                    Ok(_) => "symbol",
+                    Err(_) => "error",
+                },
+            };
+            if kind == "error" {
+                tokens.push((kind, start, self.input.len()));
+                break;
+            }
+            tokens.push((kind, start, self.pos));
+        }
+        tokens
+    }
+}
+
+/// Read elisp `(a b . rest)` as the list it denotes when `rest` is written
+/// as a list, and as `(a b rest)` otherwise, so `(key . value)` pairs become
+/// ordinary `(key value)` entries and `(key . [1 2])` keeps its vector
+fn splice_dotted(mut items: Vec<ParsedExpr>, tail_is_list: bool) -> Result<Vec<ParsedExpr>, String> {
+    let Some(dot) = items.iter().position(|item| is_symbol_named(item, ".")) else {
+        return Ok(items);
+    };
+    if dot == 0 || dot + 2 != items.len() {
+        return Err("misplaced dot in list".to_string());
+    }
+    let tail = items.pop().unwrap();
+    items.pop();
+    match tail {
+        ParsedExpr::List(rest) if tail_is_list => items.extend(rest),
+        ParsedExpr::Nil => {}
+        other => items.push(other),
+    }
+    Ok(items)
+}
+
+/// Largest character code Emacs has
+const MAX_ELISP_CHAR: i64 = 0x3F_FFFF;
+
+/// `?\C-c` and `?\^c`: the control character for c
+fn control_char(code: i64) -> Result<i64, String> {
+    match code {
+        // ?\C-? is DEL
+        63 => Ok(127),
+        64..=95 | 97..=122 => Ok(code & 0x1f),
+        _ => Err("unsupported control character".to_string()),
+    }
+}
+
+/// `#s(hash-table ... data (k1 v1 k2 v2))` becomes `(hash-table (k1 v1) (k2
+/// v2))`, dropping the table's settings; other records keep all their slots
+fn elisp_record(items: Vec<ParsedExpr>) -> ParsedExpr {
+    if !items.first().is_some_and(|head| is_symbol_named(head, "hash-table")) {
+        return ParsedExpr::List(items);
+    }
+    let mut table = vec![items[0].clone()];
+    let data = items[1..].chunks(2).find(|property| is_symbol_named(&property[0], "data"));
+    if let Some([_, ParsedExpr::List(plist)]) = data {
+        table.extend(plist.chunks(2).map(|entry| ParsedExpr::List(entry.to_vec())));
+    }
+    ParsedExpr::List(table)
+}
+
+fn is_symbol_named(item: &ParsedExpr, name: &str) -> bool {
+    matches!(item, ParsedExpr::Symbol(symbol) if &**symbol == name)
+}
+
+/// The settings that decide what a text reads as, for caches of parsed text
+#[derive(Clone, Copy, PartialEq)]
+struct ParseSettings {
+    dialect: Dialect,
+    exact_decimals: bool,
+    nil_is_symbol: bool,
+    parse_true_false: bool,
+}
+
+impl ParseSettings {
+    /// What the input function reads: the text sexp_out prints, whatever
+    /// the session settings, so dumps restore the values they were taken
+    /// from
+    const CANONICAL: ParseSettings = ParseSettings {
+        dialect: Dialect::Scheme,
+        exact_decimals: false,
+        nil_is_symbol: false,
+        parse_true_false: false,
+    };
+
+    /// The session's pg_sexp.dialect and reader options, for the parsing
+    /// functions
+    fn current() -> Self {
+        ParseSettings {
+            dialect: DIALECT.get(),
+            exact_decimals: EXACT_DECIMALS.get(),
+            nil_is_symbol: NIL_IS_SYMBOL.get(),
+            parse_true_false: PARSE_TRUE_FALSE.get(),
+        }
+    }
+}
+
+/// Parse sexp text with the given reader settings, reporting errors instead
+/// of raising them
+fn parse_sexp_text(s: &str, settings: ParseSettings) -> Result<Sexp, String> {
+    let s = s.trim();
+    if s.is_empty() || s == "()" || (s == "nil" && !settings.nil_is_symbol) {
+        return Ok(Sexp::nil());
     }
+    let result = Parser::with_settings(s, settings).parse().map(|parsed| Sexp::from_parsed(&parsed));
+    count_stat(if result.is_ok() { &STAT_PARSES } else { &STAT_PARSE_ERRORS }, 1);
+    result
 }
 
 /// PostgreSQL sexp type - stored as varlena binary data
 #[derive(PostgresType, Serialize, Deserialize)]
+#[bikeshed_postgres_type_manually_impl_from_into_datum]
 #[inoutfuncs]
 pub struct Sexp {
-    data: Vec<u8>,
+    data: SexpBytes,
 }
 
 impl InOutFuncs for Sexp {
@@ -199,19 +981,8 @@ impl InOutFuncs for Sexp {
         Self: Sized,
     {
         let s = input.to_str().expect("invalid UTF-8 in sexp input");
-        let s = s.trim();
-        
-        if s.is_empty() || s == "()" || s == "nil" {
-            return Sexp::nil();
-        }
-        
-        let mut parser = Parser::new(s);
-        match parser.parse() {
-            Ok(parsed) => {
-                let mut data = vec![FORMAT_VERSION];
-                serialize_parsed(&parsed, &mut data);
-                Sexp { data }
-            }
+        match parse_sexp_text(s, ParseSettings::CANONICAL) {
+            Ok(sexp) => sexp,
             Err(e) => {
                 pgrx::error!("invalid s-expression: {}", e);
             }
@@ -219,6 +990,7 @@ impl InOutFuncs for Sexp {
     }
 
     fn output(&self, buffer: &mut pgrx::StringInfo) {
+        count_stat(&STAT_BYTES_DECODED, self.data.len() as u64);
         let output = self.to_string_repr();
         buffer.push_str(&output);
     }
@@ -227,15 +999,31 @@ impl InOutFuncs for Sexp {
 impl Sexp {
     /// Create a nil (empty list) sexp
     fn nil() -> Self {
-        Sexp {
-            data: vec![FORMAT_VERSION, tags::NIL],
-        }
+        Sexp::from_bytes(&[FORMAT_VERSION, tags::NIL])
+    }
+
+    /// Wrap a finished encoding (version byte included)
+    fn from_buf(buf: VarlenaBuf) -> Self {
+        Sexp { data: SexpBytes::Built(buf) }
+    }
+
+    /// Copy a complete encoding (version byte included) into a new value
+    fn from_bytes(data: &[u8]) -> Self {
+        Sexp::from_buf(VarlenaBuf::from_slice(data))
+    }
+
+    /// Encode a parsed expression
+    fn from_parsed(expr: &ParsedExpr) -> Self {
+        let mut data = VarlenaBuf::with_capacity(64);
+        data.push(FORMAT_VERSION);
+        serialize_parsed(expr, &mut data);
+        Sexp::from_buf(data)
     }
 
     /// Convert to string representation
     fn to_string_repr(&self) -> String {
         if self.data.len() < 2 {
-            return "()".to_string();
+            return nil_text().to_string();
         }
         let mut pos = 1; // skip version
         deserialize_to_string(&self.data, &mut pos)
@@ -254,6 +1042,7 @@ impl Sexp {
             tags::SYMBOL => SexpType::Symbol,
             tags::LIST => SexpType::List,
             tags::BOOL => SexpType::Bool,
+            tags::DECIMAL => SexpType::Decimal,
             _ => SexpType::Nil,
         }
     }
@@ -275,7 +1064,7 @@ impl Sexp {
         }
         matches!(
             self.data[1],
-            tags::INTEGER | tags::FLOAT | tags::STRING | tags::SYMBOL | tags::BOOL
+            tags::INTEGER | tags::FLOAT | tags::STRING | tags::SYMBOL | tags::BOOL | tags::DECIMAL
         )
     }
 
@@ -313,29 +1102,24 @@ impl Sexp {
             return Some(Sexp::nil());
         }
         
-        // Build new list with elements 1..n
-        let mut result = vec![FORMAT_VERSION, tags::LIST];
-        write_varint(&mut result, (len - 1) as u64);
-        
-        // Skip to element 1 and copy rest
+        // Skip to element 1
         let mut pos = 2;
         let _count = read_varint(&self.data, &mut pos);
         skip_element(&self.data, &mut pos); // skip element 0
         
-        // Copy remaining elements
+        // Build new list with elements 1..n
+        let mut result = VarlenaBuf::with_capacity(self.data.len() - pos + 12);
+        result.extend_from_slice(&[FORMAT_VERSION, tags::LIST]);
+        write_varint(&mut result, (len - 1) as u64);
         result.extend_from_slice(&self.data[pos..]);
         
-        Some(Sexp { data: result })
+        Some(Sexp::from_buf(result))
     }
 
-    /// Get nth element (0-indexed)
+    /// Get nth element (0-indexed; negative indices count from the end)
     fn nth(&self, n: i32) -> Option<Sexp> {
-        if n < 0 {
-            return None;
-        }
-        
         if self.is_atom() {
-            return if n == 0 { Some(self.clone()) } else { None };
+            return if n == 0 || n == -1 { Some(self.clone()) } else { None };
         }
         
         if self.is_nil() {
@@ -344,8 +1128,9 @@ impl Sexp {
         
         let mut pos = 2;
         let count = read_varint(&self.data, &mut pos) as i32;
+        let n = if n < 0 { n + count } else { n };
         
-        if n >= count {
+        if n < 0 || n >= count {
             return None;
         }
         
@@ -359,41 +1144,40 @@ impl Sexp {
         skip_element(&self.data, &mut pos);
         let end = pos;
         
-        let mut result = vec![FORMAT_VERSION];
-        result.extend_from_slice(&self.data[start..end]);
-        
-        Some(Sexp { data: result })
+        Some(Sexp::from_element(&self.data[start..end]))
     }
 
-    /// Check structural containment
-    fn contains(&self, needle: &Sexp) -> bool {
-        // Check if self equals needle
-        if self.equals(needle) {
-            return true;
+    /// Get the encoded direct children of a list (empty for atoms and nil)
+    fn elements(&self) -> Vec<&[u8]> {
+        list_elements(self.data.get(1..).unwrap_or_default())
+    }
+
+    /// Wrap a single encoded element as a standalone sexp
+    fn from_element(element: &[u8]) -> Sexp {
+        let mut data = VarlenaBuf::with_capacity(element.len() + 1);
+        data.push(FORMAT_VERSION);
+        data.extend_from_slice(element);
+        Sexp::from_buf(data)
+    }
+
+    /// Build a list from encoded elements (nil when there are none)
+    fn from_elements(elements: &[&[u8]]) -> Sexp {
+        if elements.is_empty() {
+            return Sexp::nil();
         }
-        
-        // If self is a list, check children recursively
-        if self.data.len() >= 2 && self.data[1] == tags::LIST {
-            let mut pos = 2;
-            let count = read_varint(&self.data, &mut pos);
-            
-            for _ in 0..count {
-                let start = pos;
-                skip_element(&self.data, &mut pos);
-                let end = pos;
-                
-                // Build child sexp and check recursively
-                let mut child_data = vec![FORMAT_VERSION];
-                child_data.extend_from_slice(&self.data[start..end]);
-                let child = Sexp { data: child_data };
-                
-                if child.contains(needle) {
-                    return true;
-                }
-            }
+        let size = elements.iter().map(|element| element.len()).sum::<usize>();
+        let mut data = VarlenaBuf::with_capacity(size + 12);
+        data.extend_from_slice(&[FORMAT_VERSION, tags::LIST]);
+        write_varint(&mut data, elements.len() as u64);
+        for element in elements {
+            data.extend_from_slice(element);
         }
-        
-        false
+        Sexp::from_buf(data)
+    }
+
+    /// Check structural containment
+    fn contains(&self, needle: &Sexp) -> bool {
+        self.equals(needle) || PreparedNeedle::new(needle).found_at(&self.data, 1)
     }
 
     /// Check equality
@@ -421,11 +1205,201 @@ impl Sexp {
     }
 }
 
-impl Clone for Sexp {
-    fn clone(&self) -> Self {
-        Sexp {
-            data: self.data.clone(),
-        }
+// ============================================================================
+// Datum Conversion
+// ============================================================================
+//
+// A datum is a plain varlena whose payload is the encoding itself. Values
+// built here are encoded straight into a palloc'd varlena and returned as is,
+// and arguments are read in place through a view of the detoasted datum, so
+// neither direction copies the encoding. Values written by earlier releases
+// went through pgrx's serde path and hold a CBOR map instead; they are
+// recognised by its first byte and still read (sexp_upgrade rewrites them).
+
+/// First byte of a CBOR map with one entry, the serde form of `Sexp`
+const LEGACY_CBOR_MAP: u8 = 0xa1;
+
+const VARHDRSZ: usize = 4;
+
+/// Growable palloc'd buffer with room for a varlena header in front of the
+/// payload. Like any palloc'd datum it belongs to the memory context that was
+/// current when it was created and is released with it.
+struct VarlenaBuf {
+    ptr: *mut u8,
+    len: usize,
+    capacity: usize,
+}
+
+impl VarlenaBuf {
+    fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(16);
+        let ptr = unsafe { pg_sys::palloc(VARHDRSZ + capacity) as *mut u8 };
+        VarlenaBuf { ptr, len: 0, capacity }
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        let mut buf = VarlenaBuf::with_capacity(bytes.len());
+        buf.extend_from_slice(bytes);
+        buf
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.len + additional;
+        if needed > self.capacity {
+            let capacity = needed.max(self.capacity * 2);
+            self.ptr = unsafe { pg_sys::repalloc(self.ptr.cast(), VARHDRSZ + capacity) as *mut u8 };
+            self.capacity = capacity;
+        }
+    }
+
+    /// Set the header and hand the buffer over as a datum
+    fn into_varlena(self) -> *mut pg_sys::varlena {
+        let ptr = self.ptr as *mut pg_sys::varlena;
+        unsafe { pgrx::varlena::set_varsize_4b(ptr, (VARHDRSZ + self.len) as i32) };
+        ptr
+    }
+}
+
+impl ByteSink for VarlenaBuf {
+    fn push(&mut self, byte: u8) {
+        self.reserve(1);
+        unsafe { *self.ptr.add(VARHDRSZ + self.len) = byte };
+        self.len += 1;
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.reserve(bytes.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(VARHDRSZ + self.len), bytes.len());
+        }
+        self.len += bytes.len();
+    }
+}
+
+impl std::ops::Deref for VarlenaBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.add(VARHDRSZ), self.len) }
+    }
+}
+
+/// Encoded bytes of a sexp, version byte first
+enum SexpBytes {
+    /// Encoded in place behind a varlena header and returned without a copy
+    Built(VarlenaBuf),
+    /// Payload of a (detoasted) argument datum, valid for the whole call
+    Arg {
+        varlena: *mut pg_sys::varlena,
+        payload: *const u8,
+        len: usize,
+    },
+    /// Copied out of a datum that may not outlive the caller, such as an SPI
+    /// result or an array element
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for SexpBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            SexpBytes::Built(buf) => buf,
+            SexpBytes::Arg { payload, len, .. } => unsafe { std::slice::from_raw_parts(*payload, *len) },
+            SexpBytes::Owned(data) => data,
+        }
+    }
+}
+
+impl From<Vec<u8>> for SexpBytes {
+    fn from(data: Vec<u8>) -> Self {
+        SexpBytes::Owned(data)
+    }
+}
+
+impl PartialEq for SexpBytes {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for SexpBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Serialize for SexpBytes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SexpBytes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(SexpBytes::Owned)
+    }
+}
+
+impl IntoDatum for Sexp {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        let ptr = match self.data {
+            SexpBytes::Built(buf) => buf.into_varlena(),
+            // An argument returned unchanged is passed straight through when
+            // it already has a full header
+            SexpBytes::Arg { varlena, .. } if unsafe { !pgrx::varlena::varatt_is_1b(varlena) } => varlena,
+            data => VarlenaBuf::from_slice(&data).into_varlena(),
+        };
+        Some(pg_sys::Datum::from(ptr))
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        pgrx::wrappers::rust_regtypein::<Self>()
+    }
+}
+
+impl FromDatum for Sexp {
+    unsafe fn from_polymorphic_datum(datum: pg_sys::Datum, is_null: bool, _typoid: pg_sys::Oid) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        let ptr = pg_sys::pg_detoast_datum_packed(datum.cast_mut_ptr());
+        let payload = pgrx::varlena::varlena_to_byte_slice(ptr);
+        if payload.first() == Some(&LEGACY_CBOR_MAP) {
+            return Some(pgrx::datum::cbor_decode(ptr));
+        }
+        Some(Sexp { data: payload.to_vec().into() })
+    }
+}
+
+unsafe impl<'fcx> pgrx::callconv::ArgAbi<'fcx> for Sexp {
+    unsafe fn unbox_arg_unchecked(arg: pgrx::callconv::Arg<'_, 'fcx>) -> Self {
+        if arg.is_null() {
+            panic!("argument {} must not be null", arg.index());
+        }
+        let varlena = pg_sys::pg_detoast_datum_packed(arg.raw_datum().cast_mut_ptr());
+        let payload = pgrx::varlena::varlena_to_byte_slice(varlena);
+        if payload.first() == Some(&LEGACY_CBOR_MAP) {
+            return pgrx::datum::cbor_decode(varlena);
+        }
+        Sexp {
+            data: SexpBytes::Arg { varlena, payload: payload.as_ptr(), len: payload.len() },
+        }
+    }
+}
+
+unsafe impl pgrx::callconv::BoxRet for Sexp {
+    unsafe fn box_into<'fcx>(self, fcinfo: &mut pgrx::callconv::FcInfo<'fcx>) -> pgrx::datum::Datum<'fcx> {
+        match self.into_datum() {
+            Some(datum) => fcinfo.return_raw_datum(datum),
+            None => fcinfo.return_null(),
+        }
+    }
+}
+
+impl Clone for Sexp {
+    fn clone(&self) -> Self {
+        Sexp::from_bytes(&self.data)
     }
 }
 
@@ -455,6 +1429,7 @@ pub enum SexpType {
     Symbol,
     List,
     Bool,
+    Decimal,
 }
 
 impl fmt::Display for SexpType {
@@ -467,6 +1442,7 @@ impl fmt::Display for SexpType {
             SexpType::Symbol => write!(f, "symbol"),
             SexpType::List => write!(f, "list"),
             SexpType::Bool => write!(f, "boolean"),
+            SexpType::Decimal => write!(f, "decimal"),
         }
     }
 }
@@ -475,7 +1451,7 @@ impl fmt::Display for SexpType {
 // Binary Serialization
 // ============================================================================
 
-fn serialize_parsed(expr: &ParsedExpr, out: &mut Vec<u8>) {
+fn serialize_parsed(expr: &ParsedExpr, out: &mut impl ByteSink) {
     match expr {
         ParsedExpr::Nil => {
             out.push(tags::NIL);
@@ -496,6 +1472,14 @@ fn serialize_parsed(expr: &ParsedExpr, out: &mut Vec<u8>) {
             out.push(tags::SYMBOL);
             write_string(out, s);
         }
+        ParsedExpr::Bool(b) => {
+            out.push(tags::BOOL);
+            out.push(*b as u8);
+        }
+        ParsedExpr::Decimal(d) => {
+            out.push(tags::DECIMAL);
+            write_string(out, d);
+        }
         ParsedExpr::List(items) => {
             if items.is_empty() {
                 out.push(tags::NIL);
@@ -510,46 +1494,20 @@ fn serialize_parsed(expr: &ParsedExpr, out: &mut Vec<u8>) {
     }
 }
 
-fn write_varint(out: &mut Vec<u8>, mut value: u64) {
-    loop {
-        let mut byte = (value & 0x7F) as u8;
-        value >>= 7;
-        if value != 0 {
-            byte |= 0x80;
-        }
-        out.push(byte);
-        if value == 0 {
-            break;
-        }
-    }
-}
-
-fn write_signed_varint(out: &mut Vec<u8>, value: i64) {
-    // Zigzag encoding
-    let encoded = ((value << 1) ^ (value >> 63)) as u64;
-    write_varint(out, encoded);
-}
-
-fn write_string(out: &mut Vec<u8>, s: &str) {
-    let bytes = s.as_bytes();
-    write_varint(out, bytes.len() as u64);
-    out.extend_from_slice(bytes);
+/// Raise a data_corrupted error for a stored value that breaks the encoding
+/// invariants. Decoding never guesses past a violation, so a damaged datum
+/// cannot turn into a plausible but wrong answer.
+fn corrupted(message: &str) -> ! {
+    ereport!(
+        PgLogLevel::ERROR,
+        PgSqlErrorCode::ERRCODE_DATA_CORRUPTED,
+        format!("corrupted sexp value: {}", message)
+    );
+    unreachable!()
 }
 
 fn read_varint(data: &[u8], pos: &mut usize) -> u64 {
-    let mut result: u64 = 0;
-    let mut shift = 0;
-    
-    while *pos < data.len() {
-        let byte = data[*pos];
-        *pos += 1;
-        result |= ((byte & 0x7F) as u64) << shift;
-        if byte & 0x80 == 0 {
-            break;
-        }
-        shift += 7;
-    }
-    result
+    read_varint_checked(data, pos).unwrap_or_else(|e| corrupted(&e))
 }
 
 fn read_signed_varint(data: &[u8], pos: &mut usize) -> i64 {
@@ -558,97 +1516,267 @@ fn read_signed_varint(data: &[u8], pos: &mut usize) -> i64 {
     ((encoded >> 1) as i64) ^ (-((encoded & 1) as i64))
 }
 
+fn try_read_string(data: &[u8], pos: &mut usize) -> Result<String, String> {
+    let len = read_varint_checked(data, pos)?;
+    let end = checked_end(data, *pos, len, "string length")?;
+    let s = std::str::from_utf8(&data[*pos..end]).map_err(|_| "invalid UTF-8 in string")?;
+    *pos = end;
+    Ok(s.to_string())
+}
+
 fn read_string(data: &[u8], pos: &mut usize) -> String {
-    let len = read_varint(data, pos) as usize;
-    if *pos + len > data.len() {
-        return String::new();
-    }
-    let s = String::from_utf8_lossy(&data[*pos..*pos + len]).to_string();
-    *pos += len;
-    s
+    try_read_string(data, pos).unwrap_or_else(|e| corrupted(&e))
 }
 
 fn skip_element(data: &[u8], pos: &mut usize) {
-    if *pos >= data.len() {
-        return;
+    try_skip_element(data, pos).unwrap_or_else(|e| corrupted(&e))
+}
+
+/// Encoded direct children of an encoded list element (empty for atoms and nil)
+fn list_elements(element: &[u8]) -> Vec<&[u8]> {
+    let mut result = Vec::new();
+    if element.first() != Some(&tags::LIST) {
+        return result;
     }
-    
-    let tag = data[*pos];
-    *pos += 1;
-    
-    match tag {
-        tags::NIL => {}
-        tags::INTEGER => {
-            read_varint(data, pos);
-        }
-        tags::FLOAT => {
-            *pos += 8;
+
+    let mut pos = 1;
+    let count = read_varint(element, &mut pos);
+    for _ in 0..count {
+        let start = pos;
+        skip_element(element, &mut pos);
+        result.push(&element[start..pos.min(element.len())]);
+    }
+    result
+}
+
+/// Element visited by a preorder walk over the binary encoding
+#[derive(Debug, Clone, Copy)]
+struct PreorderItem {
+    start: usize,
+    tag: u8,
+    depth: usize,
+}
+
+/// Iterative preorder walk over every element of an encoded sexp.
+///
+/// The binary layout is already preorder, so the walk only needs a stack of
+/// remaining child counts to know when a list ends.
+struct Preorder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    pending: Vec<u64>,
+    started: bool,
+}
+
+impl<'a> Preorder<'a> {
+    fn new(data: &'a [u8], start: usize) -> Self {
+        Preorder {
+            data,
+            pos: start,
+            pending: Vec::new(),
+            started: false,
         }
-        tags::BOOL => {
-            *pos += 1;
+    }
+}
+
+impl<'a> Iterator for Preorder<'a> {
+    type Item = PreorderItem;
+
+    fn next(&mut self) -> Option<PreorderItem> {
+        if self.started {
+            while self.pending.last() == Some(&0) {
+                self.pending.pop();
+            }
+            match self.pending.last_mut() {
+                Some(remaining) => *remaining -= 1,
+                None => return None,
+            }
         }
-        tags::STRING | tags::SYMBOL => {
-            let len = read_varint(data, pos) as usize;
-            *pos += len;
+        self.started = true;
+
+        if self.pos >= self.data.len() {
+            return None;
         }
-        tags::LIST => {
-            let count = read_varint(data, pos);
-            for _ in 0..count {
-                skip_element(data, pos);
-            }
+
+        let start = self.pos;
+        let tag = self.data[start];
+        let depth = self.pending.len();
+
+        if tag == tags::LIST {
+            self.pos += 1;
+            let count = read_varint(self.data, &mut self.pos);
+            self.pending.push(count);
+        } else {
+            skip_element(self.data, &mut self.pos);
         }
-        _ => {}
+
+        Some(PreorderItem { start, tag, depth })
+    }
+}
+
+/// Get the bytes of a symbol or string atom starting at `pos`
+fn atom_bytes_at(data: &[u8], pos: usize) -> Option<&[u8]> {
+    if pos >= data.len() || !matches!(data[pos], tags::SYMBOL | tags::STRING) {
+        return None;
     }
+    let mut p = pos + 1;
+    let len = read_varint(data, &mut p) as usize;
+    data.get(p..p.checked_add(len)?)
 }
 
+/// Canonical text of an element, as sexp_out prints it: `#t`/`#f` booleans,
+/// `m`-suffixed decimals and symbols between pipes wherever they would read
+/// as something else. It doesn't depend on pg_sexp.dialect, so a dump
+/// restores the same values whatever dialect the restoring session uses.
 fn deserialize_to_string(data: &[u8], pos: &mut usize) -> String {
+    element_to_text(data, pos, Dialect::Scheme, None)
+}
+
+/// Text of an element in a dialect's literal syntax, floats rounded to
+/// `float_precision` decimal places when given
+fn element_to_text(data: &[u8], pos: &mut usize, dialect: Dialect, float_precision: Option<usize>) -> String {
     if *pos >= data.len() {
-        return "()".to_string();
+        corrupted("truncated element");
     }
     
     let tag = data[*pos];
     *pos += 1;
     
     match tag {
-        tags::NIL => "()".to_string(),
+        tags::NIL => nil_text().to_string(),
         tags::INTEGER => {
             let n = read_signed_varint(data, pos);
             n.to_string()
         }
         tags::FLOAT => {
             if *pos + 8 > data.len() {
-                return "0.0".to_string();
+                corrupted("float exceeds data");
             }
             let bytes: [u8; 8] = data[*pos..*pos + 8].try_into().unwrap();
             *pos += 8;
             let f = f64::from_le_bytes(bytes);
-            format!("{}", f)
+            format_float(f, float_precision)
         }
         tags::BOOL => {
             if *pos >= data.len() {
-                return "#f".to_string();
+                corrupted("boolean exceeds data");
             }
             let b = data[*pos] != 0;
             *pos += 1;
-            if b { "#t".to_string() } else { "#f".to_string() }
+            bool_text(b, dialect).to_string()
         }
         tags::STRING => {
             let s = read_string(data, pos);
-            format!("\"{}\"", escape_string(&s))
+            quote_string(&s, dialect)
         }
         tags::SYMBOL => {
+            symbol_text(&read_string(data, pos), dialect)
+        }
+        tags::DECIMAL if dialect == Dialect::Smtlib => {
             read_string(data, pos)
         }
+        tags::DECIMAL => {
+            let digits = read_string(data, pos);
+            if digits.contains('.') {
+                format!("{}m", digits)
+            } else {
+                format!("{}.0m", digits)
+            }
+        }
         tags::LIST => {
             let count = read_varint(data, pos) as usize;
             let mut parts = Vec::with_capacity(count);
             for _ in 0..count {
-                parts.push(deserialize_to_string(data, pos));
+                parts.push(element_to_text(data, pos, dialect, float_precision));
             }
             format!("({})", parts.join(" "))
         }
-        _ => "()".to_string(),
+        other => corrupted(&format!("unknown tag 0x{:02x}", other)),
+    }
+}
+
+/// Float text that the parser reads back as a float: always carries a
+/// decimal point or exponent, and without a precision is the shortest form
+/// that round-trips exactly
+fn format_float(f: f64, precision: Option<usize>) -> String {
+    let Some(precision) = precision.filter(|_| f.is_finite()) else {
+        return format!("{:?}", f);
+    };
+    let mut text = format!("{:.*}", precision, f);
+    if !text.contains('.') {
+        text.push_str(".0");
+    }
+    text
+}
+
+/// Shortest text that reads back as exactly `f`, whatever
+/// pg_sexp.float_precision says, in a form float8in accepts: plain digits in
+/// the usual range, an exponent beyond it, and PostgreSQL's spellings of the
+/// infinities and NaN
+fn exact_float_text(f: f64) -> String {
+    if f.is_nan() {
+        return "NaN".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+    if f != 0.0 && !(1e-5..1e16).contains(&f.abs()) {
+        return format!("{:e}", f);
+    }
+    let text = f.to_string();
+    if text.contains('.') { text } else { text + ".0" }
+}
+
+/// Plain text form of an encoded element: strings and symbols without
+/// quoting, other atoms and lists as sexp text, nil as None
+fn element_text(element: &[u8]) -> Option<String> {
+    match element.first() {
+        None | Some(&tags::NIL) => None,
+        Some(&tags::STRING) | Some(&tags::SYMBOL) | Some(&tags::DECIMAL) => {
+            let mut pos = 1;
+            Some(read_string(element, &mut pos))
+        }
+        Some(_) => {
+            let mut pos = 0;
+            Some(deserialize_to_string(element, &mut pos))
+        }
+    }
+}
+
+/// Printed form of a symbol in a dialect: bare when it reads back as itself
+/// in the dialect's syntax, otherwise between pipes in the dialects
+/// that have them. The empty symbol is the exception: `||` reads as the
+/// symbol of two pipes, which Scheme data used before pipes quoted symbols.
+fn symbol_text(name: &str, dialect: Dialect) -> String {
+    if !matches!(dialect, Dialect::Scheme | Dialect::Smtlib)
+        || is_plain_symbol(name)
+        || reads_back_as_symbol(name, dialect)
+    {
+        return name.to_string();
+    }
+    if dialect == Dialect::Smtlib {
+        // SMT-LIB quoted symbols have no escapes
+        return format!("|{}|", name);
+    }
+    format!("|{}|", name.replace('\\', "\\\\").replace('|', "\\|"))
+}
+
+/// Cheap check for the common case of a symbol that cannot read as
+/// anything else
+fn is_plain_symbol(name: &str) -> bool {
+    name.bytes().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name.bytes().all(|c| c.is_ascii_alphanumeric() || b"-_!?*<>=/:%&$^~.+".contains(&c))
+        && !["nil", "t", "true", "false", "yes", "no", "inf", "infinity", "nan"]
+            .iter()
+            .any(|word| name.eq_ignore_ascii_case(word))
+}
+
+/// String literal in a dialect's syntax
+fn quote_string(s: &str, dialect: Dialect) -> String {
+    if dialect == Dialect::Smtlib {
+        return format!("\"{}\"", s.replace('"', "\"\""));
     }
+    format!("\"{}\"", escape_string(s))
 }
 
 fn escape_string(s: &str) -> String {
@@ -682,7 +1810,7 @@ fn sexp_cdr(sexp: Sexp) -> Option<Sexp> {
     sexp.cdr()
 }
 
-/// Get nth element (0-indexed)
+/// Get nth element (0-indexed; -1 is the last element)
 #[pg_extern(name = "nth", immutable, parallel_safe)]
 fn sexp_nth(sexp: Sexp, n: i32) -> Option<Sexp> {
     sexp.nth(n)
@@ -739,19 +1867,115 @@ fn sexp_is_string(sexp: Sexp) -> bool {
 /// Check if number
 #[pg_extern(name = "is_number", immutable, parallel_safe)]
 fn sexp_is_number(sexp: Sexp) -> bool {
-    sexp.data.len() >= 2 && matches!(sexp.data[1], tags::INTEGER | tags::FLOAT)
+    sexp.data.len() >= 2 && matches!(sexp.data[1], tags::INTEGER | tags::FLOAT | tags::DECIMAL)
+}
+
+/// Check if boolean
+#[pg_extern(name = "is_boolean", immutable, parallel_safe)]
+fn sexp_is_boolean(sexp: Sexp) -> bool {
+    sexp.data.len() >= 3 && sexp.data[1] == tags::BOOL
+}
+
+/// Size of the first slice compared when a value is stored out of line. Each
+/// further slice is twice as large, so a compressed value, which has to be
+/// decompressed from the start for every slice, is decompressed at most about
+/// twice over.
+const EQ_FIRST_SLICE: i32 = 4096;
+
+/// First payload byte of a datum. Inline values are read directly,
+/// compressed or out of line ones through a one-byte slice, which fetches a
+/// single toast chunk and decompresses no further than it needs.
+unsafe fn first_payload_byte(ptr: *mut pg_sys::varlena) -> Option<u8> {
+    if !pgrx::varlena::varatt_is_external(ptr) && !pgrx::varlena::varatt_is_compressed(ptr) {
+        return pgrx::varlena::varlena_to_byte_slice(ptr).first().copied();
+    }
+    let head = pg_sys::pg_detoast_datum_slice(ptr, 0, 1);
+    let first = pgrx::varlena::varlena_to_byte_slice(head).first().copied();
+    if head != ptr {
+        pg_sys::pfree(head.cast());
+    }
+    first
+}
+
+/// True for a legacy CBOR datum, reading only its first byte
+unsafe fn is_legacy(ptr: *mut pg_sys::varlena) -> bool {
+    first_payload_byte(ptr) == Some(LEGACY_CBOR_MAP)
+}
+
+/// Equality of two sexp datums. The encoding is canonical, so values of
+/// different sizes differ, and the sizes are known from the varlena or
+/// toast pointer headers: unequal large values are rejected after reading
+/// one byte of each to rule out the legacy CBOR format, whose sizes aren't
+/// comparable and which falls back to a decoded comparison, as sexp_cmp and
+/// sexp_hash do. Equal-sized values stored out of line are compared slice by
+/// slice, stopping at the first difference.
+unsafe fn datums_equal(a: pg_sys::Datum, b: pg_sys::Datum) -> bool {
+    let decoded_equal = || {
+        let (a, b) = (Sexp::from_datum(a, false), Sexp::from_datum(b, false));
+        a.zip(b).is_some_and(|(a, b)| a.equals(&b))
+    };
+    let (pa, pb) = (a.cast_mut_ptr::<pg_sys::varlena>(), b.cast_mut_ptr::<pg_sys::varlena>());
+    let size = pg_sys::toast_raw_datum_size(a);
+    if size != pg_sys::toast_raw_datum_size(b) {
+        return (is_legacy(pa) || is_legacy(pb)) && decoded_equal();
+    }
+
+    if !pgrx::varlena::varatt_is_external(pa) && !pgrx::varlena::varatt_is_external(pb) {
+        let x = pgrx::varlena::varlena_to_byte_slice(pg_sys::pg_detoast_datum_packed(pa));
+        let y = pgrx::varlena::varlena_to_byte_slice(pg_sys::pg_detoast_datum_packed(pb));
+        if x.first() == Some(&LEGACY_CBOR_MAP) || y.first() == Some(&LEGACY_CBOR_MAP) {
+            return decoded_equal();
+        }
+        return x == y;
+    }
+
+    let size = (size - VARHDRSZ) as i32;
+    let mut offset = 0;
+    let mut slice = EQ_FIRST_SLICE;
+    while offset < size {
+        let count = slice.min(size - offset);
+        let x = pg_sys::pg_detoast_datum_slice(pa, offset, count);
+        let y = pg_sys::pg_detoast_datum_slice(pb, offset, count);
+        let (xs, ys) = (pgrx::varlena::varlena_to_byte_slice(x), pgrx::varlena::varlena_to_byte_slice(y));
+        if offset == 0 && (xs.first() == Some(&LEGACY_CBOR_MAP) || ys.first() == Some(&LEGACY_CBOR_MAP)) {
+            return decoded_equal();
+        }
+        let same = xs == ys;
+        pg_sys::pfree(x.cast());
+        pg_sys::pfree(y.cast());
+        if !same {
+            return false;
+        }
+        offset += count;
+        slice = slice.saturating_mul(2);
+    }
+    true
 }
 
 /// Equality check
-#[pg_extern(name = "sexp_eq", immutable, parallel_safe)]
-fn sexp_eq(a: Sexp, b: Sexp) -> bool {
-    a.equals(&b)
+#[pg_extern(
+    sql = r#"
+CREATE FUNCTION sexp_eq(sexp, sexp) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'sexp_eq_wrapper';
+"#,
+    requires = [Sexp]
+)]
+fn sexp_eq(fcinfo: pg_sys::FunctionCallInfo) -> bool {
+    unsafe { datums_equal(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0), pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 1)) }
 }
 
 /// Inequality check
-#[pg_extern(name = "sexp_ne", immutable, parallel_safe)]
-fn sexp_ne(a: Sexp, b: Sexp) -> bool {
-    !a.equals(&b)
+#[pg_extern(
+    sql = r#"
+CREATE FUNCTION sexp_ne(sexp, sexp) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'sexp_ne_wrapper';
+"#,
+    requires = [Sexp]
+)]
+fn sexp_ne(fcinfo: pg_sys::FunctionCallInfo) -> bool {
+    unsafe { !datums_equal(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0), pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 1)) }
 }
 
 /// Hash function
@@ -865,152 +2089,58 @@ CREATE CAST (sexp AS text)
 
 /// Check key-based containment - matches by symbolic keys regardless of structure
 /// Container @>> needle means all key-value pairs in needle exist somewhere in container
+///
+/// Needle parts are checked from an explicit work list, so nesting depth
+/// costs heap rather than stack.
 fn sexp_contains_key_impl(container: &Sexp, needle: &Sexp) -> bool {
-    // For atoms, fall back to structural containment
-    if needle.is_atom() {
-        return container.contains(needle);
-    }
-    
-    // For nil, always matches
-    if needle.is_nil() {
-        return true;
-    }
-    
-    // For lists, check if it's a key-value pattern
-    if needle.data.len() >= 2 && needle.data[1] == tags::LIST {
-        let mut pos = 2;
-        let count = read_varint(&needle.data, &mut pos) as usize;
-        
-        if count >= 2 {
-            // Check if first element is a symbol (key)
-            let first_start = pos;
-            if needle.data[first_start] == tags::SYMBOL {
-                // This is a (key value ...) pattern
-                // Extract the key
-                let mut key_pos = first_start + 1;
-                let key_len = read_varint(&needle.data, &mut key_pos) as usize;
-                let key_bytes = &needle.data[key_pos..key_pos + key_len];
-                
-                // Skip to second element (value)
-                skip_element(&needle.data, &mut pos);
-                let value_start = pos;
-                let mut value_end = pos;
-                skip_element(&needle.data, &mut value_end);
-                
-                // Build value sexp for comparison
-                let mut value_data = vec![FORMAT_VERSION];
-                value_data.extend_from_slice(&needle.data[value_start..value_end]);
-                let value_sexp = Sexp { data: value_data };
-                
-                // Search container for matching key-value pair
-                if find_key_value_in_container(container, key_bytes, &value_sexp) {
-                    // If there are more elements (additional key-value pairs), check them too
-                    if count > 2 {
-                        // Build a sexp with remaining elements and check recursively
-                        for i in 2..count {
-                            // Get the i-th element
-                            let mut elem_pos = 2;
-                            let _ = read_varint(&needle.data, &mut elem_pos); // count
-                            for _ in 0..i {
-                                skip_element(&needle.data, &mut elem_pos);
-                            }
-                            let elem_start = elem_pos;
-                            skip_element(&needle.data, &mut elem_pos);
-                            let elem_end = elem_pos;
-                            
-                            let mut elem_data = vec![FORMAT_VERSION];
-                            elem_data.extend_from_slice(&needle.data[elem_start..elem_end]);
-                            let elem_sexp = Sexp { data: elem_data };
-                            
-                            if !sexp_contains_key_impl(container, &elem_sexp) {
-                                return false;
-                            }
-                        }
+    let mut pending = vec![needle.data.get(1..).unwrap_or_default()];
+    while let Some(part) = pending.pop() {
+        match part.first() {
+            // Nil always matches
+            None | Some(&tags::NIL) => {}
+            Some(&tags::LIST) => {
+                let children = list_elements(part);
+                if children.len() >= 2 && children[0][0] == tags::SYMBOL {
+                    // A (key value ...) pattern: the first value must appear
+                    // under the key, and the remaining elements are checked
+                    // on their own
+                    let key = atom_bytes_at(children[0], 0).unwrap_or_default();
+                    if !find_key_value_in_container(container, key, children[1]) {
+                        return false;
                     }
-                    return true;
+                    pending.extend(&children[2..]);
+                } else {
+                    // Not a key-value pattern, check all children
+                    pending.extend(children);
                 }
-                return false;
             }
-        }
-        
-        // Not a key-value pattern, check all children
-        let mut child_pos = pos;
-        for _ in 0..count {
-            let start = child_pos;
-            skip_element(&needle.data, &mut child_pos);
-            let end = child_pos;
-            
-            let mut child_data = vec![FORMAT_VERSION];
-            child_data.extend_from_slice(&needle.data[start..end]);
-            let child_sexp = Sexp { data: child_data };
-            
-            if !sexp_contains_key_impl(container, &child_sexp) {
-                return false;
+            // Atoms fall back to structural containment
+            Some(_) => {
+                if !(PreparedNeedle { bytes: part }).found_at(&container.data, 1) {
+                    return false;
+                }
             }
         }
-        return true;
     }
-    
-    // Fallback to structural containment
-    container.contains(needle)
+    true
 }
 
-/// Find a key-value pair anywhere in container
-fn find_key_value_in_container(container: &Sexp, key_bytes: &[u8], value: &Sexp) -> bool {
-    if container.data.len() < 2 {
-        return false;
-    }
-    
-    if container.data[1] == tags::LIST {
-        let mut pos = 2;
-        let count = read_varint(&container.data, &mut pos) as usize;
-        
-        // Check if this list has the key as first element
-        if count >= 2 && container.data[pos] == tags::SYMBOL {
-            let mut key_pos = pos + 1;
-            let key_len = read_varint(&container.data, &mut key_pos) as usize;
-            let container_key = &container.data[key_pos..key_pos + key_len];
-            
-            if container_key == key_bytes {
-                // Key matches, now check if value matches (at any position after the key)
-                skip_element(&container.data, &mut pos); // skip key
-                
-                for _ in 1..count {
-                    let val_start = pos;
-                    skip_element(&container.data, &mut pos);
-                    let val_end = pos;
-                    
-                    let mut val_data = vec![FORMAT_VERSION];
-                    val_data.extend_from_slice(&container.data[val_start..val_end]);
-                    let val_sexp = Sexp { data: val_data };
-                    
-                    // Check structural containment for value
-                    if val_sexp.equals(value) || val_sexp.contains(value) {
-                        return true;
-                    }
-                }
-            }
-        }
-        
-        // Search recursively in children
-        let mut child_pos = 2;
-        let _ = read_varint(&container.data, &mut child_pos); // count
-        for _ in 0..count {
-            let start = child_pos;
-            skip_element(&container.data, &mut child_pos);
-            let end = child_pos;
-            
-            let mut child_data = vec![FORMAT_VERSION];
-            child_data.extend_from_slice(&container.data[start..end]);
-            let child = Sexp { data: child_data };
-            
-            if find_key_value_in_container(&child, key_bytes, value) {
-                return true;
-            }
+/// Find a key-value pair anywhere in container: a list headed by the key
+/// symbol with some later element containing the value
+fn find_key_value_in_container(container: &Sexp, key_bytes: &[u8], value: &[u8]) -> bool {
+    let value = PreparedNeedle { bytes: value };
+    let data: &[u8] = &container.data;
+    Preorder::new(data, 1).any(|item| {
+        check_depth(item.depth);
+        if item.tag != tags::LIST {
+            return false;
         }
-    }
-    
-    false
+        let elements = list_elements(&data[item.start..]);
+        elements.len() >= 2
+            && elements[0][0] == tags::SYMBOL
+            && atom_bytes_at(elements[0], 0) == Some(key_bytes)
+            && elements[1..].iter().any(|v| value.found_at(v, 0))
+    })
 }
 
 /// Key-based containment operator (@>>)
@@ -1020,926 +2150,10036 @@ fn sexp_contains_key(container: Sexp, needle: Sexp) -> bool {
 }
 
 // ============================================================================
-// Pattern Matching
+// Symbol Containment (? operator)
 // ============================================================================
 
-/// Pattern types for matching
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum PatternType {
-    Literal,
-    Wildcard,      // _
-    WildcardRest,  // _*
-    Capture,       // ?name
-    CaptureRest,   // ??name
+/// Check whether a symbol occurs anywhere in the expression
+fn has_symbol_impl(sexp: &Sexp, symbol: &[u8]) -> bool {
+    Preorder::new(&sexp.data, 1)
+        .filter(|item| item.tag == tags::SYMBOL)
+        .any(|item| atom_bytes_at(&sexp.data, item.start) == Some(symbol))
 }
 
-/// Check if a symbol is a pattern symbol
-fn get_pattern_type(sym: &str) -> PatternType {
-    if sym == "_" {
-        PatternType::Wildcard
-    } else if sym == "_*" {
-        PatternType::WildcardRest
-    } else if sym.starts_with("??") {
-        PatternType::CaptureRest
-    } else if sym.starts_with('?') {
-        PatternType::Capture
-    } else {
-        PatternType::Literal
-    }
+/// Symbol containment operator (?)
+#[pg_extern(name = "sexp_has_symbol", immutable, parallel_safe)]
+fn sexp_has_symbol(sexp: Sexp, symbol: &str) -> bool {
+    has_symbol_impl(&sexp, symbol.as_bytes())
 }
 
-/// Match elements at current positions
-fn match_elements(expr_data: &[u8], expr_pos: &mut usize, 
-                  pat_data: &[u8], pat_pos: &mut usize) -> bool {
-    if *expr_pos >= expr_data.len() || *pat_pos >= pat_data.len() {
-        return *expr_pos >= expr_data.len() && *pat_pos >= pat_data.len();
-    }
-    
-    let pat_tag = pat_data[*pat_pos];
-    
-    // Check if pattern element is a symbol (potential pattern)
-    if pat_tag == tags::SYMBOL {
-        let saved_pat_pos = *pat_pos;
-        *pat_pos += 1;
-        let sym_len = read_varint(pat_data, pat_pos) as usize;
-        
-        if *pat_pos + sym_len <= pat_data.len() {
-            let sym = std::str::from_utf8(&pat_data[*pat_pos..*pat_pos + sym_len]).unwrap_or("");
-            let ptype = get_pattern_type(sym);
-            
-            match ptype {
-                PatternType::Wildcard | PatternType::Capture => {
-                    // Match any single element
-                    *pat_pos += sym_len;
-                    skip_element(expr_data, expr_pos);
-                    return true;
-                }
-                PatternType::WildcardRest | PatternType::CaptureRest => {
-                    // Rest patterns are handled at list level
-                    *pat_pos = saved_pat_pos;
-                    return false;
-                }
-                PatternType::Literal => {
-                    // Restore and continue with normal matching
-                    *pat_pos = saved_pat_pos;
-                }
-            }
-        } else {
-            *pat_pos = saved_pat_pos;
-        }
-    }
-    
-    // Normal element matching
-    let expr_tag = expr_data[*expr_pos];
-    let pat_tag = pat_data[*pat_pos];
-    
-    if expr_tag != pat_tag {
-        return false;
-    }
-    
-    match expr_tag {
-        tags::NIL => {
-            *expr_pos += 1;
-            *pat_pos += 1;
-            true
-        }
-        tags::INTEGER => {
-            *expr_pos += 1;
-            *pat_pos += 1;
-            let expr_val = read_signed_varint(expr_data, expr_pos);
-            let pat_val = read_signed_varint(pat_data, pat_pos);
-            expr_val == pat_val
-        }
-        tags::FLOAT => {
-            if *expr_pos + 9 > expr_data.len() || *pat_pos + 9 > pat_data.len() {
-                return false;
-            }
-            *expr_pos += 1;
-            *pat_pos += 1;
-            let expr_bytes: [u8; 8] = expr_data[*expr_pos..*expr_pos + 8].try_into().unwrap();
-            let pat_bytes: [u8; 8] = pat_data[*pat_pos..*pat_pos + 8].try_into().unwrap();
-            *expr_pos += 8;
-            *pat_pos += 8;
-            expr_bytes == pat_bytes
-        }
-        tags::STRING => {
-            *expr_pos += 1;
-            *pat_pos += 1;
-            let expr_len = read_varint(expr_data, expr_pos) as usize;
-            let pat_len = read_varint(pat_data, pat_pos) as usize;
-            
-            if expr_len != pat_len {
-                *expr_pos += expr_len;
-                *pat_pos += pat_len;
-                return false;
-            }
-            
-            let result = expr_data[*expr_pos..*expr_pos + expr_len] == 
-                         pat_data[*pat_pos..*pat_pos + pat_len];
-            *expr_pos += expr_len;
-            *pat_pos += pat_len;
-            result
-        }
-        tags::SYMBOL => {
-            *expr_pos += 1;
-            *pat_pos += 1;
-            let expr_len = read_varint(expr_data, expr_pos) as usize;
-            let pat_len = read_varint(pat_data, pat_pos) as usize;
-            
-            if expr_len != pat_len {
-                *expr_pos += expr_len;
-                *pat_pos += pat_len;
-                return false;
-            }
-            
-            let result = expr_data[*expr_pos..*expr_pos + expr_len] == 
-                         pat_data[*pat_pos..*pat_pos + pat_len];
-            *expr_pos += expr_len;
-            *pat_pos += pat_len;
-            result
+// ============================================================================
+// Containment Signatures
+// ============================================================================
+
+// A signature is a 64-bit bloom filter of the atoms in a document. Whenever
+// `a @> b`, every atom of b is an atom of a, so a's signature covers b's;
+// storing the signature in a column lets a plain scan discard most rows
+// before decoding them, e.g.
+//
+//   WHERE sig @>? sexp_signature(pattern) AND doc @> pattern
+
+/// Bits set per atom
+const SIGNATURE_HASHES: u32 = 2;
+
+/// Bloom-style bitmap of every atom in the expression, list heads included
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_signature(doc: Sexp) -> i64 {
+    let data: &[u8] = &doc.data;
+    let mut signature = 0u64;
+    for item in Preorder::new(data, 1) {
+        check_depth(item.depth);
+        if item.tag == tags::LIST {
+            continue;
         }
-        tags::LIST => {
-            *expr_pos += 1;
-            *pat_pos += 1;
-            match_list_elements(expr_data, expr_pos, pat_data, pat_pos)
+        let mut end = item.start;
+        skip_element(data, &mut end);
+        let hash = signature_hash(&data[item.start..end]);
+        for i in 0..SIGNATURE_HASHES {
+            signature |= 1 << ((hash >> (6 * i)) & 63);
         }
-        _ => false,
     }
+    signature as i64
 }
 
-/// Match list elements with support for rest patterns
-fn match_list_elements(expr_data: &[u8], expr_pos: &mut usize,
-                       pat_data: &[u8], pat_pos: &mut usize) -> bool {
-    let expr_count = read_varint(expr_data, expr_pos) as usize;
-    let pat_count = read_varint(pat_data, pat_pos) as usize;
-    
-    let mut expr_i = 0;
-    let mut pat_i = 0;
-    
-    while pat_i < pat_count {
-        // Check if current pattern element is a rest pattern
-        if pat_data[*pat_pos] == tags::SYMBOL {
-            let saved_pos = *pat_pos;
-            let mut check_pos = *pat_pos + 1;
-            let sym_len = read_varint(pat_data, &mut check_pos) as usize;
-            
-            if check_pos + sym_len <= pat_data.len() {
-                let sym = std::str::from_utf8(&pat_data[check_pos..check_pos + sym_len]).unwrap_or("");
-                let ptype = get_pattern_type(sym);
-                
-                if ptype == PatternType::WildcardRest || ptype == PatternType::CaptureRest {
-                    // Rest pattern must be last in pattern list
-                    if pat_i + 1 != pat_count {
-                        return false;
-                    }
-                    
-                    // Consume all remaining expression elements
-                    while expr_i < expr_count {
-                        skip_element(expr_data, expr_pos);
-                        expr_i += 1;
-                    }
-                    
-                    // Skip the rest pattern element
-                    *pat_pos = check_pos + sym_len;
-                    return true;
-                }
-            }
-            *pat_pos = saved_pos;
-        }
-        
-        // Need exactly one expression element
-        if expr_i >= expr_count {
-            return false;
-        }
-        
-        // Match this element
-        if !match_elements(expr_data, expr_pos, pat_data, pat_pos) {
-            return false;
-        }
-        
-        expr_i += 1;
-        pat_i += 1;
-    }
-    
-    // All pattern elements matched - check for leftovers
-    expr_i == expr_count
+/// Hash of an atom's encoding for signatures. Signatures are stored, so
+/// this is FNV-1a with a fixed finalizer rather than std's hasher, whose
+/// algorithm may change between Rust releases.
+fn signature_hash(atom: &[u8]) -> u64 {
+    let mut hash = atom.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    // splitmix64 finalizer, so every bit range depends on every input byte
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
 }
 
-/// Pattern matching function
-#[pg_extern(name = "sexp_match", immutable, parallel_safe)]
-fn sexp_match_fn(expr: Sexp, pattern: Sexp) -> bool {
-    if expr.data.len() < 2 || pattern.data.len() < 2 {
-        return expr.data.len() < 2 && pattern.data.len() < 2;
-    }
-    
-    let mut expr_pos = 1; // skip version
-    let mut pat_pos = 1;  // skip version
-    
-    match_elements(&expr.data, &mut expr_pos, &pattern.data, &mut pat_pos)
+/// True if a document with signature `container` may contain one with
+/// signature `needle`; false means it certainly does not
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_signature_compatible(container: i64, needle: i64) -> bool {
+    container & needle == needle
 }
 
-/// Find first subexpression matching pattern
-fn find_pattern_recursive(data: &[u8], pos: &mut usize, pattern: &Sexp) -> Option<Sexp> {
-    if *pos >= data.len() {
+extension_sql!(
+    r#"
+-- Signature compatibility operator (@>?), a cheap necessary condition for @>
+CREATE OPERATOR @>? (
+    LEFTARG = bigint,
+    RIGHTARG = bigint,
+    FUNCTION = sexp_signature_compatible
+);
+"#,
+    name = "sexp_signature_operator",
+    requires = [sexp_signature_compatible]
+);
+
+// ============================================================================
+// Shape Constraints
+// ============================================================================
+
+/// Key of an alist entry, i.e. the head symbol of a non-empty list
+fn entry_key(element: &[u8]) -> Option<&[u8]> {
+    if element.first() != Some(&tags::LIST) {
         return None;
     }
-    
-    let start = *pos;
-    
-    // Try matching at current position
-    let mut expr_pos = start;
-    let mut pat_pos = 1; // skip version in pattern
-    
-    if match_elements(data, &mut expr_pos, &pattern.data, &mut pat_pos) {
-        // Match found! Extract this element
-        let mut result_data = vec![FORMAT_VERSION];
-        let mut end_pos = start;
-        skip_element(data, &mut end_pos);
-        result_data.extend_from_slice(&data[start..end_pos]);
-        *pos = end_pos;
-        return Some(Sexp { data: result_data });
-    }
-    
-    // No match at this position - if it's a list, search children
-    let tag = data[*pos];
-    *pos += 1;
-    
-    if tag == tags::LIST {
-        let count = read_varint(data, pos) as usize;
-        
-        for _ in 0..count {
-            if let Some(found) = find_pattern_recursive(data, pos, pattern) {
-                return Some(found);
-            }
-        }
-    } else {
-        // Skip this non-list element
-        *pos = start;
-        skip_element(data, pos);
+    let mut pos = 1;
+    if read_varint(element, &mut pos) == 0 || element.get(pos) != Some(&tags::SYMBOL) {
+        return None;
     }
-    
-    None
+    atom_bytes_at(element, pos)
 }
 
-/// Find first subexpression matching pattern
-#[pg_extern(name = "sexp_find", immutable, parallel_safe)]
-fn sexp_find(expr: Sexp, pattern: Sexp) -> Option<Sexp> {
-    if expr.data.len() < 2 {
-        return None;
+/// Check the alist shape: a list whose elements are all symbol-headed lists
+fn is_alist_impl(sexp: &Sexp) -> bool {
+    sexp.is_nil() || (sexp.is_list() && sexp.elements().iter().all(|e| entry_key(e).is_some()))
+}
+
+/// Check if value is an alist: ((key value ...) ...)
+#[pg_extern(name = "sexp_is_alist", immutable, parallel_safe)]
+fn sexp_is_alist(sexp: Sexp) -> bool {
+    is_alist_impl(&sexp)
+}
+
+/// Check if an alist has an entry for every given key
+#[pg_extern(name = "sexp_has_keys", immutable, parallel_safe)]
+fn sexp_has_keys(sexp: Sexp, keys: Vec<String>) -> bool {
+    if !is_alist_impl(&sexp) {
+        return false;
     }
-    
-    let mut pos = 1; // skip version
-    find_pattern_recursive(&expr.data, &mut pos, &pattern)
+    let elements = sexp.elements();
+    keys.iter().all(|key| {
+        elements
+            .iter()
+            .any(|e| entry_key(e) == Some(key.as_bytes()))
+    })
+}
+
+/// Check if the head of a list is the given symbol
+#[pg_extern(name = "sexp_head_is", immutable, parallel_safe)]
+fn sexp_head_is(sexp: Sexp, symbol: &str) -> bool {
+    entry_key(sexp.data.get(1..).unwrap_or_default()) == Some(symbol.as_bytes())
 }
 
+extension_sql!(
+    r#"
+-- Example domains for columns with structural invariants
+CREATE DOMAIN sexp_alist AS sexp
+    CHECK (sexp_is_alist(VALUE));
+
+CREATE DOMAIN sexp_list AS sexp
+    CHECK (is_list(VALUE));
+
+COMMENT ON DOMAIN sexp_alist IS 'sexp restricted to association lists: ((key value ...) ...)';
+COMMENT ON DOMAIN sexp_list IS 'sexp restricted to lists (including nil)';
+"#,
+    name = "sexp_shape_domains",
+    requires = [sexp_is_alist, sexp_is_list]
+);
+
 // ============================================================================
-// GIN Index Support
+// Type Modifiers: sexp(list), sexp(atom), sexp(alist)
 // ============================================================================
 
-/// Key type markers for GIN index (must match C implementation for compatibility)
-mod gin_keys {
-    pub const ATOM: u32 = 0x01000000;
-    pub const LIST_HEAD: u32 = 0x02000000;
-    pub const SYMBOL: u32 = 0x03000000;
-    pub const STRING: u32 = 0x04000000;
-    pub const INTEGER: u32 = 0x05000000;
-    pub const FLOAT: u32 = 0x06000000;
-    pub const PAIR: u32 = 0x07000000;
+/// Top-level shapes accepted as sexp type modifiers
+mod typmods {
+    pub const LIST: i32 = 1;
+    pub const ATOM: i32 = 2;
+    pub const ALIST: i32 = 3;
 }
 
-/// Hash combine function (same as C implementation)
-fn hash_combine32(seed: u32, hash: u32) -> u32 {
-    seed ^ (hash.wrapping_add(0x9e3779b9).wrapping_add(seed << 6).wrapping_add(seed >> 2))
+/// Parse type modifier, e.g. the `alist` in sexp(alist)
+#[pg_extern(name = "sexp_typmod_in", immutable, parallel_safe)]
+fn sexp_typmod_in(modifiers: Array<&core::ffi::CStr>) -> i32 {
+    let modifiers: Vec<_> = modifiers.iter().collect();
+    if modifiers.len() != 1 {
+        pgrx::error!("invalid sexp type modifier: expected one of list, atom, alist");
+    }
+
+    let shape = modifiers[0]
+        .and_then(|m| m.to_str().ok())
+        .map(|m| m.to_ascii_lowercase());
+    match shape.as_deref() {
+        Some("list") => typmods::LIST,
+        Some("atom") => typmods::ATOM,
+        Some("alist") => typmods::ALIST,
+        Some(other) => {
+            pgrx::error!("invalid sexp type modifier \"{}\": expected one of list, atom, alist", other)
+        }
+        None => pgrx::error!("invalid sexp type modifier"),
+    }
 }
 
-/// Compute hash for bytes
-fn hash_bytes(data: &[u8]) -> u32 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    
-    let mut hasher = DefaultHasher::new();
-    data.hash(&mut hasher);
-    hasher.finish() as u32
+/// Print type modifier
+#[pg_extern(name = "sexp_typmod_out", immutable, parallel_safe)]
+fn sexp_typmod_out(typmod: i32) -> &'static core::ffi::CStr {
+    match typmod {
+        typmods::LIST => c"(list)",
+        typmods::ATOM => c"(atom)",
+        typmods::ALIST => c"(alist)",
+        _ => c"",
+    }
 }
 
-/// Compute hash for i64
-fn hash_i64(val: i64) -> u32 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    
-    let mut hasher = DefaultHasher::new();
-    val.hash(&mut hasher);
-    hasher.finish() as u32
+/// Enforce the declared shape when a value is stored as sexp(shape)
+#[pg_extern(name = "sexp_enforce_typmod", immutable, parallel_safe)]
+fn sexp_enforce_typmod(value: Sexp, typmod: i32, _explicit: bool) -> Sexp {
+    let (ok, shape) = match typmod {
+        typmods::LIST => (value.is_list(), "list"),
+        typmods::ATOM => (value.is_atom(), "atom"),
+        typmods::ALIST => (is_alist_impl(&value), "alist"),
+        _ => return value,
+    };
+    if !ok {
+        pgrx::error!("sexp value does not conform to sexp({}): {}", shape, value.to_string_repr());
+    }
+    value
 }
 
-/// Compute hash for f64
-fn hash_f64(val: f64) -> u32 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    
-    let mut hasher = DefaultHasher::new();
-    val.to_bits().hash(&mut hasher);
-    hasher.finish() as u32
+extension_sql!(
+    r#"
+ALTER TYPE sexp SET (
+    TYPMOD_IN = sexp_typmod_in,
+    TYPMOD_OUT = sexp_typmod_out
+);
+
+-- Length-coercion style cast, applied whenever a value is assigned to sexp(shape)
+CREATE CAST (sexp AS sexp)
+    WITH FUNCTION sexp_enforce_typmod(sexp, integer, boolean)
+    AS IMPLICIT;
+"#,
+    name = "sexp_typmods",
+    requires = [sexp_typmod_in, sexp_typmod_out, sexp_enforce_typmod]
+);
+
+// ============================================================================
+// hstore Interoperability
+// ============================================================================
+//
+// sexp_to_hstore and hstore_to_sexp exist only if hstore was installed before
+// this extension was created; the sexp_hstore script below says how to add
+// them later.
+
+/// Expand a flat alist into (key, value) text pairs
+#[pg_extern(name = "sexp_each_text", immutable, parallel_safe)]
+fn sexp_each_text(
+    alist: Sexp,
+) -> TableIterator<'static, (name!(key, String), name!(value, Option<String>))> {
+    if !is_alist_impl(&alist) {
+        pgrx::error!("sexp_each_text requires an alist: {}", alist.to_string_repr());
+    }
+
+    let mut rows = Vec::new();
+    for entry in alist.elements() {
+        let key = String::from_utf8_lossy(entry_key(entry).unwrap_or_default()).into_owned();
+        let entry = Sexp::from_element(entry);
+        let value = match entry.length() {
+            1 => None,
+            2 => entry.nth(1).and_then(|v| element_text(&v.data[1..])),
+            _ => pgrx::error!("alist entry for \"{}\" is not a (key value) pair", key),
+        };
+        rows.push((key, value));
+    }
+    TableIterator::new(rows.into_iter())
 }
 
-/// Make a GIN key with type marker
-fn make_gin_key(type_marker: u32, value_hash: u32) -> i32 {
-    let combined = type_marker ^ value_hash;
-    (combined | 0x80000000) as i32
+/// Build a flat alist of string values from parallel key and value arrays
+#[pg_extern(name = "sexp_from_text_pairs", immutable, parallel_safe)]
+fn sexp_from_text_pairs(keys: Vec<String>, values: Vec<Option<String>>) -> Sexp {
+    if keys.len() != values.len() {
+        pgrx::error!("sexp_from_text_pairs: key and value arrays must have the same length");
+    }
+
+    let entries = keys
+        .into_iter()
+        .zip(values)
+        .map(|(key, value)| {
+            let value = value.map(ParsedExpr::String).unwrap_or(ParsedExpr::Nil);
+            ParsedExpr::List(vec![ParsedExpr::Symbol(key.into()), value])
+        })
+        .collect();
+    Sexp::from_parsed(&ParsedExpr::List(entries))
 }
 
-/// Get element hash at position
-fn get_element_hash(data: &[u8], pos: &mut usize) -> u32 {
-    if *pos >= data.len() {
-        return 0;
+extension_sql!(
+    r#"
+-- hstore conversions, created only when hstore is already installed when
+-- the extension is created. Installing hstore afterwards doesn't add them:
+-- recreate the extension, or run this block with @extschema@ replaced by the
+-- extension's schema. Every name is qualified with its schema, so the
+-- functions don't depend on the caller's search_path.
+DO $do$
+DECLARE
+    hstore_schema name;
+BEGIN
+    SELECT n.nspname INTO hstore_schema
+        FROM pg_catalog.pg_type t JOIN pg_catalog.pg_namespace n ON n.oid = t.typnamespace
+        WHERE t.oid = pg_catalog.to_regtype('hstore');
+    IF hstore_schema IS NOT NULL THEN
+        EXECUTE pg_catalog.format($fn$
+            CREATE FUNCTION @extschema@.sexp_to_hstore(@extschema@.sexp) RETURNS %1$I.hstore
+                AS 'SELECT %1$I.hstore(coalesce(array_agg(key), ''{}''), coalesce(array_agg(value), ''{}'')) FROM @extschema@.sexp_each_text($1)'
+                LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE
+        $fn$, hstore_schema);
+        EXECUTE pg_catalog.format($fn$
+            CREATE FUNCTION @extschema@.hstore_to_sexp(%1$I.hstore) RETURNS @extschema@.sexp
+                AS 'SELECT @extschema@.sexp_from_text_pairs(%1$I.akeys($1), %1$I.avals($1))'
+                LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE
+        $fn$, hstore_schema);
+    END IF;
+END
+$do$;
+"#,
+    name = "sexp_hstore",
+    requires = [sexp_each_text, sexp_from_text_pairs]
+);
+
+// ============================================================================
+// XML Interoperability (SXML)
+// ============================================================================
+
+/// Minimal XML reader producing SXML: `(tag (@ (attr "value") ...) children...)`
+struct XmlReader<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> XmlReader<'a> {
+    fn new(input: &'a str) -> Self {
+        XmlReader { input, pos: 0 }
     }
-    
-    let tag = data[*pos];
-    
-    match tag {
-        tags::NIL => {
-            *pos += 1;
-            hash_i64(0)
-        }
-        tags::INTEGER => {
-            *pos += 1;
-            let val = read_signed_varint(data, pos);
-            hash_i64(val)
-        }
-        tags::FLOAT => {
-            *pos += 1;
-            if *pos + 8 > data.len() {
-                return 0;
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    /// Advance past the next occurrence of `terminator`
+    fn skip_past(&mut self, terminator: &str) -> Result<(), String> {
+        match self.rest().find(terminator) {
+            Some(idx) => {
+                self.pos += idx + terminator.len();
+                Ok(())
             }
-            let bytes: [u8; 8] = data[*pos..*pos + 8].try_into().unwrap();
-            *pos += 8;
-            let val = f64::from_le_bytes(bytes);
-            hash_f64(val)
+            None => Err(format!("Unterminated XML construct, expected '{}'", terminator)),
         }
-        tags::STRING => {
-            *pos += 1;
-            let len = read_varint(data, pos) as usize;
-            if *pos + len > data.len() {
-                return 0;
+    }
+
+    /// Skip declarations, processing instructions, comments and doctype
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<!") && !rest.starts_with("<![CDATA[") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
             }
-            let hash = hash_bytes(&data[*pos..*pos + len]);
-            *pos += len;
-            hash
         }
-        tags::SYMBOL => {
-            *pos += 1;
-            let len = read_varint(data, pos) as usize;
-            if *pos + len > data.len() {
-                return 0;
-            }
-            let hash = hash_bytes(&data[*pos..*pos + len]);
-            *pos += len;
-            hash
+    }
+
+    fn parse_document(&mut self) -> Result<ParsedExpr, String> {
+        self.skip_misc()?;
+        if !self.rest().starts_with('<') {
+            return Err("XML document must start with an element".to_string());
         }
-        tags::LIST => {
-            *pos += 1;
-            let count = read_varint(data, pos);
-            if count > 0 {
-                // Return hash of first element (head)
-                get_element_hash(data, pos)
-            } else {
-                hash_i64(0)
-            }
+        let root = self.parse_element(0)?;
+        self.skip_misc()?;
+        if self.pos < self.input.len() {
+            return Err(format!("Unexpected content after root element at position {}", self.pos));
         }
-        _ => 0,
+        Ok(root)
     }
-}
 
-/// Extract GIN keys recursively
-fn extract_gin_keys(data: &[u8], pos: &mut usize, keys: &mut Vec<i32>, skip_pair_keys: bool) {
-    if *pos >= data.len() || keys.len() >= 1024 {
-        return;
+    fn parse_name(&mut self) -> Result<String, String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(format!("Expected XML name at position {}", self.pos));
+        }
+        self.pos += len;
+        Ok(rest[..len].to_string())
     }
-    
-    let tag = data[*pos];
-    
-    match tag {
-        tags::NIL => {
-            *pos += 1;
-            let hash = hash_i64(0);
-            let key = make_gin_key(gin_keys::ATOM, hash);
-            if !keys.contains(&key) {
-                keys.push(key);
+
+    fn parse_element(&mut self, depth: usize) -> Result<ParsedExpr, String> {
+        check_depth(depth);
+        self.pos += 1; // '<'
+        let tag = self.parse_name()?;
+
+        let mut attrs = Vec::new();
+        let self_closing = loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                break true;
             }
-        }
-        tags::INTEGER => {
-            let _start = *pos;
-            *pos += 1;
-            let val = read_signed_varint(data, pos);
-            let hash = hash_i64(val);
-            let key = make_gin_key(gin_keys::INTEGER, hash);
-            if !keys.contains(&key) {
-                keys.push(key);
+            if rest.starts_with('>') {
+                self.pos += 1;
+                break false;
             }
-        }
-        tags::FLOAT => {
-            *pos += 1;
-            if *pos + 8 <= data.len() {
-                let bytes: [u8; 8] = data[*pos..*pos + 8].try_into().unwrap();
-                let val = f64::from_le_bytes(bytes);
-                let hash = hash_f64(val);
-                let key = make_gin_key(gin_keys::FLOAT, hash);
-                if !keys.contains(&key) {
-                    keys.push(key);
-                }
+            if rest.is_empty() {
+                return Err(format!("Unterminated start tag <{}>", tag));
             }
-            *pos += 8;
-        }
-        tags::STRING => {
-            *pos += 1;
-            let len = read_varint(data, pos) as usize;
-            if *pos + len <= data.len() {
-                let hash = hash_bytes(&data[*pos..*pos + len]);
-                let key = make_gin_key(gin_keys::STRING, hash);
-                if !keys.contains(&key) {
-                    keys.push(key);
-                }
+
+            let name = self.parse_name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(format!("Expected '=' after attribute {}", name));
             }
-            *pos += len;
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(q @ ('"' | '\'')) => q,
+                _ => return Err(format!("Expected quoted value for attribute {}", name)),
+            };
+            self.pos += 1;
+            let end = self
+                .rest()
+                .find(quote)
+                .ok_or_else(|| format!("Unterminated value for attribute {}", name))?;
+            let value = decode_xml_entities(&self.rest()[..end])?;
+            self.pos += end + 1;
+            attrs.push(ParsedExpr::List(vec![ParsedExpr::Symbol(name.into()), ParsedExpr::String(value)]));
+        };
+
+        let mut items = vec![ParsedExpr::Symbol(tag.as_str().into())];
+        if !attrs.is_empty() {
+            attrs.insert(0, ParsedExpr::Symbol("@".into()));
+            items.push(ParsedExpr::List(attrs));
         }
-        tags::SYMBOL => {
-            *pos += 1;
-            let len = read_varint(data, pos) as usize;
-            if *pos + len <= data.len() {
-                let hash = hash_bytes(&data[*pos..*pos + len]);
-                let key = make_gin_key(gin_keys::SYMBOL, hash);
-                if !keys.contains(&key) {
-                    keys.push(key);
-                }
-            }
-            *pos += len;
+        if self_closing {
+            return Ok(ParsedExpr::List(items));
         }
-        tags::LIST => {
-            *pos += 1;
-            let count = read_varint(data, pos) as usize;
-            
-            if count == 0 {
-                return;
+
+        let mut text = String::new();
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                return Err(format!("Missing closing tag </{}>", tag));
             }
-            
-            let children_start = *pos;
-            
-            // Check if this is a 2-element pair with symbol head
-            let is_pair = count == 2 && data[*pos] == tags::SYMBOL;
-            
-            // Get head hash
-            let mut head_pos = *pos;
-            let head_hash = get_element_hash(data, &mut head_pos);
-            
-            if is_pair && !skip_pair_keys {
-                // Extract pair key: hash(symbol, value)
-                let mut second_pos = children_start;
-                skip_element(data, &mut second_pos); // skip first element
-                let mut second_hash_pos = second_pos;
-                let second_hash = get_element_hash(data, &mut second_hash_pos);
-                
-                let pair_hash = hash_combine32(gin_keys::PAIR, head_hash);
-                let pair_hash = hash_combine32(pair_hash, second_hash);
-                let key = make_gin_key(gin_keys::PAIR, pair_hash);
-                if !keys.contains(&key) {
-                    keys.push(key);
+            if rest.starts_with("</") {
+                flush_xml_text(&mut text, &mut items);
+                self.pos += 2;
+                let closing = self.parse_name()?;
+                if closing != tag {
+                    return Err(format!("Mismatched closing tag </{}>, expected </{}>", closing, tag));
                 }
-            } else if !is_pair {
-                // Add list head key for non-pair lists
-                let key = make_gin_key(gin_keys::LIST_HEAD, head_hash);
-                if !keys.contains(&key) {
-                    keys.push(key);
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return Err(format!("Malformed closing tag </{}>", tag));
                 }
-            }
-            
-            // Recurse into children
-            for _ in 0..count {
-                extract_gin_keys(data, pos, keys, skip_pair_keys);
+                self.pos += 1;
+                return Ok(ParsedExpr::List(items));
+            } else if rest.starts_with("<![CDATA[") {
+                let body = &rest[9..];
+                let end = body.find("]]>").ok_or("Unterminated CDATA section")?;
+                text.push_str(&body[..end]);
+                self.pos += 9 + end + 3;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with('<') {
+                flush_xml_text(&mut text, &mut items);
+                items.push(self.parse_element(depth + 1)?);
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                text.push_str(&decode_xml_entities(&rest[..end])?);
+                self.pos += end;
             }
         }
-        _ => {
-            skip_element(data, pos);
+    }
+}
+
+/// Append accumulated character data as a string child, dropping whitespace-only runs
+fn flush_xml_text(text: &mut String, items: &mut Vec<ParsedExpr>) {
+    if !text.trim().is_empty() {
+        items.push(ParsedExpr::String(std::mem::take(text)));
+    }
+    text.clear();
+}
+
+fn decode_xml_entities(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('&') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let end = rest.find(';').ok_or("Unterminated XML entity reference")?;
+        let entity = &rest[..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        out.push(decoded.ok_or_else(|| format!("Unknown XML entity &{};", entity))?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn escape_xml(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
         }
     }
 }
 
-/// Extract GIN keys from sexp value (returns array)
-#[pg_extern(name = "sexp_extract_keys", immutable, parallel_safe)]
-fn sexp_extract_keys(value: Sexp) -> Vec<i32> {
-    let mut keys = Vec::new();
-    
-    if value.data.len() >= 2 {
-        let mut pos = 1; // skip version
-        extract_gin_keys(&value.data, &mut pos, &mut keys, false);
+/// Render an SXML element (or character data) as XML text
+fn write_sxml(element: &[u8], out: &mut String) -> Result<(), String> {
+    let sexp = Sexp::from_element(element);
+    if sexp.is_nil() || !sexp.is_list() {
+        if let Some(text) = element_text(element) {
+            escape_xml(&text, out);
+        }
+        return Ok(());
     }
-    
-    if keys.is_empty() {
-        keys.push(make_gin_key(gin_keys::ATOM, 0));
+
+    let children = sexp.elements();
+    let tag = match atom_bytes_at(children[0], 0) {
+        Some(tag) if children[0][0] == tags::SYMBOL => String::from_utf8_lossy(tag).into_owned(),
+        _ => return Err(format!("SXML element must start with a tag symbol: {}", sexp.to_string_repr())),
+    };
+
+    let mut body = &children[1..];
+    out.push('<');
+    out.push_str(&tag);
+    if let Some(attrs) = body.first().filter(|el| entry_key(el) == Some(b"@".as_slice())) {
+        for attr in Sexp::from_element(attrs).elements().into_iter().skip(1) {
+            let attr = Sexp::from_element(attr);
+            let name = match attr.car().and_then(|k| element_text(&k.data[1..])) {
+                Some(name) if attr.is_list() => name,
+                _ => return Err(format!("Malformed SXML attribute: {}", attr.to_string_repr())),
+            };
+            let value = attr.nth(1).and_then(|v| element_text(&v.data[1..])).unwrap_or_default();
+            out.push(' ');
+            out.push_str(&name);
+            out.push_str("=\"");
+            escape_xml(&value, out);
+            out.push('"');
+        }
+        body = &body[1..];
     }
-    
-    keys
+
+    if body.is_empty() {
+        out.push_str("/>");
+        return Ok(());
+    }
+    out.push('>');
+    for child in body {
+        write_sxml(child, out)?;
+    }
+    out.push_str("</");
+    out.push_str(&tag);
+    out.push('>');
+    Ok(())
 }
 
-/// Extract GIN keys from query (returns array)
-#[pg_extern(name = "sexp_extract_query_keys", immutable, parallel_safe)]
-fn sexp_extract_query_keys(query: Sexp, strategy: i32) -> Vec<i32> {
-    let mut keys = Vec::new();
-    
-    // For key-based containment (@>>), skip pair keys
-    // Strategy 9 is SEXP_GIN_CONTAINS_KEY_STRATEGY
-    let skip_pair_keys = strategy == 9;
-    
-    if query.data.len() >= 2 {
-        let mut pos = 1; // skip version
-        extract_gin_keys(&query.data, &mut pos, &mut keys, skip_pair_keys);
+/// Parse an XML document into its SXML representation
+#[pg_extern(name = "xml_text_to_sexp", immutable, parallel_safe)]
+fn xml_text_to_sexp(document: &str) -> Sexp {
+    match XmlReader::new(document).parse_document() {
+        Ok(parsed) => Sexp::from_parsed(&parsed),
+        Err(e) => pgrx::error!("Invalid XML: {}", e),
     }
-    
-    if keys.is_empty() {
-        keys.push(make_gin_key(gin_keys::ATOM, 0));
+}
+
+/// Render an SXML element as XML text
+#[pg_extern(name = "sexp_to_xml_text", immutable, parallel_safe)]
+fn sexp_to_xml_text(sexp: Sexp) -> String {
+    if sexp.is_nil() || !sexp.is_list() {
+        pgrx::error!("sexp_to_xml requires an SXML element: {}", sexp.to_string_repr());
     }
-    
-    keys
+    let mut out = String::new();
+    if let Err(e) = write_sxml(&sexp.data[1..], &mut out) {
+        pgrx::error!("{}", e);
+    }
+    out
 }
 
+extension_sql!(
+    r#"
+CREATE FUNCTION xml_to_sexp(xml) RETURNS sexp
+    AS 'SELECT xml_text_to_sexp($1::text)'
+    LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+CREATE FUNCTION sexp_to_xml(sexp) RETURNS xml
+    AS 'SELECT xmlparse(DOCUMENT sexp_to_xml_text($1))'
+    LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+"#,
+    name = "sexp_xml",
+    requires = [xml_text_to_sexp, sexp_to_xml_text]
+);
+
 // ============================================================================
-// GIN Index Support (Raw PostgreSQL API)
+// Canonical S-expressions (Rivest csexp)
 // ============================================================================
 
-/// GIN strategy numbers (matching C implementation)
-const SEXP_GIN_CONTAINS_STRATEGY: i16 = 7;     // @> structural containment
-const SEXP_GIN_CONTAINED_STRATEGY: i16 = 8;    // <@ contained by  
-const SEXP_GIN_CONTAINS_KEY_STRATEGY: i16 = 9; // @>> key-based containment
-
-/// GIN search modes
-const GIN_SEARCH_MODE_DEFAULT: i32 = 0;
-const GIN_SEARCH_MODE_ALL: i32 = 2;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
 
-/// GIN ternary values
-const GIN_FALSE: i8 = 0;
-const GIN_TRUE: i8 = 1;
-const GIN_MAYBE: i8 = 2;
+fn base64_decode(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in text {
+        if c.is_ascii_whitespace() || c == b'=' {
+            continue;
+        }
+        let v = BASE64_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| format!("Invalid base64 character '{}'", c as char))?;
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
 
-/// Extract GIN keys from stored value
-/// Signature: sexp_gin_extract_value(sexp, internal) -> internal
-#[pg_extern(name = "sexp_gin_extract_value", immutable, parallel_safe)]
-fn sexp_gin_extract_value_fn(
-    value: Sexp,
-    nkeys: Internal,
-) -> Internal {
-    use pgrx::pg_sys;
-    
-    // Extract keys using our helper function
-    let keys = sexp_extract_keys(value);
-    let key_count = keys.len();
-    
-    unsafe {
-        // Set nkeys output parameter
-        let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
-        *nkeys_ptr = key_count as i32;
-        
-        // Allocate Datum array in current memory context
-        let datums = pg_sys::palloc(std::mem::size_of::<pg_sys::Datum>() * key_count) 
-            as *mut pg_sys::Datum;
-        
-        // Copy keys as int32 Datums
-        for (i, key) in keys.iter().enumerate() {
-            *datums.add(i) = pg_sys::Datum::from(*key);
+/// Whether a list is encoded as one of the canonical encoding's own forms
+/// rather than as a list: `(octets "<hex>")` as binary octets,
+/// `(display-hint <hint> <value>)` as `[hint]value`, and `(quote <form>)`,
+/// where `<form>` is one of these, as `<form>` written as a plain list. Any
+/// other list, `(quote (a b))` or `(octets 1 2)` included, is written as it
+/// is. A canonical list that reads as one of these forms is wrapped in
+/// `quote`, so encoding it again writes the same list back.
+fn is_csexp_form(mut element: &[u8]) -> bool {
+    loop {
+        if element.first() != Some(&tags::LIST) {
+            return false;
+        }
+        let children = list_elements(element);
+        match entry_key(element) {
+            Some(b"octets") => return element_octets(element).is_some(),
+            Some(b"display-hint") => {
+                return children.len() == 3
+                    && element_octets(children[1]).is_some()
+                    && element_octets(children[2]).is_some();
+            }
+            Some(b"quote") if children.len() == 2 => element = children[1],
+            _ => return false,
         }
-        
-        Internal::from(Some(pg_sys::Datum::from(datums)))
     }
 }
 
-/// Extract GIN keys from query value
-/// Signature: sexp_gin_extract_query(sexp, internal, int2, internal, internal, internal, internal) -> internal
-#[pg_extern(name = "sexp_gin_extract_query", immutable, parallel_safe)]
-fn sexp_gin_extract_query_fn(
-    query: Sexp,
-    nkeys: Internal,
-    strategy: i16,
-    _pmatch: Internal,
-    _extra_data: Internal,
-    _null_flags: Internal,
-    search_mode: Internal,
-) -> Internal {
-    use pgrx::pg_sys;
-    
-    // Handle contained-by strategy specially
-    if strategy == SEXP_GIN_CONTAINED_STRATEGY {
-        unsafe {
-            let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
-            *nkeys_ptr = 0;
-            let search_mode_ptr = search_mode.unwrap().unwrap().cast_mut_ptr::<i32>();
-            *search_mode_ptr = GIN_SEARCH_MODE_ALL;
+/// Reader for the canonical encoding: `(`, `)`, `[hint]` and `<len>:<octets>`
+struct CsexpReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CsexpReader<'a> {
+    fn parse(&mut self, depth: usize) -> Result<ParsedExpr, String> {
+        check_depth(depth);
+        match self.input.get(self.pos) {
+            None => Err("Unexpected end of canonical sexp".to_string()),
+            Some(b'(') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    match self.input.get(self.pos) {
+                        None => return Err("Unclosed list in canonical sexp".to_string()),
+                        Some(b')') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(_) => items.push(self.parse(depth + 1)?),
+                    }
+                }
+                if items.is_empty() {
+                    return Ok(ParsedExpr::Nil);
+                }
+                let reserved = matches!(
+                    items.first(),
+                    Some(ParsedExpr::Symbol(head)) if ["octets", "display-hint", "quote"].contains(&head.as_str())
+                );
+                let list = ParsedExpr::List(items);
+                if reserved && is_csexp_form(&Sexp::from_parsed(&list).data[1..]) {
+                    return Ok(ParsedExpr::List(vec![ParsedExpr::Symbol("quote".into()), list]));
+                }
+                Ok(list)
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let hint = self.octets()?;
+                if self.input.get(self.pos) != Some(&b']') {
+                    return Err("Unterminated display hint in canonical sexp".to_string());
+                }
+                self.pos += 1;
+                let value = self.octets()?;
+                Ok(ParsedExpr::List(vec![
+                    ParsedExpr::Symbol("display-hint".into()),
+                    octets_to_parsed(hint),
+                    octets_to_parsed(value),
+                ]))
+            }
+            Some(_) => Ok(octets_to_parsed(self.octets()?)),
         }
-        return Internal::default();
     }
-    
-    // Extract keys using our helper function with appropriate strategy
-    let keys = sexp_extract_query_keys(query, strategy as i32);
-    let key_count = keys.len();
-    
-    unsafe {
-        // Set nkeys output parameter
-        let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
-        *nkeys_ptr = key_count as i32;
-        
-        // Set search mode
-        let search_mode_ptr = search_mode.unwrap().unwrap().cast_mut_ptr::<i32>();
-        *search_mode_ptr = GIN_SEARCH_MODE_DEFAULT;
-        
-        // Allocate Datum array
-        let datums = pg_sys::palloc(std::mem::size_of::<pg_sys::Datum>() * key_count)
-            as *mut pg_sys::Datum;
-        
-        // Copy keys as int32 Datums
-        for (i, key) in keys.iter().enumerate() {
-            *datums.add(i) = pg_sys::Datum::from(*key);
+
+    fn octets(&mut self) -> Result<&'a [u8], String> {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
         }
-        
-        Internal::from(Some(pg_sys::Datum::from(datums)))
+        if self.pos == start || self.input.get(self.pos) != Some(&b':') {
+            return Err(format!("Expected length-prefixed octet string at position {}", start));
+        }
+        let len: usize = std::str::from_utf8(&self.input[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or("Invalid octet string length")?;
+        self.pos += 1;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.input.len())
+            .ok_or("Octet string extends past end of input")?;
+        let bytes = &self.input[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
     }
 }
 
-/// GIN consistent check
-/// Signature: sexp_gin_consistent(internal, int2, sexp, int4, internal, internal, internal, internal) -> bool
-#[pg_extern(name = "sexp_gin_consistent", immutable, parallel_safe)]
-fn sexp_gin_consistent_fn(
-    check: Internal,
-    strategy: i16,
-    _query: Sexp,
-    nkeys: i32,
-    _extra_data: Internal,
-    recheck: Internal,
-    _query_keys: Internal,
-    _null_flags: Internal,
-) -> bool {
-    use pgrx::pg_sys;
-    
-    unsafe {
-        // Always require recheck (keys are hashes, collisions possible)
-        let recheck_ptr = recheck.unwrap().unwrap().cast_mut_ptr::<bool>();
-        *recheck_ptr = true;
-        
-        let check_ptr = check.unwrap().unwrap().cast_mut_ptr::<bool>();
-        
-        match strategy {
-            SEXP_GIN_CONTAINS_STRATEGY | SEXP_GIN_CONTAINS_KEY_STRATEGY => {
-                // All query keys must be present
-                for i in 0..nkeys {
-                    if !*check_ptr.add(i as usize) {
-                        return false;
+/// Map an octet string to an atom: tokens become symbols, other UTF-8 text
+/// a string, and binary data `(octets "<hex>")`. Octets are never read as
+/// numbers or nil, so re-encoding reproduces them byte for byte.
+fn octets_to_parsed(bytes: &[u8]) -> ParsedExpr {
+    match std::str::from_utf8(bytes) {
+        Ok(text)
+            if !text.is_empty()
+                && !text.bytes().any(|c| c.is_ascii_whitespace() || b"()\";".contains(&c)) =>
+        {
+            ParsedExpr::Symbol(text.into())
+        }
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+            ParsedExpr::String(text.to_string())
+        }
+        _ => {
+            let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            ParsedExpr::List(vec![ParsedExpr::Symbol("octets".into()), ParsedExpr::String(hex)])
+        }
+    }
+}
+
+fn write_octets(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+/// Bytes of an atom element; `(octets "<hex>")` yields the decoded binary
+fn element_octets(element: &[u8]) -> Option<Vec<u8>> {
+    if entry_key(element) == Some(b"octets".as_slice()) {
+        let entry = Sexp::from_element(element);
+        let hex = entry.nth(1).and_then(|h| element_text(&h.data[1..]))?;
+        if entry.length() != 2 || hex.len() % 2 != 0 {
+            return None;
+        }
+        return (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+    }
+    if element.first() == Some(&tags::LIST) {
+        return None;
+    }
+    Some(element_text(element).unwrap_or_default().into_bytes())
+}
+
+fn write_csexp(element: &[u8], out: &mut Vec<u8>) {
+    if matches!(element.first(), None | Some(&tags::NIL)) {
+        out.extend_from_slice(b"()");
+        return;
+    }
+    if let Some(bytes) = element_octets(element) {
+        write_octets(out, &bytes);
+        return;
+    }
+
+    let children = list_elements(element);
+    if entry_key(element) == Some(b"quote".as_slice()) && children.len() == 2 && is_csexp_form(children[1]) {
+        // A list that only looks like an encoding form, written as it is
+        out.push(b'(');
+        for child in list_elements(children[1]) {
+            write_csexp(child, out);
+        }
+        out.push(b')');
+        return;
+    }
+    if entry_key(element) == Some(b"display-hint".as_slice()) && children.len() == 3 {
+        if let (Some(hint), Some(value)) = (element_octets(children[1]), element_octets(children[2])) {
+            out.push(b'[');
+            write_octets(out, &hint);
+            out.push(b']');
+            write_octets(out, &value);
+            return;
+        }
+    }
+
+    out.push(b'(');
+    for child in children {
+        write_csexp(child, out);
+    }
+    out.push(b')');
+}
+
+/// Decode a canonical (or `{base64}` transport) encoded S-expression
+#[pg_extern(name = "sexp_from_csexp", immutable, parallel_safe)]
+fn sexp_from_csexp(input: &[u8]) -> Sexp {
+    let decoded;
+    let canonical = match input.trim_ascii() {
+        [b'{', body @ .., b'}'] => {
+            decoded = base64_decode(body).unwrap_or_else(|e| pgrx::error!("Invalid transport sexp: {}", e));
+            decoded.as_slice()
+        }
+        other => other,
+    };
+
+    let mut reader = CsexpReader { input: canonical, pos: 0 };
+    let parsed = reader
+        .parse(0)
+        .unwrap_or_else(|e| pgrx::error!("Invalid canonical sexp: {}", e));
+    if reader.pos != canonical.len() {
+        pgrx::error!("Invalid canonical sexp: trailing data at position {}", reader.pos);
+    }
+    Sexp::from_parsed(&parsed)
+}
+
+/// Encode as a canonical S-expression, or as `{base64}` transport when requested
+#[pg_extern(name = "sexp_to_csexp", immutable, parallel_safe)]
+fn sexp_to_csexp(sexp: Sexp, transport: default!(bool, false)) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_csexp(sexp.data.get(1..).unwrap_or_default(), &mut out);
+    if transport {
+        out = format!("{{{}}}", base64_encode(&out)).into_bytes();
+    }
+    out
+}
+
+// ============================================================================
+// SQL Parse Trees
+// ============================================================================
+
+/// Reader for PostgreSQL's node text format (nodeToString output):
+/// `{TAG :field value ...}` becomes `(TAG (field value) ...)`, `(...)` a
+/// list, `<>` nil, `"..."` a string and other tokens numbers, booleans or
+/// symbols
+struct NodeTextReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    /// Lists and nodes open around the current token
+    depth: usize,
+}
+
+enum NodeToken {
+    Open(u8),
+    Close(u8),
+    Field(String),
+    /// A token and whether it had any backslash escape, which makes it
+    /// text even when it looks like a number, boolean or nil
+    Atom(String, bool),
+}
+
+impl<'a> NodeTextReader<'a> {
+    fn new(input: &'a str) -> Self {
+        NodeTextReader { input: input.as_bytes(), pos: 0, depth: 0 }
+    }
+
+    fn peek_token(&mut self) -> Option<NodeToken> {
+        let start = self.pos;
+        let token = self.next_token();
+        self.pos = start;
+        token
+    }
+
+    fn next_token(&mut self) -> Option<NodeToken> {
+        while self.pos < self.input.len() && self.input[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        let c = *self.input.get(self.pos)?;
+        if matches!(c, b'(' | b'{' | b'[') {
+            self.pos += 1;
+            return Some(NodeToken::Open(c));
+        }
+        if matches!(c, b')' | b'}' | b']') {
+            self.pos += 1;
+            return Some(NodeToken::Close(c));
+        }
+
+        // A token runs to whitespace or a bracket; backslash escapes the next byte
+        let mut token = Vec::new();
+        let mut escaped = false;
+        while let Some(&c) = self.input.get(self.pos) {
+            if c.is_ascii_whitespace() || matches!(c, b'(' | b')' | b'{' | b'}') {
+                break;
+            }
+            self.pos += 1;
+            if c == b'\\' {
+                escaped = true;
+                if let Some(&next) = self.input.get(self.pos) {
+                    token.push(next);
+                    self.pos += 1;
+                }
+                continue;
+            }
+            token.push(c);
+        }
+        let token = String::from_utf8_lossy(&token).into_owned();
+        Some(match token.strip_prefix(':') {
+            // Only an unescaped colon starts a field name
+            Some(field) if c == b':' => NodeToken::Field(field.to_string()),
+            _ => NodeToken::Atom(token, escaped),
+        })
+    }
+
+    fn read_value(&mut self) -> Result<ParsedExpr, String> {
+        match self.next_token() {
+            Some(NodeToken::Open(open)) => {
+                self.depth += 1;
+                check_depth(self.depth);
+                let value = match open {
+                    b'{' => self.read_node(),
+                    b'(' => self.read_list(b')'),
+                    _ => self.read_list(b']'),
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(NodeToken::Atom(token, escaped)) => Ok(node_atom(token, escaped)),
+            Some(NodeToken::Field(field)) => Err(format!("unexpected field :{}", field)),
+            Some(NodeToken::Close(c)) => Err(format!("unexpected '{}'", c as char)),
+            None => Err("unexpected end of node text".to_string()),
+        }
+    }
+
+    fn read_list(&mut self, close: u8) -> Result<ParsedExpr, String> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek_token() {
+                Some(NodeToken::Close(c)) if c == close => {
+                    self.next_token();
+                    break;
+                }
+                None => return Err("unterminated list".to_string()),
+                _ => items.push(self.read_value()?),
+            }
+        }
+        // Integer, OID, xid and bitmapset lists start with a type letter
+        if let Some(ParsedExpr::Symbol(marker)) = items.first() {
+            if matches!(&**marker, "i" | "o" | "x" | "b") {
+                items.remove(0);
+            }
+        }
+        Ok(ParsedExpr::List(items))
+    }
+
+    fn read_node(&mut self) -> Result<ParsedExpr, String> {
+        let tag = match self.next_token() {
+            Some(NodeToken::Atom(tag, _)) => tag,
+            _ => return Err("node without a tag".to_string()),
+        };
+        let mut node = vec![ParsedExpr::Symbol(tag.into())];
+        loop {
+            match self.next_token() {
+                Some(NodeToken::Close(b'}')) => break,
+                Some(NodeToken::Field(field)) => {
+                    // A field's value runs to the next field or the end of the node
+                    let mut values = Vec::new();
+                    while !matches!(self.peek_token(), Some(NodeToken::Field(_)) | Some(NodeToken::Close(b'}')) | None) {
+                        values.push(self.read_value()?);
                     }
+                    let value = match values.len() {
+                        1 => values.pop().unwrap(),
+                        _ => ParsedExpr::List(values),
+                    };
+                    node.push(ParsedExpr::List(vec![ParsedExpr::Symbol(field.into()), value]));
                 }
-                true
+                // Value nodes such as Integer and String print without fields
+                Some(NodeToken::Atom(token, escaped)) => node.push(node_atom(token, escaped)),
+                _ => return Err("unterminated node".to_string()),
             }
-            SEXP_GIN_CONTAINED_STRATEGY => {
-                // For contained-by, we can't efficiently pre-filter
-                true
+        }
+        Ok(ParsedExpr::List(node))
+    }
+}
+
+/// Value of a node text token. nodeToString escapes text that would read
+/// as something else (`\123`, `\<>`, `\true`), so an escaped token is
+/// always a string or symbol.
+fn node_atom(token: String, escaped: bool) -> ParsedExpr {
+    if token.len() >= 2 && token.starts_with('"') && token.ends_with('"') {
+        return ParsedExpr::String(token[1..token.len() - 1].to_string());
+    }
+    if escaped {
+        return ParsedExpr::Symbol(token.into());
+    }
+    if token == "<>" {
+        return ParsedExpr::Nil;
+    }
+    match token.as_str() {
+        "true" => return ParsedExpr::Bool(true),
+        "false" => return ParsedExpr::Bool(false),
+        _ => {}
+    }
+    if let Ok(i) = token.parse::<i64>() {
+        return ParsedExpr::Integer(i);
+    }
+    if token.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') {
+        if let Ok(f) = token.parse::<f64>() {
+            return ParsedExpr::Float(f);
+        }
+    }
+    ParsedExpr::Symbol(token.into())
+}
+
+/// Raw parse tree of a SQL string: a list with one `(RAWSTMT ...)` node per
+/// statement, node tags as head symbols and fields as `(name value)` pairs
+#[pg_extern(name = "sql_to_sexp", stable, parallel_safe)]
+fn sql_to_sexp(query: &str) -> Sexp {
+    let query = std::ffi::CString::new(query)
+        .unwrap_or_else(|_| pgrx::error!("sql_to_sexp: query contains a NUL byte"));
+    let text = unsafe {
+        let tree = pg_sys::raw_parser(query.as_ptr(), pg_sys::RawParseMode::RAW_PARSE_DEFAULT);
+        let text = pg_sys::nodeToString(tree.cast());
+        let result = core::ffi::CStr::from_ptr(text).to_string_lossy().into_owned();
+        pg_sys::pfree(text.cast());
+        result
+    };
+    match NodeTextReader::new(&text).read_value() {
+        Ok(parsed) => Sexp::from_parsed(&parsed),
+        Err(e) => pgrx::error!("sql_to_sexp: could not read parse tree: {}", e),
+    }
+}
+
+/// Convert one EXPLAIN (FORMAT JSON) plan node: the node type without
+/// spaces becomes the head symbol, other properties `(kebab-key value)`
+/// pairs and child plans a `(plans ...)` entry
+fn plan_node_to_parsed(node: &serde_json::Value) -> ParsedExpr {
+    let Some(fields) = node.as_object() else {
+        return json_to_parsed(node);
+    };
+    let node_type = fields.get("Node Type").and_then(|t| t.as_str()).unwrap_or("Unknown");
+    let mut parts = vec![ParsedExpr::Symbol(node_type.replace(' ', "").into())];
+    for (key, value) in fields {
+        let key_symbol = ParsedExpr::Symbol(key.to_lowercase().replace(' ', "-").into());
+        match (key.as_str(), value) {
+            ("Node Type", _) => {}
+            ("Plans", serde_json::Value::Array(children)) => {
+                let mut plans = vec![key_symbol];
+                plans.extend(children.iter().map(plan_node_to_parsed));
+                parts.push(ParsedExpr::List(plans));
+            }
+            _ => parts.push(ParsedExpr::List(vec![key_symbol, json_to_parsed(value)])),
+        }
+    }
+    ParsedExpr::List(parts)
+}
+
+/// Raise an error unless `query` parses as exactly one statement EXPLAIN
+/// plans without running it, so nothing after a `;` reaches the executor
+fn check_explainable(query: &str) {
+    let text = std::ffi::CString::new(query)
+        .unwrap_or_else(|_| pgrx::error!("plan_to_sexp: query contains a NUL byte"));
+    let explainable = unsafe {
+        let tree = pg_sys::raw_parser(text.as_ptr(), pg_sys::RawParseMode::RAW_PARSE_DEFAULT);
+        let statements = pgrx::PgList::<pg_sys::RawStmt>::from_pg(tree);
+        statements.len() == 1
+            && statements.get_ptr(0).is_some_and(|raw| {
+                let stmt = (*raw).stmt;
+                [
+                    pg_sys::NodeTag::T_SelectStmt,
+                    pg_sys::NodeTag::T_InsertStmt,
+                    pg_sys::NodeTag::T_UpdateStmt,
+                    pg_sys::NodeTag::T_DeleteStmt,
+                ]
+                .into_iter()
+                .any(|tag| pgrx::is_a(stmt, tag))
+            })
+    };
+    if !explainable {
+        pgrx::error!("plan_to_sexp: query must be a single SELECT, INSERT, UPDATE or DELETE statement");
+    }
+}
+
+/// Executor plan of a query, e.g. `(HashJoin (join-type "Inner") ... (plans
+/// (SeqScan ...) (Hash ...)))`. The query must be one SELECT, INSERT, UPDATE
+/// or DELETE statement; it is planned, not run.
+#[pg_extern(name = "plan_to_sexp", volatile)]
+fn plan_to_sexp(query: &str) -> Sexp {
+    check_explainable(query);
+    let explain = Spi::get_one::<pgrx::Json>(&format!("EXPLAIN (FORMAT JSON) {}", query))
+        .unwrap_or_else(|e| pgrx::error!("plan_to_sexp: {}", e))
+        .unwrap_or_else(|| pgrx::error!("plan_to_sexp: EXPLAIN returned no plan"));
+    match explain.0.get(0).and_then(|entry| entry.get("Plan")) {
+        Some(plan) => Sexp::from_parsed(&plan_node_to_parsed(plan)),
+        None => pgrx::error!("plan_to_sexp: unexpected EXPLAIN output"),
+    }
+}
+
+// ============================================================================
+// Array Conversions
+// ============================================================================
+
+/// Build a list of atoms from array elements, NULL elements becoming nil
+fn list_from_array<T>(values: Vec<Option<T>>, atom: impl Fn(T) -> ParsedExpr) -> Sexp {
+    let items = values
+        .into_iter()
+        .map(|v| v.map(&atom).unwrap_or(ParsedExpr::Nil))
+        .collect();
+    Sexp::from_parsed(&ParsedExpr::List(items))
+}
+
+/// Convert each element of a list to an array element, nil becoming NULL;
+/// errors on atoms `convert` rejects
+fn list_to_array<T>(
+    sexp: &Sexp,
+    type_name: &str,
+    convert: impl Fn(&[u8]) -> Option<T>,
+) -> Vec<Option<T>> {
+    if !sexp.is_list() {
+        pgrx::error!("cannot cast non-list sexp to {}: {}", type_name, sexp.to_string_repr());
+    }
+    sexp.elements()
+        .into_iter()
+        .map(|element| {
+            if element[0] == tags::NIL {
+                return None;
+            }
+            match convert(element) {
+                Some(v) => Some(v),
+                None => pgrx::error!(
+                    "cannot cast sexp element {} to {}",
+                    Sexp::from_element(element).to_string_repr(),
+                    type_name
+                ),
+            }
+        })
+        .collect()
+}
+
+fn element_integer(element: &[u8]) -> Option<i64> {
+    if element[0] != tags::INTEGER {
+        return None;
+    }
+    let mut pos = 1;
+    Some(read_signed_varint(element, &mut pos))
+}
+
+/// Decimal literal without sign on zero, leading integer zeros or trailing
+/// fraction zeros, so that equal values have equal text
+fn normalize_decimal(text: &str) -> Option<String> {
+    if !is_decimal_literal(text) {
+        return None;
+    }
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, text.trim_start_matches('+')),
+    };
+    let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    let int_part = int_part.trim_start_matches('0');
+    let frac_part = frac_part.trim_end_matches('0');
+    let digits = match (int_part, frac_part) {
+        ("", "") => return Some("0".to_string()),
+        (int_part, "") => int_part.to_string(),
+        (int_part, frac_part) => format!("{}.{}", if int_part.is_empty() { "0" } else { int_part }, frac_part),
+    };
+    Some(if negative { format!("-{}", digits) } else { digits })
+}
+
+/// Exact decimal atom for numeric text. Decimals print as `digits.fractionm`,
+/// so an integral value gains a `.0` to read back as the same decimal; text
+/// that is no decimal literal, such as NaN, becomes a float.
+fn decimal_atom(text: String) -> ParsedExpr {
+    if !is_decimal_literal(&text) {
+        return match text.parse::<f64>() {
+            Ok(f) => ParsedExpr::Float(f),
+            Err(_) => ParsedExpr::String(text),
+        };
+    }
+    let text = text.trim_start_matches('+').to_string();
+    if text.contains('.') {
+        ParsedExpr::Decimal(text)
+    } else {
+        ParsedExpr::Decimal(text + ".0")
+    }
+}
+
+/// Order of two normalized decimal texts by value
+fn compare_decimal_text(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    let split = |text: &str| match text.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned.split_once('.').unwrap_or((unsigned, ""))),
+        None => (false, text.split_once('.').unwrap_or((text, ""))),
+    };
+    let ((negative_a, (int_a, frac_a)), (negative_b, (int_b, frac_b))) = (split(a), split(b));
+    match (negative_a, negative_b) {
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+        _ => {
+            // Without leading or trailing zeros, a longer integer part is
+            // larger and fractions compare digit by digit
+            let magnitude = int_a.len().cmp(&int_b.len()).then_with(|| int_a.cmp(int_b)).then_with(|| frac_a.cmp(frac_b));
+            if negative_a { magnitude.reverse() } else { magnitude }
+        }
+    }
+}
+
+/// Exact value of a number atom as normalized decimal text; finite floats
+/// use their shortest round-tripping form, written out in plain digits
+fn element_decimal(element: &[u8]) -> Option<String> {
+    match element[0] {
+        tags::INTEGER | tags::DECIMAL => normalize_decimal(&element_text(element)?),
+        tags::FLOAT => normalize_decimal(&element_float(element).filter(|f| f.is_finite())?.to_string()),
+        _ => None,
+    }
+}
+
+fn element_float(element: &[u8]) -> Option<f64> {
+    match element[0] {
+        tags::FLOAT => Some(f64::from_le_bytes(element.get(1..9)?.try_into().ok()?)),
+        tags::INTEGER => element_integer(element).map(|n| n as f64),
+        tags::DECIMAL => element_text(element)?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Convert a text array to a list of strings
+#[pg_extern(name = "sexp_from_text_array", immutable, parallel_safe)]
+fn sexp_from_text_array(values: Vec<Option<String>>) -> Sexp {
+    list_from_array(values, ParsedExpr::String)
+}
+
+/// Convert an integer array to a list of integers
+#[pg_extern(name = "sexp_from_int4_array", immutable, parallel_safe)]
+fn sexp_from_int4_array(values: Vec<Option<i32>>) -> Sexp {
+    list_from_array(values, |n| ParsedExpr::Integer(n.into()))
+}
+
+/// Convert a bigint array to a list of integers
+#[pg_extern(name = "sexp_from_int8_array", immutable, parallel_safe)]
+fn sexp_from_int8_array(values: Vec<Option<i64>>) -> Sexp {
+    list_from_array(values, ParsedExpr::Integer)
+}
+
+/// Convert a float8 array to a list of floats
+#[pg_extern(name = "sexp_from_float8_array", immutable, parallel_safe)]
+fn sexp_from_float8_array(values: Vec<Option<f64>>) -> Sexp {
+    list_from_array(values, ParsedExpr::Float)
+}
+
+/// Convert a list of strings or symbols to a text array
+#[pg_extern(name = "sexp_to_text_array", immutable, parallel_safe)]
+fn sexp_to_text_array(sexp: Sexp) -> Vec<Option<String>> {
+    list_to_array(&sexp, "text[]", |element| match element[0] {
+        tags::STRING | tags::SYMBOL => element_text(element),
+        _ => None,
+    })
+}
+
+/// Convert a list of integers to an integer array
+#[pg_extern(name = "sexp_to_int4_array", immutable, parallel_safe)]
+fn sexp_to_int4_array(sexp: Sexp) -> Vec<Option<i32>> {
+    list_to_array(&sexp, "integer[]", |element| {
+        element_integer(element).and_then(|n| i32::try_from(n).ok())
+    })
+}
+
+/// Convert a list of integers to a bigint array
+#[pg_extern(name = "sexp_to_int8_array", immutable, parallel_safe)]
+fn sexp_to_int8_array(sexp: Sexp) -> Vec<Option<i64>> {
+    list_to_array(&sexp, "bigint[]", element_integer)
+}
+
+/// Convert a list of numbers to a float8 array
+#[pg_extern(name = "sexp_to_float8_array", immutable, parallel_safe)]
+fn sexp_to_float8_array(sexp: Sexp) -> Vec<Option<f64>> {
+    list_to_array(&sexp, "double precision[]", element_float)
+}
+
+/// Flatten the atoms of an expression into a text array (atom text form),
+/// nil elements of nested lists becoming NULL
+#[pg_extern(name = "sexp_to_array", immutable, parallel_safe)]
+fn sexp_to_array(sexp: Sexp) -> Vec<Option<String>> {
+    Preorder::new(&sexp.data, 1)
+        .filter(|item| item.tag != tags::LIST && !(item.tag == tags::NIL && item.depth == 0))
+        .map(|item| element_text(&sexp.data[item.start..]))
+        .collect()
+}
+
+extension_sql!(
+    r#"
+-- Array casts
+CREATE CAST (text[] AS sexp) WITH FUNCTION sexp_from_text_array(text[]);
+CREATE CAST (integer[] AS sexp) WITH FUNCTION sexp_from_int4_array(integer[]);
+CREATE CAST (bigint[] AS sexp) WITH FUNCTION sexp_from_int8_array(bigint[]);
+CREATE CAST (double precision[] AS sexp) WITH FUNCTION sexp_from_float8_array(double precision[]);
+
+CREATE CAST (sexp AS text[]) WITH FUNCTION sexp_to_text_array(sexp);
+CREATE CAST (sexp AS integer[]) WITH FUNCTION sexp_to_int4_array(sexp);
+CREATE CAST (sexp AS bigint[]) WITH FUNCTION sexp_to_int8_array(sexp);
+CREATE CAST (sexp AS double precision[]) WITH FUNCTION sexp_to_float8_array(sexp);
+"#,
+    name = "sexp_array_casts",
+    requires = [
+        sexp_from_text_array,
+        sexp_from_int4_array,
+        sexp_from_int8_array,
+        sexp_from_float8_array,
+        sexp_to_text_array,
+        sexp_to_int4_array,
+        sexp_to_int8_array,
+        sexp_to_float8_array
+    ]
+);
+
+// ============================================================================
+// Format Versioning
+// ============================================================================
+
+/// Storage version reported for the legacy CBOR encoding, which has no
+/// version byte of its own
+const LEGACY_FORMAT_VERSION: i32 = 0;
+
+/// Decode a stored value of any supported format version, validating it and
+/// rewriting it into the current format when it is older: the legacy CBOR
+/// encoding (version 0) and V1.
+///
+/// Every other reader in this file works on the current format only, so new
+/// versions are introduced by adding a decoder arm here and bumping
+/// `FORMAT_VERSION`; existing rows keep working through `sexp_upgrade`.
+fn decode_versioned(data: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>, String> {
+    match data.first() {
+        None => Err("empty input".to_string()),
+        Some(&format_versions::V1) => {
+            validate_elements(data, 1).map_err(|e| e.to_string())?;
+            Ok(std::borrow::Cow::Borrowed(data))
+        }
+        Some(&LEGACY_CBOR_MAP) => {
+            let legacy = VarlenaBuf::from_slice(data).into_varlena();
+            let decoded: Result<Sexp, String> = PgTryBuilder::new(move || Ok(unsafe { pgrx::datum::cbor_decode(legacy) }))
+                .catch_others(|_| Err("invalid legacy CBOR value".to_string()))
+                .execute();
+            let decoded = decoded?;
+            validate_elements(&decoded.data, 1).map_err(|e| e.to_string())?;
+            Ok(std::borrow::Cow::Owned(decoded.data.to_vec()))
+        }
+        Some(v) => Err(format!("unsupported format version {}", v)),
+    }
+}
+
+/// Format version of a stored datum, read from its first byte without
+/// decoding it
+unsafe fn datum_storage_version(datum: pg_sys::Datum) -> i32 {
+    match first_payload_byte(datum.cast_mut_ptr()) {
+        Some(LEGACY_CBOR_MAP) => LEGACY_FORMAT_VERSION,
+        Some(version) => version as i32,
+        None => FORMAT_VERSION as i32,
+    }
+}
+
+/// Format version a value is stored in, 0 for the legacy CBOR encoding. It
+/// reads the datum as stored, before legacy values are decoded.
+#[pg_extern(
+    sql = r#"
+CREATE FUNCTION sexp_storage_version(sexp) RETURNS integer
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'sexp_storage_version_wrapper';
+"#,
+    requires = [Sexp]
+)]
+fn sexp_storage_version(fcinfo: pg_sys::FunctionCallInfo) -> i32 {
+    unsafe { datum_storage_version(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0)) }
+}
+
+/// Rewrite a value into the current format version. Legacy CBOR values are
+/// decoded on the way in, so `UPDATE t SET doc = sexp_upgrade(doc) WHERE
+/// sexp_storage_version(doc) <> 1` rewrites every old row.
+#[pg_extern(name = "sexp_upgrade", immutable, parallel_safe)]
+fn sexp_upgrade(sexp: Sexp) -> Sexp {
+    if sexp.data.len() < 2 {
+        return Sexp::nil();
+    }
+    match decode_versioned(&sexp.data) {
+        Ok(data) => Sexp::from_bytes(&data),
+        Err(e) => pgrx::error!("cannot upgrade sexp: {}", e),
+    }
+}
+
+// ============================================================================
+// Binary Casts
+// ============================================================================
+
+/// Validate a stored binary value, including the format version byte
+fn validate_binary(data: &[u8]) -> Result<(), String> {
+    decode_versioned(data).map(|_| ())
+}
+
+/// Internal binary representation of a sexp
+#[pg_extern(name = "sexp_to_bytea", immutable, parallel_safe)]
+fn sexp_to_bytea(sexp: Sexp) -> Vec<u8> {
+    sexp.data.to_vec()
+}
+
+/// Rebuild a sexp from its binary representation, validating it fully
+#[pg_extern(name = "sexp_from_bytea", immutable, parallel_safe)]
+fn sexp_from_bytea(data: &[u8]) -> Sexp {
+    match decode_versioned(data) {
+        Ok(data) => Sexp::from_bytes(&data),
+        Err(e) => pgrx::error!("invalid sexp binary: {}", e),
+    }
+}
+
+/// Check whether a bytea holds a valid sexp binary representation
+#[pg_extern(name = "sexp_binary_is_valid", immutable, parallel_safe)]
+fn sexp_binary_is_valid(data: &[u8]) -> bool {
+    validate_binary(data).is_ok()
+}
+
+/// Check a bytea for structural validity: version byte, tags, varint
+/// termination, length bounds and trailing bytes. Reports the byte offset
+/// and description of the first problem, both NULL for a valid value.
+#[pg_extern(name = "sexp_validate_binary", immutable, parallel_safe)]
+fn sexp_validate_binary(
+    data: &[u8],
+) -> TableIterator<'static, (name!(valid, bool), name!(error_offset, Option<i32>), name!(error, Option<String>))> {
+    let result = match data.first() {
+        None => Err(BinaryError { offset: 0, message: "empty input".to_string() }),
+        Some(&format_versions::V1) => validate_elements(data, 1),
+        Some(v) => Err(BinaryError { offset: 0, message: format!("unsupported format version {}", v) }),
+    };
+    TableIterator::once(match result {
+        Ok(()) => (true, None, None),
+        Err(e) => (false, Some(e.offset as i32), Some(e.message)),
+    })
+}
+
+extension_sql!(
+    r#"
+-- Binary casts (explicit only)
+CREATE CAST (sexp AS bytea) WITH FUNCTION sexp_to_bytea(sexp);
+CREATE CAST (bytea AS sexp) WITH FUNCTION sexp_from_bytea(bytea);
+"#,
+    name = "sexp_bytea_casts",
+    requires = [sexp_to_bytea, sexp_from_bytea]
+);
+
+// ============================================================================
+// Symbol Dictionary Compression
+// ============================================================================
+//
+// Opt-in compact storage for columns whose documents share a vocabulary of
+// symbols. A named dictionary in sexp_symbol_dict maps frequent symbols to
+// small codes; sexp_dict_compress replaces them with code references in a
+// bytea, and sexp_dict_expand restores the original value. Dictionaries are
+// append-only, so codes already in stored data never change meaning.
+
+/// Tag of a dictionary reference in compressed data (never a sexp tag)
+const DICT_SYMBOL: u8 = 0x10;
+
+/// Symbol codes of one dictionary, cached per backend for the rest of the
+/// current transaction
+#[derive(Default)]
+struct SymbolDict {
+    codes: std::collections::HashMap<Vec<u8>, u64>,
+    symbols: std::collections::HashMap<u64, Vec<u8>>,
+}
+
+thread_local! {
+    static SYMBOL_DICTS: std::cell::RefCell<std::collections::HashMap<String, std::rc::Rc<SymbolDict>>> =
+        std::cell::RefCell::new(std::collections::HashMap::new());
+}
+
+fn load_symbol_dict(schema: &str, name: &str) -> std::rc::Rc<SymbolDict> {
+    let mut dict = SymbolDict::default();
+    let query = format!("SELECT code, symbol FROM {}.sexp_symbol_dict WHERE dict_name = $1", schema);
+    Spi::connect(|client| {
+        let rows = client
+            .select(&query, None, &[name.into()])
+            .unwrap_or_else(|e| pgrx::error!("could not read symbol dictionary {}: {}", name, e));
+        for row in rows {
+            let code = row.get::<i32>(1).ok().flatten().unwrap_or_default() as u64;
+            let symbol = row.get::<String>(2).ok().flatten().unwrap_or_default().into_bytes();
+            dict.codes.insert(symbol.clone(), code);
+            dict.symbols.insert(code, symbol);
+        }
+    });
+    let dict = std::rc::Rc::new(dict);
+    let first = SYMBOL_DICTS.with(|dicts| {
+        let mut dicts = dicts.borrow_mut();
+        let first = dicts.is_empty();
+        dicts.insert(name.to_string(), dict.clone());
+        first
+    });
+    if first {
+        // Rows read here may be rolled back, or the dictionary rebuilt by
+        // another session, so nothing outlives the transaction
+        for event in [pgrx::PgXactCallbackEvent::Commit, pgrx::PgXactCallbackEvent::Abort] {
+            pgrx::register_xact_callback(event, || SYMBOL_DICTS.with(|dicts| dicts.borrow_mut().clear()));
+        }
+    }
+    dict
+}
+
+/// Rows read inside a subtransaction that aborts may be gone, so the cache
+/// is dropped with it (registered once in _PG_init)
+#[pg_guard]
+unsafe extern "C-unwind" fn symbol_dicts_subxact_callback(
+    event: pg_sys::SubXactEvent::Type,
+    _sub: pg_sys::SubTransactionId,
+    _parent: pg_sys::SubTransactionId,
+    _arg: *mut std::ffi::c_void,
+) {
+    if event == pg_sys::SubXactEvent::SUBXACT_EVENT_ABORT_SUB {
+        SYMBOL_DICTS.with(|dicts| dicts.borrow_mut().clear());
+    }
+}
+
+/// Cached dictionary, loaded on first use in a transaction. Within it, a
+/// stale cache only means newer symbols stay uncompressed; expansion reloads
+/// when it meets an unknown code.
+fn symbol_dict(schema: &str, name: &str) -> std::rc::Rc<SymbolDict> {
+    match SYMBOL_DICTS.with(|dicts| dicts.borrow().get(name).cloned()) {
+        Some(dict) => dict,
+        None => load_symbol_dict(schema, name),
+    }
+}
+
+fn dict_compress_element(data: &[u8], pos: &mut usize, dict: &SymbolDict, out: &mut Vec<u8>) {
+    let start = *pos;
+    match data.get(start) {
+        Some(&tags::LIST) => {
+            *pos += 1;
+            let count = read_varint(data, pos);
+            out.extend_from_slice(&data[start..*pos]);
+            for _ in 0..count {
+                dict_compress_element(data, pos, dict, out);
+            }
+        }
+        Some(&tags::SYMBOL) => {
+            skip_element(data, pos);
+            match atom_bytes_at(data, start).and_then(|s| dict.codes.get(s)) {
+                Some(&code) => {
+                    out.push(DICT_SYMBOL);
+                    write_varint(out, code);
+                }
+                None => out.extend_from_slice(&data[start..*pos]),
+            }
+        }
+        _ => {
+            skip_element(data, pos);
+            out.extend_from_slice(&data[start..(*pos).min(data.len())]);
+        }
+    }
+}
+
+/// Expand compressed data, failing with the first code the dictionary lacks
+fn dict_expand_element(data: &[u8], pos: &mut usize, dict: &SymbolDict, out: &mut impl ByteSink) -> Result<(), u64> {
+    let start = *pos;
+    match data.get(start) {
+        Some(&tags::LIST) => {
+            *pos += 1;
+            let count = read_varint(data, pos);
+            out.extend_from_slice(&data[start..*pos]);
+            for _ in 0..count {
+                dict_expand_element(data, pos, dict, out)?;
+            }
+        }
+        Some(&DICT_SYMBOL) => {
+            *pos += 1;
+            let code = read_varint(data, pos);
+            let symbol = dict.symbols.get(&code).ok_or(code)?;
+            out.push(tags::SYMBOL);
+            write_varint(out, symbol.len() as u64);
+            out.extend_from_slice(symbol);
+        }
+        _ => {
+            skip_element(data, pos);
+            out.extend_from_slice(&data[start..(*pos).min(data.len())]);
+        }
+    }
+    Ok(())
+}
+
+/// Distinct symbols of a value, for building dictionaries
+#[pg_extern(name = "sexp_symbols", immutable, parallel_safe)]
+fn sexp_symbols(sexp: Sexp) -> SetOfIterator<'static, String> {
+    let mut seen = std::collections::HashSet::new();
+    let symbols: Vec<String> = Preorder::new(&sexp.data, 1)
+        .filter(|item| item.tag == tags::SYMBOL)
+        .filter_map(|item| atom_bytes_at(&sexp.data, item.start))
+        .filter(|s| seen.insert(*s))
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect();
+    SetOfIterator::new(symbols)
+}
+
+/// Compress a value against a symbol dictionary
+#[pg_extern(name = "sexp_dict_compress", stable, parallel_safe)]
+fn sexp_dict_compress(sexp: Sexp, dict_name: &str, fcinfo: pg_sys::FunctionCallInfo) -> Vec<u8> {
+    let dict = symbol_dict(&extension_schema(fcinfo), dict_name);
+    let mut out = vec![FORMAT_VERSION];
+    let mut pos = 1;
+    dict_compress_element(&sexp.data, &mut pos, &dict, &mut out);
+    out
+}
+
+/// Restore a value compressed with sexp_dict_compress
+#[pg_extern(name = "sexp_dict_expand", stable, parallel_safe)]
+fn sexp_dict_expand(data: &[u8], dict_name: &str, fcinfo: pg_sys::FunctionCallInfo) -> Sexp {
+    if data.first() != Some(&FORMAT_VERSION) {
+        pgrx::error!("sexp_dict_expand: unsupported format version");
+    }
+    let expand = |dict: &SymbolDict| {
+        let mut out = VarlenaBuf::with_capacity(data.len() * 2);
+        out.push(FORMAT_VERSION);
+        let mut pos = 1;
+        dict_expand_element(data, &mut pos, dict, &mut out).map(|_| out)
+    };
+    let schema = extension_schema(fcinfo);
+    let out = match expand(&symbol_dict(&schema, dict_name)) {
+        Ok(out) => out,
+        // The cached dictionary may predate the code; reload once
+        Err(_) => expand(&load_symbol_dict(&schema, dict_name)).unwrap_or_else(|code| {
+            pgrx::error!("sexp_dict_expand: code {} not found in dictionary {}", code, dict_name)
+        }),
+    };
+    Sexp::from_buf(out)
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE sexp_symbol_dict (
+    dict_name text NOT NULL,
+    code int NOT NULL,
+    symbol text NOT NULL,
+    PRIMARY KEY (dict_name, code),
+    UNIQUE (dict_name, symbol)
+);
+-- Compressed data is unreadable without its dictionary, so dump its rows
+SELECT pg_catalog.pg_extension_config_dump('sexp_symbol_dict', '');
+
+-- Codes in stored data must keep their meaning, so rows are never changed
+-- or removed once added
+CREATE FUNCTION sexp_symbol_dict_append_only() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+    RAISE EXCEPTION 'sexp_symbol_dict is append-only'
+        USING HINT = 'Compressed values refer to its codes; add a new dictionary instead.';
+END
+$$;
+CREATE TRIGGER sexp_symbol_dict_append_only
+    BEFORE UPDATE OR DELETE OR TRUNCATE ON sexp_symbol_dict
+    FOR EACH STATEMENT EXECUTE FUNCTION sexp_symbol_dict_append_only();
+
+-- Add the most frequent symbols of a column to a dictionary, up to
+-- max_symbols entries in total. Existing codes are kept, so refreshing
+-- never invalidates compressed data. Returns the number of symbols added.
+CREATE FUNCTION sexp_dict_build(
+    dict_name text,
+    tbl regclass,
+    col name,
+    max_symbols int DEFAULT 127
+) RETURNS int
+LANGUAGE plpgsql VOLATILE AS $$
+DECLARE
+    added int;
+BEGIN
+    EXECUTE format($q$
+        INSERT INTO @extschema@.sexp_symbol_dict (dict_name, code, symbol)
+        SELECT $1, base.n + row_number() OVER (ORDER BY freq DESC, sym) - 1, sym
+        FROM (
+            SELECT s AS sym, count(*) AS freq
+            FROM %s, @extschema@.sexp_symbols(%I::@extschema@.sexp) AS s
+            WHERE s NOT IN (SELECT symbol FROM @extschema@.sexp_symbol_dict WHERE dict_name = $1)
+            GROUP BY s
+        ) candidates,
+        (SELECT coalesce(max(code) + 1, 0) AS n FROM @extschema@.sexp_symbol_dict WHERE dict_name = $1) base
+        ORDER BY freq DESC, sym
+        LIMIT greatest($2 - (SELECT count(*) FROM @extschema@.sexp_symbol_dict WHERE dict_name = $1), 0)
+    $q$, tbl, col)
+    USING dict_name, max_symbols;
+    GET DIAGNOSTICS added = ROW_COUNT;
+    RETURN added;
+END
+$$;
+"#,
+    name = "sexp_symbol_dict",
+    requires = [sexp_symbols]
+);
+
+// ============================================================================
+// Archival Compression
+// ============================================================================
+//
+// sexp_compress output is self-describing, so it can be restored without
+// any dictionary table:
+//
+//   "SXZ" | codec version | method | sexp format version | varint length | body
+//
+// The uncompressed body is an embedded symbol table (count, then each
+// symbol length-prefixed) followed by the element, with every symbol used
+// more than once replaced by a DICT_SYMBOL reference into that table. Method
+// 1 stores the body compressed with PostgreSQL's pglz; method 0 stores it as
+// is when pglz would not make it smaller.
+
+const ARCHIVE_MAGIC: &[u8] = b"SXZ";
+const ARCHIVE_VERSION: u8 = 1;
+const ARCHIVE_STORED: u8 = 0;
+const ARCHIVE_PGLZ: u8 = 1;
+
+/// Largest body an archive may declare: PostgreSQL's MaxAllocSize
+const ARCHIVE_MAX_BODY: usize = 0x3fff_ffff;
+
+/// Compress with PostgreSQL's pglz, None when that does not make the input
+/// smaller
+fn pglz_compress(input: &[u8]) -> Option<Vec<u8>> {
+    let len = i32::try_from(input.len()).ok()?;
+    // PGLZ_MAX_OUTPUT
+    let mut out = vec![0u8; input.len() + 4];
+    let written = unsafe {
+        pg_sys::pglz_compress(input.as_ptr().cast(), len, out.as_mut_ptr().cast(), pg_sys::PGLZ_strategy_always)
+    };
+    let written = usize::try_from(written).ok().filter(|&n| n < input.len())?;
+    out.truncate(written);
+    Some(out)
+}
+
+/// Decode pglz data that must expand to exactly `size` bytes
+fn pglz_decompress(input: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let len = i32::try_from(input.len()).map_err(|_| "compressed body too large")?;
+    let mut out = vec![0u8; size];
+    let written =
+        unsafe { pg_sys::pglz_decompress(input.as_ptr().cast(), len, out.as_mut_ptr().cast(), size as i32, true) };
+    if usize::try_from(written).ok() != Some(size) {
+        return Err("corrupt compressed body".to_string());
+    }
+    Ok(out)
+}
+
+/// Compress a value into a self-describing archive: repeated symbols are
+/// stored once in an embedded table and the result is pglz-compressed
+#[pg_extern(name = "sexp_compress", immutable, parallel_safe)]
+fn sexp_compress(sexp: Sexp) -> Vec<u8> {
+    let element = sexp.data.get(1..).filter(|e| !e.is_empty()).unwrap_or(&[tags::NIL]);
+
+    let mut uses: Vec<(&[u8], u64)> = Vec::new();
+    let mut seen = std::collections::HashMap::new();
+    for item in Preorder::new(element, 0).filter(|item| item.tag == tags::SYMBOL) {
+        let symbol = atom_bytes_at(element, item.start).unwrap_or_default();
+        let slot = *seen.entry(symbol).or_insert_with(|| {
+            uses.push((symbol, 0));
+            uses.len() - 1
+        });
+        uses[slot].1 += 1;
+    }
+    let mut dict = SymbolDict::default();
+    let mut body = Vec::with_capacity(element.len());
+    let repeated: Vec<&[u8]> = uses.iter().filter(|(_, n)| *n > 1).map(|(s, _)| *s).collect();
+    write_varint(&mut body, repeated.len() as u64);
+    for (code, symbol) in repeated.into_iter().enumerate() {
+        write_varint(&mut body, symbol.len() as u64);
+        body.extend_from_slice(symbol);
+        dict.codes.insert(symbol.to_vec(), code as u64);
+    }
+    dict_compress_element(element, &mut 0, &dict, &mut body);
+
+    let (method, stored) = match pglz_compress(&body) {
+        Some(compressed) => (ARCHIVE_PGLZ, compressed),
+        None => (ARCHIVE_STORED, body.clone()),
+    };
+    let mut out = Vec::with_capacity(stored.len() + 16);
+    out.extend_from_slice(ARCHIVE_MAGIC);
+    out.extend_from_slice(&[ARCHIVE_VERSION, method, sexp.data.first().copied().unwrap_or(FORMAT_VERSION)]);
+    write_varint(&mut out, body.len() as u64);
+    out.extend_from_slice(&stored);
+    out
+}
+
+fn decompress_archive(data: &[u8]) -> Result<Sexp, String> {
+    let header = data.get(..ARCHIVE_MAGIC.len() + 3).ok_or("truncated header")?;
+    if &header[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+        return Err("not a sexp archive".to_string());
+    }
+    let (version, method, format) = (header[3], header[4], header[5]);
+    if version != ARCHIVE_VERSION {
+        return Err(format!("unsupported archive version {}", version));
+    }
+    let mut pos = header.len();
+    let size = read_varint_checked(data, &mut pos)?;
+    // The declared size is checked against what the data could expand to
+    // before anything is allocated for it
+    let size = usize::try_from(size)
+        .ok()
+        .filter(|&size| size <= ARCHIVE_MAX_BODY && size <= (data.len() - pos).saturating_mul(255))
+        .ok_or("declared body size too large")?;
+    let body = match method {
+        ARCHIVE_STORED if data.len() - pos == size => data[pos..].to_vec(),
+        ARCHIVE_STORED => return Err("stored body length mismatch".to_string()),
+        ARCHIVE_PGLZ => pglz_decompress(&data[pos..], size)?,
+        other => return Err(format!("unknown compression method {}", other)),
+    };
+
+    let mut pos = 0;
+    let mut dict = SymbolDict::default();
+    for code in 0..read_varint_checked(&body, &mut pos)? {
+        dict.symbols.insert(code, try_read_string(&body, &mut pos)?.into_bytes());
+    }
+    let mut out = vec![format];
+    validate_dict_body(&body, pos, dict.symbols.len() as u64)?;
+    dict_expand_element(&body, &mut pos, &dict, &mut out).map_err(|code| format!("unknown symbol code {}", code))?;
+    if pos != body.len() {
+        return Err("trailing bytes after value".to_string());
+    }
+    decode_versioned(&out).map(|data| Sexp::from_bytes(&data))
+}
+
+/// Check a dictionary-encoded element before expanding it, so corrupt input
+/// is reported instead of tripping the expansion's assumptions
+fn validate_dict_body(body: &[u8], mut pos: usize, symbols: u64) -> Result<(), String> {
+    let mut remaining: u64 = 1;
+    while remaining > 0 {
+        remaining -= 1;
+        match body.get(pos) {
+            Some(&DICT_SYMBOL) => {
+                pos += 1;
+                if read_varint_checked(body, &mut pos)? >= symbols {
+                    return Err("unknown symbol code".to_string());
+                }
+            }
+            Some(_) => skip_element_header(body, &mut pos, &mut remaining)?,
+            None => return Err("truncated element".to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// Restore a value from sexp_compress output
+#[pg_extern(name = "sexp_decompress", immutable, parallel_safe)]
+fn sexp_decompress(data: &[u8]) -> Sexp {
+    decompress_archive(data).unwrap_or_else(|e| {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_DATA_CORRUPTED,
+            format!("sexp_decompress: {}", e)
+        );
+        unreachable!()
+    })
+}
+
+// ============================================================================
+// C Implementation Binary Compatibility
+// ============================================================================
+
+/// Storage format of the C implementation (src/pg_sexp.h)
+mod c_format {
+    pub const VERSION: u8 = 6;
+    /// The version byte keeps flags in its high nibble (SEXP_VERSION masks
+    /// them off)
+    pub const VERSION_MASK: u8 = 0x0F;
+    pub const TAG_MASK: u8 = 0xE0;
+    pub const DATA_MASK: u8 = 0x1F;
+    pub const NIL: u8 = 0x00;
+    pub const SMALLINT: u8 = 0x20;
+    pub const INTEGER: u8 = 0x40;
+    pub const FLOAT: u8 = 0x60;
+    pub const SYMBOL_REF: u8 = 0x80;
+    pub const SHORT_STRING: u8 = 0xA0;
+    pub const LONG_STRING: u8 = 0xC0;
+    pub const LIST: u8 = 0xE0;
+    pub const SMALLINT_BIAS: i64 = 16;
+    pub const MAX_DEPTH: usize = 1000;
+}
+
+/// Decoder for the C implementation's format:
+/// `[version][varint: symbol count][symbol table][root element]`
+struct CBinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    symbols: Vec<String>,
+}
+
+impl<'a> CBinaryReader<'a> {
+    fn decode(data: &'a [u8]) -> Result<ParsedExpr, String> {
+        let version = data.first().map(|v| v & c_format::VERSION_MASK);
+        if version != Some(c_format::VERSION) {
+            return Err(format!("unsupported C format version {:?}", version));
+        }
+        let mut reader = CBinaryReader { data, pos: 1, symbols: Vec::new() };
+
+        let count = reader.varint()?;
+        for _ in 0..count {
+            let len = reader.varint()?;
+            let bytes = reader.take(len)?;
+            let symbol = std::str::from_utf8(bytes).map_err(|_| "invalid UTF-8 in symbol table")?;
+            reader.symbols.push(symbol.to_string());
+        }
+
+        let root = reader.element(0)?;
+        if reader.pos != data.len() {
+            return Err(format!("trailing data at offset {}", reader.pos));
+        }
+        Ok(root)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        read_varint_checked(self.data, &mut self.pos)
+    }
+
+    fn take(&mut self, len: u64) -> Result<&'a [u8], String> {
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len))
+            .filter(|&end| end <= self.data.len())
+            .ok_or("length exceeds data")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn uint32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn string(bytes: &[u8]) -> Result<ParsedExpr, String> {
+        let s = std::str::from_utf8(bytes).map_err(|_| "invalid UTF-8 in string")?;
+        Ok(ParsedExpr::String(s.to_string()))
+    }
+
+    fn element(&mut self, depth: usize) -> Result<ParsedExpr, String> {
+        if depth > c_format::MAX_DEPTH {
+            return Err("nesting too deep".to_string());
+        }
+        let byte = *self.data.get(self.pos).ok_or("truncated element")?;
+        self.pos += 1;
+        let inline = byte & c_format::DATA_MASK;
+
+        match byte & c_format::TAG_MASK {
+            c_format::NIL => Ok(ParsedExpr::Nil),
+            c_format::SMALLINT => Ok(ParsedExpr::Integer(inline as i64 - c_format::SMALLINT_BIAS)),
+            c_format::INTEGER => {
+                let encoded = self.varint()?;
+                Ok(ParsedExpr::Integer(((encoded >> 1) as i64) ^ (-((encoded & 1) as i64))))
+            }
+            c_format::FLOAT => {
+                let bytes = self.take(8)?;
+                Ok(ParsedExpr::Float(f64::from_le_bytes(bytes.try_into().unwrap())))
+            }
+            c_format::SYMBOL_REF => {
+                let idx = self.varint()?;
+                let symbol = usize::try_from(idx)
+                    .ok()
+                    .and_then(|idx| self.symbols.get(idx))
+                    .ok_or_else(|| format!("symbol index {} out of range", idx))?;
+                Ok(ParsedExpr::Symbol(symbol.as_str().into()))
+            }
+            c_format::SHORT_STRING => Self::string(self.take(inline as u64)?),
+            c_format::LONG_STRING => {
+                let len = self.varint()?;
+                Self::string(self.take(len)?)
+            }
+            _ => {
+                let count = if inline > 0 {
+                    // Small list: payload size is implied by the elements themselves
+                    self.varint()?;
+                    inline as u64
+                } else {
+                    // Large list: skip the structural hash and SEntry offset table
+                    let count = self.uint32()? as u64;
+                    self.uint32()?;
+                    self.take(count * 4)?;
+                    count
+                };
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    items.push(self.element(depth + 1)?);
+                }
+                Ok(ParsedExpr::List(items))
+            }
+        }
+    }
+}
+
+/// Convert a value stored by the C implementation (varlena payload without
+/// header) into the Rust encoding; values already in the Rust format are
+/// validated and passed through
+#[pg_extern(name = "sexp_from_c_binary", immutable, parallel_safe)]
+fn sexp_from_c_binary(data: &[u8]) -> Sexp {
+    if data.first() == Some(&FORMAT_VERSION) {
+        return sexp_from_bytea(data);
+    }
+    match CBinaryReader::decode(data) {
+        Ok(parsed) => Sexp::from_parsed(&parsed),
+        Err(e) => pgrx::error!("invalid C sexp binary: {}", e),
+    }
+}
+
+// ============================================================================
+// Safe Quoting
+// ============================================================================
+
+/// Quote text as a string atom, escaping it so it reads back unchanged in
+/// the pg_sexp.dialect syntax, as sexp_parse reads it
+#[pg_extern(name = "sexp_quote_string", stable, parallel_safe)]
+fn sexp_quote_string(value: &str) -> String {
+    quote_string(value, DIALECT.get())
+}
+
+/// True if the text, written bare, reads back as exactly that symbol in a
+/// dialect
+fn reads_back_as_symbol(value: &str, dialect: Dialect) -> bool {
+    let mut parser = Parser::with_dialect(value, dialect);
+    match parser.parse() {
+        Ok(ParsedExpr::Symbol(symbol)) => parser.pos == value.len() && &*symbol == value,
+        _ => false,
+    }
+}
+
+/// Text as a symbol in the pg_sexp.dialect syntax.
+///
+/// Text that would not read back as that same symbol bare (delimiters,
+/// whitespace or comment characters, tokens that read as numbers, booleans
+/// or nil) is pipe-quoted in the scheme and smtlib dialects, as sexp_out
+/// writes it. The other dialects have no quoted symbols, so there such text
+/// raises an error, as does the empty symbol.
+#[pg_extern(name = "sexp_quote_symbol", stable, parallel_safe)]
+fn sexp_quote_symbol(value: &str) -> String {
+    let dialect = DIALECT.get();
+    let text = symbol_text(value, dialect);
+    if value.is_empty() || (text == value && !reads_back_as_symbol(value, dialect)) {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("cannot write {:?} as a sexp symbol", value),
+            "Use sexp_quote_string to store arbitrary text as a string atom."
+        );
+    }
+    text
+}
+
+// ============================================================================
+// Lisp Dialects
+// ============================================================================
+
+/// Read data printed by Emacs. Characters read as their code points, vectors
+/// and records as lists, hash tables as `(hash-table (key value) ...)` and
+/// dotted pairs as proper lists, so `((a . 1))` reads as `((a 1))`.
+#[pg_extern(name = "elisp_to_sexp", stable, parallel_safe)]
+fn elisp_to_sexp(input: &str) -> Sexp {
+    match Parser::with_dialect(input, Dialect::Elisp).parse() {
+        Ok(parsed) => Sexp::from_parsed(&parsed),
+        Err(e) => pgrx::error!("invalid elisp data: {}", e),
+    }
+}
+
+/// Dialect by its pg_sexp.dialect name
+fn dialect_named(name: &str) -> Dialect {
+    match name.to_ascii_lowercase().as_str() {
+        "scheme" => Dialect::Scheme,
+        "elisp" => Dialect::Elisp,
+        "clojure" => Dialect::Clojure,
+        "smtlib" => Dialect::Smtlib,
+        "kicad" => Dialect::Kicad,
+        _ => {
+            ereport!(
+                PgLogLevel::ERROR,
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                format!("unknown sexp dialect \"{}\"", name),
+                "Use one of scheme, elisp, clojure, smtlib or kicad."
+            );
+            unreachable!()
+        }
+    }
+}
+
+/// Read text in a dialect's literal syntax, pg_sexp.dialect when none is
+/// given, with the session's reader options (pg_sexp.nil_is_symbol,
+/// pg_sexp.parse_true_false and pg_sexp.exact_decimals). A `'...'::sexp`
+/// literal ignores all of them and reads the canonical syntax sexp_out
+/// prints.
+#[pg_extern(name = "sexp_parse", stable, parallel_safe)]
+fn sexp_parse(input: &str, dialect: default!(Option<&str>, "NULL")) -> Sexp {
+    let mut settings = ParseSettings::current();
+    if let Some(dialect) = dialect {
+        settings.dialect = dialect_named(dialect);
+    }
+    parse_sexp_text(input, settings).unwrap_or_else(|e| pgrx::error!("invalid s-expression: {}", e))
+}
+
+/// Print a value in a dialect's literal syntax: its boolean spelling, string
+/// escapes and decimal form. The text output function always prints the
+/// canonical form, which reads back unchanged; text printed here is for other
+/// Lisp readers and may not (elisp prints false as nil). Floats are rounded
+/// to pg_sexp.float_precision decimal places when it is set.
+#[pg_extern(name = "sexp_to_text", stable, parallel_safe)]
+fn sexp_to_text(doc: Sexp, dialect: &str) -> String {
+    let dialect = dialect_named(dialect);
+    if doc.data.len() < 2 {
+        return nil_text().to_string();
+    }
+    element_to_text(&doc.data, &mut 1, dialect, display_float_precision())
+}
+
+/// pg_sexp.float_precision for display output, None for shortest round-trip
+fn display_float_precision() -> Option<usize> {
+    usize::try_from(FLOAT_PRECISION.get()).ok()
+}
+
+// ============================================================================
+// Tokenizer
+// ============================================================================
+
+/// Lexical tokens of sexp text for editors and validators, read in the
+/// pg_sexp.dialect syntax. Offsets are 0-based character positions, `end`
+/// exclusive; token types are open, close, string, integer, float, decimal,
+/// boolean, nil, symbol, comment and error. Dialect collections such as
+/// `[`, `{`, `#{` and `#s(` are open and close tokens, elisp `?a`
+/// characters integers.
+#[pg_extern(name = "sexp_tokenize", stable, parallel_safe)]
+fn sexp_tokenize(
+    input: &str,
+) -> TableIterator<'static, (name!(token_type, String), name!(text, String), name!(start, i32), name!(end, i32))> {
+    // Tokens come in order, so character offsets are counted from the last
+    // position rather than from the start of the input
+    let (mut byte, mut chars) = (0, 0);
+    let mut char_offset = |to: usize| {
+        chars += input[byte..to].chars().count();
+        byte = to;
+        chars as i32
+    };
+    let rows: Vec<_> = Parser::with_settings(input, ParseSettings::current())
+        .tokenize()
+        .into_iter()
+        .map(|(kind, start, end)| {
+            let (start_char, end_char) = (char_offset(start), char_offset(end));
+            (kind.to_string(), input[start..end].to_string(), start_char, end_char)
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+// ============================================================================
+// Bulk Import
+// ============================================================================
+
+/// Parse a text holding any number of top-level forms, read as sexp_parse
+/// reads one
+#[pg_extern(name = "sexp_parse_forms", stable, parallel_safe)]
+fn sexp_parse_forms(input: &str) -> SetOfIterator<'static, Sexp> {
+    match Parser::with_settings(input, ParseSettings::current()).parse_forms() {
+        Ok(forms) => SetOfIterator::new(forms.iter().map(Sexp::from_parsed).collect::<Vec<_>>().into_iter()),
+        Err(e) => pgrx::error!("invalid s-expression: {}", e),
+    }
+}
+
+/// Parse many inputs in one call; NULL inputs give NULL and any invalid
+/// input raises an error
+#[pg_extern(name = "sexp_parse_batch", stable, parallel_safe)]
+fn sexp_parse_batch(inputs: Vec<Option<String>>) -> Vec<Option<Sexp>> {
+    let settings = ParseSettings::current();
+    inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            input.as_deref().map(|text| {
+                parse_sexp_text(text, settings)
+                    .unwrap_or_else(|e| pgrx::error!("invalid s-expression at array position {}: {}", i + 1, e))
+            })
+        })
+        .collect()
+}
+
+/// Parse many inputs in one call, one row per input. Invalid inputs give a
+/// NULL value and the parse error instead of failing the batch.
+#[pg_extern(name = "sexp_parse_batch_rows", stable, parallel_safe)]
+fn sexp_parse_batch_rows(
+    inputs: Vec<Option<String>>,
+) -> TableIterator<'static, (name!(ordinality, i32), name!(value, Option<Sexp>), name!(error, Option<String>))> {
+    let settings = ParseSettings::current();
+    let rows: Vec<_> = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| match input.as_deref().map(|text| parse_sexp_text(text, settings)) {
+            None => (i as i32 + 1, None, None),
+            Some(Ok(sexp)) => (i as i32 + 1, Some(sexp), None),
+            Some(Err(e)) => (i as i32 + 1, None, Some(e)),
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+extension_sql!(
+    r#"
+-- Server-side file import; pg_read_file restricts this to superusers and
+-- members of pg_read_server_files unless EXECUTE is granted explicitly
+CREATE FUNCTION sexp_import_file(path text) RETURNS SETOF sexp
+    AS 'SELECT @extschema@.sexp_parse_forms(pg_catalog.pg_read_file($1))'
+    LANGUAGE SQL STRICT VOLATILE;
+"#,
+    name = "sexp_file_import",
+    requires = [sexp_parse_forms]
+);
+
+// ============================================================================
+// Streaming Parse (large objects)
+// ============================================================================
+
+/// Bytes fetched from a large object per read
+const LO_CHUNK_SIZE: i32 = 1024 * 1024;
+
+/// Parser reading its input chunk by chunk, so only the unread part of the
+/// current chunk and the form being read are held as text. Plain lists are
+/// tracked with an explicit stack and each element is encoded as soon as it
+/// is read; everything else (atoms, strings, `|symbols|` and the dialect's
+/// literals and collections) is read by `Parser` over the buffered bytes.
+struct StreamParser<F: FnMut() -> Vec<u8>> {
+    fill: F,
+    buf: Vec<u8>,
+    pos: usize,
+    /// Start of a character split by the last chunk boundary
+    partial: Vec<u8>,
+    exhausted: bool,
+    settings: ParseSettings,
+    /// `Parser::pipe_scan` over `buf`, kept until the buffer changes so
+    /// repeated unclosed pipes do not rescan the rest of the input
+    pipe_scan: Option<(usize, Option<usize>)>,
+}
+
+/// A list the stream parser is reading: its elements encoded so far and, in
+/// elisp, the element count and body offset where a dotted tail starts and
+/// whether the last element was written as a list
+#[derive(Default)]
+struct OpenList {
+    body: Vec<u8>,
+    count: u64,
+    dot: Option<(u64, usize)>,
+    last_is_list: bool,
+}
+
+impl OpenList {
+    /// Encode the closed list, splicing a dotted tail as `splice_dotted` does
+    fn finish(mut self, out: &mut impl ByteSink) -> Result<(), String> {
+        if let Some((before, tail)) = self.dot {
+            if before == 0 || self.count != before + 1 {
+                return Err("misplaced dot in list".to_string());
+            }
+            match self.body[tail] {
+                tags::LIST if self.last_is_list => {
+                    let mut pos = tail + 1;
+                    let count = read_varint(&self.body, &mut pos);
+                    self.body.drain(tail..pos);
+                    self.count = before + count;
+                }
+                tags::NIL => {
+                    self.body.truncate(tail);
+                    self.count = before;
+                }
+                _ => {}
+            }
+        }
+        if self.count == 0 {
+            out.push(tags::NIL);
+        } else {
+            out.push(tags::LIST);
+            write_varint(out, self.count);
+            out.extend_from_slice(&self.body);
+        }
+        Ok(())
+    }
+}
+
+/// An element the stream parser has finished reading
+enum StreamElement {
+    List(OpenList),
+    Parsed(ParsedExpr),
+}
+
+impl StreamElement {
+    fn encode(self, out: &mut impl ByteSink) -> Result<(), String> {
+        match self {
+            StreamElement::List(list) => list.finish(out),
+            StreamElement::Parsed(value) => {
+                serialize_parsed(&value, out);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Length of the incomplete UTF-8 sequence ending `bytes`, if any
+fn utf8_partial_tail(bytes: &[u8]) -> usize {
+    match std::str::from_utf8(bytes) {
+        Err(e) if e.error_len().is_none() => bytes.len() - e.valid_up_to(),
+        _ => 0,
+    }
+}
+
+impl<F: FnMut() -> Vec<u8>> StreamParser<F> {
+    fn new(fill: F) -> Self {
+        StreamParser {
+            fill,
+            buf: Vec::new(),
+            pos: 0,
+            partial: Vec::new(),
+            exhausted: false,
+            settings: ParseSettings::current(),
+            pipe_scan: None,
+        }
+    }
+
+    /// Drop the bytes already read and append the next chunk; false once the
+    /// input is exhausted. A character split by the chunk boundary is held
+    /// back until it is complete, so `Parser` only sees whole characters.
+    fn refill(&mut self) -> bool {
+        if self.exhausted {
+            return false;
+        }
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.pipe_scan = None;
+        let chunk = (self.fill)();
+        let mut incoming = std::mem::take(&mut self.partial);
+        if chunk.is_empty() {
+            self.exhausted = true;
+            self.buf.extend_from_slice(&incoming);
+            return !incoming.is_empty();
+        }
+        incoming.extend_from_slice(&chunk);
+        self.partial = incoming.split_off(incoming.len() - utf8_partial_tail(&incoming));
+        self.buf.extend_from_slice(&incoming);
+        true
+    }
+
+    /// Refill until the unread input is twice as long as it was; false if
+    /// nothing could be added
+    fn grow(&mut self) -> bool {
+        let target = 2 * (self.buf.len() - self.pos).max(1);
+        let mut grew = false;
+        while self.buf.len() - self.pos < target && self.refill() {
+            grew = true;
+        }
+        grew
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos >= self.buf.len() {
+            if !self.refill() {
+                return None;
+            }
+        }
+        Some(self.buf[self.pos])
+    }
+
+    /// `Parser` over the buffered bytes, positioned at the next unread one
+    fn lexer(&self) -> Parser<'_> {
+        Parser { input: &self.buf, pos: self.pos, settings: self.settings, pipe_scan: self.pipe_scan }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if self.lexer().is_whitespace(c) {
+                self.pos += 1;
+            } else if c == b';' {
+                while let Some(c) = self.peek() {
+                    self.pos += 1;
+                    if c == b'\n' {
+                        break;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Read one form other than a plain list with `Parser`. A form that runs
+    /// into the end of the buffer may continue in the next chunk, and so may
+    /// a `|symbol|` whose closing pipe was not found in it, so either is read
+    /// again once the buffered input has doubled; a form spanning many chunks
+    /// is then read a logarithmic number of times, in linear time overall.
+    fn parse_form(&mut self) -> Result<ParsedExpr, String> {
+        loop {
+            let mut lexer = self.lexer();
+            let result = lexer.parse();
+            let (end, pipe_scan) = (lexer.pos, lexer.pipe_scan);
+            self.pipe_scan = pipe_scan;
+            let pipe_open = matches!(pipe_scan, Some((_, None)));
+            if (end < self.buf.len() && !pipe_open) || !self.grow() {
+                self.pos = end;
+                return result;
+            }
+        }
+    }
+
+    /// Encode the single expression the input holds into `out`, nil when
+    /// there is none
+    fn parse_into(&mut self, out: &mut impl ByteSink) -> Result<(), String> {
+        let mut stack: Vec<OpenList> = Vec::new();
+        let mut done = false;
+
+        loop {
+            self.skip_whitespace();
+            let element = match self.peek() {
+                None if stack.is_empty() => break,
+                None => return Err("unterminated list".to_string()),
+                Some(b'(') => {
+                    self.pos += 1;
+                    stack.push(OpenList::default());
+                    continue;
+                }
+                Some(b')') => {
+                    self.pos += 1;
+                    StreamElement::List(stack.pop().ok_or("unexpected ')'")?)
+                }
+                Some(_) => {
+                    let value = self.parse_form()?;
+                    match stack.last_mut() {
+                        Some(list)
+                            if self.settings.dialect == Dialect::Elisp
+                                && list.dot.is_none()
+                                && is_symbol_named(&value, ".") =>
+                        {
+                            list.dot = Some((list.count, list.body.len()));
+                            continue;
+                        }
+                        _ => StreamElement::Parsed(value),
+                    }
+                }
+            };
+
+            match stack.last_mut() {
+                Some(list) => {
+                    list.count += 1;
+                    list.last_is_list = matches!(element, StreamElement::List(_));
+                    element.encode(&mut list.body)?;
+                }
+                None if done => return Err("multiple top-level expressions".to_string()),
+                None => {
+                    element.encode(out)?;
+                    done = true;
+                }
+            }
+        }
+
+        if !done {
+            out.push(tags::NIL);
+        }
+        Ok(())
+    }
+}
+
+/// Parse the contents of a large object, reading it in chunks
+#[pg_extern(name = "sexp_from_lo", volatile)]
+fn sexp_from_lo(lo: pg_sys::Oid) -> Sexp {
+    let mut offset: i64 = 0;
+    let fill = || {
+        let chunk: Vec<u8> = unsafe {
+            pgrx::direct_function_call(
+                pg_sys::be_lo_get_fragment,
+                &[lo.into_datum(), offset.into_datum(), LO_CHUNK_SIZE.into_datum()],
+            )
+        }
+        .unwrap_or_default();
+        offset += chunk.len() as i64;
+        chunk
+    };
+
+    let mut data = VarlenaBuf::with_capacity(64);
+    data.push(FORMAT_VERSION);
+    match StreamParser::new(fill).parse_into(&mut data) {
+        Ok(()) => Sexp::from_buf(data),
+        Err(e) => pgrx::error!("invalid s-expression in large object {}: {}", lo.to_u32(), e),
+    }
+}
+
+// ============================================================================
+// Element Streaming
+// ============================================================================
+
+/// Largest slice read at a time from an uncompressed out of line list. A
+/// compressed one is decompressed from the start for every slice, so its
+/// slices keep doubling instead.
+const STREAM_MAX_SLICE: i32 = 1024 * 1024;
+
+/// Out of line list payload not yet read
+struct ToastSlices {
+    datum: pg_sys::Datum,
+    fetched: i32,
+    size: i32,
+    slice: i32,
+    compressed: bool,
+}
+
+impl ToastSlices {
+    /// Next slice of the payload, or None once all of it has been read
+    unsafe fn fetch(&mut self) -> Option<Vec<u8>> {
+        if self.fetched >= self.size {
+            return None;
+        }
+        let count = self.slice.min(self.size - self.fetched);
+        let chunk = pg_sys::pg_detoast_datum_slice(self.datum.cast_mut_ptr(), self.fetched, count);
+        let bytes = pgrx::varlena::varlena_to_byte_slice(chunk).to_vec();
+        pg_sys::pfree(chunk.cast());
+        self.fetched += count;
+        self.slice = match self.compressed {
+            true => self.slice.saturating_mul(2),
+            false => self.slice.saturating_mul(2).min(STREAM_MAX_SLICE),
+        };
+        Some(bytes)
+    }
+}
+
+/// Lazily decodes the elements of a list, one per call. An out of line list
+/// is read through `toast` as elements are fetched, and only the bytes from
+/// the current element on are kept in `data`.
+struct ElementStream {
+    data: SexpBytes,
+    pos: usize,
+    remaining: u64,
+    toast: Option<ToastSlices>,
+}
+
+impl ElementStream {
+    /// Read slices until `data` holds the whole element at `pos`, and return
+    /// its end offset
+    fn buffer_element(&mut self) -> usize {
+        loop {
+            let mut end = self.pos;
+            let complete = try_skip_element(&self.data, &mut end);
+            let Some(toast) = self.toast.as_mut() else {
+                return complete.map(|()| end).unwrap_or_else(|e| pgrx::error!("corrupt sexp list: {}", e));
+            };
+            if complete.is_ok() {
+                return end;
+            }
+            let Some(slice) = (unsafe { toast.fetch() }) else {
+                pgrx::error!("corrupt sexp list: {}", complete.unwrap_err());
+            };
+            if let SexpBytes::Owned(buf) = &mut self.data {
+                buf.drain(..self.pos);
+                buf.extend_from_slice(&slice);
+                self.pos = 0;
+            }
+        }
+    }
+}
+
+impl Iterator for ElementStream {
+    type Item = Sexp;
+
+    fn next(&mut self) -> Option<Sexp> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let end = self.buffer_element();
+        let element = Sexp::from_element(&self.data[self.pos..end]);
+        self.pos = end;
+        Some(element)
+    }
+}
+
+/// Elements of a list as a value-per-call set. Each row is decoded only when
+/// it is fetched, and a list stored out of line is read from its toast slice
+/// by slice as rows are fetched, so huge lists are never detoasted or
+/// expanded into every element at once.
+#[pg_extern(
+    sql = r#"
+CREATE FUNCTION sexp_stream_elements(list sexp) RETURNS SETOF sexp
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'sexp_stream_elements_wrapper';
+"#,
+    requires = [Sexp]
+)]
+fn sexp_stream_elements(fcinfo: pg_sys::FunctionCallInfo) -> SetOfIterator<'static, Sexp> {
+    let datum = unsafe { pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0) };
+    let ptr = datum.cast_mut_ptr::<pg_sys::varlena>();
+    let mut stream = if unsafe { pgrx::varlena::varatt_is_external(ptr) && !is_legacy(ptr) } {
+        let raw_size = unsafe { pg_sys::toast_raw_datum_size(datum) };
+        let mut toast = ToastSlices {
+            datum,
+            fetched: 0,
+            size: (raw_size - VARHDRSZ) as i32,
+            slice: EQ_FIRST_SLICE,
+            compressed: unsafe { pg_sys::toast_datum_size(datum) } + VARHDRSZ < raw_size,
+        };
+        let head = unsafe { toast.fetch() }.unwrap_or_default();
+        ElementStream { data: head.into(), pos: 1, remaining: 0, toast: Some(toast) }
+    } else {
+        let list = unsafe { Sexp::from_datum(datum, false) }.expect("strict function");
+        ElementStream { data: list.data, pos: 1, remaining: 0, toast: None }
+    };
+    match stream.data.get(1) {
+        Some(&tags::LIST) => {
+            stream.pos = 2;
+            stream.remaining = read_varint(&stream.data, &mut stream.pos);
+        }
+        Some(&tags::NIL) | None => {}
+        Some(_) => {
+            let value = unsafe { Sexp::from_datum(datum, false) }.expect("strict function");
+            pgrx::error!("sexp_stream_elements requires a list: {}", value.to_string_repr());
+        }
+    }
+    SetOfIterator::new(stream)
+}
+
+// ============================================================================
+// JSON and Row Conversion
+// ============================================================================
+
+/// JSON number as written (serde_json keeps the text with
+/// arbitrary_precision): integers that fit i64 stay integers, wider ones
+/// and plain fractions become exact decimals, and only numbers written with
+/// an exponent go through a float
+fn json_number(n: &serde_json::Number) -> ParsedExpr {
+    if let Some(i) = n.as_i64() {
+        return ParsedExpr::Integer(i);
+    }
+    let text = n.to_string();
+    if is_decimal_literal(&text) {
+        return decimal_atom(text);
+    }
+    ParsedExpr::Float(n.as_f64().unwrap_or(f64::NAN))
+}
+
+/// Map a JSON value to a sexp: objects become alists, arrays lists, and null
+/// nil
+fn json_to_parsed(value: &serde_json::Value) -> ParsedExpr {
+    match value {
+        serde_json::Value::Null => ParsedExpr::Nil,
+        serde_json::Value::Bool(b) => ParsedExpr::Bool(*b),
+        serde_json::Value::Number(n) => json_number(n),
+        serde_json::Value::String(s) => ParsedExpr::String(s.clone()),
+        serde_json::Value::Array(items) => ParsedExpr::List(items.iter().map(json_to_parsed).collect()),
+        serde_json::Value::Object(fields) => ParsedExpr::List(
+            fields
+                .iter()
+                .map(|(k, v)| ParsedExpr::List(vec![ParsedExpr::Symbol(k.as_str().into()), json_to_parsed(v)]))
+                .collect(),
+        ),
+    }
+}
+
+/// Inverse of the annotated jsonb mapping: `{"$sym": ..}`, `{"$float": ..}`
+/// and `{"$dec": ..}` objects become the atoms they annotate
+fn annotated_json_to_parsed(value: &serde_json::Value) -> ParsedExpr {
+    use serde_json::Value;
+    match value {
+        Value::Array(items) => ParsedExpr::List(items.iter().map(annotated_json_to_parsed).collect()),
+        Value::Object(fields) => {
+            if let (1, Some((key, Value::String(text)))) = (fields.len(), fields.iter().next()) {
+                match key.as_str() {
+                    "$sym" => return ParsedExpr::Symbol(text.as_str().into()),
+                    "$dec" => return decimal_atom(text.clone()),
+                    "$float" => match text.parse::<f64>() {
+                        Ok(f) => return ParsedExpr::Float(f),
+                        Err(_) => pgrx::error!("invalid annotated float: {}", text),
+                    },
+                    _ => {}
+                }
+            }
+            ParsedExpr::List(
+                fields
+                    .iter()
+                    .map(|(k, v)| {
+                        ParsedExpr::List(vec![ParsedExpr::Symbol(k.as_str().into()), annotated_json_to_parsed(v)])
+                    })
+                    .collect(),
+            )
+        }
+        _ => json_to_parsed(value),
+    }
+}
+
+/// Convert a jsonb document to a sexp. With `annotated`, the tagged objects
+/// written by `sexp_to_jsonb(.., annotated => true)` are decoded back into
+/// symbols, floats and decimals. Numbers keep their precision: fractions and
+/// integers beyond i64 become decimals, numbers with an exponent floats.
+#[pg_extern(name = "jsonb_to_sexp", immutable, parallel_safe)]
+fn jsonb_to_sexp(value: pgrx::JsonB, annotated: default!(bool, false)) -> Sexp {
+    if annotated {
+        Sexp::from_parsed(&annotated_json_to_parsed(&value.0))
+    } else {
+        Sexp::from_parsed(&json_to_parsed(&value.0))
+    }
+}
+
+/// Map an encoded element to JSON.
+///
+/// Plain mode is lossy: symbols and decimals become strings and alists of
+/// `(key value)` entries become objects. Annotated mode keeps every list an
+/// array and tags the atoms JSON cannot tell apart, so the result converts
+/// back exactly.
+fn element_to_json(element: &[u8], annotated: bool, depth: usize) -> serde_json::Value {
+    use serde_json::{json, Map, Value};
+    check_depth(depth);
+    let text = || element_text(element).unwrap_or_default();
+    match element.first() {
+        Some(&tags::BOOL) => Value::Bool(element.get(1).is_some_and(|&b| b != 0)),
+        Some(&tags::INTEGER) => Value::from(element_integer(element).unwrap_or_default()),
+        Some(&tags::FLOAT) => {
+            let f = element_float(element).unwrap_or(f64::NAN);
+            if annotated {
+                json!({ "$float": format!("{:?}", f) })
+            } else {
+                serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number)
+            }
+        }
+        Some(&tags::STRING) => Value::String(text()),
+        Some(&tags::SYMBOL) if annotated => json!({ "$sym": text() }),
+        Some(&tags::SYMBOL) => Value::String(text()),
+        Some(&tags::DECIMAL) if annotated => json!({ "$dec": text() }),
+        Some(&tags::DECIMAL) => text().parse().map_or_else(|_| Value::String(text()), Value::Number),
+        Some(&tags::LIST) => {
+            let items = list_elements(element);
+            let is_object = !annotated
+                && items.iter().all(|item| entry_key(item).is_some() && list_elements(item).len() == 2);
+            if is_object {
+                let mut fields = Map::new();
+                for item in items {
+                    let entry = list_elements(item);
+                    let key = String::from_utf8_lossy(entry_key(item).unwrap_or_default()).into_owned();
+                    fields.insert(key, element_to_json(entry[1], false, depth + 1));
+                }
+                Value::Object(fields)
+            } else {
+                Value::Array(items.into_iter().map(|item| element_to_json(item, annotated, depth + 1)).collect())
+            }
+        }
+        _ => Value::Null,
+    }
+}
+
+/// Convert a sexp to jsonb, optionally in the lossless annotated form
+#[pg_extern(name = "sexp_to_jsonb", immutable, parallel_safe)]
+fn sexp_to_jsonb(sexp: Sexp, annotated: default!(bool, false)) -> pgrx::JsonB {
+    pgrx::JsonB(element_to_json(sexp.data.get(1..).unwrap_or_default(), annotated, 0))
+}
+
+/// Build an alist from parallel key and value arrays, NULL values becoming nil
+#[pg_extern(name = "sexp_from_entries", immutable, parallel_safe)]
+fn sexp_from_entries(keys: Vec<String>, values: Vec<Option<Sexp>>) -> Sexp {
+    if keys.len() != values.len() {
+        pgrx::error!("sexp_from_entries: key and value arrays must have the same length");
+    }
+
+    let nil = Sexp::nil();
+    let entries: Vec<Sexp> = keys
+        .iter()
+        .zip(&values)
+        .map(|(key, value)| {
+            let key = Sexp::from_parsed(&ParsedExpr::Symbol(key.as_str().into()));
+            let value = value.as_ref().unwrap_or(&nil);
+            Sexp::from_elements(&[&key.data[1..], &value.data[1..]])
+        })
+        .collect();
+    let elements: Vec<&[u8]> = entries.iter().map(|e| &e.data[1..]).collect();
+    Sexp::from_elements(&elements)
+}
+
+/// Encode one SQL value as a sexp element by its type: booleans, integers,
+/// floats and numerics keep their type, sexp values are embedded as they are
+/// stored, json values are converted, and anything else becomes a string of
+/// its text output
+unsafe fn datum_to_element(
+    datum: pg_sys::Datum,
+    type_oid: pg_sys::Oid,
+    is_null: bool,
+    sexp_type: pg_sys::Oid,
+    out: &mut Vec<u8>,
+) {
+    if !is_null && type_oid == sexp_type {
+        let sexp = Sexp::from_datum(datum, false).unwrap();
+        out.extend_from_slice(sexp.data.get(1..).filter(|e| !e.is_empty()).unwrap_or(&[tags::NIL]));
+    } else {
+        serialize_parsed(&datum_to_parsed(datum, type_oid, is_null), out);
+    }
+}
+
+/// The atom or converted json value for a datum that is not a sexp
+unsafe fn datum_to_parsed(datum: pg_sys::Datum, type_oid: pg_sys::Oid, is_null: bool) -> ParsedExpr {
+    if is_null {
+        return ParsedExpr::Nil;
+    }
+    match type_oid {
+        pg_sys::BOOLOID => ParsedExpr::Bool(bool::from_datum(datum, false).unwrap()),
+        pg_sys::INT2OID => ParsedExpr::Integer(i16::from_datum(datum, false).unwrap() as i64),
+        pg_sys::INT4OID => ParsedExpr::Integer(i32::from_datum(datum, false).unwrap() as i64),
+        pg_sys::INT8OID => ParsedExpr::Integer(i64::from_datum(datum, false).unwrap()),
+        // Widened through its shortest float4 text, so 0.1::float4 stays 0.1
+        // instead of becoming 0.10000000149011612
+        pg_sys::FLOAT4OID => {
+            let value = f32::from_datum(datum, false).unwrap();
+            ParsedExpr::Float(value.to_string().parse().unwrap_or(value as f64))
+        }
+        pg_sys::FLOAT8OID => ParsedExpr::Float(f64::from_datum(datum, false).unwrap()),
+        _ => {
+            let text = datum_output_text(datum, type_oid);
+            if type_oid == pg_sys::NUMERICOID {
+                decimal_atom(text)
+            } else if type_oid == pg_sys::JSONOID || type_oid == pg_sys::JSONBOID {
+                match serde_json::from_str(&text) {
+                    Ok(value) => json_to_parsed(&value),
+                    Err(_) => ParsedExpr::String(text),
+                }
+            } else {
+                ParsedExpr::String(text)
+            }
+        }
+    }
+}
+
+/// Encode a `(name value)` pair for a SQL value
+unsafe fn datum_pair(
+    name: &[u8],
+    datum: pg_sys::Datum,
+    type_oid: pg_sys::Oid,
+    is_null: bool,
+    sexp_type: pg_sys::Oid,
+) -> Vec<u8> {
+    let mut pair = vec![tags::LIST, 2];
+    pair.extend_from_slice(name);
+    datum_to_element(datum, type_oid, is_null, sexp_type, &mut pair);
+    pair
+}
+
+/// Encoding of a symbol, for names written into many elements
+fn symbol_element(name: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    serialize_parsed(&ParsedExpr::Symbol(name.into()), &mut out);
+    out
+}
+
+/// Text output of a datum through its type's output function
+unsafe fn datum_output_text(datum: pg_sys::Datum, type_oid: pg_sys::Oid) -> String {
+    let mut output_fn = pg_sys::InvalidOid;
+    let mut is_varlena = false;
+    pg_sys::getTypeOutputInfo(type_oid, &mut output_fn, &mut is_varlena);
+    let text = pg_sys::OidOutputFunctionCall(output_fn, datum);
+    let result = core::ffi::CStr::from_ptr(text).to_string_lossy().into_owned();
+    pg_sys::pfree(text.cast());
+    result
+}
+
+/// Build an alist from alternating key/value arguments of any type, like
+/// jsonb_build_object
+#[pg_extern(
+    sql = r#"
+CREATE FUNCTION sexp_build_alist(VARIADIC "any") RETURNS sexp
+    STABLE PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'sexp_build_alist_wrapper';
+"#
+)]
+fn sexp_build_alist(fcinfo: pg_sys::FunctionCallInfo) -> Sexp {
+    let sexp_type = sexp_type_oid(fcinfo);
+    let mut args = std::ptr::null_mut();
+    let mut types = std::ptr::null_mut();
+    let mut nulls = std::ptr::null_mut();
+    let nargs = unsafe { pg_sys::extract_variadic_args(fcinfo, 0, true, &mut args, &mut types, &mut nulls) };
+    if nargs < 0 {
+        return Sexp::nil();
+    }
+    if nargs % 2 != 0 {
+        pgrx::error!("sexp_build_alist: argument list must have even number of elements");
+    }
+
+    let mut entries = Vec::with_capacity(nargs as usize / 2);
+    for i in (0..nargs as usize).step_by(2) {
+        let entry = unsafe {
+            if *nulls.add(i) {
+                pgrx::error!("sexp_build_alist: argument {} cannot be null", i + 1);
+            }
+            let key = symbol_element(&datum_output_text(*args.add(i), *types.add(i)));
+            datum_pair(&key, *args.add(i + 1), *types.add(i + 1), *nulls.add(i + 1), sexp_type)
+        };
+        entries.push(entry);
+    }
+    let elements: Vec<&[u8]> = entries.iter().map(Vec::as_slice).collect();
+    Sexp::from_elements(&elements)
+}
+
+/// Result set of a query as `((row (col value) ...) ...)`, with column values
+/// mapped as in sexp_build_alist and NULLs as nil
+#[pg_extern(name = "query_to_sexp", volatile)]
+fn query_to_sexp(query: &str, fcinfo: pg_sys::FunctionCallInfo) -> Sexp {
+    let sexp_type = sexp_type_oid(fcinfo);
+    let query = std::ffi::CString::new(query)
+        .unwrap_or_else(|_| pgrx::error!("query_to_sexp: query must not contain NUL bytes"));
+    // Only the parsed rows leave the SPI connection: a value encoded inside
+    // it would live in SPI's memory context, which is gone once it closes
+    let rows = Spi::connect(|_client| unsafe {
+        if pg_sys::SPI_execute(query.as_ptr(), false, 0) < 0 || pg_sys::SPI_tuptable.is_null() {
+            pgrx::error!("query_to_sexp: query did not return rows");
+        }
+        let tuptable = pg_sys::SPI_tuptable;
+        let tupdesc = (*tuptable).tupdesc;
+        let columns: Vec<(Vec<u8>, pg_sys::Oid)> = (1..=(*tupdesc).natts)
+            .map(|column| {
+                let name = pg_sys::SPI_fname(tupdesc, column);
+                let symbol = core::ffi::CStr::from_ptr(name).to_string_lossy().into_owned();
+                pg_sys::pfree(name.cast());
+                (symbol_element(&symbol), pg_sys::SPI_gettypeid(tupdesc, column))
+            })
+            .collect();
+
+        let row_symbol = symbol_element("row");
+        let mut rows = Vec::with_capacity(pg_sys::SPI_processed as usize);
+        for i in 0..pg_sys::SPI_processed as usize {
+            let tuple = *(*tuptable).vals.add(i);
+            let mut row = vec![tags::LIST];
+            write_varint(&mut row, columns.len() as u64 + 1);
+            row.extend_from_slice(&row_symbol);
+            for (column, (name, type_oid)) in columns.iter().enumerate() {
+                let mut is_null = false;
+                let datum = pg_sys::SPI_getbinval(tuple, tupdesc, column as i32 + 1, &mut is_null);
+                row.extend_from_slice(&datum_pair(name, datum, *type_oid, is_null, sexp_type));
+            }
+            rows.push(row);
+        }
+        rows
+    });
+    let elements: Vec<&[u8]> = rows.iter().map(Vec::as_slice).collect();
+    Sexp::from_elements(&elements)
+}
+
+/// Audit record `(op (table name) (old ...) (new ...))`, omitting missing rows
+#[pg_extern(name = "sexp_audit_entry", immutable, parallel_safe)]
+fn sexp_audit_entry(
+    operation: &str,
+    table_name: &str,
+    old_row: Option<Sexp>,
+    new_row: Option<Sexp>,
+) -> Sexp {
+    let symbol = |name: &str| Sexp::from_parsed(&ParsedExpr::Symbol(name.into()));
+    let tagged = |tag: &str, value: &Sexp| Sexp::from_elements(&[&symbol(tag).data[1..], &value.data[1..]]);
+
+    let mut parts = vec![symbol(operation), tagged("table", &symbol(table_name))];
+    if let Some(old_row) = &old_row {
+        parts.push(tagged("old", old_row));
+    }
+    if let Some(new_row) = &new_row {
+        parts.push(tagged("new", new_row));
+    }
+    let elements: Vec<&[u8]> = parts.iter().map(|p| &p.data[1..]).collect();
+    Sexp::from_elements(&elements)
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION json_to_sexp(json) RETURNS sexp
+    AS 'SELECT jsonb_to_sexp($1::jsonb)'
+    LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+-- Row as an alist of its columns, in column order
+CREATE FUNCTION row_to_sexp(anyelement) RETURNS sexp
+    AS $$
+        SELECT @extschema@.sexp_from_entries(
+            coalesce(array_agg(e.key ORDER BY e.ord), '{}'),
+            coalesce(array_agg(@extschema@.jsonb_to_sexp(e.value::jsonb) ORDER BY e.ord), '{}'))
+        FROM json_each(row_to_json($1)) WITH ORDINALITY AS e(key, value, ord)
+    $$
+    LANGUAGE SQL STABLE STRICT PARALLEL SAFE;
+
+-- Default destination of sexp_audit()
+CREATE TABLE sexp_audit_log (
+    id bigserial PRIMARY KEY,
+    logged_at timestamptz NOT NULL DEFAULT now(),
+    table_name text NOT NULL,
+    operation text NOT NULL,
+    entry sexp NOT NULL
+);
+SELECT pg_catalog.pg_extension_config_dump('sexp_audit_log', '');
+SELECT pg_catalog.pg_extension_config_dump('sexp_audit_log_id_seq', '');
+
+-- Row-level trigger recording changes as sexps. The optional first trigger
+-- argument names the audit table, which needs the columns of sexp_audit_log.
+CREATE FUNCTION sexp_audit() RETURNS trigger
+    AS $$
+    DECLARE
+        audit_table regclass := coalesce(TG_ARGV[0], '@extschema@.sexp_audit_log')::regclass;
+        old_row @extschema@.sexp;
+        new_row @extschema@.sexp;
+    BEGIN
+        IF TG_LEVEL <> 'ROW' THEN
+            RAISE EXCEPTION 'sexp_audit() must be used as a row-level trigger';
+        END IF;
+        IF TG_OP IN ('UPDATE', 'DELETE') THEN
+            old_row := @extschema@.row_to_sexp(OLD);
+        END IF;
+        IF TG_OP IN ('INSERT', 'UPDATE') THEN
+            new_row := @extschema@.row_to_sexp(NEW);
+        END IF;
+
+        EXECUTE format('INSERT INTO %s (table_name, operation, entry) VALUES ($1, $2, $3)', audit_table)
+            USING format('%I.%I', TG_TABLE_SCHEMA, TG_TABLE_NAME),
+                  lower(TG_OP),
+                  @extschema@.sexp_audit_entry(lower(TG_OP), TG_TABLE_NAME, old_row, new_row);
+        RETURN coalesce(NEW, OLD);
+    END
+    $$
+    LANGUAGE plpgsql;
+"#,
+    name = "sexp_audit",
+    requires = [jsonb_to_sexp, sexp_from_entries, sexp_audit_entry]
+);
+
+/// Send a NOTIFY whose payload is the sexp's single-line text form. Strings
+/// render their newlines escaped, so the payload is always one line.
+#[pg_extern(name = "sexp_notify", volatile)]
+fn sexp_notify(channel: &str, payload: Sexp) {
+    // Limits from async.c: channel names are identifiers and payloads must
+    // fit in a notification queue entry
+    let max_payload = pg_sys::BLCKSZ as usize - pg_sys::NAMEDATALEN as usize - 128;
+    if channel.is_empty() || channel.len() >= pg_sys::NAMEDATALEN as usize {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("sexp_notify: channel name must be 1 to {} bytes", pg_sys::NAMEDATALEN - 1)
+        );
+    }
+    let text = payload.to_string_repr();
+    if text.len() >= max_payload {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("sexp_notify: payload is {} bytes, limit is {}", text.len(), max_payload - 1)
+        );
+    }
+    let channel = std::ffi::CString::new(channel)
+        .unwrap_or_else(|_| pgrx::error!("sexp_notify: channel name must not contain NUL bytes"));
+    let text = std::ffi::CString::new(text)
+        .unwrap_or_else(|_| pgrx::error!("sexp_notify: payload must not contain NUL bytes"));
+    unsafe { pg_sys::Async_Notify(channel.as_ptr(), text.as_ptr()) };
+}
+
+// ============================================================================
+// CSV Conversion
+// ============================================================================
+
+/// Append one CSV field, quoted when it holds a separator, quote or line
+/// break. As in COPY's CSV format, nil is an empty field and the empty
+/// string is written as "".
+fn write_csv_field(out: &mut String, element: &[u8]) {
+    let Some(text) = element_text(element) else {
+        return;
+    };
+    if text.is_empty() || text.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&text.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(&text);
+    }
+}
+
+/// Render a list of lists as CSV, one line per row. Strings and symbols are
+/// written without sexp quoting, nested lists as sexp text. Floats and
+/// booleans follow the output settings, so the function is only stable.
+#[pg_extern(stable, parallel_safe)]
+fn sexp_to_csv(table: Sexp) -> String {
+    let mut out = String::new();
+    for row in list_arg(&table, "sexp_to_csv") {
+        if row[0] != tags::LIST && row[0] != tags::NIL {
+            pgrx::error!("sexp_to_csv: row is not a list: {}", Sexp::from_element(row).to_string_repr());
+        }
+        for (i, field) in list_elements(row).into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write_csv_field(&mut out, field);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Split CSV text into records of (field, was quoted). Lines may end in LF
+/// or CRLF; empty lines are skipped.
+fn parse_csv(text: &str) -> Vec<Vec<(String, bool)>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    loop {
+        match chars.next() {
+            Some('"') if field.is_empty() && !quoted => {
+                quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => field.push(c),
+                        None => pgrx::error!(
+                            "sexp_from_csv: unterminated quoted field in record {}",
+                            records.len() + 1
+                        ),
+                    }
+                }
+            }
+            Some(',') => {
+                record.push((std::mem::take(&mut field), quoted));
+                quoted = false;
+            }
+            Some('\r') if chars.peek() == Some(&'\n') => {}
+            Some('\n') | None => {
+                if !record.is_empty() || !field.is_empty() || quoted {
+                    record.push((std::mem::take(&mut field), quoted));
+                    records.push(std::mem::take(&mut record));
+                    quoted = false;
+                }
+                if chars.peek().is_none() {
+                    return records;
+                }
+            }
+            Some(c) if quoted => pgrx::error!(
+                "sexp_from_csv: unexpected {:?} after quoted field in record {}",
+                c,
+                records.len() + 1
+            ),
+            Some(c) => field.push(c),
+        }
+    }
+}
+
+/// Value of a CSV field: quoted fields are strings, unquoted ones numbers
+/// when they look like one (without leading zeros, which usually mark
+/// codes rather than quantities), nil when empty and strings otherwise.
+/// Integers too large for i64 become exact decimals, and fractions do too
+/// when pg_sexp.exact_decimals is on.
+fn csv_value(text: String, quoted: bool) -> ParsedExpr {
+    if quoted {
+        return ParsedExpr::String(text);
+    }
+    if text.is_empty() {
+        return ParsedExpr::Nil;
+    }
+    let digits = text.strip_prefix('-').unwrap_or(&text);
+    let numeric = digits.starts_with(|c: char| c.is_ascii_digit())
+        && !(digits.starts_with('0') && digits[1..].starts_with(|c: char| c.is_ascii_digit()))
+        && digits.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    if numeric {
+        if let Ok(i) = text.parse::<i64>() {
+            return ParsedExpr::Integer(i);
+        }
+        if is_decimal_literal(&text) && (!text.contains('.') || EXACT_DECIMALS.get()) {
+            return decimal_atom(text);
+        }
+        if let Ok(f) = text.parse::<f64>() {
+            return ParsedExpr::Float(f);
+        }
+    }
+    ParsedExpr::String(text)
+}
+
+/// Parse CSV into a list of rows. With `header`, the first record names the
+/// columns and every other record becomes an alist of (column value)
+/// entries; without it, each record is a plain list of values.
+#[pg_extern(stable, parallel_safe)]
+fn sexp_from_csv(csv: &str, header: bool) -> Sexp {
+    let mut records = parse_csv(csv).into_iter();
+    let columns: Option<Vec<ParsedExpr>> = if header {
+        records.next().map(|names| {
+            names
+                .into_iter()
+                .map(|(name, _)| {
+                    if name.is_empty() {
+                        pgrx::error!("sexp_from_csv: header has an empty column name");
+                    }
+                    ParsedExpr::Symbol(name.into())
+                })
+                .collect()
+        })
+    } else {
+        None
+    };
+
+    let rows: Vec<ParsedExpr> = records
+        .enumerate()
+        .map(|(i, record)| {
+            let values = record.into_iter().map(|(text, quoted)| csv_value(text, quoted));
+            let Some(columns) = &columns else {
+                return ParsedExpr::List(values.collect());
+            };
+            if values.len() != columns.len() {
+                pgrx::error!(
+                    "sexp_from_csv: record {} has {} fields, header has {}",
+                    i + 2,
+                    values.len(),
+                    columns.len()
+                );
+            }
+            ParsedExpr::List(
+                columns.iter().zip(values).map(|(key, value)| ParsedExpr::List(vec![key.clone(), value])).collect(),
+            )
+        })
+        .collect();
+    if rows.is_empty() {
+        return Sexp::nil();
+    }
+    Sexp::from_parsed(&ParsedExpr::List(rows))
+}
+
+// ============================================================================
+// List Manipulation
+// ============================================================================
+
+/// Collect the atoms of `element`, splicing nested lists up to `depth` levels
+/// (all levels when `None`); `nesting` is how deep `element` itself lies
+fn flatten_into<'a>(element: &'a [u8], depth: Option<i32>, nesting: usize, out: &mut Vec<&'a [u8]>) {
+    check_depth(nesting);
+    for child in list_elements(element) {
+        match child[0] {
+            tags::LIST | tags::NIL if depth != Some(0) => {
+                flatten_into(child, depth.map(|d| d - 1), nesting + 1, out);
+            }
+            _ => out.push(child),
+        }
+    }
+}
+
+/// Flatten nested lists up to the given depth (NULL = fully)
+#[pg_extern(name = "sexp_flatten", immutable, parallel_safe)]
+fn sexp_flatten(sexp: Sexp, depth: default!(Option<i32>, "NULL")) -> Sexp {
+    if !sexp.is_list() {
+        return sexp;
+    }
+    if depth.is_some_and(|d| d < 0) {
+        pgrx::error!("sexp_flatten: depth must not be negative");
+    }
+
+    let mut atoms = Vec::new();
+    flatten_into(&sexp.data[1..], depth, 0, &mut atoms);
+    Sexp::from_elements(&atoms)
+}
+
+/// Elements of a list argument, erroring on atoms
+fn list_arg<'a>(sexp: &'a Sexp, function: &str) -> Vec<&'a [u8]> {
+    if !sexp.is_list() {
+        pgrx::error!("{} requires a list: {}", function, sexp.to_string_repr());
+    }
+    sexp.elements()
+}
+
+/// Index into a list of `len` elements, negative indices counting from the end
+fn resolve_index(index: i32, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index as i64 } else { index as i64 };
+    usize::try_from(index).ok().filter(|&i| i < len)
+}
+
+/// Elements in the half-open index range [from, to), clamped to the list;
+/// negative bounds count from the end as in Python slices
+fn slice_elements<'a>(elements: &[&'a [u8]], from: i32, to: i32) -> Vec<&'a [u8]> {
+    let len = elements.len() as i64;
+    let clamp = |i: i32| {
+        let i = if i < 0 { len + i as i64 } else { i as i64 };
+        i.clamp(0, len) as usize
+    };
+    let (from, to) = (clamp(from), clamp(to));
+    if from >= to {
+        return Vec::new();
+    }
+    elements[from..to].to_vec()
+}
+
+/// First n elements of a list
+#[pg_extern(name = "sexp_take", immutable, parallel_safe)]
+fn sexp_take(list: Sexp, n: i32) -> Sexp {
+    let elements = list_arg(&list, "sexp_take");
+    Sexp::from_elements(&slice_elements(&elements, 0, n))
+}
+
+/// List without its first n elements
+#[pg_extern(name = "sexp_drop", immutable, parallel_safe)]
+fn sexp_drop(list: Sexp, n: i32) -> Sexp {
+    let elements = list_arg(&list, "sexp_drop");
+    Sexp::from_elements(&slice_elements(&elements, n, i32::MAX))
+}
+
+/// Elements from index `from` (inclusive) to `to` (exclusive); negative
+/// indices count from the end
+#[pg_extern(name = "sexp_slice", immutable, parallel_safe)]
+fn sexp_slice(list: Sexp, from: i32, to: i32) -> Sexp {
+    let elements = list_arg(&list, "sexp_slice");
+    Sexp::from_elements(&slice_elements(&elements, from, to))
+}
+
+/// Last element of a list (NULL for nil)
+#[pg_extern(name = "sexp_last", immutable, parallel_safe)]
+fn sexp_last(list: Sexp) -> Option<Sexp> {
+    list_arg(&list, "sexp_last").last().map(|e| Sexp::from_element(e))
+}
+
+/// List without its last element
+#[pg_extern(name = "sexp_butlast", immutable, parallel_safe)]
+fn sexp_butlast(list: Sexp) -> Sexp {
+    let elements = list_arg(&list, "sexp_butlast");
+    Sexp::from_elements(elements.split_last().map_or(&[][..], |(_, rest)| rest))
+}
+
+/// Remove structurally equal duplicates, keeping first occurrences in order
+#[pg_extern(name = "sexp_dedupe", immutable, parallel_safe)]
+fn sexp_dedupe(list: Sexp) -> Sexp {
+    let mut seen = std::collections::HashSet::new();
+    let unique: Vec<&[u8]> = list_arg(&list, "sexp_dedupe")
+        .into_iter()
+        .filter(|e| seen.insert(*e))
+        .collect();
+    Sexp::from_elements(&unique)
+}
+
+/// Combine two lists as sets. Elements of `a` come first, in order, and
+/// structural duplicates are dropped from the result.
+fn list_set_op(a: &Sexp, b: &Sexp, function: &str, keep: impl Fn(bool, bool) -> bool) -> Sexp {
+    use std::collections::HashSet;
+    let a = list_arg(a, function);
+    let b = list_arg(b, function);
+    let in_a: HashSet<&[u8]> = a.iter().copied().collect();
+    let in_b: HashSet<&[u8]> = b.iter().copied().collect();
+    let mut seen = HashSet::new();
+    let result: Vec<&[u8]> = a
+        .iter()
+        .chain(&b)
+        .copied()
+        .filter(|e| keep(in_a.contains(e), in_b.contains(e)) && seen.insert(*e))
+        .collect();
+    Sexp::from_elements(&result)
+}
+
+/// Elements found in either list
+#[pg_extern(name = "sexp_union", immutable, parallel_safe)]
+fn sexp_union(a: Sexp, b: Sexp) -> Sexp {
+    list_set_op(&a, &b, "sexp_union", |_, _| true)
+}
+
+/// Elements of `a` that are also in `b`
+#[pg_extern(name = "sexp_intersect", immutable, parallel_safe)]
+fn sexp_intersect(a: Sexp, b: Sexp) -> Sexp {
+    list_set_op(&a, &b, "sexp_intersect", |in_a, in_b| in_a && in_b)
+}
+
+/// Elements of `a` that are not in `b`
+#[pg_extern(name = "sexp_except", immutable, parallel_safe)]
+fn sexp_except(a: Sexp, b: Sexp) -> Sexp {
+    list_set_op(&a, &b, "sexp_except", |in_a, in_b| in_a && !in_b)
+}
+
+/// Pair up the elements of two lists: ((a1 b1) (a2 b2) ...), stopping at
+/// the shorter list
+#[pg_extern(name = "sexp_zip", immutable, parallel_safe)]
+fn sexp_zip(a: Sexp, b: Sexp) -> Sexp {
+    let pairs: Vec<Sexp> = list_arg(&a, "sexp_zip")
+        .into_iter()
+        .zip(list_arg(&b, "sexp_zip"))
+        .map(|(x, y)| Sexp::from_elements(&[x, y]))
+        .collect();
+    let elements: Vec<&[u8]> = pairs.iter().map(|p| &p.data[1..]).collect();
+    Sexp::from_elements(&elements)
+}
+
+/// Split a list of pairs into a list of the two element lists:
+/// ((a1 b1) (a2 b2)) becomes ((a1 a2) (b1 b2))
+#[pg_extern(name = "sexp_unzip", immutable, parallel_safe)]
+fn sexp_unzip(list: Sexp) -> Sexp {
+    let mut firsts = Vec::new();
+    let mut seconds = Vec::new();
+    for element in list_arg(&list, "sexp_unzip") {
+        match list_elements(element).as_slice() {
+            [x, y] => {
+                firsts.push(*x);
+                seconds.push(*y);
+            }
+            _ => pgrx::error!(
+                "sexp_unzip: element is not a pair: {}",
+                Sexp::from_element(element).to_string_repr()
+            ),
+        }
+    }
+    let (firsts, seconds) = (Sexp::from_elements(&firsts), Sexp::from_elements(&seconds));
+    Sexp::from_elements(&[&firsts.data[1..], &seconds.data[1..]])
+}
+
+/// Look up a user function to call on sexp values, checking that it is
+/// sexp -> sexp first: anything else would be handed a datum of the wrong
+/// type. The caller must also be allowed to execute it, as with a direct call.
+/// `fcinfo` is the calling function's, which must return sexp.
+fn sexp_function(func: pg_sys::Oid, caller: &str, fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::FmgrInfo {
+    let sexp_type = sexp_type_oid(fcinfo);
+    unsafe {
+        #[cfg(any(feature = "pg14", feature = "pg15"))]
+        let aclresult = pg_sys::pg_proc_aclcheck(func, pg_sys::GetUserId(), pg_sys::ACL_EXECUTE as pg_sys::AclMode);
+        #[cfg(not(any(feature = "pg14", feature = "pg15")))]
+        let aclresult = pg_sys::object_aclcheck(
+            pg_sys::ProcedureRelationId,
+            func,
+            pg_sys::GetUserId(),
+            pg_sys::ACL_EXECUTE as pg_sys::AclMode,
+        );
+        if aclresult != pg_sys::AclResult::ACLCHECK_OK {
+            pg_sys::aclcheck_error(aclresult, pg_sys::ObjectType::OBJECT_FUNCTION, pg_sys::get_func_name(func));
+        }
+
+        let mut argtypes: *mut pg_sys::Oid = std::ptr::null_mut();
+        let mut nargs = 0;
+        let rettype = pg_sys::get_func_signature(func, &mut argtypes, &mut nargs);
+        if nargs != 1 || *argtypes != sexp_type || rettype != sexp_type || pg_sys::get_func_retset(func) {
+            let name = core::ffi::CStr::from_ptr(pg_sys::format_procedure(func)).to_string_lossy();
+            pgrx::error!("{}: function {} must take one sexp argument and return sexp", caller, name);
+        }
+        pg_sys::pfree(argtypes.cast());
+
+        let mut flinfo: pg_sys::FmgrInfo = std::mem::zeroed();
+        pg_sys::fmgr_info(func, &mut flinfo);
+        flinfo
+    }
+}
+
+/// Apply a function taking and returning sexp to every element of a list.
+/// The function is arbitrary and may be volatile, so this is too.
+#[pg_extern(name = "sexp_map_oid", volatile)]
+fn sexp_map_oid(list: Sexp, func: pg_sys::Oid, fcinfo: pg_sys::FunctionCallInfo) -> Sexp {
+    let elements = list_arg(&list, "sexp_map");
+    let mut flinfo = sexp_function(func, "sexp_map", fcinfo);
+
+    let mapped: Vec<Sexp> = elements
+        .into_iter()
+        .map(|element| unsafe {
+            let arg = Sexp::from_element(element).into_datum().unwrap();
+            // FunctionCall1Coll raises an error if the function returns NULL
+            let result = pg_sys::FunctionCall1Coll(&mut flinfo, pg_sys::InvalidOid, arg);
+            Sexp::from_datum(result, false).unwrap()
+        })
+        .collect();
+    let elements: Vec<&[u8]> = mapped.iter().map(|m| &m.data[1..]).collect();
+    Sexp::from_elements(&elements)
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION sexp_map(sexp, regprocedure) RETURNS sexp
+    AS 'SELECT @extschema@.sexp_map_oid($1, $2::oid)'
+    LANGUAGE SQL VOLATILE STRICT PARALLEL UNSAFE;
+"#,
+    name = "sexp_map",
+    requires = [sexp_map_oid]
+);
+
+/// Rebuild `element` bottom-up (post-order) or top-down (pre-order),
+/// replacing every node with the function's result for it. In pre-order the
+/// children visited are those of the replacement.
+fn walk_apply(element: &[u8], flinfo: &mut pg_sys::FmgrInfo, pre_order: bool, depth: usize) -> Sexp {
+    check_depth(depth);
+    let apply = |flinfo: &mut pg_sys::FmgrInfo, node: Sexp| unsafe {
+        // FunctionCall1Coll raises an error if the function returns NULL
+        let result = pg_sys::FunctionCall1Coll(flinfo, pg_sys::InvalidOid, node.into_datum().unwrap());
+        Sexp::from_datum(result, false).unwrap()
+    };
+    let node = if pre_order {
+        apply(flinfo, Sexp::from_element(element))
+    } else {
+        Sexp::from_element(element)
+    };
+    let node = if node.is_list() && !node.is_nil() {
+        let children: Vec<Sexp> = node
+            .elements()
+            .into_iter()
+            .map(|child| walk_apply(child, flinfo, pre_order, depth + 1))
+            .collect();
+        let elements: Vec<&[u8]> = children.iter().map(|c| &c.data[1..]).collect();
+        Sexp::from_elements(&elements)
+    } else {
+        node
+    };
+    if pre_order {
+        node
+    } else {
+        apply(flinfo, node)
+    }
+}
+
+/// Apply a function taking and returning sexp to every node of a document,
+/// atoms and lists alike, rebuilding the tree from its results. The function
+/// runs once per node and may be volatile, so this is too.
+#[pg_extern(name = "sexp_walk_oid", volatile)]
+fn sexp_walk_oid(doc: Sexp, func: pg_sys::Oid, order: &str, fcinfo: pg_sys::FunctionCallInfo) -> Sexp {
+    let pre_order = match order {
+        "pre" => true,
+        "post" => false,
+        _ => pgrx::error!("sexp_walk: order must be 'pre' or 'post', not '{}'", order),
+    };
+    let mut flinfo = sexp_function(func, "sexp_walk", fcinfo);
+    walk_apply(doc.data.get(1..).unwrap_or(&[tags::NIL]), &mut flinfo, pre_order, 1)
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION sexp_walk(doc sexp, fn regprocedure, "order" text DEFAULT 'post') RETURNS sexp
+    AS 'SELECT @extschema@.sexp_walk_oid($1, $2::oid, $3)'
+    LANGUAGE SQL VOLATILE STRICT PARALLEL UNSAFE;
+"#,
+    name = "sexp_walk",
+    requires = [sexp_walk_oid]
+);
+
+/// Replace the element at index i
+#[pg_extern(name = "sexp_set_nth", immutable, parallel_safe)]
+fn sexp_set_nth(list: Sexp, i: i32, value: Sexp) -> Sexp {
+    let mut elements = list_arg(&list, "sexp_set_nth");
+    match usize::try_from(i).ok().filter(|&i| i < elements.len()) {
+        Some(i) => elements[i] = &value.data[1..],
+        None => pgrx::error!("sexp_set_nth: index {} out of range for list of length {}", i, elements.len()),
+    }
+    Sexp::from_elements(&elements)
+}
+
+/// Insert a value before index i (i = length appends)
+#[pg_extern(name = "sexp_insert_at", immutable, parallel_safe)]
+fn sexp_insert_at(list: Sexp, i: i32, value: Sexp) -> Sexp {
+    let mut elements = list_arg(&list, "sexp_insert_at");
+    match usize::try_from(i).ok().filter(|&i| i <= elements.len()) {
+        Some(i) => elements.insert(i, &value.data[1..]),
+        None => pgrx::error!("sexp_insert_at: index {} out of range for list of length {}", i, elements.len()),
+    }
+    Sexp::from_elements(&elements)
+}
+
+/// Replace the element at index i with the elements of another list
+/// (an empty replacement removes the element)
+#[pg_extern(name = "sexp_splice", immutable, parallel_safe)]
+fn sexp_splice(list: Sexp, i: i32, replacement: Sexp) -> Sexp {
+    let mut elements = list_arg(&list, "sexp_splice");
+    let inserted = list_arg(&replacement, "sexp_splice");
+    match usize::try_from(i).ok().filter(|&i| i < elements.len()) {
+        Some(i) => {
+            elements.splice(i..=i, inserted);
+        }
+        None => pgrx::error!("sexp_splice: index {} out of range for list of length {}", i, elements.len()),
+    }
+    Sexp::from_elements(&elements)
+}
+
+// ============================================================================
+// Path Lookup
+// ============================================================================
+
+/// Position reached while walking a path: a plain node, or an alist entry
+/// found by key whose value is everything after the key
+#[derive(Clone, Copy)]
+enum PathStep<'a> {
+    Node(&'a [u8]),
+    Entry(&'a [u8]),
+}
+
+/// Find the entry headed by `key` among `elements`
+fn find_entry<'a>(elements: &[&'a [u8]], key: &[u8]) -> Option<&'a [u8]> {
+    elements.iter().copied().find(|e| entry_key(e) == Some(key))
+}
+
+/// Walk `path` from an encoded element. Numeric steps index into lists
+/// (skipping the key of an entry, negative from the end); other steps select the alist entry with
+/// that key symbol.
+fn walk_path<'a>(element: &'a [u8], path: &[String]) -> Option<PathStep<'a>> {
+    let mut current = PathStep::Node(element);
+    for step in path {
+        let candidates = match current {
+            PathStep::Node(node) => list_elements(node),
+            PathStep::Entry(entry) => {
+                let tail = list_elements(entry).split_off(1);
+                // (key (k v) ...) and (key ((k v) ...)) both expose the inner alist
+                match tail.as_slice() {
+                    [value] if value[0] == tags::LIST && step.parse::<i32>().is_err() => list_elements(value),
+                    _ => tail,
+                }
+            }
+        };
+
+        current = match step.parse::<i32>() {
+            Ok(index) => PathStep::Node(candidates[resolve_index(index, candidates.len())?]),
+            Err(_) => PathStep::Entry(find_entry(&candidates, step.as_bytes())?),
+        };
+    }
+    Some(current)
+}
+
+/// Value found at `path`: the node itself, or an entry's value (nil for a
+/// bare key, the single value, or the list of values)
+fn path_value(element: &[u8], path: &[String]) -> Option<Sexp> {
+    match walk_path(element, path)? {
+        PathStep::Node(node) => Some(Sexp::from_element(node)),
+        PathStep::Entry(entry) => {
+            let tail = list_elements(entry).split_off(1);
+            Some(match tail.as_slice() {
+                [value] => Sexp::from_element(value),
+                values => Sexp::from_elements(values),
+            })
+        }
+    }
+}
+
+/// Type name of the value at a path (NULL if absent)
+#[pg_extern(name = "sexp_typeof_path", immutable, parallel_safe)]
+fn sexp_typeof_path(sexp: Sexp, path: Vec<String>) -> Option<String> {
+    path_value(sexp.data.get(1..).unwrap_or_default(), &path).map(|v| v.get_type().to_string())
+}
+
+/// Length of the list at a path (NULL if absent)
+#[pg_extern(name = "sexp_length_at", immutable, parallel_safe)]
+fn sexp_length_at(sexp: Sexp, path: Vec<String>) -> Option<i32> {
+    path_value(sexp.data.get(1..).unwrap_or_default(), &path).map(|v| v.length())
+}
+
+/// Keys of the alist entries among a node's elements
+fn alist_keys(sexp: &Sexp) -> Vec<String> {
+    sexp.elements()
+        .into_iter()
+        .filter_map(entry_key)
+        .map(|k| String::from_utf8_lossy(k).into_owned())
+        .collect()
+}
+
+/// Keys of a top-level alist
+#[pg_extern(name = "sexp_keys", immutable, parallel_safe)]
+fn sexp_keys(sexp: Sexp) -> Vec<String> {
+    alist_keys(&sexp)
+}
+
+/// Keys of the alist at a path (NULL if absent)
+#[pg_extern(name = "sexp_keys_at", immutable, parallel_safe)]
+fn sexp_keys_at(sexp: Sexp, path: Vec<String>) -> Option<Vec<String>> {
+    path_value(sexp.data.get(1..).unwrap_or_default(), &path).map(|v| alist_keys(&v))
+}
+
+// ============================================================================
+// Table Projection
+// ============================================================================
+
+/// Output column of a sexp_table spec entry `(name type step ...)`; the
+/// path defaults to the column name
+struct TableColumn {
+    name: String,
+    type_name: String,
+    path: Vec<String>,
+}
+
+fn table_columns(spec: &Sexp) -> Vec<TableColumn> {
+    let entries = alist_arg(spec, "sexp_table");
+    if entries.is_empty() {
+        pgrx::error!("sexp_table: spec must name at least one column");
+    }
+    entries
+        .into_iter()
+        .map(|entry| {
+            let parts = list_elements(entry);
+            let name = String::from_utf8_lossy(entry_key(entry).unwrap_or_default()).into_owned();
+            let type_name = match parts.get(1) {
+                Some(t) if t[0] == tags::SYMBOL || t[0] == tags::STRING => element_text(t).unwrap_or_default(),
+                _ => pgrx::error!("sexp_table: column \"{}\" needs a type name", name),
+            };
+            let path = match parts.get(2..).unwrap_or_default() {
+                [] => vec![name.clone()],
+                steps => steps.iter().map(|s| element_text(s).unwrap_or_default()).collect(),
+            };
+            TableColumn { name, type_name, path }
+        })
+        .collect()
+}
+
+/// Names and types of the columns a spec projects
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_table_columns(
+    spec: Sexp,
+) -> TableIterator<'static, (name!(name, String), name!(type_name, String))> {
+    TableIterator::new(table_columns(&spec).into_iter().map(|c| (c.name, c.type_name)))
+}
+
+/// Each spec column for every element of a list, twice: `cells` holds the
+/// text other types are cast from, with floats at full precision, and
+/// `vals` the value itself for sexp columns. Absent paths and nil give NULL.
+/// Text of lists follows the output settings, so this is only stable.
+#[pg_extern(stable, parallel_safe)]
+fn sexp_table_cells(
+    doc: Sexp,
+    spec: Sexp,
+) -> TableIterator<'static, (name!(cells, Vec<Option<String>>), name!(vals, Vec<Option<Sexp>>))> {
+    let columns = table_columns(&spec);
+    let rows: Vec<(Vec<Option<String>>, Vec<Option<Sexp>>)> = list_arg(&doc, "sexp_table")
+        .into_iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| {
+                    let Some(value) = path_value(row, &column.path) else {
+                        return (None, None);
+                    };
+                    let text = match value.data[1..] {
+                        [tags::BOOL, b] => Some((b != 0).to_string()),
+                        [tags::FLOAT, ..] => element_float(&value.data[1..]).map(exact_float_text),
+                        ref element => element_text(element),
+                    };
+                    (text, Some(value))
+                })
+                .unzip()
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+extension_sql!(
+    r#"
+-- Project a list of alists into typed rows, one per element, in the manner
+-- of JSON_TABLE. The spec is an alist of (column type path-step ...)
+-- entries, the path defaulting to the column name; values are cast from
+-- their text form and absent or nil values give NULL. Like any function
+-- returning record, it needs a matching column definition list:
+--   SELECT * FROM sexp_table(doc, '((id int) (city text address city))')
+--       AS t(id int, city text)
+CREATE FUNCTION sexp_table(doc sexp, spec sexp) RETURNS SETOF record
+    AS $$
+    DECLARE
+        columns text;
+    BEGIN
+        SELECT string_agg(
+                CASE WHEN type_name::regtype = '@extschema@.sexp'::regtype
+                    THEN format('c.vals[%s] AS %I', ord, name)
+                    ELSE format('c.cells[%s]::%s AS %I', ord, type_name::regtype, name)
+                END, ', ' ORDER BY ord)
+            INTO columns
+            FROM @extschema@.sexp_table_columns(spec) WITH ORDINALITY AS t(name, type_name, ord);
+        RETURN QUERY EXECUTE format('SELECT %s FROM @extschema@.sexp_table_cells($1, $2) AS c', columns)
+            USING doc, spec;
+    END
+    $$
+    LANGUAGE plpgsql STABLE STRICT;
+"#,
+    name = "sexp_table",
+    requires = [sexp_table_columns, sexp_table_cells]
+);
+
+// ============================================================================
+// Path/Value Pairs
+// ============================================================================
+
+/// Entries of the alist nested under an entry, if it has one: a single list
+/// value made of entries, or several values that are all entries
+fn nested_entries<'a>(tail: &[&'a [u8]]) -> Option<Vec<&'a [u8]>> {
+    let is_entries = |items: &[&[u8]]| !items.is_empty() && items.iter().all(|e| entry_key(e).is_some());
+    match tail {
+        [value] if value[0] == tags::LIST => {
+            let inner = list_elements(value);
+            is_entries(&inner).then_some(inner)
+        }
+        [_] => None,
+        values => is_entries(values).then(|| values.to_vec()),
+    }
+}
+
+/// Path step for a symbol key. Steps are read back as atoms, so a key that
+/// would read as an integer or string is pipe-quoted; `.` and `\` are
+/// escaped with a backslash.
+fn path_step(key: &str) -> String {
+    let reads_as_other = key.is_empty()
+        || key.parse::<i64>().is_ok()
+        || key.starts_with(['"', '|'])
+        || key.ends_with('|');
+    let step = if reads_as_other { format!("|{}|", key) } else { key.to_string() };
+    step.replace('\\', "\\\\").replace('.', "\\.")
+}
+
+/// Split a dotted path on unescaped dots and encode each step as a key atom:
+/// `"text"` is a string, an integer literal an integer, `|text|` or anything
+/// else a symbol. None if a step is empty.
+fn path_keys(path: &str) -> Option<Vec<Vec<u8>>> {
+    let mut steps = vec![String::new()];
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => steps.last_mut().unwrap().push(chars.next().unwrap_or('\\')),
+            '.' => steps.push(String::new()),
+            c => steps.last_mut().unwrap().push(c),
+        }
+    }
+    steps
+        .into_iter()
+        .map(|step| {
+            let atom = if let Some(text) = step.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                ParsedExpr::String(text.to_string())
+            } else if let Some(text) = step.strip_prefix('|').and_then(|s| s.strip_suffix('|')) {
+                ParsedExpr::Symbol(text.to_string())
+            } else if let Ok(n) = step.parse::<i64>() {
+                ParsedExpr::Integer(n)
+            } else if step.is_empty() {
+                return None;
+            } else {
+                ParsedExpr::Symbol(step)
+            };
+            let mut key = Vec::new();
+            serialize_parsed(&atom, &mut key);
+            Some(key)
+        })
+        .collect()
+}
+
+fn flatten_pairs(entries: &[&[u8]], prefix: &str, depth: usize, rows: &mut Vec<(String, Sexp)>) {
+    check_depth(depth);
+    for entry in entries {
+        let key = path_step(&String::from_utf8_lossy(entry_key(entry).unwrap_or_default()));
+        let path = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+        let tail = list_elements(entry).split_off(1);
+        match nested_entries(&tail) {
+            Some(inner) => flatten_pairs(&inner, &path, depth + 1, rows),
+            None => rows.push((
+                path,
+                match tail.as_slice() {
+                    [value] => Sexp::from_element(value),
+                    values => Sexp::from_elements(values),
+                },
+            )),
+        }
+    }
+}
+
+/// Flatten nested alists into (path, value) rows, one per leaf entry, with
+/// the keys leading to it joined by dots. Dots and backslashes inside a key
+/// are escaped with a backslash, and keys that would read back as numbers
+/// or strings are pipe-quoted, so sexp_from_pairs rebuilds the document.
+/// A leaf's value is the rest of its entry: one element as itself, none or
+/// several as a list. sexp_from_pairs writes every entry as `(key value)`,
+/// so only documents of such entries round-trip: a bare key `(a)` comes back
+/// as `(a ())` and `(tags a b)` as `(tags (a b))`.
+#[pg_extern(name = "sexp_flatten_pairs", immutable, parallel_safe)]
+fn sexp_flatten_pairs(doc: Sexp) -> TableIterator<'static, (name!(path, String), name!(value, Sexp))> {
+    let mut rows = Vec::new();
+    flatten_pairs(&alist_arg(&doc, "sexp_flatten_pairs"), "", 1, &mut rows);
+    TableIterator::new(rows)
+}
+
+/// Nested alist being rebuilt from (path, value) pairs, keys (encoded
+/// atoms) in first-seen order and indexed by their bytes
+#[derive(Default)]
+struct PairTree {
+    entries: Vec<(Vec<u8>, PairNode)>,
+    index: std::collections::HashMap<Vec<u8>, usize>,
+}
+
+enum PairNode {
+    Leaf(Vec<u8>),
+    Branch(PairTree),
+}
+
+impl PairTree {
+    fn insert(&mut self, path: &str, value: &[u8]) {
+        let Some(steps) = path_keys(path) else {
+            pgrx::error!("sexp_from_pairs: invalid path \"{}\"", path);
+        };
+        check_depth(steps.len());
+        let depth = steps.len();
+        let mut tree = self;
+        for (i, step) in steps.into_iter().enumerate() {
+            let last = i + 1 == depth;
+            let slot = match tree.index.get(&step) {
+                Some(&slot) => slot,
+                None => {
+                    let node = match last {
+                        true => PairNode::Leaf(value.to_vec()),
+                        false => PairNode::Branch(PairTree::default()),
+                    };
+                    tree.index.insert(step.clone(), tree.entries.len());
+                    tree.entries.push((step, node));
+                    tree.entries.len() - 1
+                }
+            };
+            tree = match &mut tree.entries[slot].1 {
+                PairNode::Branch(branch) if !last => branch,
+                PairNode::Leaf(leaf) if last && leaf.as_slice() == value => return,
+                _ => pgrx::error!("sexp_from_pairs: conflicting values at path \"{}\"", path),
+            };
+        }
+    }
+
+    /// Encode as an alist; nested alists are written as the single value of
+    /// their entry, `(key ((k v) ...))`, so one-entry branches read back
+    fn encode(&self, out: &mut impl ByteSink) {
+        if self.entries.is_empty() {
+            out.push(tags::NIL);
+            return;
+        }
+        out.push(tags::LIST);
+        write_varint(out, self.entries.len() as u64);
+        for (key, node) in &self.entries {
+            out.push(tags::LIST);
+            write_varint(out, 2);
+            out.extend_from_slice(key);
+            match node {
+                PairNode::Leaf(value) => out.extend_from_slice(value),
+                PairNode::Branch(branch) => branch.encode(out),
+            }
+        }
+    }
+
+    fn finish(&self) -> Sexp {
+        let mut data = VarlenaBuf::with_capacity(64);
+        data.push(FORMAT_VERSION);
+        self.encode(&mut data);
+        Sexp::from_buf(data)
+    }
+}
+
+fn pair_value(value: &Option<Sexp>) -> &[u8] {
+    value.as_ref().and_then(|v| v.data.get(1..)).filter(|e| !e.is_empty()).unwrap_or(&[tags::NIL])
+}
+
+/// Rebuild a nested alist from parallel arrays of dotted paths and values,
+/// the inverse of sexp_flatten_pairs for alists of `(key value)` entries
+#[pg_extern(name = "sexp_from_pairs", immutable, parallel_safe)]
+fn sexp_from_pair_arrays(paths: Vec<String>, values: Vec<Option<Sexp>>) -> Sexp {
+    if paths.len() != values.len() {
+        pgrx::error!("sexp_from_pairs: path and value arrays must have the same length");
+    }
+    let mut tree = PairTree::default();
+    for (path, value) in paths.iter().zip(&values) {
+        tree.insert(path, pair_value(value));
+    }
+    tree.finish()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_from_pairs_transfn(
+    state: Internal,
+    path: Option<String>,
+    value: Option<Sexp>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let mut state = agg_state::<PairTree>(state, fcinfo, "sexp_from_pairs_transfn");
+    if let Some(path) = path {
+        let tree = unsafe { state.get_mut::<PairTree>() }.unwrap();
+        tree.insert(&path, pair_value(&value));
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_from_pairs_finalfn(state: Internal) -> Option<Sexp> {
+    unsafe { state.get::<PairTree>() }.map(PairTree::finish)
+}
+
+extension_sql!(
+    r#"
+-- Rebuild a nested alist from (path, value) rows; rows with a NULL path are
+-- ignored and a NULL value becomes nil
+CREATE AGGREGATE sexp_from_pairs(text, sexp) (
+    SFUNC = sexp_from_pairs_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_from_pairs_finalfn
+);
+"#,
+    name = "sexp_from_pairs_agg",
+    requires = [sexp_from_pairs_transfn, sexp_from_pairs_finalfn]
+);
+
+// ============================================================================
+// Structural Diff
+// ============================================================================
+
+/// Value of an alist entry as walk_path sees it: the single value, or the
+/// list of values
+fn entry_value(entry: &[u8]) -> Sexp {
+    match list_elements(entry).split_off(1).as_slice() {
+        [value] => Sexp::from_element(value),
+        values => Sexp::from_elements(values),
+    }
+}
+
+fn diff_edit(op: &str, path: &[Vec<u8>], values: &[&[u8]]) -> Sexp {
+    let op = Sexp::from_parsed(&ParsedExpr::Symbol(op.into()));
+    let steps: Vec<&[u8]> = path.iter().map(Vec::as_slice).collect();
+    let path = Sexp::from_elements(&steps);
+    let mut parts = vec![&op.data[1..], &path.data[1..]];
+    parts.extend_from_slice(values);
+    Sexp::from_elements(&parts)
+}
+
+/// Entries of an alist keyed by their key and how many earlier entries
+/// share it, so a repeated key pairs up by occurrence
+fn occurrence_keys<'a>(entries: &[&'a [u8]]) -> Vec<((&'a [u8], usize), &'a [u8])> {
+    let mut seen: std::collections::HashMap<&[u8], usize> = std::collections::HashMap::new();
+    entries
+        .iter()
+        .map(|&entry| {
+            let key = entry_key(entry).unwrap_or_default();
+            let count = seen.entry(key).or_default();
+            *count += 1;
+            ((key, *count - 1), entry)
+        })
+        .collect()
+}
+
+fn diff_elements(old: &[u8], new: &[u8], path: &mut Vec<Vec<u8>>, depth: usize, edits: &mut Vec<Sexp>) {
+    check_depth(depth);
+    if old == new {
+        return;
+    }
+    let is_alist = |e: &[u8]| e[0] == tags::LIST && list_elements(e).iter().all(|x| entry_key(x).is_some());
+    if is_alist(old) && is_alist(new) {
+        let old_entries = occurrence_keys(&list_elements(old));
+        let new_entries = occurrence_keys(&list_elements(new));
+        let old_index: std::collections::HashMap<_, _> = old_entries.iter().copied().collect();
+        let new_index: std::collections::HashMap<_, _> = new_entries.iter().copied().collect();
+        for (key, entry) in &old_entries {
+            path.push(list_elements(entry)[0].to_vec());
+            match new_index.get(key) {
+                Some(other) => {
+                    let (before, after) = (entry_value(entry), entry_value(other));
+                    diff_elements(&before.data[1..], &after.data[1..], path, depth + 1, edits);
+                }
+                None => edits.push(diff_edit("remove", path, &[&entry_value(entry).data[1..]])),
+            }
+            path.pop();
+        }
+        for (key, entry) in &new_entries {
+            if !old_index.contains_key(key) {
+                path.push(list_elements(entry)[0].to_vec());
+                edits.push(diff_edit("add", path, &[&entry_value(entry).data[1..]]));
+                path.pop();
+            }
+        }
+    } else if old[0] == tags::LIST && new[0] == tags::LIST && list_elements(old).len() == list_elements(new).len() {
+        for (i, (a, b)) in list_elements(old).into_iter().zip(list_elements(new)).enumerate() {
+            path.push(Sexp::from_parsed(&ParsedExpr::Integer(i as i64)).data[1..].to_vec());
+            diff_elements(a, b, path, depth + 1, edits);
+            path.pop();
+        }
+    } else {
+        edits.push(diff_edit("replace", path, &[old, new]));
+    }
+}
+
+/// Structural diff between two documents, as a list of edits whose paths
+/// are lists of keys and element indexes: `(add path new)` and
+/// `(remove path old)` for alist keys, `(replace path old new)` otherwise. Alists are compared
+/// by key, the n-th entry of a repeated key with the n-th in the other
+/// document, and lists of equal length element by element. Nil when equal.
+#[pg_extern(name = "sexp_diff", immutable, parallel_safe)]
+fn sexp_diff(old: Sexp, new: Sexp) -> Sexp {
+    let root = |s: &Sexp| s.data.get(1..).filter(|e| !e.is_empty()).map(<[u8]>::to_vec).unwrap_or_else(|| vec![tags::NIL]);
+    let mut edits = Vec::new();
+    diff_elements(&root(&old), &root(&new), &mut Vec::new(), 1, &mut edits);
+    let elements: Vec<&[u8]> = edits.iter().map(|e| &e.data[1..]).collect();
+    Sexp::from_elements(&elements)
+}
+
+extension_sql!(
+    r#"
+-- Default destination of sexp_diff_history()
+CREATE TABLE sexp_history (
+    id bigserial PRIMARY KEY,
+    changed_at timestamptz NOT NULL DEFAULT now(),
+    table_name text NOT NULL,
+    column_name text NOT NULL,
+    row_key text,
+    diff sexp NOT NULL
+);
+SELECT pg_catalog.pg_extension_config_dump('sexp_history', '');
+SELECT pg_catalog.pg_extension_config_dump('sexp_history_id_seq', '');
+
+-- Row-level UPDATE trigger appending sexp_diff(OLD.col, NEW.col) to a
+-- history table whenever the document changes. Trigger arguments: the sexp
+-- column, then optionally the history table (default sexp_history, same
+-- columns) and a column identifying the row, stored as row_key.
+CREATE FUNCTION sexp_diff_history() RETURNS trigger
+    AS $$
+    DECLARE
+        column_name text := TG_ARGV[0];
+        history_table regclass := coalesce(TG_ARGV[1], '@extschema@.sexp_history')::regclass;
+        old_doc @extschema@.sexp;
+        new_doc @extschema@.sexp;
+        row_key text;
+        changes @extschema@.sexp;
+    BEGIN
+        IF TG_LEVEL <> 'ROW' OR TG_OP <> 'UPDATE' OR column_name IS NULL THEN
+            RAISE EXCEPTION 'sexp_diff_history(column) must be used as a row-level UPDATE trigger';
+        END IF;
+        EXECUTE format('SELECT ($1).%I, ($2).%I', column_name, column_name)
+            INTO old_doc, new_doc USING OLD, NEW;
+        IF TG_ARGV[2] IS NOT NULL THEN
+            EXECUTE format('SELECT ($1).%I::text', TG_ARGV[2]) INTO row_key USING NEW;
+        END IF;
+
+        changes := @extschema@.sexp_diff(coalesce(old_doc, @extschema@.sexp_nil()),
+                                         coalesce(new_doc, @extschema@.sexp_nil()));
+        IF NOT @extschema@.is_nil(changes) THEN
+            EXECUTE format('INSERT INTO %s (table_name, column_name, row_key, diff) VALUES ($1, $2, $3, $4)', history_table)
+                USING format('%I.%I', TG_TABLE_SCHEMA, TG_TABLE_NAME), column_name, row_key, changes;
+        END IF;
+        RETURN NEW;
+    END
+    $$
+    LANGUAGE plpgsql;
+"#,
+    name = "sexp_diff_history",
+    requires = [sexp_diff, sexp_nil_func, sexp_is_nil]
+);
+
+// ============================================================================
+// Ordering
+// ============================================================================
+
+/// Rank of each type in the total order: nil < bool < number < symbol <
+/// string < list
+fn type_rank(tag: u8) -> u8 {
+    match tag {
+        tags::NIL => 0,
+        tags::BOOL => 1,
+        tags::INTEGER | tags::FLOAT | tags::DECIMAL => 2,
+        tags::SYMBOL => 3,
+        tags::STRING => 4,
+        _ => 5,
+    }
+}
+
+/// Total order over encoded elements, consistent with structural equality.
+/// Numbers compare by exact value across integer, float and decimal,
+/// integers first on ties;
+/// lists compare element-wise, then by length.
+fn compare_elements(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    let (tag_a, tag_b) = (a.first().copied().unwrap_or(tags::NIL), b.first().copied().unwrap_or(tags::NIL));
+    let by_rank = type_rank(tag_a).cmp(&type_rank(tag_b));
+    if by_rank != Ordering::Equal {
+        return by_rank;
+    }
+
+    match tag_a {
+        tags::NIL => Ordering::Equal,
+        tags::BOOL => a.get(1).cmp(&b.get(1)),
+        tags::INTEGER | tags::FLOAT | tags::DECIMAL => {
+            compare_numbers(a, b).then(tag_a.cmp(&tag_b)).then_with(|| a.cmp(b))
+        }
+        tags::SYMBOL | tags::STRING => atom_bytes_at(a, 0).cmp(&atom_bytes_at(b, 0)),
+        _ => {
+            let (xs, ys) = (list_elements(a), list_elements(b));
+            xs.iter()
+                .zip(&ys)
+                .map(|(x, y)| compare_elements(x, y))
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or_else(|| xs.len().cmp(&ys.len()))
+        }
+    }
+}
+
+/// Order of two number atoms by value alone. Every finite pair compares by
+/// exact decimal value, floats by their shortest round-tripping digits, so
+/// the order is transitive across integers, floats and decimals, where going
+/// through f64 for some pairs would not be. The infinities sort around the
+/// finite values and NaN after everything.
+fn compare_numbers(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    if let (Some(x), Some(y)) = (element_integer(a), element_integer(b)) {
+        return x.cmp(&y);
+    }
+    if a[0] == tags::FLOAT && b[0] == tags::FLOAT {
+        // Two finite floats order by value directly, as their digits would
+        let (x, y) = (element_float(a).unwrap_or(f64::NAN), element_float(b).unwrap_or(f64::NAN));
+        if x.is_finite() && y.is_finite() {
+            return x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal);
+        }
+    }
+    let key = |element: &[u8]| match element_decimal(element) {
+        Some(digits) => (1, Some(digits)),
+        None => match element_float(element) {
+            Some(f) if f == f64::NEG_INFINITY => (0, None),
+            Some(f) if f == f64::INFINITY => (2, None),
+            _ => (3, None),
+        },
+    };
+    match (key(a), key(b)) {
+        ((1, Some(x)), (1, Some(y))) => compare_decimal_text(&x, &y),
+        ((x, _), (y, _)) => x.cmp(&y),
+    }
+}
+
+fn sexp_compare(a: &Sexp, b: &Sexp) -> std::cmp::Ordering {
+    compare_elements(a.data.get(1..).unwrap_or_default(), b.data.get(1..).unwrap_or_default())
+}
+
+/// Three-way comparison (btree support function)
+#[pg_extern(name = "sexp_cmp", immutable, parallel_safe)]
+fn sexp_cmp(a: Sexp, b: Sexp) -> i32 {
+    sexp_compare(&a, &b) as i32
+}
+
+#[pg_extern(name = "sexp_lt", immutable, parallel_safe)]
+fn sexp_lt(a: Sexp, b: Sexp) -> bool {
+    sexp_compare(&a, &b).is_lt()
+}
+
+#[pg_extern(name = "sexp_le", immutable, parallel_safe)]
+fn sexp_le(a: Sexp, b: Sexp) -> bool {
+    sexp_compare(&a, &b).is_le()
+}
+
+#[pg_extern(name = "sexp_gt", immutable, parallel_safe)]
+fn sexp_gt(a: Sexp, b: Sexp) -> bool {
+    sexp_compare(&a, &b).is_gt()
+}
+
+#[pg_extern(name = "sexp_ge", immutable, parallel_safe)]
+fn sexp_ge(a: Sexp, b: Sexp) -> bool {
+    sexp_compare(&a, &b).is_ge()
+}
+
+/// Sort list elements by the total order
+#[pg_extern(name = "sexp_sort", immutable, parallel_safe)]
+fn sexp_sort(list: Sexp) -> Sexp {
+    let mut elements = list_arg(&list, "sexp_sort");
+    elements.sort_by(|a, b| compare_elements(a, b));
+    Sexp::from_elements(&elements)
+}
+
+/// Sort list elements by the value found at `path` within each element;
+/// elements without a value at the path sort first
+#[pg_extern(name = "sexp_sort_by", immutable, parallel_safe)]
+fn sexp_sort_by(list: Sexp, path: Vec<String>) -> Sexp {
+    let elements = list_arg(&list, "sexp_sort_by");
+    let mut keyed: Vec<(Option<Sexp>, &[u8])> =
+        elements.into_iter().map(|e| (path_value(e, &path), e)).collect();
+    keyed.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) => sexp_compare(a, b),
+        _ => a.is_some().cmp(&b.is_some()),
+    });
+    let sorted: Vec<&[u8]> = keyed.into_iter().map(|(_, e)| e).collect();
+    Sexp::from_elements(&sorted)
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR < (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_lt,
+    COMMUTATOR = >,
+    NEGATOR = >=,
+    RESTRICT = scalarltsel,
+    JOIN = scalarltjoinsel
+);
+
+CREATE OPERATOR <= (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_le,
+    COMMUTATOR = >=,
+    NEGATOR = >,
+    RESTRICT = scalarlesel,
+    JOIN = scalarlejoinsel
+);
+
+CREATE OPERATOR > (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_gt,
+    COMMUTATOR = <,
+    NEGATOR = <=,
+    RESTRICT = scalargtsel,
+    JOIN = scalargtjoinsel
+);
+
+CREATE OPERATOR >= (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_ge,
+    COMMUTATOR = <=,
+    NEGATOR = <,
+    RESTRICT = scalargesel,
+    JOIN = scalargejoinsel
+);
+
+-- B-tree operator class (ORDER BY, DISTINCT, merge joins, sorted indexes)
+CREATE OPERATOR CLASS sexp_btree_ops
+    DEFAULT FOR TYPE sexp USING btree AS
+    OPERATOR 1 < (sexp, sexp),
+    OPERATOR 2 <= (sexp, sexp),
+    OPERATOR 3 = (sexp, sexp),
+    OPERATOR 4 >= (sexp, sexp),
+    OPERATOR 5 > (sexp, sexp),
+    FUNCTION 1 sexp_cmp(sexp, sexp);
+"#,
+    name = "sexp_ordering",
+    requires = ["sexp_operators", sexp_cmp, sexp_lt, sexp_le, sexp_gt, sexp_ge]
+);
+
+extension_sql!(
+    r#"
+-- BRIN minmax operator class: block ranges keep the smallest and largest
+-- value under the btree ordering, so range predicates can skip whole ranges
+CREATE OPERATOR CLASS sexp_minmax_ops
+    DEFAULT FOR TYPE sexp USING brin AS
+    OPERATOR 1 < (sexp, sexp),
+    OPERATOR 2 <= (sexp, sexp),
+    OPERATOR 3 = (sexp, sexp),
+    OPERATOR 4 >= (sexp, sexp),
+    OPERATOR 5 > (sexp, sexp),
+    FUNCTION 1 brin_minmax_opcinfo(internal),
+    FUNCTION 2 brin_minmax_add_value(internal, internal, internal, internal),
+    FUNCTION 3 brin_minmax_consistent(internal, internal, internal),
+    FUNCTION 4 brin_minmax_union(internal, internal, internal);
+"#,
+    name = "sexp_brin",
+    requires = ["sexp_ordering"]
+);
+
+// ============================================================================
+// Aggregates
+// ============================================================================
+
+/// Transition state shared by the list-building aggregates: the encoded
+/// elements collected so far, concatenated in input order
+#[derive(Default)]
+struct ListAggState {
+    count: u64,
+    elements: Vec<u8>,
+}
+
+impl ListAggState {
+    fn push(&mut self, element: &[u8]) {
+        self.count += 1;
+        self.elements.extend_from_slice(element);
+    }
+
+    fn append(&mut self, other: &ListAggState) {
+        self.count += other.count;
+        self.elements.extend_from_slice(&other.elements);
+    }
+}
+
+/// Return the aggregate's state, creating it in the aggregate memory context
+/// on the first call so it survives between rows
+fn agg_state<T: Default>(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+    fn_name: &str,
+) -> Internal {
+    if state.unwrap().is_some() {
+        return state;
+    }
+    let mut agg_context = std::ptr::null_mut();
+    if unsafe { pg_sys::AggCheckCallContext(fcinfo, &mut agg_context) } == 0 {
+        pgrx::error!("{} called in non-aggregate context", fn_name);
+    }
+    unsafe {
+        pgrx::PgMemoryContexts::For(agg_context)
+            .switch_to(|_| Internal::new(T::default()))
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_agg_transfn(
+    state: Internal,
+    value: Option<Sexp>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let mut state = agg_state::<ListAggState>(state, fcinfo, "sexp_agg_transfn");
+    let agg = unsafe { state.get_mut::<ListAggState>() }.unwrap();
+    match &value {
+        Some(value) => agg.push(&value.data[1..]),
+        None => agg.push(&[tags::NIL]),
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_object_agg_transfn(
+    state: Internal,
+    key: Option<&str>,
+    value: Option<Sexp>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let Some(key) = key else {
+        pgrx::error!("sexp_object_agg: key must not be NULL");
+    };
+    let mut state = agg_state::<ListAggState>(state, fcinfo, "sexp_object_agg_transfn");
+    let key = Sexp::from_parsed(&ParsedExpr::Symbol(key.into()));
+    let value = value.unwrap_or_else(Sexp::nil);
+    let entry = Sexp::from_elements(&[&key.data[1..], &value.data[1..]]);
+    unsafe { state.get_mut::<ListAggState>() }.unwrap().push(&entry.data[1..]);
+    state
+}
+
+/// Merge two partial states from parallel workers
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_agg_combinefn(
+    state: Internal,
+    other: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let Some(other) = (unsafe { other.get::<ListAggState>() }) else {
+        return state;
+    };
+    // The incoming state may live in a short-lived context, so always copy
+    // it into our own
+    let mut state = agg_state::<ListAggState>(state, fcinfo, "sexp_agg_combinefn");
+    unsafe { state.get_mut::<ListAggState>() }.unwrap().append(other);
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_agg_finalfn(state: Internal) -> Option<Sexp> {
+    let agg = unsafe { state.get::<ListAggState>() }?;
+    if agg.count == 0 {
+        return Some(Sexp::nil());
+    }
+    let mut data = VarlenaBuf::with_capacity(agg.elements.len() + 12);
+    data.extend_from_slice(&[FORMAT_VERSION, tags::LIST]);
+    write_varint(&mut data, agg.count);
+    data.extend_from_slice(&agg.elements);
+    Some(Sexp::from_buf(data))
+}
+
+/// Serialize a partial state as its element count followed by the elements
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_agg_serialfn(state: Internal) -> Vec<u8> {
+    let agg = unsafe { state.get::<ListAggState>() }
+        .expect("sexp_agg_serialfn: state must not be NULL");
+    let mut out = Vec::with_capacity(agg.elements.len() + 10);
+    write_varint(&mut out, agg.count);
+    out.extend_from_slice(&agg.elements);
+    out
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_agg_deserialfn(bytes: &[u8], _state: Internal) -> Internal {
+    let mut pos = 0;
+    let count = read_varint(bytes, &mut pos);
+    Internal::new(ListAggState { count, elements: bytes[pos..].to_vec() })
+}
+
+extension_sql!(
+    r#"
+-- Collect values into a list; NULL inputs become nil
+CREATE AGGREGATE sexp_agg(sexp) (
+    SFUNC = sexp_agg_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_agg_finalfn,
+    COMBINEFUNC = sexp_agg_combinefn,
+    SERIALFUNC = sexp_agg_serialfn,
+    DESERIALFUNC = sexp_agg_deserialfn,
+    PARALLEL = SAFE
+);
+
+-- Collect key/value pairs into an alist
+CREATE AGGREGATE sexp_object_agg(text, sexp) (
+    SFUNC = sexp_object_agg_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_agg_finalfn,
+    COMBINEFUNC = sexp_agg_combinefn,
+    SERIALFUNC = sexp_agg_serialfn,
+    DESERIALFUNC = sexp_agg_deserialfn,
+    PARALLEL = SAFE
+);
+"#,
+    name = "sexp_aggregates",
+    requires = [
+        sexp_agg_transfn,
+        sexp_object_agg_transfn,
+        sexp_agg_combinefn,
+        sexp_agg_finalfn,
+        sexp_agg_serialfn,
+        sexp_agg_deserialfn
+    ]
+);
+
+/// Subtrees seen across rows, keyed by their encoding, with the number of
+/// rows containing each, and the bytes the keys take
+#[derive(Default)]
+struct SubtreeCountState {
+    min_rows: i64,
+    subtrees: std::collections::HashMap<Vec<u8>, i64>,
+    bytes: usize,
+}
+
+/// Each distinct list subtree of an encoded element, in preorder
+fn distinct_subtrees(data: &[u8], start: usize) -> Vec<&[u8]> {
+    let mut seen = std::collections::HashSet::new();
+    Preorder::new(data, start)
+        .filter(|item| item.tag == tags::LIST)
+        .map(|item| {
+            check_depth(item.depth);
+            let mut end = item.start;
+            skip_element(data, &mut end);
+            &data[item.start..end]
+        })
+        .filter(|subtree| seen.insert(*subtree))
+        .collect()
+}
+
+/// Every distinct list subtree of a document once; counting them with GROUP
+/// BY across rows does what sexp_common_subtrees does in bounded memory
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_list_subtrees(doc: Sexp) -> SetOfIterator<'static, Sexp> {
+    let subtrees: Vec<Sexp> = distinct_subtrees(&doc.data, 1).into_iter().map(Sexp::from_element).collect();
+    SetOfIterator::new(subtrees)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_common_subtrees_transfn(
+    state: Internal,
+    doc: Option<Sexp>,
+    min_rows: i32,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let mut state = agg_state::<SubtreeCountState>(state, fcinfo, "sexp_common_subtrees_transfn");
+    let counts = unsafe { state.get_mut::<SubtreeCountState>() }.unwrap();
+    counts.min_rows = min_rows as i64;
+    let Some(doc) = doc else {
+        return state;
+    };
+
+    // The state cannot spill to disk, so it is held to work_mem
+    let limit = unsafe { pg_sys::work_mem } as usize * 1024;
+    for subtree in distinct_subtrees(&doc.data, 1) {
+        if let Some(count) = counts.subtrees.get_mut(subtree) {
+            *count += 1;
+            continue;
+        }
+        counts.bytes += subtree.len();
+        if counts.bytes > limit {
+            ereport!(
+                PgLogLevel::ERROR,
+                PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+                "sexp_common_subtrees: distinct subtrees exceed work_mem",
+                "Count them with sexp_list_subtrees() and GROUP BY instead, which can spill to disk."
+            );
+        }
+        counts.subtrees.insert(subtree.to_vec(), 1);
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_common_subtrees_finalfn(state: Internal) -> Option<Sexp> {
+    let counts = unsafe { state.get::<SubtreeCountState>() }?;
+    let mut common: Vec<(&i64, &Vec<u8>)> =
+        counts.subtrees.iter().map(|(subtree, n)| (n, subtree)).filter(|(n, _)| **n > counts.min_rows).collect();
+    common.sort_by(|(n1, a), (n2, b)| n2.cmp(n1).then_with(|| compare_elements(a, b)));
+
+    let entries: Vec<Sexp> = common
+        .into_iter()
+        .map(|(n, subtree)| {
+            let count = Sexp::from_parsed(&ParsedExpr::Integer(*n));
+            Sexp::from_elements(&[&count.data[1..], subtree])
+        })
+        .collect();
+    let elements: Vec<&[u8]> = entries.iter().map(|e| &e.data[1..]).collect();
+    Some(Sexp::from_elements(&elements))
+}
+
+extension_sql!(
+    r#"
+-- List subtrees that occur in more than min_rows rows, as (count subtree)
+-- pairs, most frequent first. Distinct subtrees are held in memory up to
+-- work_mem; beyond that, count sexp_list_subtrees() with GROUP BY.
+CREATE AGGREGATE sexp_common_subtrees(sexp, int) (
+    SFUNC = sexp_common_subtrees_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_common_subtrees_finalfn
+);
+"#,
+    name = "sexp_common_subtrees",
+    requires = [sexp_common_subtrees_transfn, sexp_common_subtrees_finalfn]
+);
+
+/// Documents grouped by head symbol, groups in order of first appearance
+#[derive(Default)]
+struct HeadGroupState {
+    groups: Vec<(Vec<u8>, ListAggState)>,
+    index: std::collections::HashMap<Vec<u8>, usize>,
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_group_by_head_transfn(
+    state: Internal,
+    doc: Option<Sexp>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let mut state = agg_state::<HeadGroupState>(state, fcinfo, "sexp_group_by_head_transfn");
+    let Some(doc) = doc else {
+        return state;
+    };
+    let element = &doc.data[1..];
+    let Some(head) = entry_key(element) else {
+        return state;
+    };
+    let grouped = unsafe { state.get_mut::<HeadGroupState>() }.unwrap();
+    let slot = match grouped.index.get(head) {
+        Some(&slot) => slot,
+        None => {
+            grouped.index.insert(head.to_vec(), grouped.groups.len());
+            grouped.groups.push((head.to_vec(), ListAggState::default()));
+            grouped.groups.len() - 1
+        }
+    };
+    grouped.groups[slot].1.push(element);
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_group_by_head_finalfn(state: Internal) -> Option<Sexp> {
+    let grouped = unsafe { state.get::<HeadGroupState>() }?;
+    if grouped.groups.is_empty() {
+        return Some(Sexp::nil());
+    }
+    let size = grouped.groups.iter().map(|(head, docs)| head.len() + docs.elements.len() + 24).sum::<usize>();
+    let mut data = VarlenaBuf::with_capacity(size + 12);
+    data.extend_from_slice(&[FORMAT_VERSION, tags::LIST]);
+    write_varint(&mut data, grouped.groups.len() as u64);
+    for (head, docs) in &grouped.groups {
+        let key = Sexp::from_parsed(&ParsedExpr::Symbol(String::from_utf8_lossy(head).as_ref().into()));
+        data.push(tags::LIST);
+        write_varint(&mut data, docs.count + 1);
+        data.extend_from_slice(&key.data[1..]);
+        data.extend_from_slice(&docs.elements);
+    }
+    Some(Sexp::from_buf(data))
+}
+
+extension_sql!(
+    r#"
+-- Group documents by head symbol into an alist of (head doc ...) entries;
+-- NULLs and documents without a head symbol are ignored
+CREATE AGGREGATE sexp_group_by_head(sexp) (
+    SFUNC = sexp_group_by_head_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_group_by_head_finalfn
+);
+"#,
+    name = "sexp_group_by_head",
+    requires = [sexp_group_by_head_transfn, sexp_group_by_head_finalfn]
+);
+
+fn subtree_hash(element: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    element.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Precision of the HyperLogLog sketch: 2^12 registers, about 1.6% error
+const HLL_PRECISION: u32 = 12;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// HyperLogLog sketch holding, per register, the longest run of leading
+/// zeros seen among the hashes routed to it
+struct HllState {
+    registers: Vec<u8>,
+}
+
+impl Default for HllState {
+    fn default() -> Self {
+        HllState { registers: vec![0; HLL_REGISTERS] }
+    }
+}
+
+impl HllState {
+    fn add(&mut self, bytes: &[u8]) {
+        let hash = subtree_hash(bytes);
+        let slot = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[slot] {
+            self.registers[slot] = rank;
+        }
+    }
+
+    fn merge(&mut self, other: &HllState) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    fn estimate(&self) -> i64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        let raw = alpha * m * m / sum;
+        // Small cardinalities are counted far more accurately from the
+        // number of registers never touched
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as i64
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_head_cardinality_transfn(
+    state: Internal,
+    doc: Option<Sexp>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let mut state = agg_state::<HllState>(state, fcinfo, "sexp_head_cardinality_transfn");
+    if let Some(head) = doc.as_ref().and_then(|doc| entry_key(&doc.data[1..])) {
+        unsafe { state.get_mut::<HllState>() }.unwrap().add(head);
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_cardinality_transfn(
+    state: Internal,
+    doc: Option<Sexp>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let mut state = agg_state::<HllState>(state, fcinfo, "sexp_cardinality_transfn");
+    if let Some(doc) = doc {
+        unsafe { state.get_mut::<HllState>() }.unwrap().add(&doc.data[1..]);
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_cardinality_combinefn(
+    state: Internal,
+    other: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let Some(other) = (unsafe { other.get::<HllState>() }) else {
+        return state;
+    };
+    let mut state = agg_state::<HllState>(state, fcinfo, "sexp_cardinality_combinefn");
+    unsafe { state.get_mut::<HllState>() }.unwrap().merge(other);
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_cardinality_finalfn(state: Internal) -> i64 {
+    unsafe { state.get::<HllState>() }.map_or(0, HllState::estimate)
+}
+
+/// Serialize a partial sketch as its raw registers
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_cardinality_serialfn(state: Internal) -> Vec<u8> {
+    unsafe { state.get::<HllState>() }
+        .expect("sexp_cardinality_serialfn: state must not be NULL")
+        .registers
+        .clone()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn sexp_cardinality_deserialfn(bytes: &[u8], _state: Internal) -> Internal {
+    if bytes.len() != HLL_REGISTERS {
+        pgrx::error!("sexp_cardinality_deserialfn: invalid sketch of {} bytes", bytes.len());
+    }
+    Internal::new(HllState { registers: bytes.to_vec() })
+}
+
+extension_sql!(
+    r#"
+-- Estimate the number of distinct head symbols with HyperLogLog; NULLs and
+-- documents without a head symbol are ignored
+CREATE AGGREGATE sexp_head_cardinality_agg(sexp) (
+    SFUNC = sexp_head_cardinality_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_cardinality_finalfn,
+    COMBINEFUNC = sexp_cardinality_combinefn,
+    SERIALFUNC = sexp_cardinality_serialfn,
+    DESERIALFUNC = sexp_cardinality_deserialfn,
+    PARALLEL = SAFE
+);
+
+-- Estimate the number of structurally distinct documents with HyperLogLog
+CREATE AGGREGATE sexp_cardinality_agg(sexp) (
+    SFUNC = sexp_cardinality_transfn,
+    STYPE = internal,
+    FINALFUNC = sexp_cardinality_finalfn,
+    COMBINEFUNC = sexp_cardinality_combinefn,
+    SERIALFUNC = sexp_cardinality_serialfn,
+    DESERIALFUNC = sexp_cardinality_deserialfn,
+    PARALLEL = SAFE
+);
+"#,
+    name = "sexp_cardinality_aggregates",
+    requires = [
+        sexp_head_cardinality_transfn,
+        sexp_cardinality_transfn,
+        sexp_cardinality_combinefn,
+        sexp_cardinality_finalfn,
+        sexp_cardinality_serialfn,
+        sexp_cardinality_deserialfn
+    ]
+);
+
+// ============================================================================
+// Introspection
+// ============================================================================
+
+/// Shape summary gathered in a single preorder pass
+#[derive(Debug, Default, PartialEq)]
+struct ShapeStats {
+    depth: i32,
+    atom_count: i64,
+    list_count: i64,
+    nil_count: i64,
+    max_fanout: i32,
+}
+
+fn shape_stats(sexp: &Sexp) -> ShapeStats {
+    let mut stats = ShapeStats::default();
+    for item in Preorder::new(&sexp.data, 1) {
+        match item.tag {
+            tags::LIST => {
+                let mut pos = item.start + 1;
+                let count = read_varint(&sexp.data, &mut pos);
+                stats.list_count += 1;
+                stats.depth = stats.depth.max(item.depth as i32 + 1);
+                stats.max_fanout = stats.max_fanout.max(count as i32);
+            }
+            tags::NIL => stats.nil_count += 1,
+            _ => stats.atom_count += 1,
+        }
+    }
+    stats
+}
+
+/// Shape summary: nesting depth, atom and list counts, widest list and
+/// binary size, as the single row sexp_stats returns as a sexp_shape
+#[pg_extern(name = "sexp_shape_stats", immutable, parallel_safe)]
+fn sexp_shape_stats(
+    sexp: Sexp,
+) -> TableIterator<
+    'static,
+    (
+        name!(depth, i32),
+        name!(atom_count, i64),
+        name!(list_count, i64),
+        name!(max_fanout, i32),
+        name!(byte_size, i64),
+    ),
+> {
+    let stats = shape_stats(&sexp);
+    TableIterator::once((
+        stats.depth,
+        stats.atom_count,
+        stats.list_count,
+        stats.max_fanout,
+        sexp.data.len() as i64,
+    ))
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE sexp_shape AS (
+    depth int4,
+    atom_count int8,
+    list_count int8,
+    max_fanout int4,
+    byte_size int8
+);
+
+CREATE FUNCTION sexp_stats(sexp) RETURNS sexp_shape
+    AS 'SELECT depth, atom_count, list_count, max_fanout, byte_size FROM @extschema@.sexp_shape_stats($1)'
+    LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+"#,
+    name = "sexp_stats",
+    requires = [sexp_shape_stats]
+);
+
+/// Maximum list nesting depth (0 for atoms and nil)
+#[pg_extern(name = "sexp_depth", immutable, parallel_safe)]
+fn sexp_depth(sexp: Sexp) -> i32 {
+    shape_stats(&sexp).depth
+}
+
+/// Total number of nodes: atoms, lists and nils
+#[pg_extern(name = "sexp_node_count", immutable, parallel_safe)]
+fn sexp_node_count(sexp: Sexp) -> i64 {
+    let stats = shape_stats(&sexp);
+    stats.atom_count + stats.list_count + stats.nil_count
+}
+
+/// Size of the binary representation in bytes
+#[pg_extern(name = "sexp_size_bytes", immutable, parallel_safe)]
+fn sexp_size_bytes(sexp: Sexp) -> i64 {
+    sexp.data.len() as i64
+}
+
+// ============================================================================
+// Random Generation (testing)
+// ============================================================================
+
+/// splitmix64: small, seedable and identical across platforms
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in 0..n
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+const RANDOM_SYMBOLS: &[&str] = &["define", "lambda", "let", "if", "x", "y", "foo", "bar", "node", "value"];
+
+fn random_list(rng: &mut SplitMix64, depth: i32, max_fanout: i32) -> ParsedExpr {
+    let fanout = 1 + rng.below(max_fanout as u64) as usize;
+    ParsedExpr::List((0..fanout).map(|_| random_expr(rng, depth - 1, max_fanout)).collect())
+}
+
+fn random_expr(rng: &mut SplitMix64, depth: i32, max_fanout: i32) -> ParsedExpr {
+    // Below the root, two in three nodes are lists until the depth runs out
+    if depth > 0 && max_fanout > 0 && rng.below(3) != 0 {
+        return random_list(rng, depth, max_fanout);
+    }
+    match rng.below(5) {
+        0 => ParsedExpr::Integer(rng.below(2001) as i64 - 1000),
+        1 => ParsedExpr::Float((rng.below(2_000_000) as f64 - 1_000_000.0) / 1000.0),
+        2 => ParsedExpr::String(format!("s{}", rng.below(1000))),
+        3 => ParsedExpr::Nil,
+        _ => ParsedExpr::Symbol(RANDOM_SYMBOLS[rng.below(RANDOM_SYMBOLS.len() as u64) as usize].into()),
+    }
+}
+
+/// Deterministic pseudo-random expression for tests and benchmarks
+#[pg_extern(name = "sexp_random", immutable, parallel_safe)]
+fn sexp_random(seed: i64, max_depth: i32, max_fanout: i32) -> Sexp {
+    if max_depth < 0 || max_fanout < 0 {
+        pgrx::error!("sexp_random: max_depth and max_fanout must not be negative");
+    }
+    let mut rng = SplitMix64(seed as u64);
+    // The root is a list whenever depth allows
+    let expr = if max_depth > 0 && max_fanout > 0 {
+        random_list(&mut rng, max_depth, max_fanout)
+    } else {
+        random_expr(&mut rng, max_depth, max_fanout)
+    };
+    Sexp::from_parsed(&expr)
+}
+
+// ============================================================================
+// Test Assertions
+// ============================================================================
+
+/// Path, expected and actual value at the first point where two encoded
+/// elements differ, walking lists element by element
+fn first_difference(expected: &[u8], actual: &[u8], path: &mut Vec<usize>) -> Option<(String, String)> {
+    check_depth(path.len());
+    if expected == actual {
+        return None;
+    }
+    if expected.first() == Some(&tags::LIST) && actual.first() == Some(&tags::LIST) {
+        let (xs, ys) = (list_elements(expected), list_elements(actual));
+        for (i, (x, y)) in xs.iter().zip(&ys).enumerate() {
+            path.push(i);
+            if let Some(diff) = first_difference(x, y, path) {
+                return Some(diff);
+            }
+            path.pop();
+        }
+        if xs.len() != ys.len() {
+            let render = |e: &[u8]| Sexp::from_element(e).to_string_repr();
+            return Some((
+                format!("{} (length {})", render(expected), xs.len()),
+                format!("{} (length {})", render(actual), ys.len()),
+            ));
+        }
+    }
+    Some((
+        Sexp::from_element(expected).to_string_repr(),
+        Sexp::from_element(actual).to_string_repr(),
+    ))
+}
+
+/// Raise an error describing the first structural difference between two values
+#[pg_extern(name = "sexp_assert_eq", immutable, parallel_safe)]
+fn sexp_assert_eq(expected: Sexp, actual: Sexp) {
+    let mut path = Vec::new();
+    let diff = first_difference(
+        expected.data.get(1..).unwrap_or_default(),
+        actual.data.get(1..).unwrap_or_default(),
+        &mut path,
+    );
+    if let Some((want, got)) = diff {
+        let path: Vec<String> = path.iter().map(|i| i.to_string()).collect();
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_DATA_EXCEPTION,
+            format!("sexp values differ at path {{{}}}: expected {}, actual {}", path.join(","), want, got),
+            format!("expected: {}\nactual:   {}", expected.to_string_repr(), actual.to_string_repr())
+        );
+    }
+}
+
+/// TAP line for a test result. When pgTAP is installed the result goes
+/// through its ok() and diag(), so it is numbered and counted against the
+/// plan; otherwise an unnumbered TAP line is produced here. ok() advances
+/// pgTAP's test counter, so the callers are volatile like pgTAP's own.
+fn tap_result(passed: bool, description: Option<&str>, diagnostics: &str) -> String {
+    let pgtap = Spi::get_one::<bool>(
+        "SELECT to_regprocedure('ok(boolean, text)') IS NOT NULL AND to_regprocedure('diag(text)') IS NOT NULL",
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false);
+    if pgtap {
+        return Spi::get_one_with_args::<String>(
+            "SELECT ok($1, $2) || CASE WHEN $1 THEN '' ELSE E'\\n' || diag($3) END",
+            &[passed.into(), description.into(), diagnostics.into()],
+        )
+        .unwrap_or_else(|e| pgrx::error!("{}", e))
+        .unwrap_or_default();
+    }
+
+    let description = description.unwrap_or_default();
+    let status = if passed { "ok" } else { "not ok" };
+    let mut out = if description.is_empty() { status.to_string() } else { format!("{} - {}", status, description) };
+    if !passed {
+        out.push_str(&format!("\n# Failed test: \"{}\"", description));
+        for line in diagnostics.lines() {
+            out.push_str("\n# ");
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+fn tap_render(value: &Option<Sexp>) -> String {
+    value.as_ref().map_or("NULL".to_string(), Sexp::to_string_repr)
+}
+
+/// pgTAP-style equality test, with the structural diff in the diagnostics
+#[pg_extern(name = "is_sexp", volatile)]
+fn is_sexp(have: Option<Sexp>, want: Option<Sexp>, description: default!(Option<&str>, "NULL")) -> String {
+    let mut diagnostics = format!("    have: {}\n    want: {}", tap_render(&have), tap_render(&want));
+    let passed = match (&have, &want) {
+        (Some(have), Some(want)) => {
+            let passed = have.equals(want);
+            if !passed {
+                diagnostics.push_str(&format!("\n    diff: {}", sexp_diff(have.clone(), want.clone()).to_string_repr()));
+            }
+            passed
+        }
+        _ => have.is_none() && want.is_none(),
+    };
+    tap_result(passed, description, &diagnostics)
+}
+
+/// pgTAP-style pattern match test (see sexp_match for the pattern syntax)
+#[pg_extern(name = "matches_sexp_pattern", volatile)]
+fn matches_sexp_pattern(have: Option<Sexp>, pattern: Sexp, description: default!(Option<&str>, "NULL")) -> String {
+    let passed = have.as_ref().is_some_and(|have| matches_pattern(have, &pattern));
+    let diagnostics = format!("    have:    {}\n    pattern: {}", tap_render(&have), pattern.to_string_repr());
+    tap_result(passed, description, &diagnostics)
+}
+
+// ============================================================================
+// Lint Diagnostics
+// ============================================================================
+
+/// Nesting depth beyond which sexp_lint reports excessive depth
+const LINT_MAX_DEPTH: i32 = 64;
+
+type LintRow = (String, String, String);
+
+fn lint_path(path: &[usize]) -> String {
+    let steps: Vec<String> = path.iter().map(|i| i.to_string()).collect();
+    format!("{{{}}}", steps.join(","))
+}
+
+/// Symbols such as `1,000` or `-3.2.1` that were probably meant as numbers
+fn looks_like_number(symbol: &[u8]) -> bool {
+    let digits = symbol.strip_prefix(b"-").or_else(|| symbol.strip_prefix(b"+")).unwrap_or(symbol);
+    digits.first().is_some_and(u8::is_ascii_digit)
+        && digits.iter().all(|c| c.is_ascii_digit() || matches!(c, b'.' | b',' | b'_'))
+}
+
+fn lint_element(element: &[u8], path: &mut Vec<usize>, out: &mut Vec<LintRow>) {
+    check_depth(path.len());
+    match element.first() {
+        Some(&tags::SYMBOL) => {
+            if atom_bytes_at(element, 0).is_some_and(looks_like_number) {
+                out.push((
+                    lint_path(path),
+                    "numeric-symbol".to_string(),
+                    format!("symbol {} looks like a number", Sexp::from_element(element).to_string_repr()),
+                ));
+            }
+            return;
+        }
+        Some(&tags::LIST) => {}
+        _ => return,
+    }
+
+    let children = list_elements(element);
+
+    // Duplicate keys in an alist
+    if children.len() > 1 && children.iter().all(|c| entry_key(c).is_some()) {
+        let mut seen = std::collections::HashSet::new();
+        for child in &children {
+            let key = entry_key(child).unwrap_or_default();
+            if !seen.insert(key) {
+                out.push((
+                    lint_path(path),
+                    "duplicate-key".to_string(),
+                    format!("alist key {} appears more than once", String::from_utf8_lossy(key)),
+                ));
+            }
+        }
+    }
+
+    // Mixed atom types in a flat list of three or more values, ignoring a
+    // leading head symbol
+    let body = match children.first() {
+        Some(head) if head[0] == tags::SYMBOL => &children[1..],
+        _ => &children[..],
+    };
+    if body.len() > 2 && body.iter().all(|c| c[0] != tags::LIST && c[0] != tags::NIL) {
+        let mut kinds: Vec<SexpType> = Vec::new();
+        for child in body {
+            let kind = match Sexp::from_element(child).get_type() {
+                SexpType::Integer | SexpType::Float | SexpType::Decimal => SexpType::Float,
+                other => other,
+            };
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        if kinds.len() > 1 {
+            let names: Vec<String> = kinds
+                .iter()
+                .map(|k| if *k == SexpType::Float { "number".to_string() } else { k.to_string() })
+                .collect();
+            out.push((
+                lint_path(path),
+                "mixed-types".to_string(),
+                format!("list mixes atom types: {}", names.join(", ")),
+            ));
+        }
+    }
+
+    for (i, child) in children.iter().enumerate() {
+        path.push(i);
+        lint_element(child, path, out);
+        path.pop();
+    }
+}
+
+/// Data quality warnings: excessive depth, duplicate alist keys, mixed atom
+/// types in flat lists and symbols that look like numbers
+#[pg_extern(name = "sexp_lint", immutable, parallel_safe)]
+fn sexp_lint(
+    sexp: Sexp,
+) -> TableIterator<'static, (name!(path, String), name!(code, String), name!(message, String))> {
+    let mut rows = Vec::new();
+    let depth = shape_stats(&sexp).depth;
+    if depth > LINT_MAX_DEPTH {
+        rows.push((
+            lint_path(&[]),
+            "excessive-depth".to_string(),
+            format!("nesting depth {} exceeds {}", depth, LINT_MAX_DEPTH),
+        ));
+    }
+    lint_element(sexp.data.get(1..).unwrap_or_default(), &mut Vec::new(), &mut rows);
+    TableIterator::new(rows.into_iter())
+}
+
+// ============================================================================
+// Numeric-coercing Comparison
+// ============================================================================
+
+/// Structural equality that compares integers and floats by value, so
+/// `(a 1)` equals `(a 1.0)`
+fn numeric_eq(a: &[u8], b: &[u8], depth: usize) -> bool {
+    check_depth(depth);
+    match (a.first().copied(), b.first().copied()) {
+        (Some(tags::INTEGER), Some(tags::INTEGER)) => element_integer(a) == element_integer(b),
+        (Some(tags::DECIMAL), Some(tags::INTEGER | tags::FLOAT | tags::DECIMAL))
+        | (Some(tags::INTEGER | tags::FLOAT), Some(tags::DECIMAL)) => match (element_decimal(a), element_decimal(b)) {
+            (Some(x), Some(y)) => x == y,
+            // A float too large or small to print as a plain decimal
+            _ => element_float(a) == element_float(b),
+        },
+        (Some(tags::INTEGER | tags::FLOAT), Some(tags::INTEGER | tags::FLOAT)) => element_float(a) == element_float(b),
+        (Some(tags::LIST), Some(tags::LIST)) => {
+            let (xs, ys) = (list_elements(a), list_elements(b));
+            xs.len() == ys.len() && xs.iter().zip(&ys).all(|(x, y)| numeric_eq(x, y, depth + 1))
+        }
+        _ => a == b,
+    }
+}
+
+/// Equality comparing numbers by value across integer and float
+#[pg_extern(name = "sexp_eq_num", immutable, parallel_safe)]
+fn sexp_eq_num(a: Sexp, b: Sexp) -> bool {
+    numeric_eq(a.data.get(1..).unwrap_or_default(), b.data.get(1..).unwrap_or_default(), 0)
+}
+
+/// Structural containment comparing numbers by value across integer and float
+#[pg_extern(name = "sexp_contains_num", immutable, parallel_safe)]
+fn sexp_contains_num(container: Sexp, needle: Sexp) -> bool {
+    let needle = needle.data.get(1..).unwrap_or_default();
+    Preorder::new(&container.data, 1).any(|item| {
+        let mut end = item.start;
+        skip_element(&container.data, &mut end);
+        numeric_eq(&container.data[item.start..end.min(container.data.len())], needle, item.depth)
+    })
+}
+
+// ============================================================================
+// Order-insensitive Equivalence
+// ============================================================================
+
+/// Structural equality that treats alists as unordered maps: two lists whose
+/// elements are all symbol-headed lists are equivalent when their entries
+/// pair up one-to-one, in any order
+fn equiv_elements(a: &[u8], b: &[u8], numeric: bool, depth: usize) -> bool {
+    check_depth(depth);
+    if a.first() != Some(&tags::LIST) || b.first() != Some(&tags::LIST) {
+        return if numeric { numeric_eq(a, b, depth) } else { a == b };
+    }
+    let (xs, ys) = (list_elements(a), list_elements(b));
+    if xs.len() != ys.len() {
+        return false;
+    }
+    let is_map = |items: &[&[u8]]| items.iter().all(|e| entry_key(e).is_some());
+    if !(is_map(&xs) && is_map(&ys)) {
+        return xs.iter().zip(&ys).all(|(x, y)| equiv_elements(x, y, numeric, depth + 1));
+    }
+    let mut used = vec![false; ys.len()];
+    xs.iter().all(|x| {
+        let found = ys
+            .iter()
+            .enumerate()
+            .position(|(i, y)| !used[i] && equiv_elements(x, y, numeric, depth + 1));
+        found.map(|i| used[i] = true).is_some()
+    })
+}
+
+/// Equivalence ignoring alist entry order (==)
+#[pg_extern(name = "sexp_equiv", immutable, parallel_safe)]
+fn sexp_equiv(a: Sexp, b: Sexp) -> bool {
+    equiv_elements(a.data.get(1..).unwrap_or_default(), b.data.get(1..).unwrap_or_default(), false, 1)
+}
+
+/// Equivalence ignoring alist entry order and comparing numbers by value
+#[pg_extern(name = "sexp_equiv_num", immutable, parallel_safe)]
+fn sexp_equiv_num(a: Sexp, b: Sexp) -> bool {
+    equiv_elements(a.data.get(1..).unwrap_or_default(), b.data.get(1..).unwrap_or_default(), true, 1)
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR == (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_equiv,
+    COMMUTATOR = ==
+);
+"#,
+    name = "sexp_equiv_operator",
+    requires = [sexp_equiv]
+);
+
+// ============================================================================
+// Bulk Containment
+// ============================================================================
+
+/// A containment needle prepared once and tested against many documents.
+/// Subtrees are only compared byte-for-byte when their tag and encoded
+/// length match the needle's.
+struct PreparedNeedle<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PreparedNeedle<'a> {
+    fn new(needle: &'a Sexp) -> Self {
+        PreparedNeedle { bytes: needle.data.get(1..).unwrap_or_default() }
+    }
+
+    fn found_in(&self, haystack: &Sexp) -> bool {
+        self.found_at(&haystack.data, 1)
+    }
+
+    /// Search the element starting at `start` and everything inside it
+    fn found_at(&self, data: &[u8], start: usize) -> bool {
+        let Some(&tag) = self.bytes.first() else {
+            return false;
+        };
+        Preorder::new(data, start).any(|item| {
+            check_depth(item.depth);
+            item.tag == tag
+                && data.len() - item.start >= self.bytes.len()
+                && {
+                    let mut end = item.start;
+                    skip_element(data, &mut end);
+                    end - item.start == self.bytes.len()
+                        && &data[item.start..end] == self.bytes
+                }
+        })
+    }
+}
+
+/// True if any of the documents contains the needle (NULL documents are skipped)
+#[pg_extern(name = "sexp_any_contains", immutable, parallel_safe)]
+fn sexp_any_contains(haystacks: Vec<Option<Sexp>>, needle: Sexp) -> bool {
+    let needle = PreparedNeedle::new(&needle);
+    haystacks.iter().flatten().any(|haystack| needle.found_in(haystack))
+}
+
+/// True if every document contains the needle (a NULL document never does)
+#[pg_extern(name = "sexp_all_contain", immutable, parallel_safe)]
+fn sexp_all_contain(haystacks: Vec<Option<Sexp>>, needle: Sexp) -> bool {
+    let needle = PreparedNeedle::new(&needle);
+    haystacks
+        .iter()
+        .all(|haystack| haystack.as_ref().is_some_and(|haystack| needle.found_in(haystack)))
+}
+
+fn collect_occurrences(element: &[u8], needle: &[u8], path: &mut Vec<i32>, out: &mut Vec<Vec<i32>>) {
+    check_depth(path.len() + 1);
+    if element == needle {
+        out.push(path.clone());
+        return;
+    }
+    for (i, child) in list_elements(element).into_iter().enumerate() {
+        path.push(i as i32);
+        collect_occurrences(child, needle, path, out);
+        path.pop();
+    }
+}
+
+/// Integer path of every structurally equal occurrence of `needle`, in
+/// preorder. The root itself is the empty path; occurrences nested inside
+/// another occurrence are not reported.
+#[pg_extern(name = "sexp_occurrences", immutable, parallel_safe)]
+fn sexp_occurrences(haystack: Sexp, needle: Sexp) -> SetOfIterator<'static, Vec<i32>> {
+    let mut paths = Vec::new();
+    collect_occurrences(
+        haystack.data.get(1..).unwrap_or_default(),
+        needle.data.get(1..).unwrap_or_default(),
+        &mut Vec::new(),
+        &mut paths,
+    );
+    SetOfIterator::new(paths)
+}
+
+fn first_occurrence(element: &[u8], needle: &[u8], path: &mut Vec<i32>) -> bool {
+    check_depth(path.len() + 1);
+    if element == needle {
+        return true;
+    }
+    for (i, child) in list_elements(element).into_iter().enumerate() {
+        path.push(i as i32);
+        if first_occurrence(child, needle, path) {
+            return true;
+        }
+        path.pop();
+    }
+    false
+}
+
+/// Path of the first occurrence of `needle` in preorder, NULL if absent
+#[pg_extern(name = "sexp_path_of", immutable, parallel_safe)]
+fn sexp_path_of(haystack: Sexp, needle: Sexp) -> Option<Vec<i32>> {
+    let mut path = Vec::new();
+    first_occurrence(
+        haystack.data.get(1..).unwrap_or_default(),
+        needle.data.get(1..).unwrap_or_default(),
+        &mut path,
+    )
+    .then_some(path)
+}
+
+/// Enclosing lists of the node at an integer path, nearest first, up to
+/// `levels` levels (stopping at the root). Each row carries the ancestor's
+/// own path. A path that does not resolve yields no rows.
+#[pg_extern(name = "sexp_at_ancestors", immutable, parallel_safe)]
+fn sexp_at_ancestors(
+    haystack: Sexp,
+    path: Vec<i32>,
+    levels: default!(i32, 1),
+) -> TableIterator<'static, (name!(level, i32), name!(path, Vec<i32>), name!(context, Sexp))> {
+    // Resolve the path, remembering every node along the way
+    let mut nodes = vec![haystack.data.get(1..).unwrap_or_default()];
+    let mut resolved = Vec::with_capacity(path.len());
+    for &step in &path {
+        let children = list_elements(nodes[nodes.len() - 1]);
+        match resolve_index(step, children.len()) {
+            Some(i) => {
+                nodes.push(children[i]);
+                resolved.push(i as i32);
+            }
+            None => return TableIterator::new(Vec::new()),
+        }
+    }
+
+    let rows: Vec<_> = (1..=levels.max(0).min(resolved.len() as i32))
+        .map(|level| {
+            let depth = resolved.len() - level as usize;
+            (level, resolved[..depth].to_vec(), Sexp::from_element(nodes[depth]))
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+// ============================================================================
+// Tree Rendering
+// ============================================================================
+
+fn tree_label(element: &[u8]) -> String {
+    match element.first() {
+        Some(&tags::LIST) => format!("list ({})", list_elements(element).len()),
+        None => nil_text().to_string(),
+        _ => element_to_text(element, &mut 0, Dialect::Scheme, display_float_precision()),
+    }
+}
+
+fn write_tree(element: &[u8], prefix: &str, depth: usize, out: &mut String) {
+    check_depth(depth);
+    let children = list_elements(element);
+    for (i, child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        out.push_str(prefix);
+        out.push_str(if last { "`-- " } else { "|-- " });
+        out.push_str(&tree_label(child));
+        out.push('\n');
+        write_tree(child, &format!("{}{}", prefix, if last { "    " } else { "|   " }), depth + 1, out);
+    }
+}
+
+/// Indented ASCII tree, one node per line, floats rounded to
+/// pg_sexp.float_precision
+#[pg_extern(name = "sexp_to_tree_text", stable, parallel_safe)]
+fn sexp_to_tree_text(sexp: Sexp) -> String {
+    let root = sexp.data.get(1..).unwrap_or_default();
+    let mut out = tree_label(root);
+    out.push('\n');
+    write_tree(root, "", 0, &mut out);
+    out.truncate(out.trim_end().len());
+    out
+}
+
+// ============================================================================
+// Boolean Conversions
+// ============================================================================
+
+/// Boolean value of a #t/#f atom
+#[pg_extern(name = "sexp_to_bool", immutable, parallel_safe)]
+fn sexp_to_bool(sexp: Sexp) -> bool {
+    if !sexp_is_boolean(sexp.clone()) {
+        pgrx::error!("cannot cast sexp {} to boolean", sexp.to_string_repr());
+    }
+    sexp.data[2] != 0
+}
+
+/// Boolean as a #t/#f atom
+#[pg_extern(name = "sexp_from_bool", immutable, parallel_safe)]
+fn sexp_from_bool(value: bool) -> Sexp {
+    Sexp::from_parsed(&ParsedExpr::Bool(value))
+}
+
+extension_sql!(
+    r#"
+CREATE CAST (sexp AS boolean) WITH FUNCTION sexp_to_bool(sexp);
+CREATE CAST (boolean AS sexp) WITH FUNCTION sexp_from_bool(boolean);
+"#,
+    name = "sexp_bool_casts",
+    requires = [sexp_to_bool, sexp_from_bool]
+);
+
+// ============================================================================
+// Exact Decimals
+// ============================================================================
+
+/// Numeric value of a decimal, integer or float atom
+#[pg_extern(name = "sexp_to_numeric", immutable, parallel_safe)]
+fn sexp_to_numeric(sexp: Sexp) -> AnyNumeric {
+    let text = match sexp.data.get(1) {
+        Some(&tags::DECIMAL) | Some(&tags::INTEGER) => element_text(&sexp.data[1..]),
+        Some(&tags::FLOAT) => element_float(&sexp.data[1..]).filter(|f| f.is_finite()).map(|f| format!("{:?}", f)),
+        _ => None,
+    };
+    match text.and_then(|t| t.parse::<AnyNumeric>().ok()) {
+        Some(n) => n,
+        None => pgrx::error!("cannot cast sexp {} to numeric", sexp.to_string_repr()),
+    }
+}
+
+/// Numeric as an exact decimal atom
+#[pg_extern(name = "sexp_from_numeric", immutable, parallel_safe)]
+fn sexp_from_numeric(value: AnyNumeric) -> Sexp {
+    let text = value.to_string();
+    if !is_decimal_literal(&text) {
+        pgrx::error!("cannot represent numeric {} as a sexp decimal", text);
+    }
+    Sexp::from_parsed(&decimal_atom(text))
+}
+
+extension_sql!(
+    r#"
+CREATE CAST (sexp AS numeric) WITH FUNCTION sexp_to_numeric(sexp);
+CREATE CAST (numeric AS sexp) WITH FUNCTION sexp_from_numeric(numeric);
+"#,
+    name = "sexp_numeric_casts",
+    requires = [sexp_to_numeric, sexp_from_numeric]
+);
+
+// ============================================================================
+// Alist Editing
+// ============================================================================
+
+fn alist_arg<'a>(doc: &'a Sexp, function: &str) -> Vec<&'a [u8]> {
+    if !is_alist_impl(doc) {
+        pgrx::error!("{} requires an alist: {}", function, doc.to_string_repr());
+    }
+    doc.elements()
+}
+
+/// Set `(key value)` in a top-level alist, replacing the first entry with
+/// that key or appending a new one
+#[pg_extern(name = "sexp_assoc_set", immutable, parallel_safe)]
+fn sexp_assoc_set(doc: Sexp, key: &str, value: Sexp) -> Sexp {
+    let mut entries = alist_arg(&doc, "sexp_assoc_set");
+    let key_sexp = Sexp::from_parsed(&ParsedExpr::Symbol(key.into()));
+    let entry = Sexp::from_elements(&[&key_sexp.data[1..], &value.data[1..]]);
+
+    match entries.iter().position(|e| entry_key(e) == Some(key.as_bytes())) {
+        Some(i) => entries[i] = &entry.data[1..],
+        None => entries.push(&entry.data[1..]),
+    }
+    Sexp::from_elements(&entries)
+}
+
+/// Remove every entry with the given key from a top-level alist
+#[pg_extern(name = "sexp_assoc_del", immutable, parallel_safe)]
+fn sexp_assoc_del(doc: Sexp, key: &str) -> Sexp {
+    let entries: Vec<&[u8]> = alist_arg(&doc, "sexp_assoc_del")
+        .into_iter()
+        .filter(|e| entry_key(e) != Some(key.as_bytes()))
+        .collect();
+    Sexp::from_elements(&entries)
+}
+
+// ============================================================================
+// Data Masking
+// ============================================================================
+
+/// Parsed sexp_anonymize rules
+struct MaskRules {
+    keys: std::collections::HashSet<Vec<u8>>,
+    patterns: Vec<regex::Regex>,
+    salt: Vec<u8>,
+    placeholder: Option<String>,
+}
+
+impl MaskRules {
+    fn parse(rules: &Sexp) -> MaskRules {
+        let mut parsed = MaskRules {
+            keys: std::collections::HashSet::new(),
+            patterns: Vec::new(),
+            salt: Vec::new(),
+            placeholder: None,
+        };
+        for entry in alist_arg(rules, "sexp_anonymize") {
+            let name = entry_key(entry).unwrap_or_default();
+            let values: Vec<String> = list_elements(entry)[1..].iter().filter_map(|v| element_text(v)).collect();
+            match name {
+                b"keys" => parsed.keys.extend(values.into_iter().map(String::into_bytes)),
+                b"patterns" => {
+                    for pattern in values {
+                        match regex::Regex::new(&pattern) {
+                            Ok(re) => parsed.patterns.push(re),
+                            Err(e) => pgrx::error!("sexp_anonymize: invalid pattern {:?}: {}", pattern, e),
+                        }
+                    }
+                }
+                b"salt" => parsed.salt = values.concat().into_bytes(),
+                b"placeholder" => parsed.placeholder = values.into_iter().next(),
+                other => pgrx::error!("sexp_anonymize: unknown rule {}", String::from_utf8_lossy(other)),
+            }
+        }
+        // An unsalted hash of a short value such as a phone number is
+        // reversed by hashing every candidate
+        if parsed.salt.is_empty() && parsed.placeholder.is_none() {
+            pgrx::error!("sexp_anonymize: rules need a non-empty (salt \"...\") unless a (placeholder \"...\") is given");
+        }
+        parsed
+    }
+
+    /// Replacement text: the placeholder, or the first 16 hex digits of
+    /// HMAC-SHA256(salt, value)
+    fn mask(&self, value: &str) -> String {
+        use hmac::Mac;
+        if let Some(placeholder) = &self.placeholder {
+            return placeholder.clone();
+        }
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.salt).expect("HMAC accepts any key length");
+        mac.update(value.as_bytes());
+        mac.finalize().into_bytes()[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+fn write_masked(rules: &MaskRules, value: &str, out: &mut impl ByteSink) {
+    let replacement = rules.mask(value);
+    out.push(tags::STRING);
+    write_varint(out, replacement.len() as u64);
+    out.extend_from_slice(replacement.as_bytes());
+}
+
+/// Copy an element, masking strings matching a pattern and every atom under
+/// a masked key; list heads are kept so the shape of the document survives
+fn anonymize_element(data: &[u8], pos: &mut usize, rules: &MaskRules, masked: bool, depth: usize, out: &mut impl ByteSink) {
+    check_depth(depth);
+    let start = *pos;
+    match data.get(start) {
+        Some(&tags::LIST) => {
+            *pos += 1;
+            let count = read_varint(data, pos);
+            out.extend_from_slice(&data[start..*pos]);
+            let mut children_masked = masked;
+            for i in 0..count {
+                if i == 0 && data[*pos] == tags::SYMBOL {
+                    children_masked |= atom_bytes_at(data, *pos).is_some_and(|key| rules.keys.contains(key));
+                    let head = *pos;
+                    skip_element(data, pos);
+                    out.extend_from_slice(&data[head..*pos]);
+                    continue;
+                }
+                anonymize_element(data, pos, rules, children_masked, depth + 1, out);
+            }
+        }
+        Some(&tags::STRING) => {
+            *pos += 1;
+            let value = read_string(data, pos);
+            if masked || rules.patterns.iter().any(|re| re.is_match(&value)) {
+                write_masked(rules, &value, out);
+            } else {
+                out.extend_from_slice(&data[start..*pos]);
+            }
+        }
+        Some(&tags::NIL) | None => {
+            skip_element(data, pos);
+            out.extend_from_slice(&data[start..*pos]);
+        }
+        Some(_) if masked => {
+            // Numbers, symbols and booleans under a masked key are hashed
+            // through their printed form and come back as strings
+            let value = deserialize_to_string(data, pos);
+            write_masked(rules, &value, out);
+        }
+        Some(_) => {
+            skip_element(data, pos);
+            out.extend_from_slice(&data[start..*pos]);
+        }
+    }
+}
+
+/// Mask atoms for copying production data to staging. `rules` is an alist
+/// of:
+///
+/// - `(keys k ...)`: every atom inside entries headed by these keys, except
+///   list heads; numbers and symbols are masked to strings
+/// - `(patterns "regex" ...)`: any string matching one of the regexes
+/// - `(salt "...")`: HMAC key, so hashes are stable for a given salt;
+///   required unless a placeholder is given
+/// - `(placeholder "...")`: fixed replacement instead of a hash
+///
+/// The parsed rules, compiled regexes included, are cached in the call
+/// site's fn_extra while the rules stay the same.
+#[pg_extern(name = "sexp_anonymize", immutable, parallel_safe)]
+fn sexp_anonymize(doc: Sexp, rules: Sexp, fcinfo: pg_sys::FunctionCallInfo) -> Sexp {
+    let rules = unsafe {
+        let flinfo = (*fcinfo).flinfo;
+        let mut mcxt = pgrx::PgMemoryContexts::For((*flinfo).fn_mcxt);
+        let mut cached = (*flinfo).fn_extra as *mut (Vec<u8>, MaskRules);
+        if cached.is_null() {
+            cached = mcxt.leak_and_drop_on_delete((rules.data.to_vec(), MaskRules::parse(&rules)));
+            (*flinfo).fn_extra = cached.cast();
+        } else if (*cached).0[..] != rules.data[..] {
+            *cached = (rules.data.to_vec(), MaskRules::parse(&rules));
+        }
+        &(*cached).1
+    };
+    let mut out = VarlenaBuf::with_capacity(doc.data.len());
+    out.push(FORMAT_VERSION);
+    if doc.data.len() > 1 {
+        anonymize_element(&doc.data, &mut 1, rules, false, 1, &mut out);
+    }
+    Sexp::from_buf(out)
+}
+
+/// Paths to redact, merged so that shared prefixes are walked once
+#[derive(Default)]
+struct PathTrie {
+    terminal: bool,
+    children: Vec<(String, PathTrie)>,
+}
+
+impl PathTrie {
+    fn insert(&mut self, path: &[String]) {
+        let Some((step, rest)) = path.split_first() else {
+            self.terminal = true;
+            return;
+        };
+        let slot = match self.children.iter().position(|(s, _)| s == step) {
+            Some(slot) => slot,
+            None => {
+                self.children.push((step.clone(), PathTrie::default()));
+                self.children.len() - 1
+            }
+        };
+        self.children[slot].1.insert(rest);
+    }
+}
+
+fn encode_items(items: &[Vec<u8>]) -> Vec<u8> {
+    let refs: Vec<&[u8]> = items.iter().map(Vec::as_slice).collect();
+    Sexp::from_elements(&refs).data[1..].to_vec()
+}
+
+/// Apply the path steps in `children` to a list of candidate items, the
+/// way walk_path selects them: numeric steps by index, other steps every
+/// entry with that key. A finished path removes the item, or replaces it
+/// (an entry's value, for key steps) with `mask`.
+fn redact_items<'a>(
+    items: Vec<Vec<u8>>,
+    children: impl Iterator<Item = &'a (String, PathTrie)>,
+    mask: Option<&[u8]>,
+) -> Vec<Vec<u8>> {
+    let mut items: Vec<Option<Vec<u8>>> = items.into_iter().map(Some).collect();
+    for (step, child) in children {
+        let index = step.parse::<i32>().ok();
+        let targets: Vec<usize> = match index {
+            Some(index) => resolve_index(index, items.len()).into_iter().collect(),
+            None => (0..items.len())
+                .filter(|&i| items[i].as_deref().and_then(entry_key) == Some(step.as_bytes()))
+                .collect(),
+        };
+        for i in targets {
+            let Some(item) = items[i].take() else { continue };
+            items[i] = match (child.terminal, mask, index) {
+                (true, None, _) => None,
+                (true, Some(mask), Some(_)) => Some(mask.to_vec()),
+                (true, Some(mask), None) => Some(encode_items(&[list_elements(&item)[0].to_vec(), mask.to_vec()])),
+                (false, _, Some(_)) if item[0] == tags::LIST => {
+                    let inner = list_elements(&item).into_iter().map(<[u8]>::to_vec).collect();
+                    Some(encode_items(&redact_items(inner, child.children.iter(), mask)))
+                }
+                (false, _, Some(_)) => Some(item),
+                (false, _, None) => Some(redact_entry(&item, child, mask)),
+            };
+        }
+    }
+    items.into_iter().flatten().collect()
+}
+
+/// Continue redaction inside an alist entry: key steps see the inner alist
+/// of `(key ((k v) ...))`, numeric steps index the values after the key
+fn redact_entry(entry: &[u8], trie: &PathTrie, mask: Option<&[u8]>) -> Vec<u8> {
+    let items = list_elements(entry);
+    let mut tail: Vec<Vec<u8>> = items[1..].iter().map(|item| item.to_vec()).collect();
+    let is_key = |(step, _): &&(String, PathTrie)| step.parse::<i32>().is_err();
+    if tail.len() == 1 && tail[0][0] == tags::LIST {
+        let inner = list_elements(&tail[0]).into_iter().map(<[u8]>::to_vec).collect();
+        tail = vec![encode_items(&redact_items(inner, trie.children.iter().filter(is_key), mask))];
+    } else {
+        tail = redact_items(tail, trie.children.iter().filter(is_key), mask);
+    }
+    tail = redact_items(tail, trie.children.iter().filter(|child| !is_key(child)), mask);
+    tail.insert(0, items[0].to_vec());
+    encode_items(&tail)
+}
+
+/// Implementation of sexp_redact, with the text[][] of paths flattened into
+/// rows of `width` steps; a row's trailing NULLs shorten its path
+#[pg_extern(name = "sexp_redact_flat", immutable, parallel_safe)]
+fn sexp_redact_flat(doc: Sexp, paths: Vec<Option<String>>, width: i32, mask: Option<Sexp>) -> Sexp {
+    let mut trie = PathTrie::default();
+    for row in paths.chunks(width.max(1) as usize) {
+        let len = row.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+        let path: Option<Vec<String>> = row[..len].iter().cloned().collect();
+        match path {
+            Some(path) if !path.is_empty() => trie.insert(&path),
+            _ => pgrx::error!("sexp_redact: paths must be non-empty with no NULL steps"),
+        }
+    }
+    if doc.data.len() < 2 || doc.data[1] != tags::LIST {
+        return doc;
+    }
+    let mask = mask.as_ref().map(|m| m.data.get(1..).filter(|e| !e.is_empty()).unwrap_or(&[tags::NIL]));
+    let items = doc.elements().into_iter().map(<[u8]>::to_vec).collect();
+    let encoded = encode_items(&redact_items(items, trie.children.iter(), mask));
+    let mut data = VarlenaBuf::with_capacity(encoded.len() + 1);
+    data.push(FORMAT_VERSION);
+    data.extend_from_slice(&encoded);
+    Sexp::from_buf(data)
+}
+
+extension_sql!(
+    r#"
+-- Remove the values at several paths in one pass, or replace them with
+-- mask. Paths are the rows of a text[][] (pad shorter rows with NULLs) or a
+-- single text[] path.
+CREATE FUNCTION sexp_redact(doc sexp, paths text[], mask sexp DEFAULT NULL) RETURNS sexp
+    AS 'SELECT @extschema@.sexp_redact_flat($1, $2, coalesce(pg_catalog.array_length($2, pg_catalog.array_ndims($2)), 0), $3)'
+    LANGUAGE SQL IMMUTABLE PARALLEL SAFE;
+"#,
+    name = "sexp_redact",
+    requires = [sexp_redact_flat]
+);
+
+// ============================================================================
+// Expression Index Keys
+// ============================================================================
+//
+// sexp_field and sexp_field_int are meant for btree expression indexes, so
+// their results must never change for a stored value: they ignore every
+// output setting, and the text format below is stable across versions.
+
+/// Single value of the first top-level `(key value)` entry of an alist
+fn field_value<'a>(doc: &'a Sexp, key: &str) -> Option<&'a [u8]> {
+    if !is_alist_impl(doc) {
+        return None;
+    }
+    let entry = doc
+        .elements()
+        .into_iter()
+        .find(|e| entry_key(e) == Some(key.as_bytes()))?;
+    match list_elements(entry).as_slice() {
+        [_, value] => Some(value),
+        _ => None,
+    }
+}
+
+/// Value of a top-level alist field as text, for expression indexes.
+///
+/// Strings, symbols and decimals give their raw text, integers their decimal
+/// digits, floats their shortest round-trip form (exact_float_text: plain
+/// digits with a `.`, an exponent outside 1e-5..1e16, `NaN`, `Infinity`)
+/// and booleans `#t`/`#f`.
+/// Missing keys, multi-value entries, nil and lists give NULL, as does any
+/// document that is not an alist.
+#[pg_extern(name = "sexp_field", immutable, parallel_safe)]
+fn sexp_field(doc: Sexp, key: &str) -> Option<String> {
+    let value = field_value(&doc, key)?;
+    match value[0] {
+        tags::STRING | tags::SYMBOL | tags::DECIMAL => element_text(value),
+        tags::INTEGER => element_integer(value).map(|n| n.to_string()),
+        tags::FLOAT => element_float(value).map(exact_float_text),
+        tags::BOOL => Some(if value.get(1).is_some_and(|&b| b != 0) { "#t" } else { "#f" }.to_string()),
+        _ => None,
+    }
+}
+
+/// Integer value of a top-level alist field, NULL unless it is an integer atom
+#[pg_extern(name = "sexp_field_int", immutable, parallel_safe)]
+fn sexp_field_int(doc: Sexp, key: &str) -> Option<i64> {
+    element_integer(field_value(&doc, key)?)
+}
+
+/// Name of a list's head symbol, NULL for atoms and lists headed by
+/// anything else
+#[pg_extern(name = "sexp_head_text", immutable, parallel_safe)]
+fn sexp_head_text(doc: Sexp) -> Option<String> {
+    entry_key(doc.data.get(1..).unwrap_or_default()).map(|head| String::from_utf8_lossy(head).into_owned())
+}
+
+extension_sql!(
+    r#"
+-- Head symbol prefix test. Being a plain SQL function it is inlined, so
+-- `doc ^@ 'http/'` becomes `sexp_head_text(doc) ^@ 'http/'` and can use an
+-- expression index such as
+--   CREATE INDEX ON t USING spgist (sexp_head_text(doc));
+--   CREATE INDEX ON t (sexp_head_text(doc) COLLATE "C");
+CREATE FUNCTION sexp_head_starts_with(doc sexp, prefix text) RETURNS boolean
+    AS 'SELECT @extschema@.sexp_head_text($1) OPERATOR(pg_catalog.^@) $2'
+    LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+CREATE OPERATOR ^@ (
+    LEFTARG = sexp,
+    RIGHTARG = text,
+    FUNCTION = sexp_head_starts_with,
+    RESTRICT = prefixsel,
+    JOIN = prefixjoinsel
+);
+"#,
+    name = "sexp_head_prefix_operator",
+    requires = [sexp_head_text]
+);
+
+// ============================================================================
+// Pattern Matching
+// ============================================================================
+
+/// Pattern types for matching
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PatternType {
+    Literal,
+    Wildcard,      // _
+    WildcardRest,  // _*
+    Capture,       // ?name
+    CaptureRest,   // ??name
+}
+
+/// Check if a symbol is a pattern symbol
+fn get_pattern_type(sym: &str) -> PatternType {
+    if sym == "_" {
+        PatternType::Wildcard
+    } else if sym == "_*" {
+        PatternType::WildcardRest
+    } else if sym.starts_with("??") {
+        PatternType::CaptureRest
+    } else if sym.starts_with('?') {
+        PatternType::Capture
+    } else {
+        PatternType::Literal
+    }
+}
+
+/// Rest pattern (`_*` or `??name`) starting at `pos`, with the offset just
+/// past it
+fn rest_pattern_at(pat_data: &[u8], pos: usize) -> Option<usize> {
+    let sym = atom_bytes_at(pat_data, pos).filter(|_| pat_data[pos] == tags::SYMBOL)?;
+    let ptype = get_pattern_type(std::str::from_utf8(sym).unwrap_or(""));
+    if ptype != PatternType::WildcardRest && ptype != PatternType::CaptureRest {
+        return None;
+    }
+    let mut end = pos;
+    skip_element(pat_data, &mut end);
+    Some(end)
+}
+
+/// Match the element at `expr_pos` against the pattern element at `pat_pos`,
+/// advancing both past what was matched.
+///
+/// Lists are walked with an explicit stack of (expression, pattern)
+/// elements left in each open list, so hostile nesting cannot exhaust the
+/// native stack; pg_sexp.max_depth bounds the walk.
+fn match_elements(expr_data: &[u8], expr_pos: &mut usize,
+                  pat_data: &[u8], pat_pos: &mut usize) -> bool {
+    count_stat(&STAT_PATTERN_MATCHES, 1);
+    let mut open_lists: Vec<(u64, u64)> = Vec::new();
+    let mut root_pending = true;
+
+    loop {
+        // Pick the next pair of elements to compare
+        if let Some((expr_left, pat_left)) = open_lists.last_mut() {
+            if *pat_left == 0 {
+                // All pattern elements matched - check for leftovers
+                if *expr_left != 0 {
+                    return false;
+                }
+                open_lists.pop();
+                continue;
+            }
+            if let Some(after_rest) = rest_pattern_at(pat_data, *pat_pos) {
+                // Rest pattern must be last in pattern list; it consumes all
+                // remaining expression elements
+                if *pat_left != 1 {
+                    return false;
+                }
+                for _ in 0..*expr_left {
+                    skip_element(expr_data, expr_pos);
+                }
+                *pat_pos = after_rest;
+                open_lists.pop();
+                continue;
+            }
+            // Need exactly one expression element
+            if *expr_left == 0 {
+                return false;
+            }
+            *expr_left -= 1;
+            *pat_left -= 1;
+        } else if root_pending {
+            root_pending = false;
+        } else {
+            return true;
+        }
+
+        if *expr_pos >= expr_data.len() || *pat_pos >= pat_data.len() {
+            return *expr_pos >= expr_data.len() && *pat_pos >= pat_data.len();
+        }
+
+        // Check if pattern element is a symbol (potential pattern)
+        if pat_data[*pat_pos] == tags::SYMBOL {
+            let sym = atom_bytes_at(pat_data, *pat_pos).unwrap_or_default();
+            match get_pattern_type(std::str::from_utf8(sym).unwrap_or("")) {
+                PatternType::Wildcard | PatternType::Capture => {
+                    // Match any single element
+                    skip_element(pat_data, pat_pos);
+                    skip_element(expr_data, expr_pos);
+                    continue;
+                }
+                // Rest patterns are only valid inside a list
+                PatternType::WildcardRest | PatternType::CaptureRest => return false,
+                PatternType::Literal => {}
+            }
+        }
+
+        // Normal element matching
+        let expr_tag = expr_data[*expr_pos];
+        if expr_tag != pat_data[*pat_pos] {
+            return false;
+        }
+        if expr_tag == tags::LIST {
+            *expr_pos += 1;
+            *pat_pos += 1;
+            let expr_count = read_varint(expr_data, expr_pos);
+            let pat_count = read_varint(pat_data, pat_pos);
+            open_lists.push((expr_count, pat_count));
+            check_depth(open_lists.len());
+            continue;
+        }
+
+        // Atoms match when their encodings are identical
+        let (expr_start, pat_start) = (*expr_pos, *pat_pos);
+        skip_element(expr_data, expr_pos);
+        skip_element(pat_data, pat_pos);
+        if expr_data[expr_start..*expr_pos] != pat_data[pat_start..*pat_pos] {
+            return false;
+        }
+    }
+}
+
+/// Match a whole expression against a whole pattern
+fn matches_pattern(expr: &Sexp, pattern: &Sexp) -> bool {
+    if expr.data.len() < 2 || pattern.data.len() < 2 {
+        return expr.data.len() < 2 && pattern.data.len() < 2;
+    }
+    
+    let mut expr_pos = 1; // skip version
+    let mut pat_pos = 1;  // skip version
+    
+    match_elements(&expr.data, &mut expr_pos, &pattern.data, &mut pat_pos)
+}
+
+/// Pattern matching function
+#[pg_extern(name = "sexp_match", immutable, parallel_safe)]
+fn sexp_match_fn(expr: Sexp, pattern: Sexp) -> bool {
+    matches_pattern(&expr, &pattern)
+}
+
+/// Pattern matching against a text pattern (sexp ~ text).
+///
+/// The parsed pattern is cached in the call site's fn_extra, so a constant
+/// pattern is parsed once per query rather than once per row. What the text
+/// reads as depends on the parser settings, so the function is only stable
+/// and the cache is kept only while they stay the same.
+#[pg_extern(name = "sexp_match", stable, parallel_safe)]
+fn sexp_match_text(expr: Sexp, pattern: &str, fcinfo: pg_sys::FunctionCallInfo) -> bool {
+    let settings = ParseSettings::current();
+    let parse = || match parse_sexp_text(pattern, settings) {
+        Ok(parsed) => (pattern.to_string(), settings, parsed),
+        Err(e) => pgrx::error!("invalid sexp pattern: {}", e),
+    };
+    let cached = unsafe {
+        let flinfo = (*fcinfo).flinfo;
+        // The parsed value is palloc'd, so it must be built in the context
+        // the cache lives in
+        let mut mcxt = pgrx::PgMemoryContexts::For((*flinfo).fn_mcxt);
+        let mut cached = (*flinfo).fn_extra as *mut (String, ParseSettings, Sexp);
+        if cached.is_null() {
+            let parsed = mcxt.switch_to(|_| parse());
+            cached = mcxt.leak_and_drop_on_delete(parsed);
+            (*flinfo).fn_extra = cached.cast();
+        } else if (*cached).0 != pattern || (*cached).1 != settings {
+            *cached = mcxt.switch_to(|_| parse());
+        }
+        &(*cached).2
+    };
+    matches_pattern(&expr, cached)
+}
+
+/// One pattern's progress through a preorder walk of an expression shared
+/// with other patterns. Each step takes the next element of the walk and
+/// makes the decision `match_elements` makes for it; an element the pattern
+/// matched with a wildcard, a capture or a rest pattern is skipped along with
+/// everything under it.
+struct PatternWalk<'p, 'e> {
+    pattern: &'p [u8],
+    pos: usize,
+    open_lists: Vec<(u64, u64)>,
+    root_pending: bool,
+    /// Elements deeper than this are inside one already matched
+    skip_depth: Option<usize>,
+    captured: Vec<(&'p [u8], &'e [u8])>,
+    failed: bool,
+}
+
+impl<'p, 'e> PatternWalk<'p, 'e> {
+    fn new(pattern: &'p [u8]) -> Self {
+        count_stat(&STAT_PATTERN_MATCHES, 1);
+        PatternWalk {
+            pattern,
+            pos: 1, // skip version
+            open_lists: Vec::new(),
+            root_pending: true,
+            skip_depth: None,
+            captured: Vec::new(),
+            failed: pattern.len() < 2,
+        }
+    }
+
+    fn capture(&mut self, name: &'p [u8], value: &'e [u8]) -> bool {
+        match self.captured.iter().find(|(bound, _)| *bound == name) {
+            Some((_, existing)) => *existing == value,
+            None => {
+                self.captured.push((name, value));
+                true
+            }
+        }
+    }
+
+    /// Match the rest pattern at `pos`, which ends the innermost open list,
+    /// against the `rest` expression elements left in it
+    fn take_rest(&mut self, after_rest: usize, rest: &'e [u8]) -> bool {
+        let sym = atom_bytes_at(self.pattern, self.pos).unwrap_or_default();
+        if get_pattern_type(std::str::from_utf8(sym).unwrap_or("")) == PatternType::CaptureRest
+            && !self.capture(sym, rest)
+        {
+            return false;
+        }
+        self.pos = after_rest;
+        self.open_lists.pop();
+        true
+    }
+
+    /// Match the next element of the walk; false once the pattern can't match
+    fn step(&mut self, expr: &'e [u8], item: PreorderItem) -> bool {
+        if self.skip_depth.is_some_and(|depth| item.depth > depth) {
+            return true;
+        }
+        self.skip_depth = None;
+
+        // Close the lists this element is past, as the next pair is picked in
+        // match_elements
+        while let Some(&(expr_left, pat_left)) = self.open_lists.last() {
+            if pat_left == 0 {
+                if expr_left != 0 {
+                    return false;
+                }
+                self.open_lists.pop();
+                continue;
+            }
+            if let Some(after_rest) = rest_pattern_at(self.pattern, self.pos) {
+                if pat_left != 1 {
+                    return false;
+                }
+                let mut end = item.start;
+                for _ in 0..expr_left {
+                    skip_element(expr, &mut end);
+                }
+                if !self.take_rest(after_rest, &expr[item.start..end]) {
+                    return false;
+                }
+                if expr_left > 0 {
+                    self.skip_depth = Some(item.depth - 1);
+                    return true;
+                }
+                continue;
+            }
+            if expr_left == 0 {
+                return false;
+            }
+            if let Some((expr_left, pat_left)) = self.open_lists.last_mut() {
+                *expr_left -= 1;
+                *pat_left -= 1;
+            }
+            break;
+        }
+        if self.open_lists.is_empty() && !std::mem::take(&mut self.root_pending) {
+            return false;
+        }
+        if self.pos >= self.pattern.len() {
+            return false;
+        }
+
+        let pattern = self.pattern;
+        if pattern[self.pos] == tags::SYMBOL {
+            let sym = atom_bytes_at(pattern, self.pos).unwrap_or_default();
+            match get_pattern_type(std::str::from_utf8(sym).unwrap_or("")) {
+                PatternType::Wildcard => {
+                    skip_element(pattern, &mut self.pos);
+                    self.skip_depth = Some(item.depth);
+                    return true;
+                }
+                PatternType::Capture => {
+                    skip_element(pattern, &mut self.pos);
+                    self.skip_depth = Some(item.depth);
+                    let mut end = item.start;
+                    skip_element(expr, &mut end);
+                    return self.capture(sym, &expr[item.start..end]);
+                }
+                // Rest patterns are only valid inside a list
+                PatternType::WildcardRest | PatternType::CaptureRest => return false,
+                PatternType::Literal => {}
+            }
+        }
+
+        if item.tag != pattern[self.pos] {
+            return false;
+        }
+        if item.tag == tags::LIST {
+            let mut expr_pos = item.start + 1;
+            self.pos += 1;
+            let expr_count = read_varint(expr, &mut expr_pos);
+            let pat_count = read_varint(pattern, &mut self.pos);
+            self.open_lists.push((expr_count, pat_count));
+            check_depth(self.open_lists.len());
+            return true;
+        }
+
+        // Atoms match when their encodings are identical
+        let (mut expr_end, pat_start) = (item.start, self.pos);
+        skip_element(expr, &mut expr_end);
+        skip_element(pattern, &mut self.pos);
+        expr[item.start..expr_end] == pattern[pat_start..self.pos]
+    }
+
+    /// Whether the pattern matched once the walk is over: the lists still
+    /// open may only have a rest pattern left
+    fn finish(&mut self, expr: &'e [u8]) -> bool {
+        if self.failed {
+            return false;
+        }
+        while let Some(&(expr_left, pat_left)) = self.open_lists.last() {
+            let rest = rest_pattern_at(self.pattern, self.pos).filter(|_| pat_left == 1);
+            match (pat_left, rest) {
+                (0, _) if expr_left == 0 => {
+                    self.open_lists.pop();
+                }
+                (_, Some(after_rest)) if expr_left == 0 => {
+                    if !self.take_rest(after_rest, &expr[expr.len()..]) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        !self.root_pending
+    }
+}
+
+/// Match an expression against several whole patterns in a single preorder
+/// walk, advancing every pattern still in the running at each element. The
+/// walk stops as soon as a failure decides the result: any failure when
+/// `all` is set, every pattern failing otherwise.
+fn matches_patterns(expr: &Sexp, patterns: &[&Sexp], all: bool) -> bool {
+    if expr.data.len() < 2 {
+        let empty = |pattern: &&Sexp| pattern.data.len() < 2;
+        return if all { patterns.iter().all(empty) } else { patterns.iter().any(empty) };
+    }
+    let mut walks: Vec<PatternWalk> = patterns.iter().map(|pattern| PatternWalk::new(&pattern.data)).collect();
+    for item in Preorder::new(&expr.data, 1) {
+        for walk in walks.iter_mut().filter(|walk| !walk.failed) {
+            walk.failed = !walk.step(&expr.data, item);
+        }
+        let failed = walks.iter().filter(|walk| walk.failed).count();
+        if (all && failed > 0) || failed == walks.len() {
+            break;
+        }
+    }
+    if all {
+        walks.iter_mut().all(|walk| walk.finish(&expr.data))
+    } else {
+        walks.iter_mut().any(|walk| walk.finish(&expr.data))
+    }
+}
+
+/// True if the expression matches any of the patterns (NULL patterns are
+/// skipped). The expression is walked once for all the patterns.
+#[pg_extern(name = "sexp_match_any", immutable, parallel_safe)]
+fn sexp_match_any(expr: Sexp, patterns: Vec<Option<Sexp>>) -> bool {
+    let patterns: Vec<&Sexp> = patterns.iter().flatten().collect();
+    matches_patterns(&expr, &patterns, false)
+}
+
+/// True if the expression matches every pattern (a NULL pattern never
+/// matches). The expression is walked once for all the patterns.
+#[pg_extern(name = "sexp_match_all", immutable, parallel_safe)]
+fn sexp_match_all(expr: Sexp, patterns: Vec<Option<Sexp>>) -> bool {
+    if patterns.iter().any(Option::is_none) {
+        return false;
+    }
+    let patterns: Vec<&Sexp> = patterns.iter().flatten().collect();
+    matches_patterns(&expr, &patterns, true)
+}
+
+/// Find first subexpression matching pattern, in preorder
+fn find_pattern(data: &[u8], start: usize, pattern: &Sexp) -> Option<Sexp> {
+    Preorder::new(data, start).find_map(|item| {
+        check_depth(item.depth);
+        let mut expr_pos = item.start;
+        let mut pat_pos = 1; // skip version in pattern
+        if !match_elements(data, &mut expr_pos, &pattern.data, &mut pat_pos) {
+            return None;
+        }
+        let mut end_pos = item.start;
+        skip_element(data, &mut end_pos);
+        Some(Sexp::from_element(&data[item.start..end_pos]))
+    })
+}
+
+/// Find first subexpression matching pattern
+#[pg_extern(name = "sexp_find", immutable, parallel_safe)]
+fn sexp_find(expr: Sexp, pattern: Sexp) -> Option<Sexp> {
+    if expr.data.len() < 2 {
+        return None;
+    }
+    
+    find_pattern(&expr.data, 1, &pattern)
+}
+
+/// Keep only the list elements that match a pattern
+#[pg_extern(name = "sexp_filter", immutable, parallel_safe)]
+fn sexp_filter(list: Sexp, pattern: Sexp) -> Sexp {
+    if pattern.data.len() < 2 {
+        return Sexp::nil();
+    }
+    let kept: Vec<&[u8]> = list_arg(&list, "sexp_filter")
+        .into_iter()
+        .filter(|element| match_elements(element, &mut 0, &pattern.data, &mut 1))
+        .collect();
+    Sexp::from_elements(&kept)
+}
+
+/// Copy of `element` without the subtrees matching `pattern`, or None if
+/// the element matches itself
+fn prune_element(element: &[u8], pattern: &Sexp, depth: usize) -> Option<Sexp> {
+    check_depth(depth);
+    if match_elements(element, &mut 0, &pattern.data, &mut 1) {
+        return None;
+    }
+    if element[0] != tags::LIST {
+        return Some(Sexp::from_element(element));
+    }
+    let kept: Vec<Sexp> = list_elements(element)
+        .into_iter()
+        .filter_map(|child| prune_element(child, pattern, depth + 1))
+        .collect();
+    let kept: Vec<&[u8]> = kept.iter().map(|k| &k.data[1..]).collect();
+    Some(Sexp::from_elements(&kept))
+}
+
+/// Remove every subtree matching a pattern, e.g. all `(comment _*)` forms.
+/// Lists left without elements become nil, as does a document that matches
+/// as a whole.
+#[pg_extern(name = "sexp_prune", immutable, parallel_safe)]
+fn sexp_prune(doc: Sexp, pattern: Sexp) -> Sexp {
+    if doc.data.len() < 2 || pattern.data.len() < 2 {
+        return doc;
+    }
+    prune_element(&doc.data[1..], &pattern, 1).unwrap_or_else(Sexp::nil)
+}
+
+/// Skeleton of `element` holding only the subtrees matching `pattern` and
+/// the lists leading to them, or None if nothing inside matches. Ancestors
+/// keep their head atom so the context stays readable.
+fn select_element(element: &[u8], pattern: &Sexp, depth: usize) -> Option<Sexp> {
+    check_depth(depth);
+    if match_elements(element, &mut 0, &pattern.data, &mut 1) {
+        return Some(Sexp::from_element(element));
+    }
+    if element[0] != tags::LIST {
+        return None;
+    }
+    let children = list_elements(element);
+    let selected: Vec<Sexp> = children
+        .iter()
+        .filter_map(|child| select_element(child, pattern, depth + 1))
+        .collect();
+    if selected.is_empty() {
+        return None;
+    }
+    let mut kept: Vec<&[u8]> = Vec::with_capacity(selected.len() + 1);
+    let head = children[0];
+    if head[0] != tags::LIST && !match_elements(head, &mut 0, &pattern.data, &mut 1) {
+        kept.push(head);
+    }
+    kept.extend(selected.iter().map(|s| &s.data[1..]));
+    Some(Sexp::from_elements(&kept))
+}
+
+/// Keep only the subtrees matching a pattern together with their ancestor
+/// chain: a structural grep with context. NULL if nothing matches.
+#[pg_extern(name = "sexp_select", immutable, parallel_safe)]
+fn sexp_select(doc: Sexp, pattern: Sexp) -> Option<Sexp> {
+    if doc.data.len() < 2 || pattern.data.len() < 2 {
+        return None;
+    }
+    select_element(&doc.data[1..], &pattern, 1)
+}
+
+/// Value bound to a capture variable: one element for `?name`, the
+/// remaining list elements for `??name`
+#[derive(Debug, Clone, PartialEq)]
+enum Binding<'a> {
+    One(&'a [u8]),
+    Rest(Vec<&'a [u8]>),
+}
+
+/// Record a capture, failing if the name is already bound to another value
+fn bind<'a>(bindings: &mut Vec<(&'a [u8], Binding<'a>)>, name: &'a [u8], value: Binding<'a>) -> bool {
+    match bindings.iter().find(|(bound, _)| *bound == name) {
+        Some((_, existing)) => *existing == value,
+        None => {
+            bindings.push((name, value));
+            true
+        }
+    }
+}
+
+/// Match an encoded element against an encoded pattern element, collecting
+/// capture bindings. Recursion follows the pattern, so it is bounded by the
+/// pattern's nesting rather than the document's.
+fn match_bindings<'a>(expr: &'a [u8], pat: &'a [u8], depth: usize,
+                      bindings: &mut Vec<(&'a [u8], Binding<'a>)>) -> bool {
+    check_depth(depth);
+    if pat.first() == Some(&tags::SYMBOL) {
+        let sym = atom_bytes_at(pat, 0).unwrap_or_default();
+        match get_pattern_type(std::str::from_utf8(sym).unwrap_or("")) {
+            PatternType::Wildcard => return true,
+            PatternType::Capture => return bind(bindings, sym, Binding::One(expr)),
+            // Rest patterns are only valid as the last element of a list
+            PatternType::WildcardRest | PatternType::CaptureRest => return false,
+            PatternType::Literal => {}
+        }
+    }
+    if expr.first() != Some(&tags::LIST) || pat.first() != Some(&tags::LIST) {
+        return expr == pat;
+    }
+
+    let expr_items = list_elements(expr);
+    let mut pat_items = list_elements(pat);
+    let rest = pat_items.last().copied().filter(|last| rest_pattern_at(last, 0).is_some());
+    if rest.is_some() {
+        pat_items.pop();
+    }
+    if expr_items.len() < pat_items.len() || (rest.is_none() && expr_items.len() != pat_items.len()) {
+        return false;
+    }
+    if !pat_items.iter().zip(&expr_items).all(|(p, e)| match_bindings(e, p, depth + 1, bindings)) {
+        return false;
+    }
+    match rest.and_then(|rest| atom_bytes_at(rest, 0)) {
+        Some(sym) if get_pattern_type(std::str::from_utf8(sym).unwrap_or("")) == PatternType::CaptureRest => {
+            bind(bindings, sym, Binding::Rest(expr_items[pat_items.len()..].to_vec()))
+        }
+        _ => true,
+    }
+}
+
+/// Rebuild a template element with placeholder symbols replaced by what
+/// `lookup` returns for them. A `Rest` value is spliced into the enclosing
+/// list, or becomes a list of its own when it stands alone.
+fn substitute_placeholders<'a>(template: &[u8], lookup: &impl Fn(&[u8]) -> Option<Binding<'a>>,
+                               depth: usize, out: &mut impl ByteSink) {
+    check_depth(depth);
+    let binding = atom_bytes_at(template, 0)
+        .filter(|_| template[0] == tags::SYMBOL)
+        .and_then(lookup);
+    match binding {
+        Some(Binding::One(value)) => out.extend_from_slice(value),
+        Some(Binding::Rest(values)) => out.extend_from_slice(&Sexp::from_elements(&values).data[1..]),
+        None if template.first() == Some(&tags::LIST) => {
+            let mut count = 0u64;
+            let mut body = Vec::new();
+            for item in list_elements(template) {
+                let spliced = atom_bytes_at(item, 0)
+                    .filter(|_| item[0] == tags::SYMBOL)
+                    .and_then(lookup);
+                if let Some(Binding::Rest(values)) = spliced {
+                    count += values.len() as u64;
+                    values.iter().for_each(|value| body.extend_from_slice(value));
+                } else {
+                    count += 1;
+                    substitute_placeholders(item, lookup, depth + 1, &mut body);
+                }
+            }
+            if count == 0 {
+                out.push(tags::NIL);
+            } else {
+                out.push(tags::LIST);
+                write_varint(out, count);
+                out.extend_from_slice(&body);
+            }
+        }
+        None => out.extend_from_slice(template),
+    }
+}
+
+/// Match/case dispatch: `pairs` alternates patterns and templates, and the
+/// template for the first matching pattern is returned with its captures
+/// filled in. A trailing unpaired template is the default; with no match
+/// and no default the result is NULL.
+#[pg_extern(name = "sexp_case", immutable, parallel_safe)]
+fn sexp_case(expr: Sexp, pairs: pgrx::datum::VariadicArray<'_, Sexp>) -> Option<Sexp> {
+    let pairs: Vec<Option<Sexp>> = pairs.iter().collect();
+    let root = expr.data.get(1..).filter(|root| !root.is_empty()).unwrap_or(&[tags::NIL]);
+    for arm in pairs.chunks(2) {
+        let (pattern, template) = match arm {
+            [pattern, template] => (pattern.as_ref(), template.as_ref()),
+            [default] => return default.clone(),
+            _ => unreachable!(),
+        };
+        let Some(pattern) = pattern else { continue };
+        let pat_root = pattern.data.get(1..).filter(|root| !root.is_empty()).unwrap_or(&[tags::NIL]);
+        let mut bindings = Vec::new();
+        if !match_bindings(root, pat_root, 1, &mut bindings) {
+            continue;
+        }
+        let template = template?;
+        let lookup = |name: &[u8]| bindings.iter().find(|(bound, _)| *bound == name).map(|(_, value)| value.clone());
+        let mut data = VarlenaBuf::with_capacity(template.data.len());
+        data.push(FORMAT_VERSION);
+        substitute_placeholders(template.data.get(1..).unwrap_or(&[tags::NIL]), &lookup, 1, &mut data);
+        return Some(Sexp::from_buf(data));
+    }
+    None
+}
+
+/// Fill `?name` and `??name` placeholders in a template from a bindings
+/// alist. `?name` takes the value of a `(name value)` entry; `??name` splices
+/// every value of the `name` entry into the enclosing list. Placeholders
+/// without an entry are left as they are, so templates can be filled in
+/// stages.
+#[pg_extern(name = "sexp_fill", immutable, parallel_safe)]
+fn sexp_fill(template: Sexp, bindings: Sexp) -> Sexp {
+    let entries = alist_arg(&bindings, "sexp_fill");
+    let lookup = |sym: &[u8]| {
+        let (ptype, name) = match get_pattern_type(std::str::from_utf8(sym).unwrap_or("")) {
+            PatternType::Capture => (PatternType::Capture, &sym[1..]),
+            PatternType::CaptureRest => (PatternType::CaptureRest, &sym[2..]),
+            _ => return None,
+        };
+        let entry = entries.iter().find(|e| entry_key(e) == Some(name))?;
+        let values = list_elements(entry).split_off(1);
+        if ptype == PatternType::CaptureRest {
+            return Some(Binding::Rest(values));
+        }
+        match values.as_slice() {
+            [value] => Some(Binding::One(*value)),
+            _ => pgrx::error!(
+                "sexp_fill: ?{} needs a single value, got {}",
+                String::from_utf8_lossy(name),
+                Sexp::from_element(entry).to_string_repr()
+            ),
+        }
+    };
+    let mut data = VarlenaBuf::with_capacity(template.data.len());
+    data.push(FORMAT_VERSION);
+    substitute_placeholders(template.data.get(1..).unwrap_or(&[tags::NIL]), &lookup, 1, &mut data);
+    Sexp::from_buf(data)
+}
+
+// ============================================================================
+// Rule Queries
+// ============================================================================
+
+/// A rule `(:- head body ...)`: every way of matching the body patterns
+/// against facts, with captures agreeing across patterns, derives the head
+/// template filled in with those captures
+struct Rule<'a> {
+    head: &'a [u8],
+    body: Vec<&'a [u8]>,
+}
+
+fn parse_rules(rules: &Sexp) -> Vec<Rule<'_>> {
+    list_arg(rules, "sexp_query")
+        .into_iter()
+        .map(|rule| match list_elements(rule).as_slice() {
+            [arrow, head, body @ ..] if atom_bytes_at(arrow, 0) == Some(b":-") && arrow[0] == tags::SYMBOL => {
+                Rule { head: *head, body: body.to_vec() }
+            }
+            _ => pgrx::error!(
+                "sexp_query: rule must be (:- head body ...): {}",
+                Sexp::from_element(rule).to_string_repr()
+            ),
+        })
+        .collect()
+}
+
+/// Join the remaining body patterns against the facts, each pattern
+/// against its own slice of `sources`, extending `bindings`, and emit the
+/// filled-in head for each complete match
+fn solve<'a>(head: &[u8], body: &[&'a [u8]], sources: &[&'a [Vec<u8>]],
+             bindings: &[(&'a [u8], Binding<'a>)], derived: &mut Vec<Vec<u8>>) {
+    let (Some((pattern, rest)), Some((facts, rest_sources))) = (body.split_first(), sources.split_first()) else {
+        let lookup = |name: &[u8]| bindings.iter().find(|(bound, _)| *bound == name).map(|(_, value)| value.clone());
+        let mut fact = Vec::new();
+        substitute_placeholders(head, &lookup, 1, &mut fact);
+        derived.push(fact);
+        return;
+    };
+    for fact in facts.iter() {
+        let mut extended = bindings.to_vec();
+        if match_bindings(fact, pattern, 1, &mut extended) {
+            solve(head, rest, rest_sources, &extended, derived);
+        }
+    }
+}
+
+/// Add derived facts not seen before, raising an error past
+/// pg_sexp.query_max_facts
+fn add_facts(derived: Vec<Vec<u8>>, known: &mut std::collections::HashSet<Vec<u8>>, facts: &mut Vec<Vec<u8>>) {
+    let max = QUERY_MAX_FACTS.get();
+    for fact in derived {
+        if known.insert(fact.clone()) {
+            facts.push(fact);
+            if facts.len() > max as usize {
+                ereport!(
+                    PgLogLevel::ERROR,
+                    PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+                    format!("sexp_query: more than pg_sexp.query_max_facts ({}) facts", max),
+                    "Rules that build ever larger terms never reach a fixpoint."
+                );
+            }
+        }
+    }
+}
+
+/// Evaluate datalog-style rules over the sexp values in `column` of
+/// `source` (given by oid), returning the facts derived from them. Rules
+/// are applied until no new facts appear; derived facts feed later rounds,
+/// so rules may be recursive.
+///
+/// Evaluation is semi-naive: a round only tries matches that use at least
+/// one fact new in the previous round, so each derivation is found once
+/// instead of again in every later round. At most pg_sexp.query_max_facts
+/// facts are held.
+#[pg_extern(name = "sexp_query_oid", stable)]
+fn sexp_query_oid(rules: Sexp, source: pg_sys::Oid, column: &str) -> SetOfIterator<'static, Sexp> {
+    let rules_parsed = parse_rules(&rules);
+    let relation = Spi::get_one::<String>(&format!("SELECT {}::regclass::text", source.to_u32()))
+        .unwrap_or_else(|e| pgrx::error!("sexp_query: {}", e))
+        .unwrap_or_else(|| pgrx::error!("sexp_query: relation {} does not exist", source.to_u32()));
+    let query = format!("SELECT {} FROM {}", pgrx::spi::quote_identifier(column), relation);
+    let mut facts: Vec<Vec<u8>> = Spi::connect(|client| {
+        client
+            .select(&query, None, &[])
+            .unwrap_or_else(|e| pgrx::error!("sexp_query: {}", e))
+            .filter_map(|row| row.get::<Sexp>(1).unwrap_or_else(|e| pgrx::error!("sexp_query: {}", e)))
+            .map(|sexp| sexp.data.get(1..).filter(|e| !e.is_empty()).unwrap_or(&[tags::NIL]).to_vec())
+            .collect()
+    });
+
+    let mut known: std::collections::HashSet<Vec<u8>> = std::collections::HashSet::new();
+    let mut unique = Vec::with_capacity(facts.len());
+    add_facts(std::mem::take(&mut facts), &mut known, &mut unique);
+    let mut facts = unique;
+    let base = facts.len();
+
+    // Rules without a body hold unconditionally and are derived once
+    let mut derived = Vec::new();
+    for rule in rules_parsed.iter().filter(|rule| rule.body.is_empty()) {
+        solve(rule.head, &[], &[], &[], &mut derived);
+    }
+    add_facts(derived, &mut known, &mut facts);
+
+    // facts[..old] were known before the last round, facts[old..] are new
+    let mut old = 0;
+    while old < facts.len() {
+        pgrx::check_for_interrupts!();
+        let mut derived = Vec::new();
+        let (seen, delta) = (&facts[..old], &facts[old..]);
+        for rule in &rules_parsed {
+            // Pattern i matches a new fact; earlier patterns only old
+            // ones, so a match is tried under its first new fact only
+            for i in 0..rule.body.len() {
+                let sources: Vec<&[Vec<u8>]> = (0..rule.body.len())
+                    .map(|j| match j.cmp(&i) {
+                        std::cmp::Ordering::Less => seen,
+                        std::cmp::Ordering::Equal => delta,
+                        std::cmp::Ordering::Greater => &facts[..],
+                    })
+                    .collect();
+                solve(rule.head, &rule.body, &sources, &[], &mut derived);
+            }
+        }
+        old = facts.len();
+        add_facts(derived, &mut known, &mut facts);
+    }
+    let derived: Vec<Sexp> = facts.split_off(base).iter().map(|fact| Sexp::from_element(fact)).collect();
+    SetOfIterator::new(derived)
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION sexp_query(rules sexp, source regclass, "column" name) RETURNS SETOF sexp
+    AS 'SELECT @extschema@.sexp_query_oid($1, $2::oid, $3::text)'
+    LANGUAGE SQL STABLE STRICT;
+"#,
+    name = "sexp_query",
+    requires = [sexp_query_oid]
+);
+
+// ============================================================================
+// GIN Index Support
+// ============================================================================
+
+/// Key type markers for GIN index (must match C implementation for compatibility)
+mod gin_keys {
+    pub const ATOM: u32 = 0x01000000;
+    pub const LIST_HEAD: u32 = 0x02000000;
+    pub const SYMBOL: u32 = 0x03000000;
+    pub const STRING: u32 = 0x04000000;
+    pub const INTEGER: u32 = 0x05000000;
+    pub const FLOAT: u32 = 0x06000000;
+    pub const PAIR: u32 = 0x07000000;
+    pub const OVERFLOW: u32 = 0x08000000;
+    pub const BOOL: u32 = 0x09000000;
+    pub const DECIMAL: u32 = 0x0a000000;
+}
+
+/// Marker key of a value whose keys overflowed pg_sexp.gin_max_keys. With
+/// `heads` the value is still indexed by all of its list heads, otherwise
+/// by nothing else.
+fn overflow_key(heads: bool) -> i32 {
+    make_gin_key(gin_keys::OVERFLOW, heads as u32)
+}
+
+/// Hash combine function (same as C implementation)
+fn hash_combine32(seed: u32, hash: u32) -> u32 {
+    seed ^ (hash.wrapping_add(0x9e3779b9).wrapping_add(seed << 6).wrapping_add(seed >> 2))
+}
+
+/// Compute hash for bytes
+fn hash_bytes(data: &[u8]) -> u32 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Compute hash for i64
+fn hash_i64(val: i64) -> u32 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    
+    let mut hasher = DefaultHasher::new();
+    val.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Compute hash for f64
+fn hash_f64(val: f64) -> u32 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    
+    let mut hasher = DefaultHasher::new();
+    val.to_bits().hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Make a GIN key with type marker
+fn make_gin_key(type_marker: u32, value_hash: u32) -> i32 {
+    let combined = type_marker ^ value_hash;
+    (combined | 0x80000000) as i32
+}
+
+/// The `len` bytes at `pos`, advancing past them; data too short for them
+/// is reported as corrupted
+fn take_bytes<'a>(data: &'a [u8], pos: &mut usize, len: u64, what: &str) -> &'a [u8] {
+    let end = checked_end(data, *pos, len, what).unwrap_or_else(|e| corrupted(&e));
+    let bytes = &data[*pos..end];
+    *pos = end;
+    bytes
+}
+
+/// Get element hash at position
+fn get_element_hash(data: &[u8], pos: &mut usize) -> u32 {
+    let Some(&tag) = data.get(*pos) else {
+        corrupted("truncated element");
+    };
+    
+    match tag {
+        tags::NIL => {
+            *pos += 1;
+            hash_i64(0)
+        }
+        tags::INTEGER => {
+            *pos += 1;
+            let val = read_signed_varint(data, pos);
+            hash_i64(val)
+        }
+        tags::FLOAT => {
+            *pos += 1;
+            let bytes = take_bytes(data, pos, 8, "float");
+            hash_f64(f64::from_le_bytes(bytes.try_into().unwrap()))
+        }
+        tags::STRING | tags::SYMBOL | tags::DECIMAL => {
+            *pos += 1;
+            let len = read_varint(data, pos);
+            hash_bytes(take_bytes(data, pos, len, "string length"))
+        }
+        tags::BOOL => {
+            *pos += 1;
+            hash_i64(take_bytes(data, pos, 1, "bool")[0] as i64)
+        }
+        tags::LIST => {
+            *pos += 1;
+            let count = read_varint(data, pos);
+            if count > 0 {
+                // Return hash of first element (head)
+                get_element_hash(data, pos)
+            } else {
+                hash_i64(0)
+            }
+        }
+        _ => {
+            skip_element(data, pos);
+            0
+        }
+    }
+}
+
+/// Keys collected for one value, deduplicated in first-seen order.
+/// Extraction stops once more than `limit` keys are held.
+struct GinKeys {
+    keys: Vec<i32>,
+    /// What each key stands for
+    classes: Vec<GinKeyClass>,
+    seen: std::collections::HashSet<i32>,
+    limit: usize,
+}
+
+/// Kind of element a GIN key was made from. The type marker is folded into
+/// the key's hash, so this is kept alongside the key.
+#[derive(Clone, Copy, PartialEq)]
+enum GinKeyClass {
+    Atom,
+    Head,
+    Pair,
+    Overflow,
+}
+
+impl GinKeyClass {
+    fn name(self) -> &'static str {
+        match self {
+            GinKeyClass::Atom => "atom",
+            GinKeyClass::Head => "head",
+            GinKeyClass::Pair => "pair",
+            GinKeyClass::Overflow => "overflow",
+        }
+    }
+}
+
+impl GinKeys {
+    fn new(limit: usize) -> Self {
+        GinKeys { keys: Vec::new(), classes: Vec::new(), seen: std::collections::HashSet::new(), limit }
+    }
+
+    fn unlimited() -> Self {
+        GinKeys::new(usize::MAX)
+    }
+
+    fn push(&mut self, key: i32) {
+        self.push_class(key, GinKeyClass::Atom);
+    }
+
+    fn push_head(&mut self, key: i32) {
+        self.push_class(key, GinKeyClass::Head);
+    }
+
+    fn push_class(&mut self, key: i32, class: GinKeyClass) {
+        if self.seen.insert(key) {
+            self.keys.push(key);
+            self.classes.push(class);
+        }
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.keys.len() > self.limit
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Extract GIN keys recursively
+fn extract_gin_keys(data: &[u8], pos: &mut usize, keys: &mut GinKeys, skip_pair_keys: bool) {
+    if keys.is_over_limit() {
+        return;
+    }
+    let Some(&tag) = data.get(*pos) else {
+        corrupted("truncated element");
+    };
+    
+    match tag {
+        tags::NIL => {
+            *pos += 1;
+            let hash = hash_i64(0);
+            let key = make_gin_key(gin_keys::ATOM, hash);
+            keys.push(key);
+        }
+        tags::INTEGER => {
+            *pos += 1;
+            let val = read_signed_varint(data, pos);
+            let hash = hash_i64(val);
+            let key = make_gin_key(gin_keys::INTEGER, hash);
+            keys.push(key);
+        }
+        tags::FLOAT => {
+            *pos += 1;
+            let bytes = take_bytes(data, pos, 8, "float");
+            let hash = hash_f64(f64::from_le_bytes(bytes.try_into().unwrap()));
+            keys.push(make_gin_key(gin_keys::FLOAT, hash));
+        }
+        tags::STRING => {
+            *pos += 1;
+            let len = read_varint(data, pos);
+            let hash = hash_bytes(take_bytes(data, pos, len, "string length"));
+            keys.push(make_gin_key(gin_keys::STRING, hash));
+        }
+        tags::SYMBOL => {
+            *pos += 1;
+            let len = read_varint(data, pos);
+            let hash = hash_bytes(take_bytes(data, pos, len, "string length"));
+            keys.push(make_gin_key(gin_keys::SYMBOL, hash));
+        }
+        tags::DECIMAL => {
+            *pos += 1;
+            let len = read_varint(data, pos);
+            keys.push(make_gin_key(gin_keys::DECIMAL, hash_bytes(take_bytes(data, pos, len, "string length"))));
+        }
+        tags::BOOL => {
+            *pos += 1;
+            let b = take_bytes(data, pos, 1, "bool")[0];
+            keys.push(make_gin_key(gin_keys::BOOL, hash_i64(b as i64)));
+        }
+        tags::LIST => {
+            *pos += 1;
+            let count = read_varint(data, pos);
+            
+            if count == 0 {
+                return;
+            }
+            
+            let children_start = *pos;
+            
+            // Check if this is a 2-element pair with symbol head
+            let is_pair = count == 2 && data.get(*pos) == Some(&tags::SYMBOL);
+            
+            // Get head hash
+            let mut head_pos = *pos;
+            let head_hash = get_element_hash(data, &mut head_pos);
+            
+            if is_pair && !skip_pair_keys {
+                // Extract pair key: hash(symbol, value)
+                let mut second_pos = children_start;
+                skip_element(data, &mut second_pos); // skip first element
+                let mut second_hash_pos = second_pos;
+                let second_hash = get_element_hash(data, &mut second_hash_pos);
+                
+                let pair_hash = hash_combine32(gin_keys::PAIR, head_hash);
+                let pair_hash = hash_combine32(pair_hash, second_hash);
+                let key = make_gin_key(gin_keys::PAIR, pair_hash);
+                keys.push_class(key, GinKeyClass::Pair);
+            } else if !is_pair {
+                // Add list head key for non-pair lists
+                let key = make_gin_key(gin_keys::LIST_HEAD, head_hash);
+                keys.push_head(key);
+            }
+            
+            // Recurse into children
+            for _ in 0..count {
+                extract_gin_keys(data, pos, keys, skip_pair_keys);
+            }
+        }
+        _ => {
+            skip_element(data, pos);
+        }
+    }
+}
+
+/// Keys of a value with more than `limit` of them: every list head plus a
+/// marker, or the bare marker if even the heads don't fit
+fn overflow_keys(value: &Sexp, limit: usize) -> GinKeys {
+    let mut heads = GinKeys::new(limit - 1);
+    for item in Preorder::new(&value.data, 1) {
+        check_depth(item.depth);
+        if item.tag != tags::LIST {
+            continue;
+        }
+        let mut pos = item.start;
+        heads.push_head(make_gin_key(gin_keys::LIST_HEAD, get_element_hash(&value.data, &mut pos)));
+        if heads.is_over_limit() {
+            let mut bare = GinKeys::unlimited();
+            bare.push_class(overflow_key(false), GinKeyClass::Overflow);
+            return bare;
+        }
+    }
+    heads.push_class(overflow_key(true), GinKeyClass::Overflow);
+    heads
+}
+
+/// Extract GIN keys from sexp value (returns array).
+///
+/// A value with more than pg_sexp.gin_max_keys keys is indexed by its list
+/// heads and an overflow marker instead; every query also looks the marker
+/// up, so such values are found and rechecked rather than silently missed.
+#[pg_extern(name = "sexp_extract_keys", stable, parallel_safe)]
+fn sexp_extract_keys(value: Sexp) -> Vec<i32> {
+    value_gin_keys(&value).keys
+}
+
+/// GIN keys of a value with the kind of element each stands for: atom,
+/// head (of a list), pair (a `(symbol value)` entry) or overflow (the
+/// marker of a value past pg_sexp.gin_max_keys)
+#[pg_extern(name = "sexp_gin_key_classes", stable, parallel_safe)]
+fn sexp_gin_key_classes(value: Sexp) -> TableIterator<'static, (name!(key, i32), name!(key_class, String))> {
+    let keys = value_gin_keys(&value);
+    let rows: Vec<_> =
+        keys.keys.into_iter().zip(keys.classes).map(|(key, class)| (key, class.name().to_string())).collect();
+    TableIterator::new(rows)
+}
+
+fn value_gin_keys(value: &Sexp) -> GinKeys {
+    let limit = GIN_MAX_KEYS.get() as usize;
+    let mut keys = GinKeys::new(limit);
+    
+    if value.data.len() >= 2 {
+        let mut pos = 1; // skip version
+        extract_gin_keys(&value.data, &mut pos, &mut keys, false);
+    }
+    if keys.is_over_limit() {
+        keys = overflow_keys(value, limit);
+    }
+    
+    if keys.is_empty() {
+        keys.push(make_gin_key(gin_keys::ATOM, 0));
+    }
+    
+    keys
+}
+
+/// Extract GIN keys from query (returns array)
+#[pg_extern(name = "sexp_extract_query_keys", immutable, parallel_safe)]
+fn sexp_extract_query_keys(query: Sexp, strategy: i32) -> Vec<i32> {
+    query_gin_keys(&query, strategy).keys
+}
+
+fn query_gin_keys(query: &Sexp, strategy: i32) -> GinKeys {
+    let mut keys = GinKeys::unlimited();
+    
+    // For key-based containment (@>>), skip pair keys
+    // Strategy 9 is SEXP_GIN_CONTAINS_KEY_STRATEGY
+    let skip_pair_keys = strategy == 9;
+    
+    if query.data.len() >= 2 {
+        let mut pos = 1; // skip version
+        extract_gin_keys(&query.data, &mut pos, &mut keys, skip_pair_keys);
+    }
+    
+    if keys.is_empty() {
+        keys.push(make_gin_key(gin_keys::ATOM, 0));
+    }
+    
+    keys
+}
+
+/// Extract GIN keys from the literal atoms of a pattern.
+///
+/// Any value matching the pattern contains those atoms, so their keys are a
+/// safe filter. List head and pair keys are not extracted because rest
+/// wildcards let a pattern list match lists of other lengths.
+fn extract_pattern_keys(pattern: &Sexp) -> GinKeys {
+    let mut keys = GinKeys::unlimited();
+    for item in Preorder::new(&pattern.data, 1) {
+        if item.tag == tags::LIST {
+            continue;
+        }
+        if item.tag == tags::SYMBOL {
+            let sym = atom_bytes_at(&pattern.data, item.start).unwrap_or_default();
+            if get_pattern_type(&String::from_utf8_lossy(sym)) != PatternType::Literal {
+                continue;
+            }
+        }
+        let mut pos = item.start;
+        extract_gin_keys(&pattern.data, &mut pos, &mut keys, true);
+    }
+    keys
+}
+
+// ============================================================================
+// GIN Index Support (Raw PostgreSQL API)
+// ============================================================================
+
+/// GIN strategy numbers (matching C implementation)
+const SEXP_GIN_CONTAINS_STRATEGY: i16 = 7;     // @> structural containment
+const SEXP_GIN_CONTAINED_STRATEGY: i16 = 8;    // <@ contained by  
+const SEXP_GIN_CONTAINS_KEY_STRATEGY: i16 = 9; // @>> key-based containment
+const SEXP_GIN_HAS_SYMBOL_STRATEGY: i16 = 10;  // ? symbol containment
+const SEXP_GIN_MATCH_STRATEGY: i16 = 11;       // ~ pattern match
+
+/// GIN search modes
+const GIN_SEARCH_MODE_DEFAULT: i32 = 0;
+const GIN_SEARCH_MODE_ALL: i32 = 2;
+
+/// GIN ternary values
+const GIN_FALSE: i8 = 0;
+const GIN_TRUE: i8 = 1;
+const GIN_MAYBE: i8 = 2;
+
+/// Extract GIN keys from stored value
+/// Signature: sexp_gin_extract_value(sexp, internal) -> internal
+#[pg_extern(name = "sexp_gin_extract_value", immutable, parallel_safe)]
+fn sexp_gin_extract_value_fn(
+    value: Sexp,
+    nkeys: Internal,
+) -> Internal {
+    use pgrx::pg_sys;
+    
+    // Extract keys using our helper function
+    let keys = sexp_extract_keys(value);
+    let key_count = keys.len();
+    
+    unsafe {
+        // Set nkeys output parameter
+        let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
+        *nkeys_ptr = key_count as i32;
+        
+        // Allocate Datum array in current memory context
+        let datums = pg_sys::palloc(std::mem::size_of::<pg_sys::Datum>() * key_count) 
+            as *mut pg_sys::Datum;
+        
+        // Copy keys as int32 Datums
+        for (i, key) in keys.iter().enumerate() {
+            *datums.add(i) = pg_sys::Datum::from(*key);
+        }
+        
+        Internal::from(Some(pg_sys::Datum::from(datums)))
+    }
+}
+
+/// Extract GIN keys from query value
+/// Signature: sexp_gin_extract_query(internal, internal, int2, internal, internal, internal, internal) -> internal
+///
+/// The query is taken as a raw datum because its type depends on the strategy:
+/// sexp for the containment operators, text for the symbol operator.
+#[pg_extern(name = "sexp_gin_extract_query", immutable, parallel_safe)]
+fn sexp_gin_extract_query_fn(
+    query: Internal,
+    nkeys: Internal,
+    strategy: i16,
+    _pmatch: Internal,
+    extra_data: Internal,
+    _null_flags: Internal,
+    search_mode: Internal,
+) -> Internal {
+    use pgrx::pg_sys;
+    
+    // Handle contained-by strategy specially
+    if strategy == SEXP_GIN_CONTAINED_STRATEGY {
+        unsafe {
+            let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
+            *nkeys_ptr = 0;
+            let search_mode_ptr = search_mode.unwrap().unwrap().cast_mut_ptr::<i32>();
+            *search_mode_ptr = GIN_SEARCH_MODE_ALL;
+        }
+        return Internal::default();
+    }
+    
+    let query_datum = query.unwrap().expect("GIN query must not be NULL");
+
+    let mut keys = if strategy == SEXP_GIN_HAS_SYMBOL_STRATEGY {
+        // A single symbol key answers the query
+        let symbol = unsafe { String::from_datum(query_datum, false) }
+            .expect("GIN query must not be NULL");
+        let mut keys = GinKeys::unlimited();
+        keys.push(make_gin_key(gin_keys::SYMBOL, hash_bytes(symbol.as_bytes())));
+        keys
+    } else if strategy == SEXP_GIN_MATCH_STRATEGY {
+        let pattern = unsafe { Sexp::from_datum(query_datum, false) }
+            .expect("GIN query must not be NULL");
+        let keys = extract_pattern_keys(&pattern);
+        if keys.is_empty() {
+            // Nothing literal to look up: scan the whole index and recheck
+            unsafe {
+                let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
+                *nkeys_ptr = 0;
+                let search_mode_ptr = search_mode.unwrap().unwrap().cast_mut_ptr::<i32>();
+                *search_mode_ptr = GIN_SEARCH_MODE_ALL;
+            }
+            return Internal::default();
+        }
+        keys
+    } else {
+        // Extract keys using our helper function with appropriate strategy
+        let query = unsafe { Sexp::from_datum(query_datum, false) }
+            .expect("GIN query must not be NULL");
+        query_gin_keys(&query, strategy as i32)
+    };
+    // Overflowed values carry one of these markers instead of their keys
+    keys.keys.extend([overflow_key(true), overflow_key(false)]);
+    keys.classes.extend([GinKeyClass::Overflow, GinKeyClass::Overflow]);
+    let key_count = keys.len();
+    
+    unsafe {
+        // Set nkeys output parameter
+        let nkeys_ptr = nkeys.unwrap().unwrap().cast_mut_ptr::<i32>();
+        *nkeys_ptr = key_count as i32;
+        
+        // Set search mode
+        let search_mode_ptr = search_mode.unwrap().unwrap().cast_mut_ptr::<i32>();
+        *search_mode_ptr = GIN_SEARCH_MODE_DEFAULT;
+        
+        // Allocate Datum array
+        let datums = pg_sys::palloc(std::mem::size_of::<pg_sys::Datum>() * key_count)
+            as *mut pg_sys::Datum;
+        
+        // Copy keys as int32 Datums
+        for (i, key) in keys.keys.iter().enumerate() {
+            *datums.add(i) = pg_sys::Datum::from(*key);
+        }
+
+        // Flag the list head keys, which values indexed by heads alone must
+        // still have
+        let flags = pg_sys::palloc0(std::mem::size_of::<pg_sys::Pointer>() * key_count) as *mut pg_sys::Pointer;
+        for (i, _) in keys.classes.iter().enumerate().filter(|(_, class)| **class == GinKeyClass::Head) {
+            *flags.add(i) = flags.cast();
+        }
+        *extra_data.unwrap().unwrap().cast_mut_ptr::<*mut pg_sys::Pointer>() = flags;
+        
+        Internal::from(Some(pg_sys::Datum::from(datums)))
+    }
+}
+
+/// Ternary result for a value that may have overflowed pg_sexp.gin_max_keys,
+/// given the checks of a query's keys followed by its two overflow markers
+/// and the head flags from sexp_gin_extract_query: a possible match needing
+/// a recheck, or false.
+unsafe fn overflow_consistent(check: &[i8], head_flags: *const pg_sys::Pointer) -> i8 {
+    let real = check.len() - 2;
+    if check[real + 1] != GIN_FALSE {
+        return GIN_MAYBE;
+    }
+    if check[real] == GIN_FALSE {
+        return GIN_FALSE;
+    }
+    let is_head = |i: usize| !head_flags.is_null() && !(*head_flags.add(i)).is_null();
+    if (0..real).filter(|&i| is_head(i)).all(|i| check[i] != GIN_FALSE) {
+        GIN_MAYBE
+    } else {
+        GIN_FALSE
+    }
+}
+
+/// GIN consistent check
+/// Signature: sexp_gin_consistent(internal, int2, internal, int4, internal, internal, internal, internal) -> bool
+#[pg_extern(name = "sexp_gin_consistent", immutable, parallel_safe)]
+fn sexp_gin_consistent_fn(
+    check: Internal,
+    strategy: i16,
+    _query: Internal,
+    nkeys: i32,
+    extra_data: Internal,
+    recheck: Internal,
+    _query_keys: Internal,
+    _null_flags: Internal,
+) -> bool {
+    use pgrx::pg_sys;
+    
+    unsafe {
+        // Always require recheck (keys are hashes, collisions possible)
+        let recheck_ptr = recheck.unwrap().unwrap().cast_mut_ptr::<bool>();
+        *recheck_ptr = true;
+        
+        let check_ptr = check.unwrap().unwrap().cast_mut_ptr::<bool>();
+        // Every query but contained-by ends in the two overflow markers
+        let real = if strategy == SEXP_GIN_CONTAINED_STRATEGY { nkeys } else { (nkeys - 2).max(0) };
+        if real < nkeys && !(0..real).all(|i| *check_ptr.add(i as usize)) {
+            let check: Vec<i8> = (0..nkeys).map(|i| if *check_ptr.add(i as usize) { GIN_TRUE } else { GIN_FALSE }).collect();
+            let head_flags = extra_data.unwrap().map_or(std::ptr::null(), |d| d.cast_mut_ptr::<pg_sys::Pointer>() as *const _);
+            let matched = overflow_consistent(&check, head_flags) != GIN_FALSE;
+            if matched {
+                count_stat(&STAT_GIN_RECHECKS, 1);
+            }
+            return matched;
+        }
+        
+        let matched = match strategy {
+            SEXP_GIN_CONTAINS_STRATEGY | SEXP_GIN_CONTAINS_KEY_STRATEGY | SEXP_GIN_MATCH_STRATEGY => {
+                // All query keys must be present
+                for i in 0..real {
+                    if !*check_ptr.add(i as usize) {
+                        return false;
+                    }
+                }
+                true
+            }
+            SEXP_GIN_CONTAINED_STRATEGY => {
+                // For contained-by, we can't efficiently pre-filter
+                true
+            }
+            SEXP_GIN_HAS_SYMBOL_STRATEGY => {
+                // The symbol key is a hash, so a match still needs a recheck
+                real > 0 && *check_ptr
+            }
+            _ => {
+                pgrx::error!("sexp_gin_consistent: unknown strategy {}", strategy);
+            }
+        };
+        if matched && *recheck_ptr {
+            count_stat(&STAT_GIN_RECHECKS, 1);
+        }
+        matched
+    }
+}
+
+/// GIN triconsistent check (ternary logic)
+/// Signature: sexp_gin_triconsistent(internal, int2, internal, int4, internal, internal, internal) -> char
+#[pg_extern(name = "sexp_gin_triconsistent", immutable, parallel_safe)]
+fn sexp_gin_triconsistent_fn(
+    check: Internal,
+    strategy: i16,
+    _query: Internal,
+    nkeys: i32,
+    extra_data: Internal,
+    _query_keys: Internal,
+    _null_flags: Internal,
+) -> i8 {
+    use pgrx::pg_sys;
+    
+    unsafe {
+        let check_ptr = check.unwrap().unwrap().cast_mut_ptr::<i8>();
+        // Every query but contained-by ends in the two overflow markers
+        let real = if strategy == SEXP_GIN_CONTAINED_STRATEGY { nkeys } else { (nkeys - 2).max(0) };
+        
+        let mut all_true = true;
+        let mut any_false = false;
+        
+        for i in 0..real {
+            let val = *check_ptr.add(i as usize);
+            if val == GIN_FALSE {
+                any_false = true;
+                all_true = false;
+                break;
+            } else if val == GIN_MAYBE {
+                all_true = false;
+            }
+        }
+        
+        let result = match strategy {
+            SEXP_GIN_CONTAINS_STRATEGY | SEXP_GIN_CONTAINS_KEY_STRATEGY => {
+                if any_false {
+                    GIN_FALSE
+                } else if all_true && real == 1 {
+                    // Single-key optimization: skip recheck for single atom queries
+                    GIN_TRUE
+                } else {
+                    GIN_MAYBE
+                }
+            }
+            SEXP_GIN_CONTAINED_STRATEGY => {
+                GIN_MAYBE
+            }
+            SEXP_GIN_MATCH_STRATEGY => {
+                // Literal atoms only narrow the candidates; structure needs a recheck
+                if any_false {
+                    GIN_FALSE
+                } else {
+                    GIN_MAYBE
+                }
+            }
+            SEXP_GIN_HAS_SYMBOL_STRATEGY => {
+                // A hashed symbol key can collide, so never report a sure match
+                if any_false {
+                    GIN_FALSE
+                } else {
+                    GIN_MAYBE
+                }
+            }
+            _ => {
+                pgrx::error!("sexp_gin_triconsistent: unknown strategy {}", strategy);
+            }
+        };
+        let result = if real == nkeys {
+            result
+        } else {
+            let check = std::slice::from_raw_parts(check_ptr, nkeys as usize);
+            let overflowed = check[real as usize] != GIN_FALSE || check[real as usize + 1] != GIN_FALSE;
+            match result {
+                GIN_FALSE => {
+                    let head_flags = extra_data.unwrap().map_or(std::ptr::null(), |d| d.cast_mut_ptr::<pg_sys::Pointer>() as *const _);
+                    overflow_consistent(check, head_flags)
+                }
+                GIN_TRUE if overflowed => GIN_MAYBE,
+                other => other,
+            }
+        };
+        if result == GIN_MAYBE {
+            count_stat(&STAT_GIN_RECHECKS, 1);
+        }
+        result
+    }
+}
+
+// ============================================================================
+// Additional Operators
+// ============================================================================
+
+extension_sql!(
+    r#"
+-- Key-based containment operator (@>>)
+CREATE OPERATOR @>> (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_contains_key,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+-- Pattern match operator (~)
+CREATE OPERATOR ~ (
+    LEFTARG = sexp,
+    RIGHTARG = sexp,
+    FUNCTION = sexp_match,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+-- Pattern match against a text pattern, parsed once per call site
+CREATE OPERATOR ~ (
+    LEFTARG = sexp,
+    RIGHTARG = text,
+    FUNCTION = sexp_match,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+-- Symbol containment operator (?)
+CREATE OPERATOR ? (
+    LEFTARG = sexp,
+    RIGHTARG = text,
+    FUNCTION = sexp_has_symbol,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+-- GIN operator class for sexp containment
+-- Strategy 7 = @> (structural containment), matching jsonb convention
+-- Strategy 9 = @>> (key-based containment)
+-- Strategy 10 = ? (symbol containment)
+-- Strategy 11 = ~ (pattern match, keyed on literal atoms)
+CREATE OPERATOR CLASS sexp_gin_ops
+    DEFAULT FOR TYPE sexp USING gin AS
+    OPERATOR 7 @> (sexp, sexp),
+    OPERATOR 9 @>> (sexp, sexp),
+    OPERATOR 10 ? (sexp, text),
+    OPERATOR 11 ~ (sexp, sexp),
+    FUNCTION 1 btint4cmp(int4, int4),
+    FUNCTION 2 sexp_gin_extract_value(sexp, internal),
+    FUNCTION 3 sexp_gin_extract_query(internal, internal, int2, internal, internal, internal, internal),
+    FUNCTION 4 sexp_gin_consistent(internal, int2, internal, int4, internal, internal, internal, internal),
+    FUNCTION 6 sexp_gin_triconsistent(internal, int2, internal, int4, internal, internal, internal),
+    STORAGE int4;
+
+COMMENT ON OPERATOR CLASS sexp_gin_ops USING gin IS 'GIN index operator class for sexp containment queries';
+"#,
+    name = "sexp_additional_operators",
+    requires = [
+        "sexp_operators",
+        sexp_contains_key,
+        sexp_has_symbol,
+        sexp_match_fn,
+        sexp_match_text,
+        sexp_extract_keys, 
+        sexp_extract_query_keys,
+        sexp_gin_extract_value_fn,
+        sexp_gin_extract_query_fn,
+        sexp_gin_consistent_fn,
+        sexp_gin_triconsistent_fn
+    ]
+);
+
+extension_sql!(
+    r#"
+-- GIN key distribution of a sexp column, sampled, one row per key ordered
+-- by how many sampled documents produce it. key_class tells what the key
+-- hashes (atom, head, pair or overflow, see sexp_gin_key_classes).
+-- estimated_postings scales the sample frequency to the table's row
+-- estimate (run ANALYZE first), and distinct_keys is the number of distinct
+-- keys seen in the whole sample.
+CREATE FUNCTION sexp_gin_key_stats(
+    tbl regclass,
+    col name,
+    sample_rows int DEFAULT 10000,
+    top_n int DEFAULT 20
+) RETURNS TABLE(
+    key int4,
+    key_class text,
+    documents int8,
+    sample_fraction float8,
+    estimated_postings int8,
+    distinct_keys int8
+)
+LANGUAGE plpgsql STABLE AS $$
+DECLARE
+    total float8;
+    sampling text := '';
+BEGIN
+    SELECT reltuples INTO total FROM pg_class WHERE oid = tbl;
+    IF total > sample_rows THEN
+        sampling := format('TABLESAMPLE BERNOULLI (%s)', 100.0 * sample_rows / total);
+    END IF;
+
+    RETURN QUERY EXECUTE format($q$
+        WITH sample AS (
+            SELECT %I::@extschema@.sexp AS doc FROM %s %s WHERE %I IS NOT NULL LIMIT %s
+        ), keys AS (
+            SELECT k.key, k.key_class, count(*) AS docs
+            FROM sample, @extschema@.sexp_gin_key_classes(doc) AS k
+            GROUP BY k.key, k.key_class
+        ), n AS (
+            SELECT count(*) AS n FROM sample
+        )
+        SELECT key, key_class, docs, docs::float8 / n.n,
+               round(docs::float8 / n.n * greatest($1, n.n))::int8,
+               count(*) OVER ()
+        FROM keys, n
+        ORDER BY docs DESC, key
+        LIMIT $2
+    $q$, col, tbl, sampling, col, sample_rows)
+    USING total, top_n;
+END
+$$;
+"#,
+    name = "sexp_gin_key_stats",
+    requires = [sexp_gin_key_classes]
+);
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use super::*;
+
+    #[pg_test]
+    fn test_parse_symbol() {
+        let s = Sexp::input(c"foo");
+        assert_eq!(s.get_type(), SexpType::Symbol);
+        assert_eq!(s.to_string_repr(), "foo");
+    }
+
+    #[pg_test]
+    fn test_parse_integer() {
+        let s = Sexp::input(c"42");
+        assert_eq!(s.get_type(), SexpType::Integer);
+        assert_eq!(s.to_string_repr(), "42");
+    }
+
+    #[pg_test]
+    fn test_parse_negative() {
+        let s = Sexp::input(c"-123");
+        assert_eq!(s.get_type(), SexpType::Integer);
+        assert_eq!(s.to_string_repr(), "-123");
+    }
+
+    #[pg_test]
+    fn test_parse_float() {
+        let s = Sexp::input(c"3.14");
+        assert_eq!(s.get_type(), SexpType::Float);
+    }
+
+    #[pg_test]
+    fn test_parse_nil() {
+        let s = Sexp::input(c"()");
+        assert!(s.is_nil());
+        assert_eq!(s.to_string_repr(), "()");
+    }
+
+    #[pg_test]
+    fn test_parse_list() {
+        let s = Sexp::input(c"(foo bar baz)");
+        assert_eq!(s.get_type(), SexpType::List);
+        assert_eq!(s.length(), 3);
+    }
+
+    #[pg_test]
+    fn test_car_cdr() {
+        let s = Sexp::input(c"(a b c)");
+        
+        let car = s.car().unwrap();
+        assert_eq!(car.to_string_repr(), "a");
+        
+        let cdr = s.cdr().unwrap();
+        assert_eq!(cdr.length(), 2);
+    }
+
+    #[pg_test]
+    fn test_nth() {
+        let s = Sexp::input(c"(a b c d)");
+        
+        assert_eq!(s.nth(0).unwrap().to_string_repr(), "a");
+        assert_eq!(s.nth(1).unwrap().to_string_repr(), "b");
+        assert_eq!(s.nth(2).unwrap().to_string_repr(), "c");
+        assert_eq!(s.nth(3).unwrap().to_string_repr(), "d");
+        assert!(s.nth(4).is_none());
+    }
+
+    #[pg_test]
+    fn test_contains() {
+        let container = Sexp::input(c"(a (b c) d)");
+        let needle = Sexp::input(c"(b c)");
+        let not_there = Sexp::input(c"(x y)");
+        
+        assert!(container.contains(&needle));
+        assert!(!container.contains(&not_there));
+    }
+
+    #[pg_test]
+    fn test_equality() {
+        let a = Sexp::input(c"(foo bar)");
+        let b = Sexp::input(c"(foo bar)");
+        let c = Sexp::input(c"(foo baz)");
+        
+        assert!(a.equals(&b));
+        assert!(!a.equals(&c));
+    }
+
+    #[pg_test]
+    fn test_pattern_match_wildcard() {
+        let expr = Sexp::input(c"(foo bar baz)");
+        let pattern = Sexp::input(c"(foo _ baz)");
+        
+        assert!(sexp_match_fn(expr, pattern));
+    }
+
+    #[pg_test]
+    fn test_pattern_match_rest() {
+        let expr = Sexp::input(c"(foo a b c d)");
+        let pattern = Sexp::input(c"(foo _*)");
+        
+        assert!(sexp_match_fn(expr, pattern));
+    }
+
+    #[pg_test]
+    fn test_pattern_no_match() {
+        let expr = Sexp::input(c"(foo bar)");
+        let pattern = Sexp::input(c"(foo baz)");
+        
+        assert!(!sexp_match_fn(expr, pattern));
+    }
+
+    #[pg_test]
+    fn test_key_containment() {
+        let container = Sexp::input(c"(user (id 100) (name \"John\") (age 30))");
+        let needle = Sexp::input(c"(name \"John\")");
+        
+        assert!(sexp_contains_key_impl(&container, &needle));
+    }
+
+    #[pg_test]
+    fn test_key_containment_nested() {
+        let container = Sexp::input(c"(data (user (id 100)))");
+        let needle = Sexp::input(c"(id 100)");
+        
+        assert!(sexp_contains_key_impl(&container, &needle));
+    }
+
+    #[pg_test]
+    fn test_has_symbol() {
+        let s = Sexp::input(c"(define (square x) (* x \"y\"))");
+
+        assert!(sexp_has_symbol(s.clone(), "square"));
+        assert!(sexp_has_symbol(s.clone(), "x"));
+        assert!(!sexp_has_symbol(s.clone(), "y"));
+        assert!(!sexp_has_symbol(s, "lambda"));
+    }
+
+    #[pg_test]
+    fn test_shape_helpers() {
+        let alist = Sexp::input(c"((host \"db\") (port 5432))");
+        let plain = Sexp::input(c"(config (host \"db\"))");
+
+        assert!(sexp_is_alist(alist.clone()));
+        assert!(!sexp_is_alist(plain.clone()));
+        assert!(sexp_has_keys(alist.clone(), vec!["host".into(), "port".into()]));
+        assert!(!sexp_has_keys(alist, vec!["user".into()]));
+        assert!(sexp_head_is(plain.clone(), "config"));
+        assert!(!sexp_head_is(plain, "host"));
+    }
+
+    #[pg_test]
+    fn test_typmod_accepts_conforming() {
+        let alist = Sexp::input(c"((a 1) (b 2))");
+        let result = sexp_enforce_typmod(alist.clone(), typmods::ALIST, false);
+        assert!(result.equals(&alist));
+        assert_eq!(sexp_typmod_out(typmods::ALIST), c"(alist)");
+    }
+
+    #[pg_test(error = "sexp value does not conform to sexp(atom): (a b)")]
+    fn test_typmod_rejects_nonconforming() {
+        sexp_enforce_typmod(Sexp::input(c"(a b)"), typmods::ATOM, false);
+    }
+
+    #[pg_test]
+    fn test_text_pairs_round_trip() {
+        let alist = sexp_from_text_pairs(
+            vec!["host".into(), "port".into(), "user".into()],
+            vec![Some("db".into()), Some("5432".into()), None],
+        );
+        assert_eq!(alist.to_string_repr(), "((host \"db\") (port \"5432\") (user ()))");
+
+        let pairs: Vec<_> = sexp_each_text(Sexp::input(c"((host \"db\") (port 5432) (flag))")).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("host".to_string(), Some("db".to_string())),
+                ("port".to_string(), Some("5432".to_string())),
+                ("flag".to_string(), None),
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_xml_sxml_round_trip() {
+        let doc = r#"<?xml version="1.0"?>
+<book id="42"><title>S &amp; E</title><tags><tag/></tags></book>"#;
+        let sxml = xml_text_to_sexp(doc);
+        assert_eq!(
+            sxml.to_string_repr(),
+            "(book (@ (id \"42\")) (title \"S & E\") (tags (tag)))"
+        );
+        assert_eq!(
+            sexp_to_xml_text(sxml),
+            r#"<book id="42"><title>S &amp; E</title><tags><tag/></tags></book>"#
+        );
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_xml_depth_limit() {
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        xml_text_to_sexp(&"<a>".repeat(20));
+    }
+
+    #[pg_test]
+    fn test_csexp_round_trip() {
+        let sexp = sexp_from_csexp(b"(4:name[10:text/plain]5:alice(6:public3:key))");
+        assert_eq!(
+            sexp.to_string_repr(),
+            "(name (display-hint text/plain alice) (public key))"
+        );
+
+        let binary = sexp_from_csexp(b"(3:sig2:\x00\xff)");
+        assert_eq!(binary.to_string_repr(), "(sig (octets \"00ff\"))");
+        assert_eq!(sexp_to_csexp(binary.clone(), false), b"(3:sig2:\x00\xff)".to_vec());
+
+        let transport = sexp_to_csexp(binary.clone(), true);
+        assert_eq!(sexp_from_csexp(&transport).to_string_repr(), binary.to_string_repr());
+
+        // Octets that look like numbers or nil keep their exact bytes
+        for canonical in [&b"3:007"[..], b"3:1.0", b"3:nil", b"(1:a2:-03:1e3)", b"(2:#t0:)"] {
+            assert_eq!(sexp_to_csexp(sexp_from_csexp(canonical), false), canonical.to_vec());
+        }
+
+        // Lists shaped like the binary and display hint forms are read as
+        // quoted lists, so they aren't re-encoded as octets or hints
+        let literal = sexp_from_csexp(b"(6:octets4:00ff)");
+        assert_eq!(literal.car().unwrap().to_string_repr(), "quote");
+        assert_eq!(sexp_from_csexp(b"(5:quote(1:a))").to_string_repr(), "(quote (a))");
+        for canonical in [
+            &b"(6:octets4:00ff)"[..],
+            b"(12:display-hint4:text5:alice)",
+            b"(5:quote(1:a))",
+            b"(3:doc(6:octets2:\x00\xff)[4:text]2:hi(5:quote1:x))",
+            b"(4:sig1:[[10:image/jpeg]3:\x01\x02\x03)",
+            b"[2:\xfe\xff]0:",
+        ] {
+            assert_eq!(sexp_to_csexp(sexp_from_csexp(canonical), false), canonical.to_vec());
+            let transport = sexp_to_csexp(sexp_from_csexp(canonical), true);
+            assert_eq!(sexp_to_csexp(sexp_from_csexp(&transport), false), canonical.to_vec());
+        }
+
+        // Native lists headed by a reserved symbol survive the round trip
+        for text in [
+            "(quote (a b))",
+            "(quote (quote (octets \"00ff\")))",
+            "(octets a b)",
+            "(display-hint (a) b)",
+            "(x (quote (octets ab)) (quote (display-hint a b)))",
+        ] {
+            let native = Sexp::input(&std::ffi::CString::new(text).unwrap());
+            assert_eq!(sexp_from_csexp(&sexp_to_csexp(native, false)).to_string_repr(), text);
+        }
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_csexp_depth_limit() {
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_from_csexp(&[b'('; 20]);
+    }
+
+    #[pg_test]
+    fn test_array_conversions() {
+        let list = sexp_from_int4_array(vec![Some(1), None, Some(-3)]);
+        assert_eq!(list.to_string_repr(), "(1 () -3)");
+        assert_eq!(sexp_to_int8_array(list), vec![Some(1), None, Some(-3)]);
+
+        let list = sexp_from_text_array(vec![Some("a b".into()), Some("c".into())]);
+        assert_eq!(list.to_string_repr(), "(\"a b\" \"c\")");
+        assert_eq!(sexp_to_float8_array(Sexp::input(c"(1 2.5)")), vec![Some(1.0), Some(2.5)]);
+    }
+
+    #[pg_test(error = "cannot cast sexp element foo to integer[]")]
+    fn test_array_conversion_rejects_mixed_list() {
+        sexp_to_int4_array(Sexp::input(c"(1 foo)"));
+    }
+
+    #[pg_test]
+    fn test_sexp_to_array() {
+        assert_eq!(
+            sexp_to_array(Sexp::input(c"(a \"b c\" (1 2.5) ())")),
+            vec![Some("a".into()), Some("b c".into()), Some("1".into()), Some("2.5".into()), None]
+        );
+        assert_eq!(sexp_to_array(Sexp::input(c"foo")), vec![Some("foo".to_string())]);
+        assert!(sexp_to_array(Sexp::input(c"()")).is_empty());
+    }
+
+    #[pg_test]
+    fn test_bytea_round_trip() {
+        let sexp = Sexp::input(c"(define (f x) \"body\" 1.5 -7)");
+        let bytes = sexp_to_bytea(sexp.clone());
+        assert!(sexp_binary_is_valid(&bytes));
+        assert_eq!(sexp_from_bytea(&bytes).to_string_repr(), sexp.to_string_repr());
+
+        assert!(!sexp_binary_is_valid(&[]));
+        assert!(!sexp_binary_is_valid(&[FORMAT_VERSION, tags::LIST, 2, tags::NIL]));
+        assert!(!sexp_binary_is_valid(&[FORMAT_VERSION, tags::STRING, 5, b'a']));
+        assert!(!sexp_binary_is_valid(&[FORMAT_VERSION, 0x7f]));
+        assert!(!sexp_binary_is_valid(&[FORMAT_VERSION, tags::NIL, tags::NIL]));
+    }
+
+    #[pg_test(error = "invalid sexp binary: unsupported format version 9")]
+    fn test_bytea_rejects_unknown_version() {
+        sexp_from_bytea(&[9, tags::NIL]);
+    }
+
+    #[pg_test]
+    fn test_from_c_binary() {
+        // (define x "hi" -3 1000) as written by the C extension
+        let data = [
+            6, 2, 6, b'd', b'e', b'f', b'i', b'n', b'e', 1, b'x', // symbol table
+            0xE4, 11, 0x80, 0, 0x80, 1, 0xA2, b'h', b'i', 0x2D, 0x40, 0xD0, 0x0F,
+        ];
+        assert_eq!(sexp_from_c_binary(&data).to_string_repr(), "(define x \"hi\" -3 1000)");
+        let mut flagged = data;
+        flagged[0] |= 0x30;
+        assert_eq!(sexp_from_c_binary(&flagged).to_string_repr(), "(define x \"hi\" -3 1000)");
+
+        let rust = Sexp::input(c"(a b)");
+        assert_eq!(sexp_from_c_binary(&rust.data).to_string_repr(), "(a b)");
+    }
+
+    #[pg_test]
+    fn test_storage_version() {
+        let sexp = Sexp::input(c"(a 1)");
+        let version = Spi::get_one::<i32>("SELECT sexp_storage_version('(a 1)')").unwrap();
+        assert_eq!(version, Some(FORMAT_VERSION as i32));
+        assert_eq!(sexp_upgrade(sexp.clone()).data, sexp.data);
+
+        // A value in the legacy CBOR encoding reports version 0 and is
+        // rewritten into the current format, through sexp_upgrade or a bytea
+        let legacy = pgrx::datum::cbor_encode(&sexp);
+        assert_eq!(unsafe { datum_storage_version(pg_sys::Datum::from(legacy.cast_mut())) }, 0);
+        let upgraded = sexp_upgrade(unsafe { Sexp::from_datum(pg_sys::Datum::from(legacy.cast_mut()), false) }.unwrap());
+        assert_eq!(upgraded.data, sexp.data);
+        let bytes = unsafe { pgrx::varlena::varlena_to_byte_slice(legacy) };
+        assert_eq!(sexp_from_bytea(bytes).data, sexp.data);
+        assert!(sexp_binary_is_valid(bytes));
+        assert!(!sexp_binary_is_valid(&[LEGACY_CBOR_MAP, 0xff]));
+        let upgraded = unsafe { datum_storage_version(sexp_from_bytea(bytes).into_datum().unwrap()) };
+        assert_eq!(upgraded, FORMAT_VERSION as i32);
+    }
+
+    #[pg_test]
+    fn test_parse_forms() {
+        let forms: Vec<String> = sexp_parse_forms("(set-logic QF_LIA) ; comment\n(assert (> x 0))\n42 ")
+            .map(|s| s.to_string_repr())
+            .collect();
+        assert_eq!(forms, vec!["(set-logic QF_LIA)", "(assert (> x 0))", "42"]);
+        assert_eq!(sexp_parse_forms("  ; nothing\n").count(), 0);
+        // The parser GUCs change what the text reads as
+        let volatility =
+            Spi::get_one::<String>("SELECT provolatile::text FROM pg_proc WHERE proname = 'sexp_parse_forms'").unwrap();
+        assert_eq!(volatility.as_deref(), Some("s"));
+    }
+
+    /// Text of what StreamParser reads from `input` fed in `chunk_size` byte
+    /// chunks
+    fn stream_parse(input: &str, chunk_size: usize) -> Result<String, String> {
+        let mut chunks = input.as_bytes().chunks(chunk_size).map(<[u8]>::to_vec).collect::<Vec<_>>().into_iter();
+        let mut data = vec![FORMAT_VERSION];
+        StreamParser::new(|| chunks.next().unwrap_or_default()).parse_into(&mut data)?;
+        Ok(Sexp::from_bytes(&data).to_string_repr())
+    }
+
+    #[pg_test]
+    fn test_stream_parser_across_chunks() {
+        let input = "(define (f x) \"a \\\"quoted\\\" s\" ; note\n 12 3.5 café)";
+        for size in [1, 2, 3, 7, 1024] {
+            assert_eq!(stream_parse(input, size).unwrap(), "(define (f x) \"a \\\"quoted\\\" s\" 12 3.5 café)");
+        }
+        assert_eq!(stream_parse("  ; nothing\n", 2).unwrap(), "()");
+        assert_eq!(stream_parse("(a (b", 2).unwrap_err(), "unterminated list");
+        assert_eq!(stream_parse("(a) b", 2).unwrap_err(), "multiple top-level expressions");
+        assert_eq!(stream_parse("(a))", 2).unwrap_err(), "unexpected ')'");
+        let long = format!("(\"{}\" {})", "x".repeat(5000), "y".repeat(5000));
+        assert_eq!(stream_parse(&long, 3).unwrap(), long);
+    }
+
+    #[pg_test]
+    fn test_stream_parser_dialects() {
+        let cases = [
+            ("scheme", "(a |two words| #t (b . c) \"s\" (|| x) (|> y f |> g))"),
+            ("elisp", "((name . \"x\") (tags . (a b)) (v . nil) (w . [1 2]) ?a ?\\( ?\\x41 #s(point 1 2))"),
+            ("clojure", "{:a [1, 2] :b #{x}}"),
+            ("smtlib", "(assert (= |x y| \"a\"\"b\" 1.50 #b0101))"),
+            ("kicad", "(pad yes no)"),
+        ];
+        for (dialect, input) in cases {
+            Spi::run(&format!("SET pg_sexp.dialect = '{}'", dialect)).unwrap();
+            let expected =
+                Sexp::from_parsed(&Parser::with_settings(input, ParseSettings::current()).parse().unwrap()).to_string_repr();
+            for size in [1, 2, 5] {
+                assert_eq!(stream_parse(input, size).unwrap(), expected, "{} in {} byte chunks", dialect, size);
+            }
+        }
+        Spi::run("SET pg_sexp.dialect = 'elisp'").unwrap();
+        assert_eq!(stream_parse("(a . b c)", 3).unwrap_err(), "misplaced dot in list");
+        Spi::run("RESET pg_sexp.dialect").unwrap();
+    }
+
+    #[pg_test]
+    fn test_sexp_from_lo() {
+        let lo = Spi::get_one::<pg_sys::Oid>("SELECT lo_from_bytea(0, '(a (b c) \"d\")')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(sexp_from_lo(lo).to_string_repr(), "(a (b c) \"d\")");
+        // The object can change within a statement, and workers cannot read it
+        let volatility =
+            Spi::get_one::<String>("SELECT provolatile::text || proparallel FROM pg_proc WHERE proname = 'sexp_from_lo'")
+                .unwrap();
+        assert_eq!(volatility.as_deref(), Some("vu"));
+    }
+
+    #[pg_test]
+    fn test_row_to_sexp() {
+        let row = Spi::get_one::<Sexp>(
+            "SELECT row_to_sexp(r) FROM (SELECT 1 AS id, 'x' AS name, true AS ok, NULL::int AS gone) r",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(row.to_string_repr(), "((id 1) (name \"x\") (ok #t) (gone ()))");
+    }
+
+    #[pg_test]
+    fn test_audit_trigger() {
+        Spi::run("CREATE TABLE audited (id int, note text)").unwrap();
+        Spi::run("CREATE TRIGGER audited_log AFTER INSERT OR UPDATE ON audited FOR EACH ROW EXECUTE FUNCTION sexp_audit()").unwrap();
+        Spi::run("INSERT INTO audited VALUES (1, 'a')").unwrap();
+        Spi::run("UPDATE audited SET note = 'b'").unwrap();
+
+        let entry = Spi::get_one::<Sexp>("SELECT entry FROM sexp_audit_log WHERE operation = 'update'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            entry.to_string_repr(),
+            "(update (table audited) (old ((id 1) (note \"a\"))) (new ((id 1) (note \"b\"))))"
+        );
+
+        // The trigger does not depend on the caller's search_path
+        let schema = Spi::get_one::<String>("SELECT current_schema()").unwrap().unwrap();
+        Spi::run("SET search_path = pg_catalog").unwrap();
+        Spi::run(&format!("INSERT INTO {}.audited VALUES (2, 'c')", pgrx::spi::quote_identifier(&schema))).unwrap();
+        Spi::run("RESET search_path").unwrap();
+        let inserts = Spi::get_one::<i64>("SELECT count(*) FROM sexp_audit_log WHERE operation = 'insert'").unwrap();
+        assert_eq!(inserts, Some(2));
+
+        // The audit table argument is a table name, not SQL
+        Spi::run("CREATE TABLE \"Audit Log\" (LIKE sexp_audit_log INCLUDING DEFAULTS)").unwrap();
+        Spi::run(
+            "CREATE TRIGGER audited_named AFTER DELETE ON audited FOR EACH ROW \
+             EXECUTE FUNCTION sexp_audit('\"Audit Log\"')",
+        )
+        .unwrap();
+        Spi::run("DELETE FROM audited").unwrap();
+        let logged = Spi::get_one::<i64>("SELECT count(*) FROM \"Audit Log\" WHERE operation = 'delete'").unwrap();
+        assert_eq!(logged, Some(1));
+
+        let dumped = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_extension, unnest(extconfig) c \
+             WHERE extname = 'pg_sexp_rs' AND c IN ('sexp_audit_log'::regclass, 'sexp_audit_log_id_seq'::regclass)",
+        )
+        .unwrap();
+        assert_eq!(dumped, Some(2));
+    }
+
+    #[pg_test]
+    fn test_flatten() {
+        let nested = || Sexp::input(c"(a (b (c ()) d) e)");
+        assert_eq!(sexp_flatten(nested(), None).to_string_repr(), "(a b c d e)");
+        assert_eq!(sexp_flatten(nested(), Some(1)).to_string_repr(), "(a b (c ()) d e)");
+        assert_eq!(sexp_flatten(nested(), Some(0)).to_string_repr(), "(a (b (c ()) d) e)");
+        assert_eq!(sexp_flatten(Sexp::input(c"atom"), None).to_string_repr(), "atom");
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_flatten_depth_limit() {
+        let nested = Sexp::input(&std::ffi::CString::new(format!("{}{}", "(a ".repeat(20), ")".repeat(20))).unwrap());
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_flatten(nested, None);
+    }
+
+    #[pg_test]
+    fn test_take_drop_slice() {
+        let list = || Sexp::input(c"(a b c d)");
+        assert_eq!(sexp_take(list(), 2).to_string_repr(), "(a b)");
+        assert_eq!(sexp_take(list(), 10).to_string_repr(), "(a b c d)");
+        assert_eq!(sexp_drop(list(), 3).to_string_repr(), "(d)");
+        assert_eq!(sexp_drop(list(), 4).to_string_repr(), "()");
+        assert_eq!(sexp_slice(list(), 1, 3).to_string_repr(), "(b c)");
+        assert_eq!(sexp_slice(list(), 3, 1).to_string_repr(), "()");
+    }
+
+    #[pg_test]
+    fn test_last_butlast() {
+        assert_eq!(sexp_last(Sexp::input(c"(a b (c))")).unwrap().to_string_repr(), "(c)");
+        assert!(sexp_last(Sexp::nil()).is_none());
+        assert_eq!(sexp_butlast(Sexp::input(c"(a b (c))")).to_string_repr(), "(a b)");
+        assert_eq!(sexp_butlast(Sexp::input(c"(a)")).to_string_repr(), "()");
+    }
+
+    #[pg_test]
+    fn test_sort() {
+        let sorted = sexp_sort(Sexp::input(c"((b) \"s\" 2.5 b 10 () a 2 (a z))"));
+        assert_eq!(sorted.to_string_repr(), "(() 2 2.5 10 a b \"s\" (a z) (b))");
+        assert_eq!(sexp_cmp(Sexp::input(c"1"), Sexp::input(c"1.0")), -1);
+        assert_eq!(sexp_cmp(Sexp::input(c"(a b)"), Sexp::input(c"(a b)")), 0);
+    }
+
+    #[pg_test]
+    fn test_sort_by_path() {
+        let people = Sexp::input(c"(((name \"b\") (age 40)) ((name \"a\")) ((name \"c\") (age 30)))");
+        assert_eq!(
+            sexp_sort_by(people, vec!["age".into()]).to_string_repr(),
+            "(((name \"a\")) ((name \"c\") (age 30)) ((name \"b\") (age 40)))"
+        );
+    }
+
+    #[pg_test]
+    fn test_dedupe() {
+        let list = Sexp::input(c"(b (x 1) a b (x 1) (x 1.0) \"a\")");
+        assert_eq!(sexp_dedupe(list).to_string_repr(), "(b (x 1) a (x 1.0) \"a\")");
+    }
+
+    #[pg_test]
+    fn test_zip_unzip() {
+        let zipped = sexp_zip(Sexp::input(c"(a b c)"), Sexp::input(c"(1 2)"));
+        assert_eq!(zipped.to_string_repr(), "((a 1) (b 2))");
+        assert_eq!(sexp_unzip(zipped).to_string_repr(), "((a b) (1 2))");
+        assert_eq!(sexp_unzip(Sexp::nil()).to_string_repr(), "(() ())");
+    }
+
+    #[pg_test]
+    fn test_sexp_map() {
+        Spi::run("CREATE FUNCTION wrap(sexp) RETURNS sexp AS $$ SELECT ('(w ' || $1::text || ')')::sexp $$ LANGUAGE SQL").unwrap();
+        let mapped = Spi::get_one::<Sexp>("SELECT sexp_map('(a (b) 1)', 'wrap(sexp)')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(mapped.to_string_repr(), "((w a) (w (b)) (w 1))");
+
+        // The SQL wrapper does not depend on the caller's search_path
+        Spi::run("CREATE FUNCTION same(sexp) RETURNS sexp AS $$ SELECT $1 $$ LANGUAGE SQL").unwrap();
+        let schema = Spi::get_one::<String>("SELECT quote_ident(current_schema())").unwrap().unwrap();
+        Spi::run("SET search_path = pg_catalog").unwrap();
+        let mapped = Spi::get_one::<String>(&format!(
+            "SELECT {0}.sexp_map('(a b)'::{0}.sexp, '{0}.same({0}.sexp)')::text",
+            schema
+        ))
+        .unwrap();
+        Spi::run("RESET search_path").unwrap();
+        assert_eq!(mapped.as_deref(), Some("(a b)"));
+    }
+
+    #[pg_test(error = "sexp_map: function wrap_text(text) must take one sexp argument and return sexp")]
+    fn test_sexp_map_rejects_other_argument_types() {
+        Spi::run("CREATE FUNCTION wrap_text(text) RETURNS sexp AS $$ SELECT $1::sexp $$ LANGUAGE SQL").unwrap();
+        Spi::run("SELECT sexp_map('(a b)', 'wrap_text(text)')").unwrap();
+    }
+
+    #[pg_test(error = "permission denied for function secret_wrap")]
+    fn test_sexp_map_checks_execute_privilege() {
+        Spi::run("CREATE FUNCTION public.secret_wrap(sexp) RETURNS sexp AS $$ SELECT $1 $$ LANGUAGE SQL").unwrap();
+        Spi::run("REVOKE EXECUTE ON FUNCTION public.secret_wrap(sexp) FROM PUBLIC").unwrap();
+        Spi::run("CREATE ROLE sexp_map_unprivileged").unwrap();
+        Spi::run("SET ROLE sexp_map_unprivileged").unwrap();
+        Spi::run("SELECT sexp_map('(a b)', 'public.secret_wrap(sexp)')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_filter() {
+        let events = Sexp::input(c"((click 1) (view 2) (click 3 extra) (click 4))");
+        assert_eq!(
+            sexp_filter(events, Sexp::input(c"(click _)")).to_string_repr(),
+            "((click 1) (click 4))"
+        );
+    }
+
+    #[pg_test]
+    fn test_positional_edits() {
+        let list = || Sexp::input(c"(a b c)");
+        assert_eq!(sexp_set_nth(list(), 1, Sexp::input(c"(x)")).to_string_repr(), "(a (x) c)");
+        assert_eq!(sexp_insert_at(list(), 3, Sexp::input(c"d")).to_string_repr(), "(a b c d)");
+        assert_eq!(sexp_insert_at(list(), 0, Sexp::input(c"z")).to_string_repr(), "(z a b c)");
+        assert_eq!(sexp_splice(list(), 1, Sexp::input(c"(x y)")).to_string_repr(), "(a x y c)");
+        assert_eq!(sexp_splice(list(), 2, Sexp::nil()).to_string_repr(), "(a b)");
+    }
+
+    #[pg_test(error = "sexp_set_nth: index 3 out of range for list of length 3")]
+    fn test_set_nth_out_of_range() {
+        sexp_set_nth(Sexp::input(c"(a b c)"), 3, Sexp::nil());
+    }
+
+    #[pg_test]
+    fn test_negative_indexing() {
+        let list = || Sexp::input(c"(a b c d)");
+        assert_eq!(sexp_nth(list(), -1).unwrap().to_string_repr(), "d");
+        assert_eq!(sexp_nth(list(), -4).unwrap().to_string_repr(), "a");
+        assert!(sexp_nth(list(), -5).is_none());
+        assert_eq!(sexp_slice(list(), -3, -1).to_string_repr(), "(b c)");
+        assert_eq!(sexp_drop(list(), -1).to_string_repr(), "(d)");
+        assert_eq!(
+            path_value(&Sexp::input(c"((k 1 2 3))").data[1..], &["k".into(), "-1".into()])
+                .unwrap()
+                .to_string_repr(),
+            "3"
+        );
+    }
+
+    #[pg_test]
+    fn test_stats() {
+        let sexp = Sexp::input(c"(a (b c (d)) () \"s\")");
+        let rows: Vec<_> = sexp_shape_stats(sexp.clone()).collect();
+        assert_eq!(rows, vec![(3, 5, 3, 4, sexp.data.len() as i64)]);
+        let atom: Vec<_> = sexp_shape_stats(Sexp::input(c"42")).map(|r| (r.0, r.1, r.2)).collect();
+        assert_eq!(atom, vec![(0, 1, 0)]);
+
+        // A composite value, usable per column or expanded in a select list
+        let depth = Spi::get_one::<i32>("SELECT (sexp_stats('(a (b c (d)) () \"s\")')).depth").unwrap();
+        assert_eq!(depth, Some(3));
+        let (atoms, fanout) = Spi::get_two::<i64, i32>(
+            "SELECT s.atom_count, s.max_fanout FROM (SELECT (sexp_stats(d)).* FROM (VALUES ('(x (y z))'::sexp)) v(d)) s",
+        )
+        .unwrap();
+        assert_eq!((atoms, fanout), (Some(3), Some(2)));
+        let kind = Spi::get_one::<String>("SELECT pg_typeof(sexp_stats('a'))::text").unwrap();
+        assert_eq!(kind.as_deref(), Some("sexp_shape"));
+    }
+
+    #[pg_test]
+    fn test_depth_and_node_count() {
+        assert_eq!(sexp_depth(Sexp::input(c"(a (b (c)))")), 3);
+        assert_eq!(sexp_depth(Sexp::input(c"a")), 0);
+        assert_eq!(sexp_node_count(Sexp::input(c"(a (b ()))")), 5);
+        assert_eq!(sexp_size_bytes(Sexp::nil()), 2);
+    }
+
+    #[pg_test]
+    fn test_random_is_deterministic() {
+        let a = sexp_random(42, 4, 3);
+        assert_eq!(a.to_string_repr(), sexp_random(42, 4, 3).to_string_repr());
+        assert!(sexp_depth(a) <= 4);
+        assert!(Preorder::new(&sexp_random(7, 5, 2).data, 1)
+            .filter(|item| item.tag == tags::LIST)
+            .all(|item| item.depth < 5));
+        assert_eq!(sexp_depth(sexp_random(1, 0, 3)), 0);
+        assert!((0..50).all(|seed| sexp_random(seed, 1, 3).is_list()));
+    }
+
+    #[pg_test]
+    fn test_assert_eq_passes_on_equal() {
+        sexp_assert_eq(Sexp::input(c"(a (b 1))"), Sexp::input(c"(a (b 1))"));
+    }
+
+    #[pg_test(error = "sexp values differ at path {1,1}: expected 1, actual 1.5")]
+    fn test_assert_eq_reports_path() {
+        sexp_assert_eq(Sexp::input(c"(a (b 1) c)"), Sexp::input(c"(a (b 1.5) c)"));
+    }
+
+    #[pg_test(error = "sexp values differ at path {}: expected (a b) (length 2), actual (a) (length 1)")]
+    fn test_assert_eq_reports_length() {
+        sexp_assert_eq(Sexp::input(c"(a b)"), Sexp::input(c"(a)"));
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_assert_eq_depth_limit() {
+        let nested = |leaf: &str| {
+            Sexp::input(&std::ffi::CString::new(format!("{}{}{}", "(".repeat(20), leaf, ")".repeat(20))).unwrap())
+        };
+        let (expected, actual) = (nested("1"), nested("2"));
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_assert_eq(expected, actual);
+    }
+
+    #[pg_test]
+    fn test_lint() {
+        let sexp = Sexp::input(c"((id 1) (tags 1 \"two\" 3) (id 2) (price 1,50))");
+        let codes: Vec<(String, String)> = sexp_lint(sexp).map(|(path, code, _)| (path, code)).collect();
+        assert_eq!(
+            codes,
+            vec![
+                ("{}".to_string(), "duplicate-key".to_string()),
+                ("{1}".to_string(), "mixed-types".to_string()),
+                ("{3,1}".to_string(), "numeric-symbol".to_string()),
+            ]
+        );
+        assert_eq!(sexp_lint(Sexp::input(c"(define (f x) (+ x 1))")).count(), 0);
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_lint_depth_limit() {
+        let nested = Sexp::input(&std::ffi::CString::new(format!("{}{}", "(a ".repeat(20), ")".repeat(20))).unwrap());
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_lint(nested).count();
+    }
+
+    #[pg_test]
+    fn test_numeric_equality() {
+        assert!(sexp_eq_num(Sexp::input(c"(a 1 (b 2))"), Sexp::input(c"(a 1.0 (b 2.0))")));
+        assert!(!sexp_eq_num(Sexp::input(c"(a 1)"), Sexp::input(c"(a 1.5)")));
+        assert!(!sexp_eq_num(Sexp::input(c"(a 1)"), Sexp::input(c"(a \"1\")")));
+        assert!(sexp_contains_num(Sexp::input(c"(x (a 1.0) y)"), Sexp::input(c"(a 1)")));
+        assert!(!sexp_contains(Sexp::input(c"(x (a 1.0) y)"), Sexp::input(c"(a 1)")));
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_numeric_equality_depth_limit() {
+        let nested = || Sexp::input(&std::ffi::CString::new(format!("{}{}", "(a ".repeat(20), ")".repeat(20))).unwrap());
+        let (a, b) = (nested(), nested());
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_eq_num(a, b);
+    }
+
+    #[pg_test]
+    fn test_tree_text() {
+        let tree = sexp_to_tree_text(Sexp::input(c"(define (f x) \"doc\" ())"));
+        assert_eq!(
+            tree,
+            "list (4)\n|-- define\n|-- list (2)\n|   |-- f\n|   `-- x\n|-- \"doc\"\n`-- ()"
+        );
+        assert_eq!(sexp_to_tree_text(Sexp::input(c"atom")), "atom");
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_tree_text_depth_limit() {
+        let nested = Sexp::input(&std::ffi::CString::new(format!("{}{}", "(".repeat(20), ")".repeat(20))).unwrap());
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_to_tree_text(nested);
+    }
+
+    #[pg_test]
+    fn test_float_round_trip() {
+        for text in ["(1.0 0.1 -2.5e-8 1e300 123456789.123 5e-324)", "(0.30000000000000004)"] {
+            let sexp = Sexp::input(&std::ffi::CString::new(text).unwrap());
+            let printed = sexp.to_string_repr();
+            let reparsed = Sexp::input(&std::ffi::CString::new(printed.clone()).unwrap());
+            assert_eq!(reparsed.data, sexp.data, "{} printed as {}", text, printed);
+        }
+        assert_eq!(Sexp::input(c"2.0").to_string_repr(), "2.0");
+    }
+
+    #[pg_test]
+    fn test_float_precision_guc() {
+        Spi::run("SET pg_sexp.float_precision = 2").unwrap();
+        assert_eq!(sexp_to_text(Sexp::input(c"(3.14159 2.0)"), "scheme"), "(3.14 2.00)");
+        assert_eq!(sexp_to_tree_text(Sexp::input(c"(3.14159)")), "list (1)\n`-- 3.14");
+        // The output function keeps every float exact, so COPY and pg_dump do too
+        assert_eq!(Sexp::input(c"(3.14159 2.0)").to_string_repr(), "(3.14159 2.0)");
+        let copied = Spi::get_one::<String>("SELECT '(3.14159 2.0)'::sexp::text").unwrap();
+        assert_eq!(copied.as_deref(), Some("(3.14159 2.0)"));
+        Spi::run("SET pg_sexp.float_precision = 0").unwrap();
+        assert_eq!(sexp_to_text(Sexp::input(c"2.7"), "scheme"), "3.0");
+        assert_eq!(Sexp::input(c"2.7").to_string_repr(), "2.7");
+        Spi::run("RESET pg_sexp.float_precision").unwrap();
+    }
+
+    #[pg_test]
+    fn test_nil_rendering_gucs() {
+        Spi::run("SET pg_sexp.nil_output = 'nil'").unwrap();
+        assert_eq!(Sexp::input(c"(a ())").to_string_repr(), "(a nil)");
+        Spi::run("RESET pg_sexp.nil_output").unwrap();
+
+        assert!(Sexp::input(c"nil").is_nil());
+        Spi::run("SET pg_sexp.nil_is_symbol = on").unwrap();
+        let sym = sexp_parse("(nil ())", None);
+        assert_eq!(sym.to_string_repr(), "(|nil| ())");
+        assert_eq!(sexp_typeof(sym.car().unwrap()), "symbol");
+        // The input function reads nil as the empty list regardless
+        assert!(Sexp::input(c"nil").is_nil());
+        Spi::run("RESET pg_sexp.nil_is_symbol").unwrap();
+    }
+
+    #[pg_test]
+    fn test_boolean_literals() {
+        let sexp = Sexp::input(c"(#t #f true)");
+        assert_eq!(sexp.to_string_repr(), "(#t #f true)");
+        assert!(sexp_is_boolean(sexp.car().unwrap()));
+        assert!(!sexp_is_boolean(sexp.nth(2).unwrap()));
+        assert!(sexp_to_bool(sexp.car().unwrap()));
+        assert!(!sexp_to_bool(sexp_from_bool(false)));
+
+        Spi::run("SET pg_sexp.parse_true_false = on").unwrap();
+        assert!(sexp_is_boolean(sexp_parse("false", None)));
+        assert!(!sexp_is_boolean(Sexp::input(c"false")));
+        Spi::run("RESET pg_sexp.parse_true_false").unwrap();
+    }
+
+    #[pg_test]
+    fn test_exact_decimals() {
+        let price = Sexp::input(c"(price 12.30m -0.5m)");
+        assert_eq!(price.to_string_repr(), "(price 12.30m -0.5m)");
+        assert_eq!(sexp_typeof(price.nth(1).unwrap()), "decimal");
+        assert_eq!(sexp_to_numeric(price.nth(1).unwrap()).to_string(), "12.30");
+        assert_eq!(sexp_from_numeric(AnyNumeric::from(7)).to_string_repr(), "7.0m");
+        assert_eq!(sexp_typeof(Sexp::input(c"7.0m")), "decimal");
+        // The suffix needs a fraction, so durations stay symbols
+        assert_eq!(sexp_typeof(Sexp::input(c"5m")), "symbol");
+        assert_eq!(sexp_typeof(Sexp::input(c"30m")), "symbol");
+
+        Spi::run("SET pg_sexp.exact_decimals = on").unwrap();
+        assert_eq!(sexp_typeof(sexp_parse("0.10", None)), "decimal");
+        assert_eq!(sexp_typeof(Sexp::input(c"0.10")), "float");
+        Spi::run("RESET pg_sexp.exact_decimals").unwrap();
+        assert_eq!(sexp_typeof(sexp_parse("0.10", None)), "float");
+
+        // Decimals compare by exact value, not through floats
+        assert!(sexp_eq_num(Sexp::input(c"(a 1.50m 2.0m)"), Sexp::input(c"(a 1.5 2)")));
+        assert!(sexp_eq_num(Sexp::input(c"-0.0m"), Sexp::input(c"0.0m")));
+        assert!(!sexp_eq_num(Sexp::input(c"0.10000000000000000001m"), Sexp::input(c"0.1m")));
+
+        // Ordering is exact too, beyond what a float can tell apart
+        let ordered = "SELECT array_agg(v::text ORDER BY v) FROM unnest(ARRAY['100000000000000000000.2m', \
+                       '100000000000000000000.1m', '-3.5m', '-3.25m', '0.10000000000000000001m', '0.1m']::sexp[]) v";
+        assert_eq!(
+            Spi::get_one::<Vec<String>>(ordered).unwrap().unwrap(),
+            vec!["-3.5m", "-3.25m", "0.1m", "0.10000000000000000001m", "100000000000000000000.1m", "100000000000000000000.2m"]
+        );
+        // Integers, floats and decimals share one transitive order, even
+        // where f64 can't tell the integer and float apart
+        let ordered = "SELECT array_agg(v::text ORDER BY v) FROM unnest(ARRAY['9007199254740992.5m', \
+                       '9007199254740993', '9007199254740992.0']::sexp[]) v";
+        assert_eq!(
+            Spi::get_one::<Vec<String>>(ordered).unwrap().unwrap(),
+            vec!["9007199254740992.0", "9007199254740992.5m", "9007199254740993"]
+        );
+        let (a, b, c) = (
+            Sexp::input(c"9007199254740993"),
+            Sexp::input(c"9007199254740992.0"),
+            Sexp::input(c"9007199254740992.5m"),
+        );
+        assert!(sexp_lt(b.clone(), c.clone()) && sexp_lt(c, a.clone()) && sexp_lt(b, a));
+        // Equal values with different digits still differ as bytes
+        assert!(Spi::get_one::<bool>("SELECT '1.10m'::sexp <> '1.1m'::sexp").unwrap().unwrap());
+        assert!(Spi::get_one::<bool>("SELECT '1.10m'::sexp > '1.1m'::sexp OR '1.10m'::sexp < '1.1m'::sexp").unwrap().unwrap());
+    }
+
+    #[pg_test]
+    fn test_gin_decimal_keys() {
+        Spi::run("CREATE TABLE prices (doc sexp)").unwrap();
+        Spi::run("INSERT INTO prices SELECT format('(item (id %s) (price %s.5m))', i, i % 4)::sexp FROM generate_series(1, 20) i")
+            .unwrap();
+        let query = "SELECT count(*) FROM prices WHERE doc @> '1.5m' AND doc @> '(price 1.5m)'";
+        let seqscan = Spi::get_one::<i64>(query).unwrap();
+        Spi::run("CREATE INDEX ON prices USING gin (doc)").unwrap();
+        Spi::run("SET enable_seqscan = off").unwrap();
+        let indexed = Spi::get_one::<i64>(query).unwrap();
+        Spi::run("RESET enable_seqscan").unwrap();
+        assert_eq!(seqscan, Some(5));
+        assert_eq!(indexed, seqscan);
+    }
+
+    #[pg_test]
+    fn test_typeof_path() {
+        let doc = || Sexp::input(c"((server (host \"db\") (ports 80 443)) (debug))");
+        assert_eq!(sexp_typeof_path(doc(), vec!["server".into(), "host".into()]).unwrap(), "string");
+        assert_eq!(sexp_typeof_path(doc(), vec!["server".into(), "ports".into()]).unwrap(), "list");
+        assert_eq!(sexp_typeof_path(doc(), vec!["debug".into()]).unwrap(), "nil");
+        assert!(sexp_typeof_path(doc(), vec!["missing".into()]).is_none());
+    }
+
+    #[pg_test]
+    fn test_length_at() {
+        let doc = || Sexp::input(c"((server (ports 80 443 8080)) (tags (a b)))");
+        assert_eq!(sexp_length_at(doc(), vec!["server".into(), "ports".into()]), Some(3));
+        assert_eq!(sexp_length_at(doc(), vec!["tags".into()]), Some(2));
+        assert_eq!(sexp_length_at(doc(), vec![]), Some(2));
+        assert_eq!(sexp_length_at(doc(), vec!["nope".into()]), None);
+    }
+
+    #[pg_test]
+    fn test_keys_at() {
+        let doc = || Sexp::input(c"((server (host \"db\") (port 5432)) (debug #t))");
+        assert_eq!(sexp_keys(doc()), vec!["server", "debug"]);
+        assert_eq!(sexp_keys_at(doc(), vec!["server".into()]).unwrap(), vec!["host", "port"]);
+        assert!(sexp_keys_at(doc(), vec!["debug".into()]).unwrap().is_empty());
+        assert!(sexp_keys_at(doc(), vec!["missing".into()]).is_none());
+    }
+
+    #[pg_test]
+    fn test_assoc_set_del() {
+        let doc = || Sexp::input(c"((host \"a\") (port 1))");
+        assert_eq!(
+            sexp_assoc_set(doc(), "port", Sexp::input(c"2")).to_string_repr(),
+            "((host \"a\") (port 2))"
+        );
+        assert_eq!(
+            sexp_assoc_set(doc(), "debug", Sexp::input(c"#t")).to_string_repr(),
+            "((host \"a\") (port 1) (debug #t))"
+        );
+        assert_eq!(sexp_assoc_set(Sexp::nil(), "a", Sexp::nil()).to_string_repr(), "((a ()))");
+        assert_eq!(sexp_assoc_del(doc(), "host").to_string_repr(), "((port 1))");
+        assert_eq!(sexp_assoc_del(doc(), "missing").to_string_repr(), "((host \"a\") (port 1))");
+    }
+
+    #[pg_test]
+    fn test_gin_pattern_match() {
+        Spi::run("CREATE TABLE forms (f sexp)").unwrap();
+        Spi::run("INSERT INTO forms VALUES ('(define x 1)'), ('(define y \"s\")'), ('(set! x 2)')").unwrap();
+        Spi::run("CREATE INDEX ON forms USING gin (f)").unwrap();
+        Spi::run("SET enable_seqscan = off").unwrap();
+        let hits = Spi::get_one::<i64>("SELECT count(*) FROM forms WHERE f ~ '(define _ _)'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hits, 2);
+        let hits = Spi::get_one::<i64>("SELECT count(*) FROM forms WHERE f ~ '(?op x _*)'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hits, 2);
+    }
+
+    #[pg_test]
+    fn test_gin_bool_keys() {
+        Spi::run("CREATE TABLE flags (doc sexp)").unwrap();
+        Spi::run(
+            "INSERT INTO flags SELECT format('(cfg (id %s) (debug %s))', i, CASE WHEN i % 3 = 0 THEN '#t' ELSE '#f' END)::sexp \
+             FROM generate_series(1, 30) i",
+        )
+        .unwrap();
+        let queries = ["doc @> '#t'", "doc @> '(debug #t)'", "doc @>> '(debug #f)'"];
+        let counts = |queries: &[&str]| -> Vec<Option<i64>> {
+            queries
+                .iter()
+                .map(|q| Spi::get_one::<i64>(&format!("SELECT count(*) FROM flags WHERE {}", q)).unwrap())
+                .collect()
+        };
+        let seqscan = counts(&queries);
+        Spi::run("CREATE INDEX ON flags USING gin (doc)").unwrap();
+        Spi::run("SET enable_seqscan = off").unwrap();
+        let indexed = counts(&queries);
+        Spi::run("RESET enable_seqscan").unwrap();
+        assert_eq!(seqscan, vec![Some(10), Some(10), Some(20)]);
+        assert_eq!(indexed, seqscan);
+    }
+
+    #[pg_test]
+    fn test_brin_minmax() {
+        Spi::run("CREATE TABLE events (e sexp)").unwrap();
+        Spi::run("INSERT INTO events SELECT i::text::sexp FROM generate_series(1, 1000) i").unwrap();
+        Spi::run("CREATE INDEX ON events USING brin (e)").unwrap();
+        Spi::run("SET enable_seqscan = off").unwrap();
+        let hits = Spi::get_one::<i64>("SELECT count(*) FROM events WHERE e >= '990'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hits, 11);
+    }
+
+    #[pg_test]
+    fn test_aggregates() {
+        let list = Spi::get_one::<Sexp>(
+            "SELECT sexp_agg(v ORDER BY v) FROM (VALUES ('b'::sexp), ('(a 1)'), (NULL)) t(v)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(list.to_string_repr(), "(b (a 1) ())");
+        let alist = Spi::get_one::<Sexp>(
+            "SELECT sexp_object_agg(k, v::sexp) FROM (VALUES ('x', '1'), ('y', '\"s\"')) t(k, v)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(alist.to_string_repr(), "((x 1) (y \"s\"))");
+        let empty = Spi::get_one::<Sexp>("SELECT sexp_agg(v) FROM (SELECT NULL::sexp WHERE false) t(v)")
+            .unwrap();
+        assert!(empty.is_none());
+    }
+
+    #[pg_test]
+    fn test_aggregates_parallel() {
+        Spi::run("CREATE TABLE agg_docs (doc sexp)").unwrap();
+        Spi::run(
+            "INSERT INTO agg_docs SELECT format('(item %s (tag t%s))', i, i % 7)::sexp FROM generate_series(1, 5000) i",
+        )
+        .unwrap();
+        Spi::run("ANALYZE agg_docs").unwrap();
+        let query = "SELECT sexp_agg(doc), sexp_cardinality_agg(doc) FROM agg_docs";
+        // Elements in a canonical order, since workers interleave rows
+        let run = || {
+            Spi::connect(|client| {
+                let row = client.select(query, None, &[]).unwrap().first();
+                let list = row.get::<Sexp>(1).unwrap().unwrap();
+                let mut items: Vec<String> =
+                    list.elements().iter().map(|e| Sexp::from_element(e).to_string_repr()).collect();
+                items.sort();
+                (items, row.get::<i64>(2).unwrap().unwrap())
+            })
+        };
+        let serial = run();
+
+        let settings = [
+            ("parallel_setup_cost", "0"),
+            ("parallel_tuple_cost", "0"),
+            ("min_parallel_table_scan_size", "0"),
+            ("max_parallel_workers_per_gather", "2"),
+        ];
+        for (setting, value) in settings {
+            Spi::run(&format!("SET {} = {}", setting, value)).unwrap();
+        }
+        let plan = Spi::connect(|client| {
+            client
+                .select(&format!("EXPLAIN (COSTS OFF) {}", query), None, &[])
+                .unwrap()
+                .map(|row| row.get::<String>(1).unwrap().unwrap())
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+        assert!(plan.contains("Partial Aggregate"), "expected a parallel plan:\n{}", plan);
+        let parallel = run();
+        for (setting, _) in settings {
+            Spi::run(&format!("RESET {}", setting)).unwrap();
+        }
+
+        assert_eq!(serial.0.len(), 5000);
+        assert_eq!(parallel, serial);
+    }
+
+    #[pg_test]
+    fn test_bulk_containment() {
+        let docs = || {
+            vec![
+                Some(Sexp::input(c"(a (b c))")),
+                None,
+                Some(Sexp::input(c"(x (b c) y)")),
+            ]
+        };
+        assert!(sexp_any_contains(docs(), Sexp::input(c"(b c)")));
+        assert!(!sexp_any_contains(docs(), Sexp::input(c"(c b)")));
+        assert!(!sexp_all_contain(docs(), Sexp::input(c"(b c)")));
+        assert!(sexp_all_contain(vec![Some(Sexp::input(c"(a b)")), Some(Sexp::input(c"b"))], Sexp::input(c"b")));
+    }
+
+    #[pg_test]
+    fn test_list_set_ops() {
+        let a = || Sexp::input(c"(x y (z) x)");
+        let b = || Sexp::input(c"((z) w y)");
+        assert_eq!(sexp_union(a(), b()).to_string_repr(), "(x y (z) w)");
+        assert_eq!(sexp_intersect(a(), b()).to_string_repr(), "(y (z))");
+        assert_eq!(sexp_except(a(), b()).to_string_repr(), "(x)");
+        assert_eq!(sexp_except(b(), b()).to_string_repr(), "()");
+    }
+
+    #[pg_test]
+    fn test_annotated_jsonb_round_trip() {
+        let doc = Sexp::input(c"((name \"x\") (tags a b) (ratio 2.0) (price 12.30m) (ok #t) ())");
+        let plain = sexp_to_jsonb(Sexp::input(c"((name \"x\") (n 1))"), false);
+        assert_eq!(plain.0, serde_json::json!({"name": "x", "n": 1}));
+        let annotated = sexp_to_jsonb(doc.clone(), true);
+        assert_eq!(annotated.0[2], serde_json::json!([{"$sym": "ratio"}, {"$float": "2.0"}]));
+        assert_eq!(
+            jsonb_to_sexp(annotated, true).to_string_repr(),
+            doc.to_string_repr()
+        );
+
+        // Numbers do not go through a float unless written with an exponent
+        let numbers = Spi::get_one::<Sexp>(
+            "SELECT jsonb_to_sexp('[12345678901234567890, 0.10000000000000000001, -7, 1.5e3]')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(numbers.to_string_repr(), "(12345678901234567890.0m 0.10000000000000000001m -7 1500.0)");
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_jsonb_depth_limit() {
+        let nested = Sexp::input(&std::ffi::CString::new(format!("{}{}", "(a ".repeat(20), ")".repeat(20))).unwrap());
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        sexp_to_jsonb(nested, true);
+    }
+
+    #[pg_test]
+    fn test_build_alist() {
+        let alist = Spi::get_one::<Sexp>(
+            "SELECT sexp_build_alist('id', 7, 'name', 'x'::text, 'score', 1.5::float8, 'ok', true, 'body', '(a b)'::sexp, 'none', NULL::int)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            alist.to_string_repr(),
+            "((id 7) (name \"x\") (score 1.5) (ok #t) (body (a b)) (none ()))"
+        );
+    }
+
+    #[pg_test]
+    fn test_field_extractors() {
+        let doc = || Sexp::input(c"((id 42) (name \"ann\") (tags a b) (ratio 0.5))");
+        assert_eq!(sexp_field(doc(), "id").as_deref(), Some("42"));
+        assert_eq!(sexp_field(doc(), "name").as_deref(), Some("ann"));
+        assert_eq!(sexp_field(doc(), "ratio").as_deref(), Some("0.5"));
+        assert_eq!(sexp_field(doc(), "tags"), None);
+        assert_eq!(sexp_field(Sexp::input(c"(1 2)"), "id"), None);
+        // Entries inside a list that is not an alist are not fields
+        assert_eq!(sexp_field(Sexp::input(c"(user (id 1))"), "id"), None);
+        assert_eq!(sexp_field(Sexp::input(c"((big 1e300) (tiny -2.5e-7))"), "big").as_deref(), Some("1e300"));
+        assert_eq!(sexp_field(Sexp::input(c"((big 1e300) (tiny -2.5e-7))"), "tiny").as_deref(), Some("-2.5e-7"));
+        assert_eq!(sexp_field_int(doc(), "id"), Some(42));
+        assert_eq!(sexp_field_int(doc(), "name"), None);
+    }
+
+    #[pg_test]
+    fn test_gin_key_stats() {
+        Spi::run("CREATE TABLE docs (d sexp)").unwrap();
+        Spi::run("INSERT INTO docs SELECT ('(event ' || i || ')')::sexp FROM generate_series(1, 50) i").unwrap();
+        Spi::run("ANALYZE docs").unwrap();
+        let (documents, distinct) = Spi::get_two::<i64, i64>(
+            "SELECT documents, distinct_keys FROM sexp_gin_key_stats('docs', 'd') LIMIT 1",
+        )
+        .unwrap();
+        // Every document shares the `event` keys, and each adds its own integer
+        assert_eq!(documents, Some(50));
+        assert!(distinct.unwrap() > 50);
+        let classes = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(DISTINCT key_class ORDER BY key_class) \
+             FROM sexp_gin_key_stats('docs', 'd', top_n => 100)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(classes, vec!["atom", "pair"]);
+        let head = Spi::get_one::<String>("SELECT key_class FROM sexp_gin_key_classes('(f a b)') LIMIT 1").unwrap();
+        assert_eq!(head.as_deref(), Some("head"));
+    }
+
+    #[pg_test]
+    fn test_equiv() {
+        let a = || Sexp::input(c"((host \"a\") (opts ((x 1) (y 2))) (list 1 2))");
+        let b = || Sexp::input(c"((opts ((y 2) (x 1))) (list 1 2) (host \"a\"))");
+        assert!(sexp_equiv(a(), b()));
+        assert!(!sexp_equiv(Sexp::input(c"(list 1 2)"), Sexp::input(c"(list 2 1)")));
+        assert!(!sexp_equiv(Sexp::input(c"((x 1))"), Sexp::input(c"((x 1.0))")));
+        assert!(sexp_equiv_num(Sexp::input(c"((x 1) (y 2))"), Sexp::input(c"((y 2.0) (x 1))")));
+        let via_operator = Spi::get_one::<bool>("SELECT '((a 1) (b 2))'::sexp == '((b 2) (a 1))'")
+            .unwrap()
+            .unwrap();
+        assert!(via_operator);
+    }
+
+    #[pg_test]
+    fn test_occurrences() {
+        let paths: Vec<Vec<i32>> =
+            sexp_occurrences(Sexp::input(c"(f x (g x (h y)) x)"), Sexp::input(c"x")).collect();
+        assert_eq!(paths, vec![vec![1], vec![2, 1], vec![3]]);
+        let root: Vec<Vec<i32>> = sexp_occurrences(Sexp::input(c"(a b)"), Sexp::input(c"(a b)")).collect();
+        assert_eq!(root, vec![Vec::<i32>::new()]);
+        assert_eq!(sexp_occurrences(Sexp::input(c"(a b)"), Sexp::input(c"c")).count(), 0);
+    }
+
+    #[pg_test]
+    fn test_path_of_and_ancestors() {
+        let doc = || Sexp::input(c"(f (g (h x)) x)");
+        assert_eq!(sexp_path_of(doc(), Sexp::input(c"x")), Some(vec![1, 1, 1]));
+        assert_eq!(sexp_path_of(doc(), Sexp::input(c"y")), None);
+        let rows: Vec<(i32, Vec<i32>, String)> = sexp_at_ancestors(doc(), vec![1, 1, 1], 5)
+            .map(|(level, path, context)| (level, path, context.to_string_repr()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (1, vec![1, 1], "(h x)".to_string()),
+                (2, vec![1], "(g (h x))".to_string()),
+                (3, vec![], "(f (g (h x)) x)".to_string()),
+            ]
+        );
+        assert_eq!(sexp_at_ancestors(doc(), vec![9], 1).count(), 0);
+    }
+
+    #[pg_test]
+    fn test_common_subtrees() {
+        let common = Spi::get_one::<Sexp>(
+            "SELECT sexp_common_subtrees(d, 1) FROM (VALUES
+                ('(req (user 1) (ok))'::sexp), ('(req (user 2) (ok))'), ('(resp (ok) (ok))')) t(d)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(common.to_string_repr(), "((3 (ok)))");
+        let grouped = Spi::get_one::<String>(
+            "SELECT string_agg(n || ' ' || s::text, ', ') FROM (
+                SELECT s, count(*) AS n FROM (VALUES
+                    ('(req (user 1) (ok))'::sexp), ('(req (user 2) (ok))'), ('(resp (ok) (ok))')) t(d),
+                    sexp_list_subtrees(d) s
+                GROUP BY s HAVING count(*) > 1) c",
+        )
+        .unwrap();
+        assert_eq!(grouped.as_deref(), Some("3 (ok)"));
+    }
+
+    #[pg_test(error = "sexp_common_subtrees: distinct subtrees exceed work_mem")]
+    fn test_common_subtrees_work_mem() {
+        Spi::run("SET work_mem = '64kB'").unwrap();
+        Spi::run(
+            "SELECT sexp_common_subtrees(('(row ' || i || ' (pad \"' || repeat('x', 100) || '\"))')::sexp, 1) \
+             FROM generate_series(1, 1000) i",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_symbol_dict() {
+        Spi::run("CREATE TABLE configs (c sexp)").unwrap();
+        Spi::run("INSERT INTO configs VALUES ('((host \"a\") (port 80))'), ('((host \"b\") (debug #t))')").unwrap();
+        let added = Spi::get_one::<i32>("SELECT sexp_dict_build('configs', 'configs', 'c')").unwrap();
+        assert_eq!(added, Some(3));
+        // A refresh keeps existing codes and finds nothing new
+        let added = Spi::get_one::<i32>("SELECT sexp_dict_build('configs', 'configs', 'c')").unwrap();
+        assert_eq!(added, Some(0));
+
+        let doc = Sexp::input(c"((host \"a\") (port 80) (other x))");
+        let compressed = sexp_dict_compress(doc.clone(), "configs");
+        assert!(compressed.len() < doc.data.len());
+        assert_eq!(sexp_dict_expand(&compressed, "configs").to_string_repr(), doc.to_string_repr());
+
+        // Dictionary rows must survive pg_dump along with the data using them
+        let dumped = Spi::get_one::<bool>(
+            "SELECT 'sexp_symbol_dict'::regclass = ANY (extconfig) FROM pg_extension WHERE extname = 'pg_sexp_rs'",
+        )
+        .unwrap();
+        assert_eq!(dumped, Some(true));
+    }
+
+    #[pg_test(error = "sexp_symbol_dict is append-only")]
+    fn test_symbol_dict_append_only() {
+        Spi::run("INSERT INTO sexp_symbol_dict VALUES ('d', 0, 'host')").unwrap();
+        Spi::run("UPDATE sexp_symbol_dict SET symbol = 'port'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_sql_to_sexp() {
+        let tree = sql_to_sexp("SELECT a FROM t WHERE b = 1; DELETE FROM u");
+        assert_eq!(tree.elements().len(), 2);
+        assert!(sexp_contains(tree.clone(), Sexp::input(c"(relname t)")));
+        assert!(sexp_match_fn(tree.nth(0).unwrap(), Sexp::input(c"(RAWSTMT (stmt (SELECTSTMT _*)) _*)")));
+        let node = Sexp::from_parsed(&NodeTextReader::new("{A :x (i 1 2) :y <> :z \"s\\ t\"}").read_value().unwrap());
+        assert_eq!(node.to_string_repr(), "(A (x (1 2)) (y ()) (z \"s t\"))");
+        // Escaped tokens are text, whatever they look like
+        let escaped = NodeTextReader::new(r"{A :x \123 :y \true :z \<> :w \:k}").read_value().unwrap();
+        let node = Sexp::from_parsed(&escaped);
+        assert_eq!(node.to_string_repr(), "(A (x |123|) (y true) (z <>) (w :k))");
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (8)")]
+    fn test_node_text_depth_limit() {
+        Spi::run("SET pg_sexp.max_depth = 8").unwrap();
+        let _ = NodeTextReader::new(&"{A :x ".repeat(20)).read_value();
+    }
+
+    #[pg_test]
+    fn test_plan_to_sexp() {
+        Spi::run("CREATE TABLE planned (id int)").unwrap();
+        let plan = plan_to_sexp("SELECT * FROM planned ORDER BY id");
+        assert!(sexp_match_fn(plan.clone(), Sexp::input(c"(Sort _*)")));
+        assert!(sexp_contains(plan, Sexp::input(c"(relation-name \"planned\")")));
+    }
+
+    #[pg_test(error = "plan_to_sexp: query must be a single SELECT, INSERT, UPDATE or DELETE statement")]
+    fn test_plan_to_sexp_rejects_extra_statements() {
+        Spi::run("CREATE TABLE planned (id int)").unwrap();
+        plan_to_sexp("SELECT 1; DROP TABLE planned");
+    }
+
+    #[pg_test]
+    fn test_tokenize() {
+        let tokens: Vec<(String, String, i32, i32)> = sexp_tokenize("(é 1 \"s\") ; c").collect();
+        let kinds: Vec<&str> = tokens.iter().map(|t| t.0.as_str()).collect();
+        assert_eq!(kinds, vec!["open", "symbol", "integer", "string", "close", "comment"]);
+        assert_eq!(tokens[3], ("string".to_string(), "\"s\"".to_string(), 5, 8));
+        let last = sexp_tokenize("(a \"open").last().unwrap();
+        assert_eq!((last.0.as_str(), last.2), ("error", 3));
+
+        // Collections of the session dialect are brackets, not errors
+        let kinds = |input: &str| sexp_tokenize(input).map(|t| t.0).collect::<Vec<String>>();
+        Spi::run("SET pg_sexp.dialect = 'clojure'").unwrap();
+        assert_eq!(
+            kinds("{:a [1], #{x}}"),
+            ["open", "symbol", "open", "integer", "close", "open", "symbol", "close", "close"]
+        );
+        Spi::run("SET pg_sexp.dialect = 'elisp'").unwrap();
+        assert_eq!(kinds("[?a #s(x)]"), ["open", "integer", "open", "symbol", "close", "close"]);
+        Spi::run("RESET pg_sexp.dialect").unwrap();
+        let tokens: Vec<(String, String, i32, i32)> = sexp_tokenize("(é ü)").collect();
+        assert_eq!((tokens[2].2, tokens[3].2, tokens[3].3), (3, 4, 5));
+    }
+
+    #[pg_test]
+    fn test_validate_binary() {
+        let check = |data: &[u8]| sexp_validate_binary(data).next().unwrap();
+        assert_eq!(check(&Sexp::input(c"(a \"b\" 1)").data), (true, None, None));
+        // (a b) with the second symbol's length running past the end
+        assert_eq!(
+            check(&[1, tags::LIST, 2, tags::SYMBOL, 1, b'a', tags::SYMBOL, 5, b'b']),
+            (false, Some(6), Some("string length exceeds data".to_string()))
+        );
+        assert_eq!(check(&[1, tags::NIL, 0]), (false, Some(2), Some("trailing data".to_string())));
+        assert_eq!(check(&[1, 9]), (false, Some(1), Some("unknown tag 0x09".to_string())));
+    }
+
+    #[pg_test(error = "corrupted sexp value: string length exceeds data")]
+    fn test_corrupt_string_fails_loudly() {
+        let corrupt = Sexp::from_bytes(&[FORMAT_VERSION, tags::SYMBOL, 5, b'a']);
+        corrupt.to_string_repr();
+    }
+
+    #[pg_test(error = "corrupted sexp value: truncated element")]
+    fn test_truncated_list_fails_loudly() {
+        let corrupt = Sexp::from_bytes(&[FORMAT_VERSION, tags::LIST, 3, tags::NIL]);
+        corrupt.elements();
+    }
+
+    #[pg_test(error = "corrupted sexp value: string length exceeds data")]
+    fn test_gin_keys_of_corrupt_value() {
+        let corrupt = Sexp::from_bytes(&[FORMAT_VERSION, tags::LIST, 2, tags::SYMBOL, 1, b'k', tags::STRING, 9, b'v']);
+        sexp_extract_keys(corrupt);
+    }
+
+    #[pg_test]
+    fn test_deep_nesting_without_recursion() {
+        let depth = 200_000;
+        let mut data = vec![FORMAT_VERSION];
+        for _ in 0..depth {
+            data.extend_from_slice(&[tags::LIST, 1]);
+        }
+        data.extend_from_slice(&[tags::SYMBOL, 1, b'x']);
+        let deep = Sexp::from_bytes(&data);
+        Spi::run("SET pg_sexp.max_depth = 1000000").unwrap();
+        assert!(deep.contains(&Sexp::input(c"x")));
+        assert!(sexp_match_fn(deep.clone(), deep.clone()));
+    }
+
+    #[pg_test]
+    fn test_match_any_all() {
+        let any = Spi::get_one::<bool>(
+            "SELECT sexp_match_any('(define x 1)', ARRAY['(set! _ _)', '(define _ _)']::sexp[])",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(any);
+        let all = Spi::get_one::<bool>(
+            "SELECT sexp_match_all('(define x 1)', ARRAY['(define _*)', '(_ x 1)', '(set! _ _)']::sexp[])",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!all);
+        let all = Spi::get_one::<bool>(
+            "SELECT sexp_match_all('(define x 1)', ARRAY['(define _*)', '(_ x ?v)']::sexp[])",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(all);
+
+        // The shared walk agrees with matching each pattern on its own
+        Spi::run(
+            "CREATE TEMP TABLE match_exprs AS SELECT e::sexp AS e FROM unnest(ARRAY[\
+                 'a', '42', '()', '(a b)', '(a)', '(b b)', '((x) (x))', '(a (b c) c)', \
+                 '(a (b c d) c)', '(a (b (c d)) (c d))', '(a (b) (c) d)']) e",
+        )
+        .unwrap();
+        Spi::run(
+            "CREATE TEMP TABLE match_patterns AS SELECT p::sexp AS p FROM unnest(ARRAY[\
+                 '_', '?x', '42', '()', '(a b)', '(a _*)', '(a ??r)', '(?x ?x)', '(a (b ?y) ?y)', \
+                 '(a (b _*) c)', '(_ (_ _) _)', '(a ??r c)', '(a (b ??r) ??r)', '(a (_*) _*)']) p",
+        )
+        .unwrap();
+        let disagreeing = Spi::get_one::<i64>(
+            "SELECT count(*) FROM match_exprs, match_patterns \
+             WHERE sexp_match_any(e, ARRAY[p]) <> sexp_match(e, p) \
+                OR sexp_match_all(e, ARRAY[p, p]) <> sexp_match(e, p)",
+        )
+        .unwrap();
+        assert_eq!(disagreeing, Some(0));
+        let disagreeing = Spi::get_one::<i64>(
+            "SELECT count(*) FROM match_exprs \
+             WHERE sexp_match_any(e, (SELECT array_agg(p) FROM match_patterns)) \
+                   <> (SELECT bool_or(sexp_match(e, p)) FROM match_patterns) \
+                OR sexp_match_all(e, (SELECT array_agg(p) FROM match_patterns WHERE p::text LIKE '(a%')) \
+                   <> (SELECT bool_and(sexp_match(e, p)) FROM match_patterns WHERE p::text LIKE '(a%')",
+        )
+        .unwrap();
+        assert_eq!(disagreeing, Some(0));
+    }
+
+    #[pg_test]
+    fn test_case_dispatch() {
+        let result = Spi::get_one::<Sexp>(
+            "SELECT sexp_case('(define x (+ 1 2))', \
+                 '(set! ?n ?v)', '(assign ?n ?v)', \
+                 '(define ?n ?v)', '(let ((?n ?v)) ?n)')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.to_string_repr(), "(let ((x (+ 1 2))) x)");
+        let result = Spi::get_one::<Sexp>(
+            "SELECT sexp_case('(call f 1 2 3)', '(call ?f ??args)', '(apply ?f (list ??args))')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.to_string_repr(), "(apply f (list 1 2 3))");
+        let result = Spi::get_one::<Sexp>("SELECT sexp_case('(other)', '(call _*)', 'call', 'unknown')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.to_string_repr(), "unknown");
+        let result = Spi::get_one::<Sexp>("SELECT sexp_case('(other)', '(call _*)', 'call')").unwrap();
+        assert!(result.is_none());
+    }
+
+    #[pg_test]
+    fn test_case_repeated_capture() {
+        // sexp_case binds a repeated capture once; sexp_match and ~ keep
+        // matching each capture on its own
+        let arm = |expr: &str| {
+            Spi::get_one::<Sexp>(&format!(
+                "SELECT sexp_case('{}', '(?x ?x)', 'same', '(f (??r) ??r)', 'spliced', 'other')",
+                expr
+            ))
+            .unwrap()
+            .unwrap()
+            .to_string_repr()
+        };
+        assert_eq!(arm("((a b) (a b))"), "same");
+        assert_eq!(arm("(1 2)"), "other");
+        assert_eq!(arm("(f (a b) a b)"), "spliced");
+        assert_eq!(arm("(f (a b) a c)"), "other");
+        assert!(sexp_match_fn(Sexp::input(c"(1 2)"), Sexp::input(c"(?x ?x)")));
+        assert_eq!(Spi::get_one::<bool>("SELECT '(1 2)'::sexp ~ '(?x ?x)'::sexp").unwrap(), Some(true));
+    }
+
+    #[pg_test]
+    fn test_fill_template() {
+        let filled = sexp_fill(
+            Sexp::input(c"(select ?cols (from ?table) (where ??conds) ?limit)"),
+            Sexp::input(c"((cols (a b)) (table users) (conds (= a 1) (> b 2)))"),
+        );
+        assert_eq!(filled.to_string_repr(), "(select (a b) (from users) (where (= a 1) (> b 2)) ?limit)");
+    }
+
+    #[pg_test]
+    fn test_quote_helpers() {
+        let text = "say \"hi\"\n\\ (done) ; not a comment";
+        let quoted = parse_sexp_text(&sexp_quote_string(text), ParseSettings::CANONICAL).unwrap();
+        assert_eq!(element_text(&quoted.data[1..]).as_deref(), Some(text));
+        assert_eq!(sexp_quote_symbol("user-id"), "user-id");
+        for bad in ["", "a b", "(x)", "42", "1.5", "#t", "nil", "a;b", "a\"b"] {
+            assert!(!reads_back_as_symbol(bad, Dialect::Scheme), "{:?} should be rejected", bad);
+        }
+    }
+
+    #[pg_test]
+    fn test_quote_symbol_pipes() {
+        assert_eq!(sexp_quote_symbol("42"), "|42|");
+        assert_eq!(sexp_quote_symbol("a b"), "|a b|");
+        let symbol = Spi::get_one::<Sexp>(&format!("SELECT '{}'::sexp", sexp_quote_symbol("nil"))).unwrap().unwrap();
+        assert_eq!(sexp_typeof(symbol), "symbol");
+    }
+
+    #[pg_test(error = "cannot write \"42\" as a sexp symbol")]
+    fn test_quote_symbol_rejects_number() {
+        Spi::run("SET pg_sexp.dialect = 'clojure'").unwrap();
+        sexp_quote_symbol("42");
+    }
+
+    #[pg_test]
+    fn test_group_by_head() {
+        let grouped = Spi::get_one::<Sexp>(
+            "SELECT sexp_group_by_head(d ORDER BY n) FROM (VALUES \
+                 (1, '(define x 1)'::sexp), (2, '(set! x 2)'), (3, '42'), \
+                 (4, '(define y 3)'), (5, NULL)) t(n, d)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            grouped.to_string_repr(),
+            "((define (define x 1) (define y 3)) (set! (set! x 2)))"
+        );
+    }
+
+    #[pg_test]
+    fn test_head_cardinality_agg() {
+        let estimate = Spi::get_one::<i64>(
+            "SELECT sexp_head_cardinality_agg(('(h' || i % 5000 || ' ' || i || ')')::sexp) \
+             FROM generate_series(1, 20000) i",
+        )
+        .unwrap()
+        .unwrap();
+        assert!((4750..=5250).contains(&estimate), "estimate {} too far from 5000", estimate);
+
+        let estimate = Spi::get_one::<i64>(
+            "SELECT sexp_cardinality_agg(sexp_from_int8_array(ARRAY[i % 300])) \
+             FROM generate_series(1, 3000) i",
+        )
+        .unwrap()
+        .unwrap();
+        assert!((285..=315).contains(&estimate), "estimate {} too far from 300", estimate);
+
+        let empty = Spi::get_one::<i64>("SELECT sexp_head_cardinality_agg(NULL::sexp)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(empty, 0);
+    }
+
+    #[pg_test]
+    fn test_stream_elements() {
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*) FROM sexp_stream_elements(\
+                 (SELECT sexp_agg(sexp_from_int8_array(ARRAY[i])) FROM generate_series(1, 50000) i))",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(count, 50000);
+        let first = Spi::get_one::<Sexp>("SELECT e FROM sexp_stream_elements('((a 1) b \"c\")') e LIMIT 1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.to_string_repr(), "(a 1)");
+        let none = Spi::get_one::<i64>("SELECT count(*) FROM sexp_stream_elements('()')").unwrap();
+        assert_eq!(none, Some(0));
+
+        // Lists stored out of line, uncompressed and compressed, read slice by
+        // slice with elements straddling the slice boundaries
+        Spi::run("CREATE TABLE stream_docs (n int, doc sexp)").unwrap();
+        Spi::run("ALTER TABLE stream_docs ALTER COLUMN doc SET STORAGE EXTERNAL").unwrap();
+        Spi::run(
+            "INSERT INTO stream_docs SELECT 1, ('(' || string_agg(format('(%s \"%s\")', i, md5(i::text)), ' ') || ')')::sexp \
+             FROM generate_series(1, 20000) i",
+        )
+        .unwrap();
+        Spi::run("ALTER TABLE stream_docs ALTER COLUMN doc SET STORAGE EXTENDED").unwrap();
+        Spi::run("INSERT INTO stream_docs SELECT 2, doc FROM stream_docs").unwrap();
+        let same = Spi::get_one::<i64>(
+            "SELECT count(*) FROM stream_docs d \
+             WHERE (SELECT sexp_agg(e) FROM sexp_stream_elements(d.doc) e) = d.doc",
+        )
+        .unwrap();
+        assert_eq!(same, Some(2));
+        let streamed = Spi::get_one::<i64>(
+            "SELECT count(*) FROM stream_docs d, LATERAL sexp_stream_elements(d.doc) e",
+        )
+        .unwrap();
+        assert_eq!(streamed, Some(40000));
+    }
+
+    #[pg_test]
+    fn test_core_encoding() {
+        use pg_sexp_core::{read, Builder, Value};
+
+        let mut builder = Builder::new();
+        builder
+            .begin_list()
+            .symbol("point")
+            .int(-3)
+            .float(1.5)
+            .string("a \"b\"")
+            .begin_list()
+            .end_list()
+            .value(&sexp_to_bytea(Sexp::input(c"(tag x)")))
+            .decimal("12.30")
+            .end_list();
+        let point = sexp_from_bytea(&builder.finish().unwrap());
+        assert_eq!(point.to_string_repr(), "(point -3 1.5 \"a \\\"b\\\"\" () (tag x) 12.30m)");
+
+        let encoded = sexp_to_bytea(Sexp::input(c"(tag \"x\" 2)"));
+        let Ok(Value::List(items)) = read(&encoded) else {
+            panic!("expected a list");
+        };
+        assert_eq!(items.iter().collect::<Vec<_>>(), [Value::Symbol("tag"), Value::String("x"), Value::Integer(2)]);
+    }
+
+    #[pg_test]
+    fn test_query_to_sexp() {
+        let result = Spi::get_one::<Sexp>(
+            "SELECT query_to_sexp('SELECT id, name, score FROM (VALUES (1, ''ann'', 2.5::float8), \
+                 (2, NULL, 1.0)) t(id, name, score) ORDER BY id')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            result.to_string_repr(),
+            "((row (id 1) (name \"ann\") (score 2.5)) (row (id 2) (name ()) (score 1.0)))"
+        );
+
+        // sexp columns are embedded as stored, whatever the dialect reads
+        Spi::run("CREATE TABLE stored_docs AS SELECT '(ok #t |a b|)'::sexp AS doc, 0.1::float4 AS ratio").unwrap();
+        Spi::run("SET pg_sexp.dialect = 'elisp'").unwrap();
+        let result = Spi::get_one::<Sexp>("SELECT query_to_sexp('SELECT doc, ratio FROM stored_docs')")
+        .unwrap()
+        .unwrap();
+        Spi::run("RESET pg_sexp.dialect").unwrap();
+        assert_eq!(result.to_string_repr(), "((row (doc (ok #t |a b|)) (ratio 0.1)))");
+    }
+
+    #[pg_test]
+    fn test_notify() {
+        Spi::run("LISTEN sexp_events").unwrap();
+        Spi::run("SELECT sexp_notify('sexp_events', '(insert (table t) (id 1) (note \"two\nlines\"))')").unwrap();
+    }
+
+    #[pg_test(error = "sexp_notify: payload is 9000 bytes, limit is 7999")]
+    fn test_notify_payload_limit() {
+        Spi::run("SELECT sexp_notify('sexp_events', sexp_quote_string(repeat('x', 8998))::sexp)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_stat_view() {
+        // The test server does not preload the library, so counters are off
+        let parses = Spi::get_one::<i64>("SELECT parses FROM pg_sexp_stat").unwrap();
+        assert_eq!(parses, None);
+        Spi::run("SELECT sexp_stat_reset()").unwrap();
+        let public_reset = Spi::get_one::<bool>(
+            "SELECT has_function_privilege('public', 'sexp_stat_reset()', 'EXECUTE')",
+        )
+        .unwrap();
+        assert_eq!(public_reset, Some(false));
+    }
+
+    #[pg_test]
+    fn test_elisp_to_sexp() {
+        let data = elisp_to_sexp(
+            "((name . \"org\") (version . [9 6]) (sep . ?\\s) (initial . ?a) \
+             (deps (emacs . \"26\")) (table . #s(hash-table test equal data (k1 1 k2 (2 . 3)))))",
+        );
+        assert_eq!(
+            data.to_string_repr(),
+            "((name \"org\") (version (9 6)) (sep 32) (initial 97) (deps (emacs \"26\")) \
+             (table hash-table (k1 1) (k2 (2 3))))"
+        );
+    }
+
+    #[pg_test]
+    fn test_elisp_char_escapes() {
+        let chars = elisp_to_sexp(r"(?\x41 ?\101 ?\C-a ?\^? ?é ?\é)");
+        assert_eq!(chars.to_string_repr(), "(65 65 1 127 233 233)");
+    }
+
+    #[pg_test(error = "invalid elisp data: misplaced dot in list")]
+    fn test_elisp_misplaced_dot() {
+        elisp_to_sexp("(a . b c)");
+    }
+
+    #[pg_test]
+    fn test_dialect_guc() {
+        Spi::run("SET pg_sexp.dialect = 'clojure'").unwrap();
+        let map = Spi::get_one::<Sexp>("SELECT sexp_parse('{:name \"ann\", :tags [a b], :admin true}')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(map.to_string_repr(), "((:name \"ann\") (:tags (a b)) (:admin #t))");
+        assert_eq!(sexp_to_text(map, "clojure"), "((:name \"ann\") (:tags (a b)) (:admin true))");
+        Spi::run("SET pg_sexp.dialect = 'kicad'").unwrap();
+        let hide = Spi::get_one::<String>("SELECT sexp_parse('(hide yes)')::text").unwrap().unwrap();
+        assert_eq!(hide, "(hide #t)");
+        let hide = Spi::get_one::<String>("SELECT sexp_parse('(hide yes)', 'scheme')::text").unwrap().unwrap();
+        assert_eq!(hide, "(hide yes)");
+        let hide = Spi::get_one::<String>("SELECT sexp_to_text('(hide #t)', 'kicad')").unwrap().unwrap();
+        assert_eq!(hide, "(hide yes)");
+        // Literals read the canonical syntax whatever the dialect
+        let hide = Spi::get_one::<String>("SELECT '(hide yes)'::sexp::text").unwrap().unwrap();
+        assert_eq!(hide, "(hide yes)");
+        Spi::run("RESET pg_sexp.dialect").unwrap();
+    }
+
+    #[pg_test]
+    fn test_dump_restore_ignores_dialect() {
+        // Values dumped as text under one session's settings and restored
+        // under another's, as pg_dump and a database-level SET would do
+        Spi::run(
+            "CREATE TABLE dumped AS SELECT d FROM unnest(ARRAY[\
+                 '(#t #f yes no t true false |nil| ?x [a] |a b| 1.50 0.1)'::sexp, \
+                 sexp_parse('(a [1 2] #s(p 1) ?a (k . v))', 'elisp'), \
+                 sexp_parse('{:k #{x}}', 'clojure')]) d",
+        )
+        .unwrap();
+        let dump: Vec<String> = Spi::connect(|client| {
+            client
+                .select("SELECT d::text FROM dumped", None, &[])
+                .unwrap()
+                .map(|row| row.get::<String>(1).unwrap().unwrap())
+                .collect()
+        });
+        let restore_settings = [
+            "pg_sexp.nil_is_symbol = on",
+            "pg_sexp.parse_true_false = on",
+            "pg_sexp.exact_decimals = on",
+        ];
+        for dialect in ["scheme", "elisp", "clojure", "smtlib", "kicad"] {
+            Spi::run(&format!("SET pg_sexp.dialect = '{}'", dialect)).unwrap();
+            for setting in restore_settings {
+                Spi::run(&format!("SET {}", setting)).unwrap();
             }
-            _ => {
-                pgrx::error!("sexp_gin_consistent: unknown strategy {}", strategy);
+            Spi::run("CREATE TEMP TABLE restored (d sexp)").unwrap();
+            for text in &dump {
+                Spi::run_with_args("INSERT INTO restored VALUES ($1::text::sexp)", &[text.as_str().into()]).unwrap();
             }
+            let same = Spi::get_one::<i64>(
+                "SELECT count(*) FROM restored r JOIN dumped o ON sexp_to_bytea(r.d) = sexp_to_bytea(o.d)",
+            )
+            .unwrap();
+            assert_eq!(same, Some(dump.len() as i64), "restored under {}", dialect);
+            Spi::run("DROP TABLE restored").unwrap();
+            Spi::run("RESET ALL").unwrap();
         }
+        let values = Sexp::input(c"(#t #f yes t |nil|)");
+        assert_eq!(sexp_to_text(values, "elisp"), "(t nil yes t nil)");
     }
-}
 
-/// GIN triconsistent check (ternary logic)
-/// Signature: sexp_gin_triconsistent(internal, int2, sexp, int4, internal, internal, internal) -> char
-#[pg_extern(name = "sexp_gin_triconsistent", immutable, parallel_safe)]
-fn sexp_gin_triconsistent_fn(
-    check: Internal,
-    strategy: i16,
-    _query: Sexp,
-    nkeys: i32,
-    _extra_data: Internal,
-    _query_keys: Internal,
-    _null_flags: Internal,
-) -> i8 {
-    use pgrx::pg_sys;
-    
-    unsafe {
-        let check_ptr = check.unwrap().unwrap().cast_mut_ptr::<i8>();
-        
-        let mut all_true = true;
-        let mut any_false = false;
-        
-        for i in 0..nkeys {
-            let val = *check_ptr.add(i as usize);
-            if val == GIN_FALSE {
-                any_false = true;
-                all_true = false;
-                break;
-            } else if val == GIN_MAYBE {
-                all_true = false;
-            }
+    #[pg_test(error = "unknown sexp dialect \"lisp\"")]
+    fn test_to_text_rejects_unknown_dialect() {
+        sexp_to_text(Sexp::nil(), "lisp");
+    }
+
+    #[pg_test]
+    fn test_smtlib_dialect() {
+        Spi::run("SET pg_sexp.dialect = 'smtlib'").unwrap();
+        let script = "(assert (! (= ((_ extract 7 4) x) #b0101 #x0f) :named c1)) \
+                      (set-info :source \"a \"\"quoted\"\" \\ note\") (define-fun r () Real 1.50)";
+        let forms: Vec<String> = Spi::connect(|client| {
+            client
+                .select("SELECT sexp_to_text(f, 'smtlib') FROM sexp_parse_forms($1) f", None, &[script.into()])
+                .unwrap()
+                .map(|row| row.get::<String>(1).unwrap().unwrap())
+                .collect()
+        });
+        assert_eq!(
+            forms,
+            [
+                "(assert (! (= ((_ extract 7 4) x) #b0101 #x0f) :named c1))",
+                "(set-info :source \"a \"\"quoted\"\" \\ note\")",
+                "(define-fun r () Real 1.50)",
+            ]
+        );
+        let decimal = Spi::get_one::<String>("SELECT sexp_typeof(nth(sexp_parse('(r 1.50)'), 1))").unwrap();
+        Spi::run("RESET pg_sexp.dialect").unwrap();
+        assert_eq!(decimal.as_deref(), Some("decimal"));
+    }
+
+    #[pg_test]
+    fn test_pipe_symbols() {
+        let sexp = Sexp::input(c"(|hello world| |42| |a\\|b| plain)");
+        assert_eq!(sexp.elements().len(), 4);
+        assert_eq!(sexp_typeof(sexp.nth(1).unwrap()), "symbol");
+        assert_eq!(sexp.to_string_repr(), "(|hello world| |42| |a\\|b| plain)");
+        let built = sexp_from_text_pairs(vec!["first name".to_string()], vec![Some("ann".to_string())]);
+        assert_eq!(built.to_string_repr(), "((|first name| \"ann\"))");
+
+        // Bare pipes keep their old meaning in Scheme data
+        let old = Sexp::input(c"(|| a (|> x f |> g) |a|b)");
+        assert_eq!(old.to_string_repr(), "(|| a (|> x f |> g) |a|b)");
+        assert_eq!(sexp_typeof(old.car().unwrap()), "symbol");
+        assert_eq!(element_text(old.elements()[0]).as_deref(), Some("||"));
+        assert_eq!(element_text(old.elements()[3]).as_deref(), Some("|a|b"));
+        let piped = Sexp::from_parsed(&ParsedExpr::Symbol(intern_symbol("|a|")));
+        assert_eq!(piped.to_string_repr(), "|\\|a\\||");
+        assert!(Sexp::input(c"|\\|a\\||").equals(&piped));
+    }
+
+    #[pg_test]
+    fn test_compress_roundtrip() {
+        let doc = Spi::get_one::<Sexp>(
+            "SELECT sexp_agg(('(event (kind click) (target \"button-' || i % 7 || '\") (at ' || i || '))')::sexp) \
+             FROM generate_series(1, 500) i",
+        )
+        .unwrap()
+        .unwrap();
+        let archive = sexp_compress(doc.clone());
+        assert!(archive.len() * 3 < doc.data.len(), "{} vs {}", archive.len(), doc.data.len());
+        assert!(sexp_decompress(&archive).equals(&doc));
+
+        for text in [c"x", c"()", c"(a \"b\" 1.5 #t)"] {
+            let small = Sexp::input(text);
+            assert!(sexp_decompress(&sexp_compress(small.clone())).equals(&small));
         }
-        
-        match strategy {
-            SEXP_GIN_CONTAINS_STRATEGY | SEXP_GIN_CONTAINS_KEY_STRATEGY => {
-                if any_false {
-                    GIN_FALSE
-                } else if all_true && nkeys == 1 {
-                    // Single-key optimization: skip recheck for single atom queries
-                    GIN_TRUE
-                } else {
-                    GIN_MAYBE
-                }
-            }
-            SEXP_GIN_CONTAINED_STRATEGY => {
-                GIN_MAYBE
-            }
-            _ => {
-                pgrx::error!("sexp_gin_triconsistent: unknown strategy {}", strategy);
-            }
+        let mut truncated = archive.clone();
+        truncated.truncate(archive.len() / 2);
+        assert!(decompress_archive(&truncated).is_err());
+    }
+
+    #[pg_test]
+    fn test_archive_declared_size() {
+        let mut input = b"abcabcabcabcabcabcabcabc-0123456789-".repeat(40);
+        input.extend((0..=255u8).cycle().take(3000));
+        let compressed = pglz_compress(&input).unwrap();
+        assert_eq!(pglz_decompress(&compressed, input.len()).unwrap(), input);
+        assert!(pglz_decompress(&compressed, input.len() + 1).is_err());
+        assert!(pglz_compress(b"tiny").is_none());
+
+        // A huge declared size is refused before it is allocated
+        for size in [u32::MAX as u64, 1000] {
+            let mut archive = ARCHIVE_MAGIC.to_vec();
+            archive.extend_from_slice(&[ARCHIVE_VERSION, ARCHIVE_PGLZ, FORMAT_VERSION]);
+            write_varint(&mut archive, size);
+            archive.extend_from_slice(b"abc");
+            assert_eq!(decompress_archive(&archive).unwrap_err(), "declared body size too large");
         }
     }
-}
 
-// ============================================================================
-// Additional Operators
-// ============================================================================
+    #[pg_test]
+    fn test_anonymize() {
+        let doc = "'((user (email \"ann@example.com\") (name \"Ann\")) (note \"ssn 123-45-6789\") (id \"a1\"))'";
+        let rules = "'((keys email) (patterns \"[0-9]{3}-[0-9]{2}-[0-9]{4}\") (salt \"pepper\"))'";
+        let anonymize = |doc: &str, rules: &str| {
+            Spi::get_one::<Sexp>(&format!("SELECT sexp_anonymize({}, {})", doc, rules)).unwrap().unwrap()
+        };
+        let masked = anonymize(doc, rules);
+        let email = path_value(&masked.data[1..], &["user".to_string(), "email".to_string()]).unwrap();
+        let email = element_text(&email.data[1..]).unwrap();
+        assert_eq!(email.len(), 16);
+        assert_ne!(email, "ann@example.com");
+        assert!(!masked.to_string_repr().contains("123-45"));
+        assert!(masked.to_string_repr().contains("\"Ann\""));
+        assert!(anonymize(doc, rules).equals(&masked), "hashes must be stable");
+
+        let fixed = anonymize(doc, "'((keys name id) (placeholder \"***\"))'");
+        assert_eq!(
+            fixed.to_string_repr(),
+            "((user (email \"ann@example.com\") (name \"***\")) (note \"ssn 123-45-6789\") (id \"***\"))"
+        );
+
+        // Non-string atoms under a masked key are masked too, list heads are kept
+        let numbers = anonymize(
+            "'((account (ssn 123456789) (pin 42.5) (kind savings)) (id 7))'",
+            "'((keys account) (placeholder \"*\"))'",
+        );
+        assert_eq!(numbers.to_string_repr(), "((account (ssn \"*\") (pin \"*\") (kind \"*\")) (id 7))");
+
+        // The cached rules follow the rules argument from row to row
+        let per_row = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(sexp_anonymize('(a \"x\")', r)::text ORDER BY n) \
+             FROM (VALUES (1, '((keys a) (placeholder \"1\"))'::sexp), (2, '((keys a) (placeholder \"2\"))')) v(n, r)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(per_row, vec!["(a \"1\")", "(a \"2\")"]);
+    }
 
-extension_sql!(
-    r#"
--- Key-based containment operator (@>>)
-CREATE OPERATOR @>> (
-    LEFTARG = sexp,
-    RIGHTARG = sexp,
-    FUNCTION = sexp_contains_key,
-    RESTRICT = contsel,
-    JOIN = contjoinsel
-);
+    #[pg_test(error = "sexp_anonymize: rules need a non-empty (salt \"...\") unless a (placeholder \"...\") is given")]
+    fn test_anonymize_requires_salt() {
+        Spi::run("SELECT sexp_anonymize('(email \"ann@example.com\")', '((keys email))')").unwrap();
+    }
 
--- Pattern match operator (~)
-CREATE OPERATOR ~ (
-    LEFTARG = sexp,
-    RIGHTARG = sexp,
-    FUNCTION = sexp_match,
-    RESTRICT = contsel,
-    JOIN = contjoinsel
-);
+    #[pg_test]
+    fn test_redact_paths() {
+        let doc = "'((user (email \"a@x.org\") (name \"Ann\") (phones \"1\" \"2\")) (tags a b c) (id 7))'";
+        let removed = Spi::get_one::<Sexp>(&format!(
+            "SELECT sexp_redact({}, ARRAY[['user', 'email'], ['tags', '1'], ['id', NULL]])",
+            doc
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            removed.to_string_repr(),
+            "((user (name \"Ann\") (phones \"1\" \"2\")) (tags a c))"
+        );
+        let masked = Spi::get_one::<Sexp>(&format!(
+            "SELECT sexp_redact({}, ARRAY[['user', 'email'], ['user', 'phones']], '\"REDACTED\"')",
+            doc
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            masked.to_string_repr(),
+            "((user (email \"REDACTED\") (name \"Ann\") (phones \"REDACTED\")) (tags a b c) (id 7))"
+        );
+
+        // The SQL wrapper does not depend on the caller's search_path
+        let schema = Spi::get_one::<String>("SELECT quote_ident(current_schema())").unwrap().unwrap();
+        Spi::run("SET search_path = pg_catalog").unwrap();
+        let redacted = Spi::get_one::<String>(&format!(
+            "SELECT {0}.sexp_redact('((id 7) (pin 1))'::{0}.sexp, ARRAY[['pin']])::text",
+            schema
+        ))
+        .unwrap();
+        Spi::run("RESET search_path").unwrap();
+        assert_eq!(redacted.as_deref(), Some("((id 7))"));
+    }
 
--- GIN operator class for sexp containment
--- Strategy 7 = @> (structural containment), matching jsonb convention
--- Strategy 9 = @>> (key-based containment)
-CREATE OPERATOR CLASS sexp_gin_ops
-    DEFAULT FOR TYPE sexp USING gin AS
-    OPERATOR 7 @> (sexp, sexp),
-    OPERATOR 9 @>> (sexp, sexp),
-    FUNCTION 1 btint4cmp(int4, int4),
-    FUNCTION 2 sexp_gin_extract_value(sexp, internal),
-    FUNCTION 3 sexp_gin_extract_query(sexp, internal, int2, internal, internal, internal, internal),
-    FUNCTION 4 sexp_gin_consistent(internal, int2, sexp, int4, internal, internal, internal, internal),
-    FUNCTION 6 sexp_gin_triconsistent(internal, int2, sexp, int4, internal, internal, internal),
-    STORAGE int4;
+    #[pg_test]
+    fn test_flatten_pairs() {
+        let rows = Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT path, value::text FROM sexp_flatten_pairs(\
+                     '((id 7) (user (name \"Ann\") (address ((city \"Oslo\") (zip 150)))) (tags a b))')",
+                    None,
+                    &[],
+                )
+                .unwrap()
+                .map(|row| (row.get::<String>(1).unwrap().unwrap(), row.get::<String>(2).unwrap().unwrap()))
+                .collect::<Vec<_>>()
+        });
+        let expected = [
+            ("id", "7"),
+            ("user.name", "\"Ann\""),
+            ("user.address.city", "\"Oslo\""),
+            ("user.address.zip", "150"),
+            ("tags", "(a b)"),
+        ];
+        assert_eq!(rows.len(), expected.len());
+        for ((path, value), (want_path, want_value)) in rows.iter().zip(expected) {
+            assert_eq!((path.as_str(), value.as_str()), (want_path, want_value));
+        }
+    }
 
-COMMENT ON OPERATOR CLASS sexp_gin_ops USING gin IS 'GIN index operator class for sexp containment queries';
-"#,
-    name = "sexp_additional_operators",
-    requires = [
-        "sexp_operators",
-        sexp_contains_key, 
-        sexp_match_fn, 
-        sexp_extract_keys, 
-        sexp_extract_query_keys,
-        sexp_gin_extract_value_fn,
-        sexp_gin_extract_query_fn,
-        sexp_gin_consistent_fn,
-        sexp_gin_triconsistent_fn
-    ]
-);
+    #[pg_test]
+    fn test_from_pairs_round_trip() {
+        let doc = "'((id 7) (user ((name \"Ann\") (address ((city \"Oslo\"))))))'::sexp";
+        let rebuilt = Spi::get_one::<Sexp>(&format!(
+            "SELECT sexp_from_pairs(path, value) FROM sexp_flatten_pairs({})",
+            doc
+        ))
+        .unwrap()
+        .unwrap();
+        let original = Spi::get_one::<Sexp>(&format!("SELECT {}", doc)).unwrap().unwrap();
+        assert!(rebuilt.equals(&original));
+
+        let built = sexp_from_pair_arrays(
+            vec!["a.b".to_string(), "a.c".to_string(), "d".to_string()],
+            vec![Some(Sexp::input(c"1")), Some(Sexp::input(c"2")), None],
+        );
+        assert_eq!(built.to_string_repr(), "((a ((b 1) (c 2))) (d ()))");
+
+        // Dotted keys are escaped rather than nested
+        let dotted = "'((com.example 1) (com ((example 2))) (|7| x))'::sexp";
+        let paths = Spi::get_one::<Vec<String>>(&format!(
+            "SELECT array_agg(path) FROM sexp_flatten_pairs({})",
+            dotted
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(paths, vec!["com\\.example", "com.example", "|7|"]);
+        let rebuilt = Spi::get_one::<Sexp>(&format!(
+            "SELECT sexp_from_pairs(path, value) FROM sexp_flatten_pairs({})",
+            dotted
+        ))
+        .unwrap()
+        .unwrap();
+        let original = Spi::get_one::<Sexp>(&format!("SELECT {}", dotted)).unwrap().unwrap();
+        assert!(rebuilt.equals(&original));
+
+        // Entries other than (key value) pairs come back as pairs
+        let rebuilt = Spi::get_one::<Sexp>(
+            "SELECT sexp_from_pairs(path, value) FROM sexp_flatten_pairs('((a) (tags x y) (b ()))')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(rebuilt.to_string_repr(), "((a ()) (tags (x y)) (b ()))");
+
+        // Steps read as atoms, so string and integer keys keep their type
+        let typed = sexp_from_pair_arrays(
+            vec!["\"name\"".to_string(), "7.x".to_string(), "|7|".to_string()],
+            vec![Some(Sexp::input(c"1")), Some(Sexp::input(c"2")), Some(Sexp::input(c"3"))],
+        );
+        assert_eq!(typed.to_string_repr(), "((\"name\" 1) (7 ((x 2))) (|7| 3))");
+    }
 
-// ============================================================================
-// Tests
-// ============================================================================
+    #[pg_test(error = "sexp_from_pairs: conflicting values at path \"a.b\"")]
+    fn test_from_pairs_conflict() {
+        sexp_from_pair_arrays(
+            vec!["a".to_string(), "a.b".to_string()],
+            vec![Some(Sexp::input(c"1")), Some(Sexp::input(c"2"))],
+        );
+    }
 
-#[cfg(any(test, feature = "pg_test"))]
-#[pg_schema]
-mod tests {
-    use super::*;
+    #[pg_test]
+    fn test_head_prefix_operator() {
+        Spi::run("CREATE TABLE routes (doc sexp)").unwrap();
+        Spi::run(
+            "INSERT INTO routes VALUES ('(http/get \"/a\")'), ('(http/post \"/b\")'), ('(grpc/call x)'), ('42'), ('(\"http/x\")')",
+        )
+        .unwrap();
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM routes WHERE doc ^@ 'http/'").unwrap();
+        assert_eq!(count, Some(2));
+
+        Spi::run("CREATE INDEX routes_head ON routes USING spgist (sexp_head_text(doc))").unwrap();
+        Spi::run("SET enable_seqscan = off").unwrap();
+        let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT * FROM routes WHERE doc ^@ 'http/'")
+            .unwrap()
+            .unwrap();
+        Spi::run("RESET enable_seqscan").unwrap();
+        assert!(plan.0.to_string().contains("routes_head"));
+
+        // The operator's function does not depend on the caller's search_path
+        let schema = Spi::get_one::<String>("SELECT quote_ident(current_schema())").unwrap().unwrap();
+        Spi::run("SET search_path = pg_catalog").unwrap();
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM {0}.routes WHERE doc OPERATOR({0}.^@) 'http/'",
+            schema
+        ))
+        .unwrap();
+        Spi::run("RESET search_path").unwrap();
+        assert_eq!(count, Some(2));
+    }
 
     #[pg_test]
-    fn test_parse_symbol() {
-        let s = Sexp::input(c"foo");
-        assert_eq!(s.get_type(), SexpType::Symbol);
-        assert_eq!(s.to_string_repr(), "foo");
+    fn test_walk() {
+        Spi::run(
+            "CREATE FUNCTION bump(x sexp) RETURNS sexp AS $$ \
+             SELECT CASE WHEN sexp_typeof(x) = 'integer' \
+                         THEN (x::numeric + 1)::text::sexp ELSE x END $$ LANGUAGE SQL",
+        )
+        .unwrap();
+        let bumped = Spi::get_one::<Sexp>("SELECT sexp_walk('(a 1 (b 2 (c 3)))', 'bump(sexp)')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(bumped.to_string_repr(), "(a 2 (b 3 (c 4)))");
+
+        Spi::run(
+            "CREATE FUNCTION tag_lists(x sexp) RETURNS sexp AS $$ \
+             SELECT CASE WHEN is_list(x) AND sexp_length(x) > 0 \
+                         THEN ('(n ' || substr(x::text, 2))::sexp ELSE x END $$ LANGUAGE SQL",
+        )
+        .unwrap();
+        let tagged = Spi::get_one::<Sexp>("SELECT sexp_walk('((1) 2)', 'tag_lists(sexp)', 'post')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(tagged.to_string_repr(), "(n (n 1) 2)");
+
+        // The SQL wrapper does not depend on the caller's search_path
+        Spi::run("CREATE FUNCTION unchanged(sexp) RETURNS sexp AS $$ SELECT $1 $$ LANGUAGE SQL").unwrap();
+        let schema = Spi::get_one::<String>("SELECT quote_ident(current_schema())").unwrap().unwrap();
+        Spi::run("SET search_path = pg_catalog").unwrap();
+        let walked = Spi::get_one::<String>(&format!(
+            "SELECT {0}.sexp_walk('(a (b))'::{0}.sexp, '{0}.unchanged({0}.sexp)')::text",
+            schema
+        ))
+        .unwrap();
+        Spi::run("RESET search_path").unwrap();
+        assert_eq!(walked.as_deref(), Some("(a (b))"));
+    }
+
+    #[pg_test(error = "sexp_walk: function length(text) must take one sexp argument and return sexp")]
+    fn test_walk_rejects_other_signatures() {
+        Spi::run("SELECT sexp_walk('(a 1)', 'length(text)')").unwrap();
     }
 
     #[pg_test]
-    fn test_parse_integer() {
-        let s = Sexp::input(c"42");
-        assert_eq!(s.get_type(), SexpType::Integer);
-        assert_eq!(s.to_string_repr(), "42");
+    fn test_prune() {
+        let doc = Sexp::input(c"(config (comment \"top\") (server (port 80) (comment x y) (debug)) ((comment)))");
+        let pruned = sexp_prune(doc, Sexp::input(c"(comment _*)"));
+        assert_eq!(pruned.to_string_repr(), "(config (server (port 80) (debug)) ())");
+        let all = sexp_prune(Sexp::input(c"(comment a)"), Sexp::input(c"(comment _*)"));
+        assert!(all.is_nil());
     }
 
     #[pg_test]
-    fn test_parse_negative() {
-        let s = Sexp::input(c"-123");
-        assert_eq!(s.get_type(), SexpType::Integer);
-        assert_eq!(s.to_string_repr(), "-123");
+    fn test_select() {
+        let doc = Sexp::input(c"(config (name main) (server (port 80) (debug on)) (client (debug off) (retries 3)) (log (level 2)))");
+        let selected = sexp_select(doc, Sexp::input(c"(debug _)")).unwrap();
+        assert_eq!(selected.to_string_repr(), "(config (server (debug on)) (client (debug off)))");
+        assert!(sexp_select(Sexp::input(c"(a b)"), Sexp::input(c"(debug _)")).is_none());
     }
 
     #[pg_test]
-    fn test_parse_float() {
-        let s = Sexp::input(c"3.14");
-        assert_eq!(s.get_type(), SexpType::Float);
+    fn test_rule_query() {
+        Spi::run("CREATE TABLE events (fact sexp)").unwrap();
+        Spi::run("INSERT INTO events VALUES ('(parent ann bob)'), ('(parent bob cid)'), ('(parent cid dee)'), (NULL)")
+            .unwrap();
+        let rules = "'((:- (ancestor ?x ?y) (parent ?x ?y)) \
+                      (:- (ancestor ?x ?z) (parent ?x ?y) (ancestor ?y ?z)))'";
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM sexp_query({}, 'events', 'fact')",
+            rules
+        ))
+        .unwrap();
+        assert_eq!(count, Some(6));
+        let found = Spi::get_one::<bool>(&format!(
+            "SELECT bool_or(f = '(ancestor ann dee)') FROM sexp_query({}, 'events', 'fact') f",
+            rules
+        ))
+        .unwrap();
+        assert_eq!(found, Some(true));
+
+        // Facts without a body, and derivations joining two derived facts
+        let rules = "'((:- (parent dee eve)) \
+                      (:- (path ?x ?y) (parent ?x ?y)) \
+                      (:- (path ?x ?z) (path ?x ?y) (path ?y ?z)))'";
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM sexp_query({}, 'events', 'fact') f WHERE sexp_head(f) = 'path'",
+            rules
+        ))
+        .unwrap();
+        assert_eq!(count, Some(10));
+    }
+
+    #[pg_test(error = "sexp_query: more than pg_sexp.query_max_facts (100) facts")]
+    fn test_rule_query_max_facts() {
+        Spi::run("CREATE TABLE counters (fact sexp)").unwrap();
+        Spi::run("INSERT INTO counters VALUES ('(n z)')").unwrap();
+        Spi::run("SET pg_sexp.query_max_facts = 100").unwrap();
+        Spi::run("SELECT count(*) FROM sexp_query('((:- (n (s ?x)) (n ?x)))', 'counters', 'fact')").unwrap();
     }
 
     #[pg_test]
-    fn test_parse_nil() {
-        let s = Sexp::input(c"()");
-        assert!(s.is_nil());
-        assert_eq!(s.to_string_repr(), "()");
+    fn test_diff() {
+        let diff = sexp_diff(
+            Sexp::input(c"((name \"a\") (tags x y) (size 1) (gone #t))"),
+            Sexp::input(c"((name \"b\") (tags x z) (size 1) (new 2))"),
+        );
+        assert_eq!(
+            diff.to_string_repr(),
+            "((replace (name) \"a\" \"b\") (replace (tags 1) y z) (remove (gone) #t) (add (new) 2))"
+        );
+        assert!(sexp_diff(Sexp::input(c"(a b)"), Sexp::input(c"(a b)")).is_nil());
+
+        // Repeated keys pair up by occurrence
+        let diff = sexp_diff(Sexp::input(c"((tag a) (tag b))"), Sexp::input(c"((tag a) (tag c) (tag d))"));
+        assert_eq!(diff.to_string_repr(), "((replace (tag) b c) (add (tag) d))");
     }
 
     #[pg_test]
-    fn test_parse_list() {
-        let s = Sexp::input(c"(foo bar baz)");
-        assert_eq!(s.get_type(), SexpType::List);
-        assert_eq!(s.length(), 3);
+    fn test_diff_history_trigger() {
+        Spi::run("CREATE TABLE docs (id int, doc sexp)").unwrap();
+        Spi::run(
+            "CREATE TRIGGER docs_history AFTER UPDATE ON docs FOR EACH ROW \
+             EXECUTE FUNCTION sexp_diff_history('doc', 'sexp_history', 'id')",
+        )
+        .unwrap();
+        Spi::run("INSERT INTO docs VALUES (1, '((v 1))')").unwrap();
+        Spi::run("UPDATE docs SET doc = '((v 2))'").unwrap();
+        Spi::run("UPDATE docs SET id = id").unwrap();
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM sexp_history").unwrap();
+        assert_eq!(count, Some(1));
+        let diff = Spi::get_one::<Sexp>("SELECT diff FROM sexp_history WHERE row_key = '1'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.to_string_repr(), "((replace (v) 1 2))");
+
+        // A mixed-case history table named by the trigger, and dumped defaults
+        Spi::run("CREATE TABLE \"Doc History\" (LIKE sexp_history INCLUDING DEFAULTS)").unwrap();
+        Spi::run(
+            "CREATE TRIGGER docs_named AFTER UPDATE ON docs FOR EACH ROW \
+             EXECUTE FUNCTION sexp_diff_history('doc', '\"Doc History\"')",
+        )
+        .unwrap();
+        Spi::run("UPDATE docs SET doc = '((v 3))'").unwrap();
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM \"Doc History\"").unwrap();
+        assert_eq!(count, Some(1));
+        let dumped = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_extension, unnest(extconfig) c \
+             WHERE extname = 'pg_sexp_rs' AND c IN ('sexp_history'::regclass, 'sexp_history_id_seq'::regclass)",
+        )
+        .unwrap();
+        assert_eq!(dumped, Some(2));
     }
 
     #[pg_test]
-    fn test_car_cdr() {
-        let s = Sexp::input(c"(a b c)");
-        
-        let car = s.car().unwrap();
-        assert_eq!(car.to_string_repr(), "a");
-        
-        let cdr = s.cdr().unwrap();
-        assert_eq!(cdr.length(), 2);
+    fn test_tap_assertions() {
+        let pass = is_sexp(Some(Sexp::input(c"(a 1)")), Some(Sexp::input(c"(a 1)")), Some("same"));
+        assert_eq!(pass, "ok - same");
+        let fail = is_sexp(Some(Sexp::input(c"((v 1))")), Some(Sexp::input(c"((v 2))")), Some("values"));
+        assert_eq!(
+            fail,
+            "not ok - values\n# Failed test: \"values\"\n#     have: ((v 1))\n#     want: ((v 2))\n#     diff: ((replace (v) 1 2))"
+        );
+        let matched = matches_sexp_pattern(Some(Sexp::input(c"(add 1 2)")), Sexp::input(c"(add _ _)"), None);
+        assert_eq!(matched, "ok");
+        assert!(matches_sexp_pattern(None, Sexp::input(c"_"), Some("null")).starts_with("not ok - null\n"));
     }
 
     #[pg_test]
-    fn test_nth() {
-        let s = Sexp::input(c"(a b c d)");
-        
-        assert_eq!(s.nth(0).unwrap().to_string_repr(), "a");
-        assert_eq!(s.nth(1).unwrap().to_string_repr(), "b");
-        assert_eq!(s.nth(2).unwrap().to_string_repr(), "c");
-        assert_eq!(s.nth(3).unwrap().to_string_repr(), "d");
-        assert!(s.nth(4).is_none());
+    fn test_datum_is_raw_encoding() {
+        let sexp = Sexp::input(c"(a b c)");
+        let size = Spi::get_one::<i32>("SELECT pg_column_size('(a b c)'::sexp)").unwrap();
+        assert_eq!(size, Some(sexp.data.len() as i32 + 4));
+
+        let legacy = pg_sys::Datum::from(pgrx::datum::cbor_encode(&sexp).cast_mut());
+        let decoded = unsafe { Sexp::from_datum(legacy, false) }.unwrap();
+        assert!(decoded.equals(&sexp));
     }
 
     #[pg_test]
-    fn test_contains() {
-        let container = Sexp::input(c"(a (b c) d)");
-        let needle = Sexp::input(c"(b c)");
-        let not_there = Sexp::input(c"(x y)");
-        
-        assert!(container.contains(&needle));
-        assert!(!container.contains(&not_there));
+    fn test_compressed_legacy_datum_equals_current() {
+        let sexp = parse_sexp_text(&format!("({})", "abc ".repeat(2000)), ParseSettings::CANONICAL).unwrap();
+        let legacy = pg_sys::Datum::from(pgrx::datum::cbor_encode(&sexp).cast_mut());
+        let compressed = unsafe { pg_sys::toast_compress_datum(legacy, b'p' as core::ffi::c_char) };
+        let ptr = compressed.cast_mut_ptr::<pg_sys::varlena>();
+        assert!(!ptr.is_null() && unsafe { pgrx::varlena::varatt_is_compressed(ptr) });
+
+        let current = sexp.into_datum().unwrap();
+        assert!(unsafe { datums_equal(compressed, current) });
+        assert!(unsafe { datums_equal(current, compressed) });
     }
 
     #[pg_test]
-    fn test_equality() {
-        let a = Sexp::input(c"(foo bar)");
-        let b = Sexp::input(c"(foo bar)");
-        let c = Sexp::input(c"(foo baz)");
-        
-        assert!(a.equals(&b));
-        assert!(!a.equals(&c));
+    fn test_datum_built_and_read_in_place() {
+        let sexp = Sexp::input(c"(a b c)");
+        let payload = sexp.data.as_ptr();
+        let datum = sexp.into_datum().unwrap();
+        assert_eq!(unsafe { datum.cast_mut_ptr::<u8>().add(VARHDRSZ) }.cast_const(), payload);
+
+        // Arguments returned as they are, stored with a short header or out
+        // of line
+        Spi::run("CREATE TABLE headers (doc sexp)").unwrap();
+        Spi::run("ALTER TABLE headers ALTER COLUMN doc SET STORAGE EXTERNAL").unwrap();
+        Spi::run("INSERT INTO headers VALUES ('(a b)')").unwrap();
+        Spi::run(
+            "INSERT INTO headers SELECT ('(' || string_agg(md5(i::text), ' ') || ')')::sexp \
+             FROM generate_series(1, 500) i",
+        )
+        .unwrap();
+        let same = Spi::get_one::<i64>(
+            "SELECT count(*) FROM headers \
+             WHERE doc::sexp(list)::text = doc::text AND cdr(doc) = cdr(doc::text::sexp)",
+        )
+        .unwrap();
+        assert_eq!(same, Some(2));
     }
 
     #[pg_test]
-    fn test_pattern_match_wildcard() {
-        let expr = Sexp::input(c"(foo bar baz)");
-        let pattern = Sexp::input(c"(foo _ baz)");
-        
-        assert!(sexp_match_fn(expr, pattern));
+    fn test_eq_toasted() {
+        Spi::run("CREATE TABLE big_docs (doc sexp)").unwrap();
+        Spi::run("ALTER TABLE big_docs ALTER COLUMN doc SET STORAGE EXTERNAL").unwrap();
+        Spi::run(
+            "INSERT INTO big_docs SELECT ('(' || string_agg(md5(i::text), ' ' ORDER BY i) || ')')::sexp \
+             FROM generate_series(1, 500) i, (VALUES (1), (2)) v(n) GROUP BY n",
+        )
+        .unwrap();
+        Spi::run("INSERT INTO big_docs SELECT ('(x ' || substr(doc::text, 2))::sexp FROM big_docs LIMIT 1").unwrap();
+        // Same size, differing only in the last slice
+        Spi::run(
+            "INSERT INTO big_docs SELECT regexp_replace(doc::text, '...\\)$', 'zzz)')::sexp \
+             FROM big_docs WHERE doc::text NOT LIKE '(x %' LIMIT 1",
+        )
+        .unwrap();
+        let equal = Spi::get_one::<i64>("SELECT count(*) FROM big_docs a JOIN big_docs b ON a.doc = b.doc").unwrap();
+        assert_eq!(equal, Some(6));
+        let distinct = Spi::get_one::<i64>("SELECT count(DISTINCT doc) FROM big_docs").unwrap();
+        assert_eq!(distinct, Some(3));
+        let same_size = Spi::get_one::<i64>(
+            "SELECT count(*) FROM big_docs a JOIN big_docs b \
+             ON pg_column_size(a.doc) = pg_column_size(b.doc) AND a.doc <> b.doc",
+        )
+        .unwrap();
+        assert_eq!(same_size, Some(4));
     }
 
     #[pg_test]
-    fn test_pattern_match_rest() {
-        let expr = Sexp::input(c"(foo a b c d)");
-        let pattern = Sexp::input(c"(foo _*)");
-        
-        assert!(sexp_match_fn(expr, pattern));
+    fn test_gin_keys_dedup_order() {
+        let keys = sexp_extract_keys(Sexp::input(c"(a b a \"s\" b 1 1 \"s\")"));
+        let unique = sexp_extract_keys(Sexp::input(c"(a b \"s\" 1)"));
+        assert_eq!(keys, unique);
+        let mut sorted = keys.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), keys.len());
     }
 
     #[pg_test]
-    fn test_pattern_no_match() {
-        let expr = Sexp::input(c"(foo bar)");
-        let pattern = Sexp::input(c"(foo baz)");
-        
-        assert!(!sexp_match_fn(expr, pattern));
+    fn test_gin_key_overflow() {
+        Spi::run("SET pg_sexp.gin_max_keys = 16").unwrap();
+        Spi::run("CREATE TABLE wide (doc sexp)").unwrap();
+        Spi::run(
+            "INSERT INTO wide SELECT ('(doc ' || string_agg(format('(f%s \"v%s\")', j, i * 100 + j), ' ') || ')')::sexp \
+             FROM generate_series(1, 50) i, generate_series(1, 10) j GROUP BY i",
+        )
+        .unwrap();
+        Spi::run("INSERT INTO wide VALUES ('(small (f1 \"v1\"))')").unwrap();
+        let keys = Spi::get_one::<Vec<i32>>("SELECT sexp_extract_keys(doc) FROM wide WHERE sexp_head_text(doc) = 'doc' LIMIT 1")
+            .unwrap()
+            .unwrap();
+        assert!(keys.len() <= 16);
+        assert!(keys.contains(&overflow_key(true)));
+
+        Spi::run("CREATE INDEX wide_gin ON wide USING gin (doc)").unwrap();
+        Spi::run("SET enable_seqscan = off").unwrap();
+        let found = Spi::get_one::<i64>("SELECT count(*) FROM wide WHERE doc @>> '(doc (f7 \"v307\"))'").unwrap();
+        let heads = Spi::get_one::<i64>("SELECT count(*) FROM wide WHERE doc ? 'f10'").unwrap();
+        Spi::run("RESET enable_seqscan").unwrap();
+        Spi::run("RESET pg_sexp.gin_max_keys").unwrap();
+        assert_eq!(found, Some(1));
+        assert_eq!(heads, Some(50));
     }
 
     #[pg_test]
-    fn test_key_containment() {
-        let container = Sexp::input(c"(user (id 100) (name \"John\") (age 30))");
-        let needle = Sexp::input(c"(name \"John\")");
-        
-        assert!(sexp_contains_key_impl(&container, &needle));
+    fn test_signature_prefilter() {
+        let compatible = Spi::get_one::<bool>(
+            "SELECT sexp_signature('(user (name \"alice\") (age 30))') \
+                 @>? sexp_signature('(age 30)')",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(compatible);
+        // Signatures are stored, so their value must not depend on the build
+        assert_eq!(sexp_signature(Sexp::input(c"a")), 134217760);
+
+        // The signature never rules out a row that @> would accept
+        let missed = Spi::get_one::<i64>(
+            "SELECT count(*) FROM generate_series(1, 500) i, \
+                 LATERAL (SELECT ('(item (id ' || i || ') (tag t' || i % 7 || '))')::sexp AS doc, \
+                                 ('(tag t' || i % 5 || ')')::sexp AS pattern) p \
+             WHERE doc @> pattern AND NOT sexp_signature(doc) @>? sexp_signature(pattern)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(missed, 0);
     }
 
     #[pg_test]
-    fn test_key_containment_nested() {
-        let container = Sexp::input(c"(data (user (id 100)))");
-        let needle = Sexp::input(c"(id 100)");
-        
-        assert!(sexp_contains_key_impl(&container, &needle));
+    fn test_sexp_table() {
+        let rows = Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT id, city, active, raw::text FROM sexp_table(\
+                     '(((id 1) (address ((city \"Oslo\"))) (active #t) (raw (a b))) \
+                       ((id 2) (raw 5)))', \
+                     '((id int) (city text address city) (active boolean) (raw sexp))') \
+                     AS t(id int, city text, active boolean, raw sexp)",
+                    None,
+                    &[],
+                )
+                .unwrap()
+                .map(|row| {
+                    (
+                        row.get::<i32>(1).unwrap().unwrap(),
+                        row.get::<String>(2).unwrap(),
+                        row.get::<bool>(3).unwrap(),
+                        row.get::<String>(4).unwrap().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            rows,
+            vec![
+                (1, Some("Oslo".to_string()), Some(true), "(a b)".to_string()),
+                (2, None, None, "5".to_string()),
+            ]
+        );
+
+        // Floats are cast from their exact value, not the printed one
+        Spi::run("SET pg_sexp.float_precision = 2").unwrap();
+        let ratio = Spi::get_one::<f64>(
+            "SELECT ratio FROM sexp_table('(((ratio 0.123456789)))', '((ratio float8))') AS t(ratio float8)",
+        )
+        .unwrap();
+        Spi::run("RESET pg_sexp.float_precision").unwrap();
+        assert_eq!(ratio, Some(0.123456789));
+        assert_eq!(exact_float_text(1e300), "1e300");
+        assert_eq!(exact_float_text(2.0), "2.0");
+    }
+
+    #[pg_test]
+    fn test_sexp_to_csv() {
+        let csv = Spi::get_one::<String>(
+            "SELECT sexp_to_csv('((id name note) (1 \"Smith, J\" \"say \\\"hi\\\"\") (2 \"\" ()) (3 \"a\nb\" (x y)))')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(csv, "id,name,note\n1,\"Smith, J\",\"say \"\"hi\"\"\"\n2,\"\",\n3,\"a\nb\",(x y)\n");
+    }
+
+    #[pg_test]
+    fn test_sexp_from_csv() {
+        let rows = Spi::get_one::<Sexp>(
+            "SELECT sexp_from_csv(E'1,\"Smith, J\",\\r\\n0150,2.5,\"say \"\"hi\"\"\"\\n', false)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(rows.to_string_repr(), "((1 \"Smith, J\" ()) (\"0150\" 2.5 \"say \\\"hi\\\"\"))");
+
+        let table = Spi::get_one::<Sexp>(
+            "SELECT sexp_from_csv(E'id,name\\n1,\"a\\nb\"\\n2,\"\"', true)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(table.to_string_repr(), "(((id 1) (name \"a\\nb\")) ((id 2) (name \"\")))");
+
+        // Integers past i64 stay exact instead of rounding through a float
+        let wide = Spi::get_one::<Sexp>("SELECT sexp_from_csv('12345678901234567890,-0.25', false)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(wide.to_string_repr(), "((12345678901234567890.0m -0.25))");
+        Spi::run("SET pg_sexp.exact_decimals = on").unwrap();
+        let exact = Spi::get_one::<Sexp>("SELECT sexp_from_csv('0.10,7', false)").unwrap().unwrap();
+        Spi::run("RESET pg_sexp.exact_decimals").unwrap();
+        assert_eq!(exact.to_string_repr(), "((0.10m 7))");
+    }
+
+    #[pg_test]
+    fn test_match_text_pattern() {
+        Spi::run("CREATE TABLE exprs (e sexp)").unwrap();
+        Spi::run("INSERT INTO exprs VALUES ('(define x 1)'), ('(set! x 2)'), ('(define y 3)')").unwrap();
+        let hits = Spi::get_one::<i64>("SELECT count(*) FROM exprs WHERE e ~ '(define _ _)'::text")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hits, 2);
+        let hits = Spi::get_one::<i64>(
+            "SELECT count(*) FROM exprs, (VALUES ('(set! _ _)'), ('(_ y _)')) p(pat) WHERE sexp_match(e, pat)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(hits, 2);
+
+        // The pattern text reads differently under other parser settings
+        let matching = "SELECT '(flag #t)'::sexp ~ '(flag true)'::text";
+        assert_eq!(Spi::get_one::<bool>(matching).unwrap(), Some(false));
+        Spi::run("SET pg_sexp.parse_true_false = on").unwrap();
+        assert_eq!(Spi::get_one::<bool>(matching).unwrap(), Some(true));
+        Spi::run("RESET pg_sexp.parse_true_false").unwrap();
+        let volatility = Spi::get_one::<String>(
+            "SELECT provolatile::text FROM pg_proc \
+             WHERE proname = 'sexp_match' AND proargtypes[1] = 'text'::regtype",
+        )
+        .unwrap();
+        assert_eq!(volatility.as_deref(), Some("s"));
+    }
+
+    #[pg_test]
+    fn test_parse_batch() {
+        let inputs = || vec![Some("(a 1)".to_string()), None, Some("(b".to_string())];
+        let rows: Vec<(i32, Option<String>, Option<String>)> = sexp_parse_batch_rows(inputs())
+            .map(|(i, value, error)| (i, value.map(|v| v.to_string_repr()), error))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (1, Some("(a 1)".to_string()), None),
+                (2, None, None),
+                (3, None, Some("unterminated list".to_string())),
+            ]
+        );
+        let parsed = sexp_parse_batch(inputs()[..2].to_vec());
+        assert_eq!(parsed[0].as_ref().map(|s| s.to_string_repr()).as_deref(), Some("(a 1)"));
+        assert!(parsed[1].is_none());
+    }
+
+    #[pg_test]
+    fn test_symbol_interning() {
+        let (a, b) = (intern_symbol("define"), intern_symbol("define"));
+        assert!(std::rc::Rc::ptr_eq(&a, &b));
+        let long = "x".repeat(SYMBOL_CACHE_MAX_LEN + 1);
+        assert!(!std::rc::Rc::ptr_eq(&intern_symbol(&long), &intern_symbol(&long)));
+        assert_eq!(Sexp::input(c"(define x (define y))").to_string_repr(), "(define x (define y))");
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (10)")]
+    fn test_max_depth_enforced() {
+        Spi::run("SET pg_sexp.max_depth = 10").unwrap();
+        let deep = Sexp::input(c"((((((((((((((x))))))))))))))");
+        deep.contains(&Sexp::input(c"y"));
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (10)")]
+    fn test_max_depth_occurrences() {
+        Spi::run("SET pg_sexp.max_depth = 10").unwrap();
+        sexp_occurrences(Sexp::input(c"((((((((((((((x))))))))))))))"), Sexp::input(c"y")).for_each(drop);
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (10)")]
+    fn test_max_depth_path_of() {
+        Spi::run("SET pg_sexp.max_depth = 10").unwrap();
+        sexp_path_of(Sexp::input(c"((((((((((((((x))))))))))))))"), Sexp::input(c"y"));
+    }
+
+    #[pg_test(error = "sexp nesting depth exceeds pg_sexp.max_depth (10)")]
+    fn test_max_depth_equiv() {
+        Spi::run("SET pg_sexp.max_depth = 10").unwrap();
+        let deep = || Sexp::input(c"((((((((((((((x))))))))))))))");
+        sexp_equiv(deep(), deep());
     }
 }
 
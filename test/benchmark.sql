@@ -735,6 +735,25 @@ GROUP BY data->'user'->>'id'
 LIMIT 5;
 \timing off
 
+\echo ''
+\echo '================================================================================'
+\echo 'TEST 12: GIN INDEX BUILD ON WIDE DOCUMENTS (20K rows, ~1000 distinct atoms each)'
+\echo '================================================================================'
+
+DROP TABLE IF EXISTS bench_sexp_wide CASCADE;
+CREATE TABLE bench_sexp_wide (id serial PRIMARY KEY, data sexp);
+
+INSERT INTO bench_sexp_wide (data)
+SELECT ('(doc ' || string_agg(format('(f%s "v%s")', j, i * 1000 + j), ' ') || ')')::sexp
+FROM generate_series(1, 20000) i, generate_series(1, 500) j
+GROUP BY i;
+
+\echo ''
+\echo '--- sexp: GIN index build on wide documents ---'
+\timing on
+CREATE INDEX bench_sexp_wide_idx ON bench_sexp_wide USING gin (data sexp_gin_ops);
+\timing off
+
 \echo ''
 \echo '================================================================================'
 \echo 'CLEANUP'
@@ -748,6 +767,7 @@ DROP TABLE IF EXISTS bench_sexp_deep CASCADE;
 DROP TABLE IF EXISTS bench_jsonb_deep CASCADE;
 DROP TABLE IF EXISTS bench_sexp_gin CASCADE;
 DROP TABLE IF EXISTS bench_jsonb_gin CASCADE;
+DROP TABLE IF EXISTS bench_sexp_wide CASCADE;
 
 \echo ''
 \echo '================================================================================'